@@ -172,6 +172,11 @@ fn main() {
         cfg.debug(false);
     }
 
+    let llvm_polly = tracked_env_var_os("LLVM_POLLY").is_some();
+    if llvm_polly {
+        cfg.define("LLVM_POLLY", None);
+    }
+
     build_helper::rerun_if_changed_anything_in_dir(Path::new("llvm-wrapper"));
     cfg.file("llvm-wrapper/PassWrapper.cpp")
         .file("llvm-wrapper/RustWrapper.cpp")
@@ -184,6 +189,15 @@ fn main() {
 
     let (llvm_kind, llvm_link_arg) = detect_llvm_link();
 
+    // Polly isn't an LLVM component, so `llvm-config --libs` won't mention it.
+    // When linking LLVM statically we have to pull in its archives ourselves,
+    // before the LLVM libraries they depend on. A shared libLLVM already has
+    // Polly linked into it.
+    if llvm_polly && llvm_kind == "static" {
+        println!("cargo:rustc-link-lib=static=Polly");
+        println!("cargo:rustc-link-lib=static=PollyISL");
+    }
+
     // Link in all LLVM libraries, if we're using the "wrong" llvm-config then
     // we don't pick up system libs because unfortunately they're for the host
     // of llvm-config, not the target that we're attempting to link.
@@ -36,6 +36,10 @@
 #include "llvm/Transforms/Utils/CanonicalizeAliases.h"
 #include "llvm/Transforms/Utils/NameAnonGlobals.h"
 
+#ifdef LLVM_POLLY
+#include "polly/RegisterPasses.h"
+#endif
+
 using namespace llvm;
 
 typedef struct LLVMOpaquePass *LLVMPassRef;
@@ -60,6 +64,9 @@ extern "C" void LLVMInitializePasses() {
   initializeInstCombine(Registry);
   initializeInstrumentation(Registry);
   initializeTarget(Registry);
+#ifdef LLVM_POLLY
+  polly::initializePollyPasses(Registry);
+#endif
 }
 
 extern "C" void LLVMTimeTraceProfilerInitialize() {
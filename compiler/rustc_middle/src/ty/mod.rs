@@ -2243,6 +2243,9 @@ pub struct ReprFlags: u8 {
         const IS_LINEAR          = 1 << 3;
         // If true, don't expose any niche to type's context.
         const HIDE_NICHE         = 1 << 4;
+        // If true, shuffle the fields with `field_shuffle_seed` instead of
+        // sorting them (`-Z randomize-layout`).
+        const RANDOMIZE_LAYOUT   = 1 << 5;
         // Any of these flags being set prevent field reordering optimisation.
         const IS_UNOPTIMISABLE   = ReprFlags::IS_C.bits |
                                    ReprFlags::IS_SIMD.bits |
@@ -2257,6 +2260,8 @@ pub struct ReprOptions {
     pub align: Option<Align>,
     pub pack: Option<Align>,
     pub flags: ReprFlags,
+    /// The seed used to shuffle the fields when `ReprFlags::RANDOMIZE_LAYOUT` is set.
+    pub field_shuffle_seed: u64,
 }
 
 impl ReprOptions {
@@ -2297,7 +2302,16 @@ pub fn new(tcx: TyCtxt<'_>, did: DefId) -> ReprOptions {
         if !tcx.consider_optimizing(|| format!("Reorder fields of {:?}", tcx.def_path_str(did))) {
             flags.insert(ReprFlags::IS_LINEAR);
         }
-        ReprOptions { int: size, align: max_align, pack: min_pack, flags }
+
+        // Likewise, every crate must agree on how the fields are shuffled, so
+        // the seed only depends on the type's definition.
+        let mut field_shuffle_seed = 0;
+        if tcx.sess.opts.debugging_opts.randomize_layout {
+            flags.insert(ReprFlags::RANDOMIZE_LAYOUT);
+            let seed = tcx.sess.opts.debugging_opts.layout_seed.unwrap_or(0) as u64;
+            field_shuffle_seed = tcx.def_path_hash(did).0.to_smaller_hash() ^ seed;
+        }
+        ReprOptions { int: size, align: max_align, pack: min_pack, flags, field_shuffle_seed }
     }
 
     #[inline]
@@ -2324,6 +2338,10 @@ pub fn linear(&self) -> bool {
     pub fn hide_niche(&self) -> bool {
         self.flags.contains(ReprFlags::HIDE_NICHE)
     }
+    #[inline]
+    pub fn randomize_layout(&self) -> bool {
+        self.flags.contains(ReprFlags::RANDOMIZE_LAYOUT)
+    }
 
     /// Returns the discriminant type, given these `repr` options.
     /// This must only be called on enums!
@@ -163,7 +163,7 @@ pub fn run_passes(
     passes: &[&[&dyn MirPass<'tcx>]],
 ) {
     let phase_index = mir_phase.phase_index();
-    let validate = tcx.sess.opts.debugging_opts.validate_mir;
+    let validate = tcx.sess.validate_mir();
 
     if body.phase >= mir_phase {
         return;
@@ -735,6 +735,12 @@ fn parse_wasi_exec_model(slot: &mut Option<WasiExecModel>, v: Option<&str>) -> b
     }
 ) }
 
+/// The default of `-Z threads`, which is 1 unless the build system configured
+/// a different default for a parallel compiler.
+fn default_threads() -> usize {
+    option_env!("CFG_DEFAULT_THREADS").and_then(|n| n.parse().ok()).unwrap_or(1)
+}
+
 options! {CodegenOptions, CodegenSetter, basic_codegen_options,
           build_codegen_options, "C", "codegen",
           CG_OPTIONS, cg_type_desc, cgsetters,
@@ -976,6 +982,8 @@ fn parse_wasi_exec_model(slot: &mut Option<WasiExecModel>, v: Option<&str>) -> b
         "insert function instrument code for mcount-based tracing (default: no)"),
     keep_hygiene_data: bool = (false, parse_bool, [UNTRACKED],
         "keep hygiene data after analysis (default: no)"),
+    layout_seed: Option<usize> = (None, parse_opt_uint, [TRACKED],
+        "seed used to shuffle struct fields with `-Z randomize-layout`"),
     link_native_libraries: bool = (true, parse_bool, [UNTRACKED],
         "link native libraries in the linker invocation (default: yes)"),
     link_only: bool = (false, parse_bool, [TRACKED],
@@ -1067,6 +1075,9 @@ fn parse_wasi_exec_model(slot: &mut Option<WasiExecModel>, v: Option<&str>) -> b
         "enable queries of the dependency graph for regression testing (default: no)"),
     query_stats: bool = (false, parse_bool, [UNTRACKED],
         "print some statistics about the query system (default: no)"),
+    randomize_layout: bool = (false, parse_bool, [TRACKED],
+        "randomize the order of the fields of structs and enum variants that don't have a \
+        fixed layout (default: no)"),
     relax_elf_relocations: Option<bool> = (None, parse_opt_bool, [TRACKED],
         "whether ELF relocations can be relaxed"),
     relro_level: Option<RelroLevel> = (None, parse_relro_level, [TRACKED],
@@ -1129,10 +1140,10 @@ fn parse_wasi_exec_model(slot: &mut Option<WasiExecModel>, v: Option<&str>) -> b
     thinlto: Option<bool> = (None, parse_opt_bool, [TRACKED],
         "enable ThinLTO when possible"),
     // We default to 1 here since we want to behave like
-    // a sequential compiler for now. This'll likely be adjusted
-    // in the future. Note that -Zthreads=0 is the way to get
-    // the num_cpus behavior.
-    threads: usize = (1, parse_threads, [UNTRACKED],
+    // a sequential compiler for now, unless a different default
+    // was configured when building a parallel compiler. Note that
+    // -Zthreads=0 is the way to get the num_cpus behavior.
+    threads: usize = (default_threads(), parse_threads, [UNTRACKED],
         "use a thread pool with N threads"),
     time: bool = (false, parse_bool, [UNTRACKED],
         "measure time of rustc processes (default: no)"),
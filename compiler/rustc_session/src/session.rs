@@ -628,6 +628,9 @@ pub fn asm_comments(&self) -> bool {
     pub fn verify_llvm_ir(&self) -> bool {
         self.opts.debugging_opts.verify_llvm_ir || option_env!("RUSTC_VERIFY_LLVM_IR").is_some()
     }
+    pub fn validate_mir(&self) -> bool {
+        self.opts.debugging_opts.validate_mir || option_env!("RUSTC_VALIDATE_MIR").is_some()
+    }
     pub fn borrowck_stats(&self) -> bool {
         self.opts.debugging_opts.borrowck_stats
     }
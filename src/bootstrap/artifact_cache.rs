@@ -0,0 +1,330 @@
+//! The shared artifact cache configured with `build.artifact-cache`.
+//!
+//! Developers of a team, and CI, keep building the same std and compilers
+//! from the same sources. With a cache configured, the artifacts of std and
+//! rustc are looked up by a key hashing everything they're built from (the
+//! sources, the options of `config.toml` which change how they're built and
+//! the environment variables that reach the build), and LLVM by the key of
+//! the archives of `x.py export-llvm`. If the cache has them, they are
+//! unpacked and linked into the sysroot instead of building them; otherwise
+//! they are built as usual, and stored in the cache if
+//! `build.artifact-cache-upload` is set.
+//!
+//! The cache is a directory, an HTTP(S) URL which archives are fetched from
+//! with `GET` and stored to with `PUT`, or an `s3://` URL, which is accessed
+//! with the AWS CLI. Artifacts are only cached for committed sources, since
+//! the key is made of the ids the VCS has for them.
+//!
+//! Keys are hashed with SHA-256, which needs the `artifact-cache` feature of
+//! bootstrap. x.py enables it once `config.toml` sets `build.artifact-cache`
+//! or `llvm.prebuilt-archive`, or for `x.py export-llvm`.
+
+use std::collections::hash_map::DefaultHasher;
+use std::env;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str;
+
+use build_helper::t;
+#[cfg(feature = "artifact-cache")]
+use sha2::{Digest, Sha256};
+
+use crate::builder::Builder;
+use crate::config::{Config, TargetSelection};
+use crate::{Compiler, DependencyType};
+
+/// Environment variables which change how std and rustc are built.
+const ENV_INPUTS: &[&str] = &[
+    "RUSTFLAGS",
+    "RUSTFLAGS_BOOTSTRAP",
+    "RUSTFLAGS_NOT_BOOTSTRAP",
+    "CFLAGS",
+    "CXXFLAGS",
+    "LDFLAGS",
+    "MACOSX_DEPLOYMENT_TARGET",
+];
+
+/// The name of the list of artifacts in an archive of `store_stamped`, in
+/// the format of the stamps of `compile::run_cargo` but with file names.
+const MANIFEST: &str = "artifacts.stamp";
+
+/// Returns the key of the artifacts of `what`, built by `compiler` for
+/// `target` from the paths `inputs` of the source tree, or `None` if they
+/// can't be cached: no cache is configured, this is a dry run, or some of
+/// `inputs` differ from what is committed.
+pub fn key(
+    builder: &Builder<'_>,
+    what: &str,
+    compiler: Compiler,
+    target: TargetSelection,
+    inputs: &[&str],
+) -> Option<String> {
+    if builder.config.artifact_cache.is_none() || builder.config.dry_run {
+        return None;
+    }
+    let vcs = builder.vcs;
+    if vcs.has_uncommitted_changes(&builder.src, inputs)? {
+        builder.verbose(&format!("not caching {}: its sources have uncommitted changes", what));
+        return None;
+    }
+    let mut parts = Vec::new();
+    for input in inputs {
+        parts.push(vcs.content_id(&builder.src, input)?);
+    }
+    parts.push(format!("{} {} {} {}", what, compiler.stage, compiler.host, target));
+    parts.push(config_inputs(&builder.config, target));
+    for var in ENV_INPUTS {
+        parts.push(format!("{}={:?}", var, env::var_os(var)));
+    }
+    let mut data = String::new();
+    for part in parts {
+        data.push_str(&part);
+        data.push('\0');
+    }
+    let hash = shared_hash(data.as_bytes())?;
+    Some(format!("{}-stage{}-{}-{}", what, compiler.stage, target.triple, hash))
+}
+
+/// The options of `config.toml` which change how std and rustc are built for
+/// `target`. Other options, like `build.jobs` or the install directories,
+/// don't change the artifacts, so they don't keep builds from sharing them.
+fn config_inputs(config: &Config, target: TargetSelection) -> String {
+    let mut profiles = config
+        .rust_stage_profiles
+        .iter()
+        .map(|(stage, profile)| format!("stage{}: {:?}", stage, profile))
+        .chain(
+            config
+                .rust_package_overrides
+                .iter()
+                .map(|(package, profile)| format!("{}: {:?}", package, profile)),
+        )
+        .collect::<Vec<_>>();
+    profiles.sort();
+    let target_config = config.target_config.get(&target);
+    let rust_inputs = (
+        (&config.channel, &config.description, config.rust_optimize, config.rust_rpath),
+        (config.rust_codegen_units, config.rust_codegen_units_std),
+        (config.rust_debug_assertions, config.rust_debug_assertions_std),
+        (config.rust_overflow_checks, config.rust_overflow_checks_std),
+        (config.rust_debuginfo_level_rustc, config.rust_debuginfo_level_std),
+        (config.rust_debug_logging, config.rust_remap_debuginfo, config.rust_verify_llvm_ir),
+        (config.rust_new_symbol_mangling, config.rust_validate_mir_opts),
+        (config.rust_randomize_layout, config.rust_layout_seed, &config.rust_codegen_backends),
+        (config.rust_thin_lto_import_instr_limit, &config.rustc_default_linker),
+        (&config.rust_profile_generate, &config.rust_profile_use, profiles),
+        (config.rustc_parallel, config.jemalloc, config.backtrace, &config.std_features),
+    );
+    let target_inputs = (
+        config.control_flow_guard,
+        config.llvm_libunwind,
+        config.sanitizers_enabled(target),
+        config.profiler_enabled(target),
+        target_config.and_then(|t| t.crt_static),
+        (config.llvm_assertions, config.llvm_link_shared, config.llvm_static_stdcpp),
+    );
+    format!("{:?} {:?}", rust_inputs, target_inputs)
+}
+
+/// A hash of `config_inputs`, which identifies the configuration the output
+/// for `target` is built with.
+pub fn config_hash(config: &Config, target: TargetSelection) -> String {
+    let mut hasher = DefaultHasher::new();
+    config_inputs(config, target).hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+/// Hashes `data` into part of a key, returning the first 8 bytes of its
+/// SHA-256 in hex, or `None` without the `artifact-cache` feature. Keys are
+/// shared between machines, so they're hashed with SHA-256 rather than a
+/// hasher which may differ between builds of bootstrap.
+#[cfg(feature = "artifact-cache")]
+pub fn shared_hash(data: &[u8]) -> Option<String> {
+    Some(Sha256::digest(data)[..8].iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+#[cfg(not(feature = "artifact-cache"))]
+pub fn shared_hash(_data: &[u8]) -> Option<String> {
+    None
+}
+
+/// Fetches the archive stored under `name`, returning its path in the local
+/// download cache if the cache has it.
+pub fn fetch(builder: &Builder<'_>, name: &str) -> Option<PathBuf> {
+    let cache = builder.config.artifact_cache.as_ref()?;
+    let dir = builder.out.join("cache").join("artifacts");
+    let dst = dir.join(name);
+    if dst.exists() {
+        return Some(dst);
+    }
+    if builder.config.dry_run {
+        return None;
+    }
+    t!(fs::create_dir_all(&dir));
+    let tmp = dir.join(format!("{}.partial", name));
+    let fetched = match Backend::of(cache) {
+        Backend::Dir(cache) => fs::copy(cache.join(name), &tmp).is_ok(),
+        Backend::Http(url) => Command::new("curl")
+            .arg("-fsSL")
+            .arg("-o")
+            .arg(&tmp)
+            .arg(format!("{}/{}", url, name))
+            .status()
+            .map_or(false, |status| status.success()),
+        Backend::S3(url) => Command::new("aws")
+            .args(&["s3", "cp", "--quiet"])
+            .arg(format!("{}/{}", url, name))
+            .arg(&tmp)
+            .status()
+            .map_or(false, |status| status.success()),
+    };
+    if !fetched {
+        let _ = fs::remove_file(&tmp);
+        builder.verbose(&format!("{} is not in the artifact cache", name));
+        return None;
+    }
+    t!(fs::rename(&tmp, &dst));
+    Some(dst)
+}
+
+/// Stores `archive` in the cache under `name`, if uploads are enabled.
+/// Failing to store it only warns, since the build itself succeeded.
+pub fn store(builder: &Builder<'_>, name: &str, archive: &Path) {
+    let cache = match &builder.config.artifact_cache {
+        Some(cache) if builder.config.artifact_cache_upload && !builder.config.dry_run => cache,
+        _ => return,
+    };
+    builder.info(&format!("Storing {} in the artifact cache", name));
+    let stored = match Backend::of(cache) {
+        Backend::Dir(cache) => {
+            // Copy to a temporary name first, so that concurrent builds never
+            // see a partial archive.
+            let tmp = cache.join(format!("{}.{}.partial", name, std::process::id()));
+            t!(fs::create_dir_all(&cache));
+            fs::copy(archive, &tmp).and_then(|_| fs::rename(&tmp, cache.join(name))).is_ok()
+        }
+        Backend::Http(url) => Command::new("curl")
+            .arg("-fsS")
+            .arg("-T")
+            .arg(archive)
+            .arg(format!("{}/{}", url, name))
+            .status()
+            .map_or(false, |status| status.success()),
+        Backend::S3(url) => Command::new("aws")
+            .args(&["s3", "cp", "--quiet"])
+            .arg(archive)
+            .arg(format!("{}/{}", url, name))
+            .status()
+            .map_or(false, |status| status.success()),
+    };
+    if !stored {
+        println!("warning: failed to store {} in the artifact cache {}", name, cache);
+    }
+}
+
+/// Restores the artifacts stored under `key` by `store_stamped` into `dir`
+/// and writes a stamp listing them and `target_deps`, the artifacts copied
+/// into the sysroot apart from cargo, to `stamp`, returning whether the cache
+/// had them.
+pub fn fetch_stamped(
+    builder: &Builder<'_>,
+    key: &str,
+    dir: &Path,
+    stamp: &Path,
+    target_deps: &[(PathBuf, DependencyType)],
+) -> bool {
+    let archive = match fetch(builder, &archive_name(key)) {
+        Some(archive) => archive,
+        None => return false,
+    };
+    builder.info(&format!("Using {} from the artifact cache", key));
+    if dir.exists() {
+        t!(fs::remove_dir_all(dir));
+    }
+    t!(fs::create_dir_all(dir));
+    builder.run(Command::new("tar").arg("-xzf").arg(&archive).arg("-C").arg(dir));
+
+    let manifest = t!(fs::read(dir.join(MANIFEST)));
+    let mut contents = Vec::new();
+    for part in manifest.split(|b| *b == 0).filter(|part| !part.is_empty()) {
+        contents.push(part[0]);
+        let path = dir.join(t!(str::from_utf8(&part[1..])));
+        contents.extend(path.to_str().unwrap().as_bytes());
+        contents.push(0);
+    }
+    for (path, dependency_type) in target_deps {
+        contents.push(match dependency_type {
+            DependencyType::Host => b'h',
+            DependencyType::Target => b't',
+            DependencyType::TargetSelfContained => b's',
+        });
+        contents.extend(path.to_str().unwrap().as_bytes());
+        contents.push(0);
+    }
+    t!(fs::write(stamp, &contents));
+    true
+}
+
+/// Packs the artifacts listed in `stamp` and stores them under `key`, except
+/// for `target_deps`, which `fetch_stamped` is given again.
+pub fn store_stamped(
+    builder: &Builder<'_>,
+    key: &str,
+    stamp: &Path,
+    target_deps: &[(PathBuf, DependencyType)],
+) {
+    if !builder.config.artifact_cache_upload || builder.config.dry_run {
+        return;
+    }
+    let dir = builder.out.join("tmp").join("artifact-cache").join(key);
+    if dir.exists() {
+        t!(fs::remove_dir_all(&dir));
+    }
+    t!(fs::create_dir_all(&dir));
+
+    // The artifacts end up flat in the sysroot, so they're stored flat too.
+    let mut manifest = Vec::new();
+    for part in t!(fs::read(stamp)).split(|b| *b == 0).filter(|part| !part.is_empty()) {
+        let path = Path::new(t!(str::from_utf8(&part[1..])));
+        if target_deps.iter().any(|(dep, _)| dep == path) {
+            continue;
+        }
+        let name = path.file_name().unwrap();
+        builder.copy(path, &dir.join(name));
+        manifest.push(part[0]);
+        manifest.extend(name.to_str().unwrap().as_bytes());
+        manifest.push(0);
+    }
+    t!(fs::write(dir.join(MANIFEST), &manifest));
+
+    let archive = builder.out.join("tmp").join("artifact-cache").join(archive_name(key));
+    builder.run(Command::new("tar").arg("-czf").arg(&archive).arg("-C").arg(&dir).arg("."));
+    store(builder, &archive_name(key), &archive);
+    t!(fs::remove_dir_all(&dir));
+    t!(fs::remove_file(&archive));
+}
+
+fn archive_name(key: &str) -> String {
+    format!("{}.tar.gz", key)
+}
+
+enum Backend<'a> {
+    Dir(PathBuf),
+    Http(&'a str),
+    S3(&'a str),
+}
+
+impl<'a> Backend<'a> {
+    fn of(cache: &'a str) -> Backend<'a> {
+        let url = cache.trim_end_matches('/');
+        if url.starts_with("http://") || url.starts_with("https://") {
+            Backend::Http(url)
+        } else if url.starts_with("s3://") {
+            Backend::S3(url)
+        } else {
+            Backend::Dir(PathBuf::from(cache))
+        }
+    }
+}
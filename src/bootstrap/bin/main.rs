@@ -7,7 +7,7 @@
 
 use std::env;
 
-use bootstrap::{Build, Config, Subcommand, VERSION};
+use bootstrap::{change_tracker, Build, Config, Subcommand, VERSION};
 
 fn main() {
     let args = env::args().skip(1).collect::<Vec<_>>();
@@ -16,10 +16,14 @@ fn main() {
     // check_version warnings are not printed during setup
     let changelog_suggestion =
         if matches!(config.cmd, Subcommand::Setup {..}) { None } else { check_version(&config) };
+    // nor when stdout is reserved for the JSON of `--dry-run --json`
+    let changelog_suggestion = changelog_suggestion.filter(|_| !config.json_plan);
 
     // NOTE: Since `./configure` generates a `config.toml`, distro maintainers will see the
     // changelog warning, not the `x.py setup` message.
-    let suggest_setup = !config.config.exists() && !matches!(config.cmd, Subcommand::Setup { .. });
+    let suggest_setup = !config.config.exists()
+        && !matches!(config.cmd, Subcommand::Setup { .. })
+        && !config.json_plan;
     if suggest_setup {
         println!("warning: you have not made a `config.toml`");
         println!("help: consider running `x.py setup` or copying `config.toml.example`");
@@ -42,21 +46,30 @@ fn main() {
 }
 
 fn check_version(config: &Config) -> Option<String> {
+    let latest = change_tracker::latest_change_id();
     let mut msg = String::new();
 
-    let suggestion = if let Some(seen) = config.changelog_seen {
-        if seen != VERSION {
-            msg.push_str("warning: there have been changes to x.py since you last updated.\n");
-            format!("update `config.toml` to use `changelog-seen = {}` instead", VERSION)
-        } else {
+    let suggestion = if let Some(id) = config.change_id {
+        if change_tracker::changes_since(id).is_empty() {
             return None;
         }
+        format!("update `config.toml` to use `change-id = {}` instead", latest)
     } else {
-        msg.push_str("warning: x.py has made several changes recently you may want to look at\n");
-        format!("add `changelog-seen = {}` at the top of `config.toml`", VERSION)
+        format!("add `change-id = {}` at the top of `config.toml`", latest)
     };
 
-    msg.push_str("help: consider looking at the changes in `src/bootstrap/CHANGELOG.md`\n");
+    // Configs predating `change-id` that have seen the current changelog version missed exactly
+    // the changes in the registry, so those can be listed too.
+    if config.change_id.is_none() && config.changelog_seen != Some(VERSION) {
+        msg.push_str("warning: x.py has made several changes recently you may want to look at\n");
+        msg.push_str("help: consider looking at the changes in `src/bootstrap/CHANGELOG.md`\n");
+    } else {
+        msg.push_str("warning: there have been changes to x.py since you last updated:\n");
+        for change in change_tracker::changes_since(config.change_id.unwrap_or(0)) {
+            msg.push_str(&format!("  - [{}] {}\n", change.severity, change.summary));
+        }
+    }
+
     msg.push_str("note: to silence this warning, ");
     msg.push_str(&suggestion);
 
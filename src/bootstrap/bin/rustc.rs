@@ -16,7 +16,7 @@
 //! never get replaced.
 
 use std::env;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::str::FromStr;
 use std::time::Instant;
@@ -46,14 +46,23 @@ fn main() {
     };
     let stage = env::var("RUSTC_STAGE").expect("RUSTC_STAGE was not set");
     let sysroot = env::var_os("RUSTC_SYSROOT").expect("RUSTC_SYSROOT was not set");
-    let on_fail = env::var_os("RUSTC_ON_FAIL").map(Command::new);
+    let on_fail = env::var_os("RUSTC_ON_FAIL");
 
     let rustc = env::var_os(rustc).unwrap_or_else(|| panic!("{:?} was not set", rustc));
     let libdir = env::var_os(libdir).unwrap_or_else(|| panic!("{:?} was not set", libdir));
     let mut dylib_path = bootstrap::util::dylib_path();
     dylib_path.insert(0, PathBuf::from(&libdir));
 
-    let mut cmd = Command::new(rustc);
+    // Cargo only asks for the version to identify the compiler, which isn't
+    // worth caching.
+    let mut cmd = match env::var_os("RUSTC_CACHE_WRAPPER") {
+        Some(wrapper) if version.is_none() => {
+            let mut cmd = Command::new(wrapper);
+            cmd.arg(rustc);
+            cmd
+        }
+        _ => Command::new(rustc),
+    };
     cmd.args(&args).env(bootstrap::util::dylib_path_var(), env::join_paths(&dylib_path).unwrap());
 
     // Get the name of the crate we're compiling, if any.
@@ -138,13 +147,10 @@ fn main() {
         cmd.arg("-Z").arg("force-unstable-if-unmarked");
     }
 
+    let dylib_path = env::join_paths(&dylib_path).unwrap();
+    let overrides = [(bootstrap::util::dylib_path_var(), &*dylib_path)];
     if verbose > 1 {
-        eprintln!(
-            "rustc command: {:?}={:?} {:?}",
-            bootstrap::util::dylib_path_var(),
-            env::join_paths(&dylib_path).unwrap(),
-            cmd,
-        );
+        eprintln!("rustc command: {}", bootstrap::util::reproduction(&cmd, &overrides));
         eprintln!("sysroot: {:?}", sysroot);
         eprintln!("libdir: {:?}", libdir);
     }
@@ -178,8 +184,41 @@ fn main() {
         println!("\nDid not run successfully: {}\n{:?}\n-------------", status, cmd);
     }
 
-    if let Some(mut on_fail) = on_fail {
-        on_fail.status().expect("Could not run the on_fail command");
+    match on_fail {
+        // Write a script running the command again, so that it can be
+        // debugged with the environment it failed in.
+        Some(on_fail) if on_fail == "write-script" => {
+            let dir =
+                env::var_os("RUSTC_ON_FAIL_SCRIPTS").expect("RUSTC_ON_FAIL_SCRIPTS was not set");
+            // Crates are built for several stages and targets, and all build
+            // scripts are called `build_script_build`, so tell them apart by
+            // the metadata cargo gives each of its units too.
+            let metadata = args
+                .windows(2)
+                .filter(|w| &*w[0] == "-C")
+                .find_map(|w| w[1].to_str().and_then(|arg| arg.strip_prefix("metadata=")));
+            let mut name = format!(
+                "rustc-{}-stage{}-{}",
+                crate_name.unwrap_or("unknown"),
+                stage,
+                target.and_then(|target| Path::new(target).file_stem()?.to_str()).unwrap_or("host")
+            );
+            if let Some(metadata) = metadata {
+                name.push_str(&format!("-{}", metadata));
+            }
+            let script = bootstrap::util::write_reproduction_script(
+                &PathBuf::from(dir),
+                &name,
+                &cmd,
+                &overrides,
+                &format!("rustc exited with {}", status),
+            );
+            eprintln!("Wrote a script running the failed rustc command to {}", script.display());
+        }
+        Some(on_fail) => {
+            Command::new(on_fail).status().expect("Could not run the on_fail command");
+        }
+        None => {}
     }
 
     // Preserve the exit code. In case of signal, exit with 0xfe since it's
@@ -52,12 +52,9 @@ fn main() {
     }
 
     if verbose > 1 {
-        eprintln!(
-            "rustdoc command: {:?}={:?} {:?}",
-            bootstrap::util::dylib_path_var(),
-            env::join_paths(&dylib_path).unwrap(),
-            cmd,
-        );
+        let dylib_path = env::join_paths(&dylib_path).unwrap();
+        let overrides = [(bootstrap::util::dylib_path_var(), &*dylib_path)];
+        eprintln!("rustdoc command: {}", bootstrap::util::reproduction(&cmd, &overrides));
         eprintln!("sysroot: {:?}", sysroot);
         eprintln!("libdir: {:?}", libdir);
     }
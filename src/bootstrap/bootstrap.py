@@ -143,6 +143,10 @@ def run(args, verbose=False, exception=False, **kwargs):
     if verbose:
         print("running: " + ' '.join(args))
     sys.stdout.flush()
+    if sys.stdout is not sys.__stdout__:
+        # The output of this script is sent to stderr with `--json`, see
+        # `main`, and so is that of the programs it runs.
+        kwargs.setdefault('stdout', sys.stdout)
     # Use Popen here instead of call() as it apparently allows powershell on
     # Windows to not lock up waiting for input presumably.
     ret = subprocess.Popen(args, **kwargs)
@@ -187,6 +191,31 @@ def format_build_time(duration):
     return str(datetime.timedelta(seconds=int(duration)))
 
 
+def use_color(argv, isatty, env):
+    """Whether to color messages, following the `--color` option of x.py
+
+    >>> use_color(['build', '--color=never'], True, {})
+    False
+    >>> use_color(['build', '--color', 'always'], False, {})
+    True
+    >>> use_color(['build'], False, {'GITHUB_ACTIONS': 'true'})
+    True
+    >>> use_color(['build'], False, {})
+    False
+    """
+    color = 'auto'
+    for i, arg in enumerate(argv):
+        if arg.startswith('--color='):
+            color = arg[len('--color='):]
+        elif arg == '--color' and i + 1 < len(argv):
+            color = argv[i + 1]
+    if color == 'auto':
+        # The logs of CI aren't a TTY, but they are displayed with colors
+        in_ci = env.get('GITHUB_ACTIONS') == 'true' or env.get('TF_BUILD') == 'True'
+        return isatty or in_ci
+    return color == 'always'
+
+
 def default_build_triple(verbose):
     """Build triple as in LLVM"""
     # If the user already has a host build triple with an existing `rustc`
@@ -371,12 +400,15 @@ class RustBuild(object):
         self.build_dir = ''
         self.clean = False
         self.config_toml = ''
+        self.config_dir = None
         self.rust_root = ''
         self.use_locked_deps = ''
         self.use_vendored_sources = ''
         self.verbose = False
+        self.warnings = 'default'
         self.git_version = None
         self.nix_deps_dir = None
+        self._should_fix_bins_and_dylibs = None
 
     def download_stage0(self):
         """Fetch the build system for Rust, written in Rust
@@ -471,8 +503,7 @@ class RustBuild(object):
     def _download_stage0_helper(self, filename, pattern, tarball_suffix, date=None):
         if date is None:
             date = self.date
-        cache_dst = os.path.join(self.build_dir, "cache")
-        rustc_cache = os.path.join(cache_dst, date)
+        rustc_cache = os.path.join(self.cache_root(), date)
         if not os.path.exists(rustc_cache):
             os.makedirs(rustc_cache)
 
@@ -480,12 +511,12 @@ class RustBuild(object):
         tarball = os.path.join(rustc_cache, filename)
         if not os.path.exists(tarball):
             get("{}/{}".format(url, filename), tarball, verbose=self.verbose)
+        self.link_into_build_cache(tarball, date)
         unpack(tarball, tarball_suffix, self.bin_root(), match=pattern, verbose=self.verbose)
 
     def _download_ci_llvm(self, llvm_sha, llvm_assertions):
         cache_prefix = "llvm-{}-{}".format(llvm_sha, llvm_assertions)
-        cache_dst = os.path.join(self.build_dir, "cache")
-        rustc_cache = os.path.join(cache_dst, cache_prefix)
+        rustc_cache = os.path.join(self.cache_root(), cache_prefix)
         if not os.path.exists(rustc_cache):
             os.makedirs(rustc_cache)
 
@@ -502,41 +533,75 @@ class RustBuild(object):
         tarball = os.path.join(rustc_cache, filename)
         if not os.path.exists(tarball):
             get("{}/{}".format(url, filename), tarball, verbose=self.verbose, do_verify=False)
+        self.link_into_build_cache(tarball, cache_prefix)
         unpack(tarball, tarball_suffix, self.llvm_root(),
                 match="rust-dev",
                 verbose=self.verbose)
 
-    def fix_bin_or_dylib(self, fname, rpath_libz=False):
-        """Modifies the interpreter section of 'fname' to fix the dynamic linker,
-        or the RPATH section, to fix the dynamic library search path
+    def should_fix_bins_and_dylibs(self):
+        """Whether downloaded binaries and libraries need to be patched for Nix
 
-        This method is only required on NixOS and uses the PatchELF utility to
-        change the interpreter/RPATH of ELF executables.
+        This is the value of `build.patch-binaries-for-nix` if it is set, and
+        otherwise whether we seem to be running on NixOS.
 
-        Please see https://nixos.org/patchelf.html for more information
+        >>> rb = RustBuild()
+        >>> rb.config_toml = '[build]\\npatch-binaries-for-nix = true'
+        >>> rb.should_fix_bins_and_dylibs()
+        True
         """
+        if self._should_fix_bins_and_dylibs is None:
+            self._should_fix_bins_and_dylibs = self._detect_nix()
+        return self._should_fix_bins_and_dylibs
+
+    def _detect_nix(self):
+        patch_for_nix = self.get_toml('patch-binaries-for-nix', 'build')
+        if patch_for_nix is not None:
+            return patch_for_nix == 'true'
+
         default_encoding = sys.getdefaultencoding()
         try:
             ostype = subprocess.check_output(
                 ['uname', '-s']).strip().decode(default_encoding)
         except subprocess.CalledProcessError:
-            return
+            return False
         except OSError as reason:
             if getattr(reason, 'winerror', None) is not None:
-                return
+                return False
             raise reason
 
         if ostype != "Linux":
-            return
+            return False
 
-        if not os.path.exists("/etc/NIXOS"):
-            return
-        if os.path.exists("/lib"):
+        # `/etc/NIXOS` doesn't exist on NixOS systems with a tmpfs root, so
+        # look at the distribution ID as well.
+        is_nixos = os.path.exists("/etc/NIXOS")
+        try:
+            with open("/etc/os-release") as os_release:
+                is_nixos = is_nixos or any(
+                    line.strip() in ('ID=nixos', "ID='nixos'", 'ID="nixos"')
+                    for line in os_release)
+        except (IOError, OSError):
+            pass
+        if not is_nixos or os.path.exists("/lib"):
+            return False
+
+        print("info: you seem to be running NixOS, downloaded binaries will be "
+              "patched (set `build.patch-binaries-for-nix = false` to disable)")
+        return True
+
+    def fix_bin_or_dylib(self, fname, rpath_libz=False):
+        """Modifies the interpreter section of 'fname' to fix the dynamic linker,
+        or the RPATH section, to fix the dynamic library search path
+
+        This method is only required on Nix and uses the PatchELF utility to
+        change the interpreter/RPATH of ELF executables.
+
+        Please see https://nixos.org/patchelf.html for more information
+        """
+        if not self.should_fix_bins_and_dylibs():
             return
 
-        # At this point we're pretty sure the user is running NixOS
-        nix_os_msg = "info: you seem to be running NixOS. Attempting to patch"
-        print(nix_os_msg, fname)
+        print("info: patching", fname, "for Nix")
 
         # Only build `stage0/.nix-deps` once.
         nix_deps_dir = self.nix_deps_dir
@@ -653,6 +718,43 @@ class RustBuild(object):
         """
         return os.path.join(self.build_dir, self.build, "stage0")
 
+    def cache_root(self):
+        """Return the directory downloaded tarballs are cached in
+
+        >>> rb = RustBuild()
+        >>> rb.build_dir = "build"
+        >>> rb.cache_root() == os.path.join("build", "cache")
+        True
+
+        The cache can be shared between build directories by setting
+        `build.bootstrap-cache-path`:
+
+        >>> rb.config_toml = '[build]\\nbootstrap-cache-path = "shared-cache"'
+        >>> rb.cache_root() == os.path.abspath("shared-cache")
+        True
+        """
+        cache_path = self.get_toml('bootstrap-cache-path', 'build')
+        if cache_path is not None:
+            return os.path.abspath(self.resolve_config_path(cache_path))
+        return os.path.join(self.build_dir, "cache")
+
+    def link_into_build_cache(self, tarball, subdir):
+        """Hard-link a tarball from a shared download cache into the build
+        directory's own cache, so that the build directory keeps working if the
+        shared cache is pruned. Falls back to copying across file systems."""
+        build_cache = os.path.join(self.build_dir, "cache", subdir)
+        if os.path.dirname(tarball) == build_cache:
+            return
+        dst = os.path.join(build_cache, os.path.basename(tarball))
+        if os.path.exists(dst):
+            return
+        if not os.path.exists(build_cache):
+            os.makedirs(build_cache)
+        try:
+            os.link(tarball, dst)
+        except (AttributeError, OSError):
+            shutil.copy2(tarball, dst)
+
     def llvm_root(self):
         """Return the CI LLVM root directory
 
@@ -739,10 +841,30 @@ class RustBuild(object):
         """
         config = self.get_toml(program)
         if config:
-            return os.path.expanduser(config)
+            config = os.path.expanduser(config)
+            # Bare program names are looked up in `PATH`
+            if os.path.dirname(config):
+                config = self.resolve_config_path(config)
+            return config
         return os.path.join(self.bin_root(), "bin", "{}{}".format(
             program, self.exe_suffix()))
 
+    def resolve_config_path(self, path):
+        """Resolve a relative path from config.toml against the directory
+        containing the file, so that the file can be moved along with the paths
+        it refers to
+
+        >>> rb = RustBuild()
+        >>> rb.config_dir = os.path.join(os.sep, 'src', 'rust')
+        >>> rb.resolve_config_path('build') == os.path.join(rb.config_dir, 'build')
+        True
+        >>> rb.resolve_config_path(os.path.join(os.sep, 'build')) == os.path.join(os.sep, 'build')
+        True
+        """
+        if self.config_dir is None:
+            return path
+        return os.path.join(self.config_dir, path)
+
     @staticmethod
     def get_string(line):
         """Return the value between double quotes
@@ -784,11 +906,75 @@ class RustBuild(object):
         """
         return os.path.join(self.build_dir, "bootstrap", "debug", "bootstrap")
 
-    def build_bootstrap(self):
-        """Build bootstrap"""
+    def expand_alias(self, command):
+        """Expand a leading `@<name>` in `command`, the arguments given to x.py,
+        into the invocation it's an alias for in `.bootstrap-aliases.toml`, as
+        bootstrap does
+
+        >>> rb = RustBuild()
+        >>> rb.rust_root = os.path.join("build", "does-not-exist")
+        >>> rb.expand_alias(["build", "library/std"])
+        ['build', 'library/std']
+        >>> rb.expand_alias(["@check", "--stage", "1"])
+        ['@check', '--stage', '1']
+        """
+        if not command or not command[0].startswith("@"):
+            return command
+        path = os.path.join(self.rust_root, ".bootstrap-aliases.toml")
+        if not os.path.exists(path):
+            # bootstrap reports the missing file
+            return command
+        with open(path) as aliases:
+            contents = aliases.read()
+        pattern = r'^\s*"?{}"?\s*=(.*)$'.format(re.escape(command[0][1:]))
+        for line in contents.splitlines():
+            match = re.match(pattern, line)
+            if match is not None:
+                invocation = self.get_string(match.group(1)) or ""
+                return invocation.split() + command[1:]
+        return command
+
+    def bootstrap_features(self, command):
+        """Return the cargo features bootstrap needs to be built with to run
+        `command`, the arguments given to x.py
+
+        Features which take a while to build are only enabled once a command
+        needs them. They are kept enabled afterwards, so that switching between
+        commands doesn't rebuild bootstrap every time.
+
+        >>> rb = RustBuild()
+        >>> rb.build_dir = os.path.join("build", "does-not-exist")
+        >>> rb.bootstrap_features(["build", "library/std"])
+        []
+        >>> rb.bootstrap_features(["-v", "fmt", "--check"])
+        ['fmt']
+        >>> rb.bootstrap_features(["test", "src/tools/tidy"])
+        ['fmt']
+        """
+        command = self.expand_alias(command)
+        features = set()
+        stamp = os.path.join(self.build_dir, "bootstrap", "features")
+        if os.path.exists(stamp):
+            with open(stamp) as stamp_file:
+                features.update(stamp_file.read().split())
+        # `x.py fmt` and the formatting check of `x.py test tidy`, which
+        # `x.py ci-smoke` runs with the same bootstrap binary
+        if any(arg in ("fmt", "test", "t", "ci-smoke") for arg in command):
+            features.add("fmt")
+        if os.environ.get("BOOTSTRAP_LOG"):
+            features.add("logging")
+        if self.get_toml('artifact-cache', 'build') or \
+                self.get_toml('prebuilt-archive', 'llvm') or \
+                "export-llvm" in command:
+            features.add("artifact-cache")
+        return sorted(features)
+
+    def build_bootstrap(self, command):
+        """Build bootstrap with the features needed to run `command`"""
         build_dir = os.path.join(self.build_dir, "bootstrap")
         if self.clean and os.path.exists(build_dir):
             shutil.rmtree(build_dir)
+        features = self.bootstrap_features(command)
         env = os.environ.copy()
         # `CARGO_BUILD_TARGET` breaks bootstrap build.
         # See also: <https://github.com/rust-lang/rust/issues/70208>.
@@ -821,7 +1007,7 @@ class RustBuild(object):
         if target_linker is not None:
             env["RUSTFLAGS"] += " -C linker=" + target_linker
         env["RUSTFLAGS"] += " -Wrust_2018_idioms -Wunused_lifetimes"
-        if self.get_toml("deny-warnings", "rust") != "false":
+        if self.deny_warnings():
             env["RUSTFLAGS"] += " -Dwarnings"
 
         env["PATH"] = os.path.join(self.bin_root(), "bin") + \
@@ -837,7 +1023,36 @@ class RustBuild(object):
             args.append("--locked")
         if self.use_vendored_sources:
             args.append("--frozen")
+        args.append("--no-default-features")
+        if features:
+            args += ["--features", " ".join(features)]
         run(args, env=env, verbose=self.verbose)
+        with output(os.path.join(build_dir, "features")) as stamp:
+            stamp.write(" ".join(features))
+
+    def deny_warnings(self):
+        """Whether warnings fail the build, following `--warnings` and then
+        `rust.warnings` in config.toml, or the deprecated `rust.deny-warnings`
+
+        >>> rb = RustBuild()
+        >>> rb.deny_warnings()
+        True
+        >>> rb.config_toml = '[rust]\\ndeny-warnings = false'
+        >>> rb.deny_warnings()
+        False
+        >>> rb.config_toml = '[rust]\\nwarnings = "deny"\\ndeny-warnings = false'
+        >>> rb.deny_warnings()
+        True
+        >>> rb.warnings = 'warn'
+        >>> rb.deny_warnings()
+        False
+        """
+        if self.warnings != 'default':
+            return self.warnings == 'deny'
+        warnings = self.get_toml('warnings', 'rust')
+        if warnings is not None and warnings != 'default':
+            return warnings == 'deny'
+        return self.get_toml('deny-warnings', 'rust') != 'false'
 
     def build_triple(self):
         """Build triple as in LLVM
@@ -886,8 +1101,13 @@ class RustBuild(object):
 
     def update_submodules(self):
         """Update submodules"""
-        if (not os.path.exists(os.path.join(self.rust_root, ".git"))) or \
-                self.get_toml('submodules') == "false":
+        if self.get_toml('submodules') == "false" or self.get_toml('vcs') == "none":
+            return
+        if not os.path.exists(os.path.join(self.rust_root, ".git")):
+            if os.path.isdir(os.path.join(self.rust_root, ".jj")):
+                print("warning: submodules can't be updated in a jj checkout that isn't "
+                      "colocated with git; make sure they are checked out, or use "
+                      "`jj git init --colocate`")
             return
 
         default_encoding = sys.getdefaultencoding()
@@ -998,6 +1218,28 @@ class RustBuild(object):
             ], verbose=self.verbose, cwd=self.rust_root)
 
 
+def find_config_toml(rust_root):
+    """Find the `config.toml` to use when none was given explicitly
+
+    This looks in the current directory and, if it's inside the source tree,
+    each of its parents up to the source root, and then in the source root.
+    """
+    directory = os.getcwd()
+    while True:
+        path = os.path.join(directory, 'config.toml')
+        if os.path.exists(path):
+            return path
+        parent = os.path.dirname(directory)
+        if directory == rust_root or parent == directory or \
+                not parent.startswith(rust_root):
+            break
+        directory = parent
+    path = os.path.join(rust_root, 'config.toml')
+    if os.path.exists(path):
+        return path
+    return None
+
+
 def bootstrap(help_triggered):
     """Configure, fetch, build and run the initial bootstrap"""
 
@@ -1012,6 +1254,7 @@ def bootstrap(help_triggered):
     parser.add_argument('--config')
     parser.add_argument('--build')
     parser.add_argument('--clean', action='store_true')
+    parser.add_argument('--warnings', default='default')
     parser.add_argument('-v', '--verbose', action='count', default=0)
 
     args = [a for a in sys.argv if a != '-h' and a != '--help']
@@ -1022,12 +1265,13 @@ def bootstrap(help_triggered):
     build.rust_root = os.path.abspath(os.path.join(__file__, '../../..'))
     build.verbose = args.verbose
     build.clean = args.clean
+    build.warnings = args.warnings
 
-    # Read from `RUST_BOOTSTRAP_CONFIG`, then `--config`, then fallback to `config.toml` (if it
-    # exists).
+    # Read from `RUST_BOOTSTRAP_CONFIG`, then `--config`, then fallback to the nearest
+    # `config.toml` (if it exists).
     toml_path = os.getenv('RUST_BOOTSTRAP_CONFIG') or args.config
-    if not toml_path and os.path.exists('config.toml'):
-        toml_path = 'config.toml'
+    if not toml_path:
+        toml_path = find_config_toml(build.rust_root)
 
     if toml_path:
         if not os.path.exists(toml_path):
@@ -1035,6 +1279,7 @@ def bootstrap(help_triggered):
 
         with open(toml_path) as config:
             build.config_toml = config.read()
+        build.config_dir = os.path.dirname(os.path.abspath(toml_path))
 
     profile = build.get_toml('profile')
     if profile is not None:
@@ -1056,8 +1301,12 @@ def bootstrap(help_triggered):
 
     build.check_vendored_status()
 
-    build_dir = build.get_toml('build-dir', 'build') or 'build'
-    build.build_dir = os.path.abspath(build_dir.replace("$ROOT", build.rust_root))
+    build_dir = build.get_toml('build-dir', 'build')
+    if build_dir:
+        build_dir = build.resolve_config_path(build_dir.replace("$ROOT", build.rust_root))
+    else:
+        build_dir = 'build'
+    build.build_dir = os.path.abspath(build_dir)
 
     data = stage0_data(build.rust_root)
     build.date = data['date']
@@ -1078,7 +1327,7 @@ def bootstrap(help_triggered):
     build.download_stage0()
     sys.stdout.flush()
     build.ensure_vendored()
-    build.build_bootstrap()
+    build.build_bootstrap(sys.argv[1:])
     sys.stdout.flush()
 
     # Run the bootstrap
@@ -1091,7 +1340,7 @@ def bootstrap(help_triggered):
     env["RUSTC_BOOTSTRAP"] = '1'
     if toml_path:
         env["BOOTSTRAP_CONFIG"] = toml_path
-    run(args, env=env, verbose=build.verbose)
+    run(args, env=env, verbose=build.verbose, stdout=sys.__stdout__)
 
 
 def main():
@@ -1104,11 +1353,22 @@ def main():
 
     help_triggered = (
         '-h' in sys.argv) or ('--help' in sys.argv) or (len(sys.argv) == 1)
+    # With `--json`, bootstrap prints JSON to stdout, so everything else,
+    # like the progress of downloads and submodules, goes to stderr to keep
+    # stdout parseable.
+    if '--json' in sys.argv:
+        sys.stdout = sys.stderr
+    # Windows consoles may not understand ANSI escapes
+    isatty = sys.stdout.isatty() and os.name != 'nt'
+    if use_color(sys.argv[1:], isatty, os.environ):
+        paint = "\033[1;{}m{}\033[0m".format
+    else:
+        paint = lambda code, msg: msg
     try:
         bootstrap(help_triggered)
         if not help_triggered:
-            print("Build completed successfully in {}".format(
-                format_build_time(time() - start_time)))
+            print(paint(32, "Build completed successfully in {}".format(
+                format_build_time(time() - start_time))))
     except (SystemExit, KeyboardInterrupt) as error:
         if hasattr(error, 'code') and isinstance(error.code, int):
             exit_code = error.code
@@ -1116,8 +1376,8 @@ def main():
             exit_code = 1
             print(error)
         if not help_triggered:
-            print("Build completed unsuccessfully in {}".format(
-                format_build_time(time() - start_time)))
+            print(paint(31, "Build completed unsuccessfully in {}".format(
+                format_build_time(time() - start_time))))
         sys.exit(exit_code)
 
 
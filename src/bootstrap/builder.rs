@@ -2,7 +2,7 @@
 use std::cell::{Cell, RefCell};
 use std::collections::BTreeSet;
 use std::env;
-use std::ffi::OsStr;
+use std::ffi::{OsStr, OsString};
 use std::fmt::Debug;
 use std::fs;
 use std::hash::Hash;
@@ -12,16 +12,22 @@
 use std::time::{Duration, Instant};
 
 use build_helper::{output, t};
+use serde::Serialize;
 
 use crate::cache::{Cache, Interned, INTERNER};
+use crate::change_detection;
 use crate::check;
 use crate::compile;
 use crate::config::TargetSelection;
 use crate::dist;
 use crate::doc;
-use crate::flags::{Color, Subcommand};
+use crate::flags::Subcommand;
+use crate::hooks;
 use crate::install;
+use crate::logging;
 use crate::native;
+use crate::picker;
+use crate::plugin;
 use crate::run;
 use crate::test;
 use crate::tool::{self, SourceType};
@@ -38,6 +44,64 @@ pub struct Builder<'a> {
     stack: RefCell<Vec<Box<dyn Any>>>,
     time_spent_on_dependencies: Cell<Duration>,
     pub paths: Vec<PathBuf>,
+    /// The steps ensured so far, for `--dry-run --json`.
+    plan: RefCell<Vec<PlannedStep>>,
+    /// For `--explain`, why the step at the bottom of `stack` was selected,
+    /// if it was selected by a path or run by default.
+    reason: RefCell<Option<String>>,
+    /// For `--explain`, the name and `Debug` output of each step on `stack`.
+    why: RefCell<Vec<String>>,
+    /// For `--explain`, why each step that ran so far was ensured.
+    explanations: RefCell<Vec<String>>,
+}
+
+/// A step as printed by `x.py --dry-run --json`.
+#[derive(Serialize)]
+struct PlannedStep {
+    /// The step's type, e.g. `compile::Std`.
+    step: &'static str,
+    /// The step's `Debug` representation, which identifies it completely.
+    description: String,
+    /// The target the step is for, if it has one.
+    target: Option<String>,
+    /// The stage of the compiler the step uses, if any.
+    stage: Option<u32>,
+    /// How many steps depend on this one through the chain of steps that
+    /// ensured it, with 0 for the ones selected on the command line.
+    depth: usize,
+    /// Whether the step was already ensured earlier in the plan, so that this
+    /// is only another step depending on it. This says nothing about whether
+    /// running it for real does any work, which cargo and the stamps of the
+    /// steps decide.
+    repeated: bool,
+}
+
+/// The name of a step's type without the crate name, e.g. `compile::Std`.
+pub fn step_name<S: Step>() -> &'static str {
+    let name = std::any::type_name::<S>();
+    name.find("::").map_or(name, |i| &name[i + 2..])
+}
+
+/// Whether the step named `name` is named by `filter`, e.g. `test::ui` or
+/// `dist::*`, which may leave out leading modules. Case, `_` and `-` don't
+/// matter.
+pub fn step_matches(name: &str, filter: &str) -> bool {
+    let normalize = |name: &str| name.to_lowercase().replace(|c| c == '_' || c == '-', "");
+    let name = normalize(name).replace("::", "/");
+    util::glob_ends_with(Path::new(&name), &normalize(filter).replace("::", "/"))
+}
+
+impl PlannedStep {
+    fn new<S: Step>(step: &S, depth: usize, repeated: bool) -> PlannedStep {
+        PlannedStep {
+            step: step_name::<S>(),
+            description: format!("{:?}", step),
+            target: step.target().map(|target| target.triple.to_string()),
+            stage: step.stage(),
+            depth,
+            repeated,
+        }
+    }
 }
 
 impl<'a> Deref for Builder<'a> {
@@ -60,6 +124,10 @@ pub trait Step: 'static + Clone + Debug + PartialEq + Eq + Hash {
     /// If true, then this rule should be skipped if --target was specified, but --host was not
     const ONLY_HOSTS: bool = false;
 
+    /// Whether this step checks files of expected output, which it updates
+    /// instead when run with `--bless`.
+    const BLESSABLE: bool = false;
+
     /// Primary function to execute this rule. Can call `builder.ensure()`
     /// with other steps to run those.
     fn run(self, builder: &Builder<'_>) -> Self::Output;
@@ -83,6 +151,17 @@ fn make_run(_run: RunConfig<'_>) {
         // as such calling them from ./x.py isn't logical.
         unimplemented!()
     }
+
+    /// The target this step is for, if it has one. `--dry-run --json` and the
+    /// hooks of `[hooks]` report it.
+    fn target(&self) -> Option<TargetSelection> {
+        None
+    }
+
+    /// The stage of the compiler this step uses, if it uses one.
+    fn stage(&self) -> Option<u32> {
+        None
+    }
 }
 
 pub struct RunConfig<'a> {
@@ -100,6 +179,7 @@ pub fn build_triple(&self) -> TargetSelection {
 struct StepDescription {
     default: bool,
     only_hosts: bool,
+    blessable: bool,
     should_run: fn(ShouldRun<'_>) -> ShouldRun<'_>,
     make_run: fn(RunConfig<'_>),
     name: &'static str,
@@ -134,10 +214,16 @@ fn one<P: Into<PathBuf>>(path: P) -> PathSet {
         PathSet::Set(set)
     }
 
+    /// Whether a path in the set ends with `needle`, which may also be a glob
+    /// pattern like `src/tools/*`.
     fn has(&self, needle: &Path) -> bool {
+        let matches = |path: &Path| match needle.to_str() {
+            Some(pattern) if util::is_glob(pattern) => util::glob_ends_with(path, pattern),
+            _ => path.ends_with(needle),
+        };
         match self {
-            PathSet::Set(set) => set.iter().any(|p| p.ends_with(needle)),
-            PathSet::Suite(suite) => suite.ends_with(needle),
+            PathSet::Set(set) => set.iter().any(|p| matches(p)),
+            PathSet::Suite(suite) => matches(suite),
         }
     }
 
@@ -154,13 +240,29 @@ fn from<S: Step>() -> StepDescription {
         StepDescription {
             default: S::DEFAULT,
             only_hosts: S::ONLY_HOSTS,
+            blessable: S::BLESSABLE,
             should_run: S::should_run,
             make_run: S::make_run,
             name: std::any::type_name::<S>(),
         }
     }
 
-    fn maybe_run(&self, builder: &Builder<'_>, pathset: &PathSet) {
+    /// Whether `filter`, an entry of `--exclude` like `test::tidy` or `doc::*`,
+    /// names this step by its module and type. Case, `_` and `-` are ignored,
+    /// so that `test::rustdoc-js-std` names `test::RustdocJSStd`.
+    fn is_named_by(&self, filter: &str) -> bool {
+        step_matches(self.name, filter)
+    }
+
+    /// Runs the step for `pathset`, which was matched by `selected_by`, one of
+    /// the paths given on the command line, or is run by default if that's
+    /// `None`.
+    fn maybe_run(&self, builder: &Builder<'_>, pathset: &PathSet, selected_by: Option<&Path>) {
+        let filters = builder.config.exclude.iter().filter_map(|e| e.to_str());
+        if let Some(filter) = filters.filter(|e| e.contains("::")).find(|e| self.is_named_by(e)) {
+            eprintln!("Skipping {} because it is excluded by `{}`", self.name, filter);
+            return;
+        }
         if builder.config.exclude.iter().any(|e| pathset.has(e)) {
             eprintln!("Skipping {:?} because it is excluded", pathset);
             return;
@@ -172,11 +274,31 @@ fn maybe_run(&self, builder: &Builder<'_>, pathset: &PathSet) {
         }
 
         // Determine the targets participating in this rule.
-        let targets = if self.only_hosts { &builder.hosts } else { &builder.targets };
+        let targets = if self.only_hosts {
+            if builder.skip_only_host_steps() {
+                return;
+            }
+            &builder.hosts
+        } else {
+            &builder.targets
+        };
 
         for target in targets {
-            let run = RunConfig { builder, path: pathset.path(builder), target: *target };
+            let path = pathset.path(builder);
+            if builder.config.explain {
+                let reason = match selected_by {
+                    Some(selected) => format!(
+                        "which was selected by the path `{}` for {}",
+                        selected.display(),
+                        target
+                    ),
+                    None => format!("which is run by default for {}", target),
+                };
+                builder.reason.replace(Some(reason));
+            }
+            let run = RunConfig { builder, path, target: *target };
             (self.make_run)(run);
+            builder.reason.replace(None);
         }
     }
 
@@ -197,7 +319,18 @@ fn run(v: &[StepDescription], builder: &Builder<'_>, paths: &[PathBuf]) {
             for (desc, should_run) in v.iter().zip(&should_runs) {
                 if desc.default && should_run.is_really_default {
                     for pathset in &should_run.paths {
-                        desc.maybe_run(builder, pathset);
+                        if let Some(changed) = &builder.changed_files {
+                            if !builder.config.ci_keep.iter().any(|p| pathset.has(p))
+                                && change_detection::can_skip(|p| pathset.has(p), changed)
+                            {
+                                builder.info(&format!(
+                                    "Skipping {:?} because it is unaffected by the changes",
+                                    pathset
+                                ));
+                                continue;
+                            }
+                        }
+                        desc.maybe_run(builder, pathset, None);
                     }
                 }
             }
@@ -210,21 +343,53 @@ fn run(v: &[StepDescription], builder: &Builder<'_>, paths: &[PathBuf]) {
                 Err(_) => path,
             };
 
-            let mut attempted_run = false;
-            for (desc, should_run) in v.iter().zip(&should_runs) {
-                if let Some(suite) = should_run.is_suite_path(path) {
-                    attempted_run = true;
-                    desc.maybe_run(builder, suite);
-                } else if let Some(pathset) = should_run.pathset_for_path(path) {
-                    attempted_run = true;
-                    desc.maybe_run(builder, pathset);
-                }
+            if !StepDescription::run_path(v, &should_runs, builder, path) {
+                let picked = builder.picked_paths.borrow().get(path).cloned();
+                let picked = picked.unwrap_or_else(|| {
+                    let candidates = should_runs
+                        .iter()
+                        .flat_map(|should_run| &should_run.paths)
+                        .flat_map(|pathset| match pathset {
+                            PathSet::Set(set) => set.iter().cloned().collect::<Vec<_>>(),
+                            PathSet::Suite(suite) => vec![suite.clone()],
+                        })
+                        .collect::<Vec<_>>();
+                    let picked = picker::choose(path, &candidates);
+                    builder.picked_paths.borrow_mut().insert(path.to_path_buf(), picked.clone());
+                    picked
+                });
+                StepDescription::run_path(v, &should_runs, builder, &picked);
             }
+        }
+    }
 
-            if !attempted_run {
-                panic!("error: no rules matched {}", path.display());
+    /// Runs the steps matching `path`, returning whether there were any.
+    fn run_path(
+        v: &[StepDescription],
+        should_runs: &[ShouldRun<'_>],
+        builder: &Builder<'_>,
+        path: &Path,
+    ) -> bool {
+        let mut attempted_run = false;
+        let mut blessable = false;
+        for (desc, should_run) in v.iter().zip(should_runs) {
+            if let Some(suite) = should_run.is_suite_path(path) {
+                attempted_run = true;
+                blessable |= desc.blessable;
+                desc.maybe_run(builder, suite, Some(path));
+            } else if let Some(pathset) = should_run.pathset_for_path(path) {
+                attempted_run = true;
+                blessable |= desc.blessable;
+                desc.maybe_run(builder, pathset, Some(path));
             }
         }
+        if attempted_run && !blessable && builder.config.cmd.bless() {
+            eprintln!(
+                "warning: `--bless` has no effect on `{}`, which has no expected output to update",
+                path.display()
+            );
+        }
+        attempted_run
     }
 }
 
@@ -327,10 +492,12 @@ pub enum Kind {
     Format,
     Test,
     Bench,
+    Miri,
     Dist,
     Doc,
     Install,
     Run,
+    ExportLlvm,
 }
 
 impl<'a> Builder<'a> {
@@ -340,7 +507,7 @@ macro_rules! describe {
                 vec![$(StepDescription::from::<$rule>()),+]
             }};
         }
-        match kind {
+        let mut descriptions = match kind {
             Kind::Build => describe!(
                 compile::Std,
                 compile::Rustc,
@@ -389,6 +556,7 @@ macro_rules! describe {
                 test::Codegen,
                 test::CodegenUnits,
                 test::Assembly,
+                test::ParallelRustc,
                 test::Incremental,
                 test::Debuginfo,
                 test::UiFullDeps,
@@ -404,6 +572,7 @@ macro_rules! describe {
                 test::Rls,
                 test::ErrorIndex,
                 test::Distcheck,
+                test::DylibExports,
                 test::RunMakeFullDeps,
                 test::Nomicon,
                 test::Reference,
@@ -417,9 +586,11 @@ macro_rules! describe {
                 test::EmbeddedBook,
                 test::EditionGuide,
                 test::Rustfmt,
+                test::RustAnalyzer,
                 test::Miri,
                 test::Clippy,
                 test::CompiletestTest,
+                test::CodegenCranelift,
                 test::RustdocJSStd,
                 test::RustdocJSNotStd,
                 test::RustdocTheme,
@@ -427,10 +598,20 @@ macro_rules! describe {
                 test::RustdocJson,
                 // Run bootstrap close to the end as it's unlikely to fail
                 test::Bootstrap,
+                test::BootstrapPy,
                 // Run run-make last, since these won't pass without make on Windows
                 test::RunMake,
             ),
-            Kind::Bench => describe!(test::Crate, test::CrateLibrustc),
+            Kind::Bench => describe!(
+                test::Crate,
+                test::CrateLibrustc,
+                test::CrateRustdoc,
+                test::BenchCargo,
+                test::BenchClippy,
+                test::BenchRustfmt,
+                test::BenchRustAnalyzer
+            ),
+            Kind::Miri => describe!(test::CrateMiri),
             Kind::Doc => describe!(
                 doc::UnstableBook,
                 doc::UnstableBookGen,
@@ -483,22 +664,34 @@ macro_rules! describe {
                 install::Miri,
                 install::Analysis,
                 install::Src,
-                install::Rustc
+                install::Rustc,
+                install::Validate
             ),
-            Kind::Run => describe!(run::ExpandYamlAnchors, run::BuildManifest),
+            Kind::Run => describe!(run::ExpandYamlAnchors, run::BuildManifest, run::RunTool),
+            Kind::ExportLlvm => describe!(native::ExportLlvm),
+        };
+        // Plugins from config.toml can be steps of any of these.
+        descriptions.push(StepDescription::from::<plugin::External>());
+        descriptions
+    }
+
+    /// The kind of the steps whose paths are listed for `subcommand`, which
+    /// is also the subcommand plugins are steps of.
+    pub fn kind_for_paths(subcommand: &str) -> Option<Kind> {
+        match subcommand {
+            "build" => Some(Kind::Build),
+            "doc" => Some(Kind::Doc),
+            "test" => Some(Kind::Test),
+            "bench" => Some(Kind::Bench),
+            "miri" => Some(Kind::Miri),
+            "dist" => Some(Kind::Dist),
+            "install" => Some(Kind::Install),
+            _ => None,
         }
     }
 
     pub fn get_help(build: &Build, subcommand: &str) -> Option<String> {
-        let kind = match subcommand {
-            "build" => Kind::Build,
-            "doc" => Kind::Doc,
-            "test" => Kind::Test,
-            "bench" => Kind::Bench,
-            "dist" => Kind::Dist,
-            "install" => Kind::Install,
-            _ => return None,
-        };
+        let kind = Self::kind_for_paths(subcommand)?;
 
         let builder = Self::new_internal(build, kind, vec![]);
         let builder = &builder;
@@ -525,6 +718,68 @@ pub fn get_help(build: &Build, subcommand: &str) -> Option<String> {
         Some(help)
     }
 
+    /// Returns the paths that select steps of `subcommand`, with test suites as
+    /// their directory, for shell completions.
+    pub fn step_paths(build: &Build, subcommand: &str) -> Vec<PathBuf> {
+        let kind = match Self::kind_for_paths(subcommand) {
+            Some(kind) => kind,
+            None => return Vec::new(),
+        };
+        let builder = Self::new_internal(build, kind, vec![]);
+        let builder = &builder;
+        let mut should_run = ShouldRun::new(builder);
+        for desc in Builder::get_step_descriptions(builder.kind) {
+            should_run = (desc.should_run)(should_run);
+        }
+        let mut paths = BTreeSet::new();
+        for pathset in should_run.paths {
+            match pathset {
+                PathSet::Set(set) => paths.extend(set),
+                PathSet::Suite(path) => {
+                    paths.insert(path);
+                }
+            }
+        }
+        paths.into_iter().filter(|path| !path.as_os_str().is_empty()).collect()
+    }
+
+    /// Returns the paths to pass to `x.py <kind>` to run the steps affected by
+    /// the `changed` files. Only the most specific path a file is below is
+    /// returned, and tests in a suite are returned themselves.
+    pub fn suggested_paths(build: &Build, kind: Kind, changed: &[PathBuf]) -> BTreeSet<PathBuf> {
+        let builder = Self::new_internal(build, kind, vec![]);
+        let builder = &builder;
+        let mut should_run = ShouldRun::new(builder);
+        for desc in Builder::get_step_descriptions(builder.kind) {
+            should_run = (desc.should_run)(should_run);
+        }
+        let depth = |path: &Path| path.components().count();
+        let mut suggested = BTreeSet::new();
+        for file in changed {
+            let best = should_run
+                .paths
+                .iter()
+                .filter_map(|pathset| match pathset {
+                    PathSet::Set(set) => set
+                        .iter()
+                        .filter(|path| depth(path) > 0 && file.starts_with(path))
+                        .max_by_key(|path| depth(path))
+                        .cloned(),
+                    PathSet::Suite(suite) if file.starts_with(suite) => {
+                        if file.extension() == Some("rs".as_ref()) {
+                            Some(file.clone())
+                        } else {
+                            file.parent().map(Path::to_path_buf)
+                        }
+                    }
+                    PathSet::Suite(_) => None,
+                })
+                .max_by_key(|path| depth(path));
+            suggested.extend(best);
+        }
+        suggested
+    }
+
     fn new_internal(build: &Build, kind: Kind, paths: Vec<PathBuf>) -> Builder<'_> {
         Builder {
             build,
@@ -532,6 +787,10 @@ fn new_internal(build: &Build, kind: Kind, paths: Vec<PathBuf>) -> Builder<'_> {
             kind,
             cache: Cache::new(),
             stack: RefCell::new(Vec::new()),
+            plan: RefCell::new(Vec::new()),
+            reason: RefCell::new(None),
+            why: RefCell::new(Vec::new()),
+            explanations: RefCell::new(Vec::new()),
             time_spent_on_dependencies: Cell::new(Duration::new(0, 0)),
             paths,
         }
@@ -546,19 +805,70 @@ pub fn new(build: &Build) -> Builder<'_> {
             Subcommand::Doc { ref paths, .. } => (Kind::Doc, &paths[..]),
             Subcommand::Test { ref paths, .. } => (Kind::Test, &paths[..]),
             Subcommand::Bench { ref paths, .. } => (Kind::Bench, &paths[..]),
-            Subcommand::Dist { ref paths } => (Kind::Dist, &paths[..]),
+            Subcommand::Miri { ref paths, .. } => (Kind::Miri, &paths[..]),
+            Subcommand::Dist { ref paths, .. } => (Kind::Dist, &paths[..]),
             Subcommand::Install { ref paths } => (Kind::Install, &paths[..]),
-            Subcommand::Run { ref paths } => (Kind::Run, &paths[..]),
-            Subcommand::Format { .. } | Subcommand::Clean { .. } | Subcommand::Setup { .. } => {
-                panic!()
-            }
+            Subcommand::Run { ref paths, .. } => (Kind::Run, &paths[..]),
+            Subcommand::ExportLlvm { ref paths } => (Kind::ExportLlvm, &paths[..]),
+            Subcommand::Format { .. }
+            | Subcommand::Clean { .. }
+            | Subcommand::Gc { .. }
+            | Subcommand::Setup { .. }
+            | Subcommand::Uninstall
+            | Subcommand::Suggest { .. }
+            | Subcommand::CiSmoke { .. } => panic!(),
         };
 
         Self::new_internal(build, kind, paths.to_owned())
     }
 
+    /// Whether only library crates were asked to be built, like with
+    /// `x.py build library --target <triple>`, in which case the steps that
+    /// only run for hosts, like those building the compiler and tools, are
+    /// skipped. The host compiler is still built when the libraries need it.
+    fn skip_only_host_steps(&self) -> bool {
+        self.kind == Kind::Build
+            && !self.paths.is_empty()
+            && self.paths.iter().all(|path| {
+                path.strip_prefix(".").unwrap_or(path).starts_with("library")
+            })
+    }
+
     pub fn execute_cli(&self) {
+        let llvm_to_build = self.llvm_to_build.borrow().clone();
+        native::build_llvm_concurrently(self, &llvm_to_build);
         self.run_step_descriptions(&Builder::get_step_descriptions(self.kind), &self.paths);
+        if self.kind == Kind::Dist && self.config.cmd.sign() {
+            self.ensure(dist::Signatures);
+        }
+    }
+
+    /// Prints the steps ensured so far as JSON, in the order they were
+    /// ensured in, for `--dry-run --json`.
+    pub fn print_plan(&self) {
+        println!("{}", t!(serde_json::to_string_pretty(&*self.plan.borrow())));
+    }
+
+    /// Prints why each step that ran was ensured, for `--explain`.
+    pub fn print_explanations(&self) {
+        for explanation in self.explanations.borrow().iter() {
+            println!("{}\n", explanation);
+        }
+    }
+
+    /// Records why `step` is about to run: the steps that required it, up to
+    /// the one selected by a path or run by default.
+    fn explain<S: Step>(&self, step: &S) {
+        let why = self.why.borrow();
+        let mut explanation = format!("{}: {:?}", step_name::<S>(), step);
+        for parent in why.iter().rev() {
+            explanation.push_str(&format!("\n    required by {}", parent));
+        }
+        match &*self.reason.borrow() {
+            Some(reason) => explanation.push_str(&format!("\n    {}", reason)),
+            None => explanation.push_str("\n    which x.py runs itself"),
+        }
+        self.explanations.borrow_mut().push(explanation);
     }
 
     pub fn default_doc(&self, paths: Option<&[PathBuf]>) {
@@ -633,6 +943,14 @@ fn run(self, builder: &Builder<'_>) -> Interned<PathBuf> {
                 t!(fs::create_dir_all(&sysroot));
                 INTERNER.intern_path(sysroot)
             }
+
+            fn target(&self) -> Option<TargetSelection> {
+                Some(self.target)
+            }
+
+            fn stage(&self) -> Option<u32> {
+                Some(self.compiler.stage)
+            }
         }
         self.ensure(Libdir { compiler, target })
     }
@@ -756,7 +1074,7 @@ pub fn rustdoc_cmd(&self, compiler: Compiler) -> Command {
     /// Note that this returns `None` if LLVM is disabled, or if we're in a
     /// check build or dry-run, where there's no need to build all of LLVM.
     fn llvm_config(&self, target: TargetSelection) -> Option<PathBuf> {
-        if self.config.llvm_enabled() && self.kind != Kind::Check && !self.config.dry_run {
+        if self.config.llvm_enabled(target) && self.kind != Kind::Check && !self.config.dry_run {
             let llvm_config = self.ensure(native::Llvm { target });
             if llvm_config.is_file() {
                 return Some(llvm_config);
@@ -780,7 +1098,18 @@ pub fn cargo(
         target: TargetSelection,
         cmd: &str,
     ) -> Cargo {
-        let mut cargo = Command::new(&self.initial_cargo);
+        let mut cargo = match &self.config.initial_cargo_clippy {
+            // `cargo-clippy clippy ...` behaves like `cargo clippy ...`, but it
+            // runs whichever `cargo` is in PATH, so put ours first.
+            Some(cargo_clippy) if cmd == "clippy" => {
+                let mut cargo = Cargo::new(Command::new(cargo_clippy));
+                let mut paths = vec![self.initial_cargo.parent().unwrap().to_path_buf()];
+                paths.extend(env::split_paths(&env::var_os("PATH").unwrap_or_default()));
+                cargo.env("PATH", t!(env::join_paths(paths)));
+                cargo
+            }
+            _ => Cargo::new(Command::new(&self.initial_cargo)),
+        };
         let out_dir = self.stage_out(compiler, mode);
 
         // Codegen backends are not yet tracked by -Zbinary-dep-depinfo,
@@ -802,8 +1131,11 @@ pub fn cargo(
 
         cargo.env("CARGO_TARGET_DIR", &out_dir).arg(cmd);
 
+        // `cargo bench` always builds with the release profile, which the
+        // bench profile inherits from, whether or not `--release` is passed.
+        let optimized = self.config.rust_optimize || cmd == "bench";
         let profile_var = |name: &str| {
-            let profile = if self.config.rust_optimize { "RELEASE" } else { "DEV" };
+            let profile = if optimized { "RELEASE" } else { "DEV" };
             format!("CARGO_PROFILE_{}_{}", profile, name)
         };
 
@@ -814,21 +1146,14 @@ pub fn cargo(
             cargo.env("REAL_LIBRARY_PATH", e);
         }
 
-        match self.build.config.color {
-            Color::Always => {
-                cargo.arg("--color=always");
-            }
-            Color::Never => {
-                cargo.arg("--color=never");
-            }
-            Color::Auto => {} // nothing to do
-        }
+        self.forward_color(&mut cargo.command);
 
         if cmd != "install" {
             cargo.arg("--target").arg(target.rustc_target_arg());
         } else {
             assert_eq!(target, compiler.host);
         }
+        cargo.env("RUST_TARGET_PATH", self.rust_target_path());
 
         // Set a flag for `check`/`clippy`/`fix`, so that certain build
         // scripts can do less work (i.e. not building/requiring LLVM).
@@ -877,7 +1202,17 @@ pub fn cargo(
                 // Only run clippy on a very limited subset of crates (in particular, not build scripts).
                 cargo.arg("-Zunstable-options");
                 // Explicitly does *not* set `--cfg=bootstrap`, since we're using a nightly clippy.
-                let host_version = Command::new("rustc").arg("--version").output().map_err(|_| ());
+                let mut host_rustc = match &self.config.initial_cargo_clippy {
+                    Some(cargo_clippy) => {
+                        let mut cmd = Command::new(
+                            cargo_clippy.with_file_name(exe("clippy-driver", self.config.build)),
+                        );
+                        cmd.arg("--rustc");
+                        cmd
+                    }
+                    None => Command::new("rustc"),
+                };
+                let host_version = host_rustc.arg("--version").output().map_err(|_| ());
                 let output = host_version.and_then(|output| {
                     if output.status.success() {
                         Ok(output)
@@ -903,6 +1238,19 @@ pub fn cargo(
             rustflags.arg("-Zsymbol-mangling-version=v0");
         }
 
+        // The stage0 compiler doesn't know `-Z randomize-layout` yet. Since the
+        // flags are part of RUSTFLAGS, changing them makes cargo rebuild the
+        // affected crates.
+        if self.config.rust_randomize_layout
+            && stage != 0
+            && matches!(mode, Mode::Std | Mode::Rustc | Mode::Codegen)
+        {
+            rustflags.arg("-Zrandomize-layout");
+            if let Some(seed) = self.config.rust_layout_seed {
+                rustflags.arg(&format!("-Zlayout-seed={}", seed));
+            }
+        }
+
         // FIXME: It might be better to use the same value for both `RUSTFLAGS` and `RUSTDOCFLAGS`,
         // but this breaks CI. At the very least, stage0 `rustdoc` needs `--cfg bootstrap`. See
         // #71458.
@@ -936,7 +1284,11 @@ pub fn cargo(
         // feature on the rustc side.
         cargo.arg("-Zbinary-dep-depinfo");
 
-        cargo.arg("-j").arg(self.jobs().to_string());
+        let jobs = match cmd {
+            "doc" | "rustdoc" => self.config.doc_jobs.unwrap_or_else(|| self.jobs()),
+            _ => self.jobs(),
+        };
+        cargo.arg("-j").arg(jobs.to_string());
         // Remove make-related flags to ensure Cargo can correctly set things up
         cargo.env_remove("MAKEFLAGS");
         cargo.env_remove("MFLAGS");
@@ -1042,6 +1394,13 @@ pub fn cargo(
             cargo.env("RUSTC", self.out.join("bootstrap/debug/rustc"));
         }
 
+        // Cargo would run the wrapper with the shim as the compiler, so it
+        // would cache the output of the compilers of all stages under the same
+        // compiler. The shim runs the real compiler through it instead.
+        if let Some(wrapper) = &self.config.rustc_cache {
+            cargo.env_remove("RUSTC_WRAPPER").env("RUSTC_CACHE_WRAPPER", wrapper);
+        }
+
         // Dealing with rpath here is a little special, so let's go into some
         // detail. First off, `-rpath` is a linker option on Unix platforms
         // which adds to the runtime dynamic loader path when looking for
@@ -1122,6 +1481,14 @@ pub fn cargo(
                 self.config.rust_debug_assertions.to_string()
             },
         );
+        cargo.env(
+            profile_var("OVERFLOW_CHECKS"),
+            if mode == Mode::Std {
+                self.config.rust_overflow_checks_std.to_string()
+            } else {
+                self.config.rust_overflow_checks.to_string()
+            },
+        );
 
         // `dsymutil` adds time to builds on Apple platforms for no clear benefit, and also makes
         // it more difficult for debuggers to find debug info. The compiler currently defaults to
@@ -1168,7 +1535,7 @@ pub fn cargo(
 
         // Enable usage of unstable features
         cargo.env("RUSTC_BOOTSTRAP", "1");
-        self.add_rust_test_threads(&mut cargo);
+        self.add_rust_test_threads(&mut cargo.command);
 
         // Almost all of the crates that we compile as part of the bootstrap may
         // have a build script, including the standard library. To compile a
@@ -1200,7 +1567,7 @@ pub fn cargo(
         if mode == Mode::ToolRustc {
             if let Some(llvm_config) = self.llvm_config(target) {
                 let llvm_libdir = output(Command::new(&llvm_config).arg("--libdir"));
-                add_link_lib_path(vec![llvm_libdir.trim().into()], &mut cargo);
+                add_link_lib_path(vec![llvm_libdir.trim().into()], &mut cargo.command);
             }
         }
 
@@ -1221,6 +1588,7 @@ pub fn cargo(
 
         if let Some(ref on_fail) = self.config.on_fail {
             cargo.env("RUSTC_ON_FAIL", on_fail);
+            cargo.env("RUSTC_ON_FAIL_SCRIPTS", self.out.join("on-fail"));
         }
 
         if self.config.print_step_timings {
@@ -1406,6 +1774,61 @@ pub fn cargo(
             }
         }
 
+        // A codegen profile selected for the compiler being built here takes
+        // precedence over the settings above.
+        if let Mode::Rustc | Mode::Codegen = mode {
+            if let Some(profile) = self.config.rust_stage_profiles.get(&(compiler.stage + 1)) {
+                if let Some(n) = profile.opt_level {
+                    cargo.env(profile_var("OPT_LEVEL"), n.to_string());
+                }
+                if let Some(n) = profile.debuginfo {
+                    cargo.env(profile_var("DEBUG"), n.to_string());
+                }
+                if let Some(n) = profile.codegen_units {
+                    cargo.env(profile_var("CODEGEN_UNITS"), n.to_string());
+                }
+                if let Some(b) = profile.debug_assertions {
+                    cargo.env(profile_var("DEBUG_ASSERTIONS"), b.to_string());
+                }
+                if let Some(b) = profile.overflow_checks {
+                    cargo.env(profile_var("OVERFLOW_CHECKS"), b.to_string());
+                }
+            }
+        }
+
+        // Package overrides can't be given to cargo through the environment,
+        // so they're passed as config on the command line, which is still
+        // unstable. They're only meant for the compiler's crates, so std and
+        // tools keep the profile they'd otherwise be built with.
+        let package_overrides = &self.config.rust_package_overrides;
+        if matches!(mode, Mode::Rustc | Mode::Codegen) && !package_overrides.is_empty() {
+            let profile = if optimized { "release" } else { "dev" };
+            cargo.arg("-Zunstable-options");
+            for (package, overrides) in package_overrides {
+                let mut set = |key: &str, value: String| {
+                    cargo.arg("--config").arg(format!(
+                        "profile.{}.package.\"{}\".{}={}",
+                        profile, package, key, value
+                    ));
+                };
+                if let Some(n) = overrides.opt_level {
+                    set("opt-level", n.to_string());
+                }
+                if let Some(n) = overrides.debuginfo {
+                    set("debug", n.to_string());
+                }
+                if let Some(n) = overrides.codegen_units {
+                    set("codegen-units", n.to_string());
+                }
+                if let Some(b) = overrides.debug_assertions {
+                    set("debug-assertions", b.to_string());
+                }
+                if let Some(b) = overrides.overflow_checks {
+                    set("overflow-checks", b.to_string());
+                }
+            }
+        }
+
         if self.config.rust_optimize {
             // FIXME: cargo bench/install do not accept `--release`
             if cmd != "bench" && cmd != "install" {
@@ -1423,8 +1846,6 @@ pub fn cargo(
         // Try to use a sysroot-relative bindir, in case it was configured absolutely.
         cargo.env("RUSTC_INSTALL_BINDIR", self.config.bindir_relative());
 
-        self.ci_env.force_coloring_in_ci(&mut cargo);
-
         // When we build Rust dylibs they're all intended for intermediate
         // usage, so make sure we pass the -Cprefer-dynamic flag instead of
         // linking all deps statically into the dylib.
@@ -1447,7 +1868,9 @@ pub fn cargo(
             }
         }
 
-        Cargo { command: cargo, rustflags, rustdocflags }
+        cargo.rustflags = rustflags;
+        cargo.rustdocflags = rustdocflags;
+        cargo
     }
 
     /// Ensure that a given step is built, returning its output. This will
@@ -1468,33 +1891,56 @@ pub fn ensure<S: Step>(&'a self, step: S) -> S::Output {
                 }
                 panic!(out);
             }
-            if let Some(out) = self.cache.get(&step) {
+            let cached = self.cache.get(&step);
+            if self.config.json_plan {
+                let planned = PlannedStep::new(&step, stack.len(), cached.is_some());
+                self.plan.borrow_mut().push(planned);
+            }
+            if let Some(out) = cached {
                 self.verbose(&format!("{}c {:?}", "  ".repeat(stack.len()), step));
+                logging::step_cached(step_name::<S>(), &step);
 
                 return out;
             }
             self.verbose(&format!("{}> {:?}", "  ".repeat(stack.len()), step));
+            if self.config.explain {
+                self.explain(&step);
+                self.why.borrow_mut().push(format!("{}: {:?}", step_name::<S>(), step));
+            }
             stack.push(Box::new(step.clone()));
+            self.running_steps.borrow_mut().push(step_name::<S>());
         }
 
-        let (out, dur) = {
-            let start = Instant::now();
+        let span = logging::step_span(step_name::<S>(), &step);
+        let start = Instant::now();
+        let (out, dur) = span.in_scope(|| {
             let zero = Duration::new(0, 0);
             let parent = self.time_spent_on_dependencies.replace(zero);
+            hooks::run_pre(self, &step);
             let out = step.clone().run(self);
+            hooks::run_post(self, &step, &out);
             let dur = start.elapsed();
             let deps = self.time_spent_on_dependencies.replace(parent + dur);
             (out, dur - deps)
-        };
+        });
 
         if self.config.print_step_timings && !self.config.dry_run {
             println!("[TIMING] {:?} -- {}.{:03}", step, dur.as_secs(), dur.subsec_millis());
         }
+        if let (Some(trace), false) = (&self.trace, self.config.dry_run) {
+            trace.step(step_name::<S>(), format!("{:?}", step), start);
+        }
+
+        self.check_free_space();
 
         {
             let mut stack = self.stack.borrow_mut();
             let cur_step = stack.pop().expect("step stack empty");
             assert_eq!(cur_step.downcast_ref(), Some(&step));
+            self.running_steps.borrow_mut().pop();
+            if self.config.explain {
+                self.why.borrow_mut().pop();
+            }
         }
         self.verbose(&format!("{}< {:?}", "  ".repeat(self.stack.borrow().len()), step));
         self.cache.put(step, out.clone());
@@ -1546,9 +1992,21 @@ pub struct Cargo {
     command: Command,
     rustflags: Rustflags,
     rustdocflags: Rustflags,
+    /// The variables set and removed through `env` and `env_remove`, which
+    /// the `Debug` output of `Command` doesn't show, for `-vv`.
+    env: Vec<(OsString, Option<OsString>)>,
 }
 
 impl Cargo {
+    fn new(command: Command) -> Cargo {
+        Cargo {
+            command,
+            rustflags: Rustflags(String::new()),
+            rustdocflags: Rustflags(String::new()),
+            env: Vec::new(),
+        }
+    }
+
     pub fn rustdocflag(&mut self, arg: &str) -> &mut Cargo {
         self.rustdocflags.arg(arg);
         self
@@ -1579,6 +2037,13 @@ pub fn env(&mut self, key: impl AsRef<OsStr>, value: impl AsRef<OsStr>) -> &mut
         assert_ne!(key.as_ref(), "RUSTFLAGS");
         assert_ne!(key.as_ref(), "RUSTDOCFLAGS");
         self.command.env(key.as_ref(), value.as_ref());
+        self.env.push((key.as_ref().to_owned(), Some(value.as_ref().to_owned())));
+        self
+    }
+
+    pub fn env_remove(&mut self, key: impl AsRef<OsStr>) -> &mut Cargo {
+        self.command.env_remove(key.as_ref());
+        self.env.push((key.as_ref().to_owned(), None));
         self
     }
 
@@ -1592,13 +2057,16 @@ fn from(mut cargo: Cargo) -> Command {
         let rustflags = &cargo.rustflags.0;
         if !rustflags.is_empty() {
             cargo.command.env("RUSTFLAGS", rustflags);
+            cargo.env.push(("RUSTFLAGS".into(), Some(rustflags.into())));
         }
 
         let rustdocflags = &cargo.rustdocflags.0;
         if !rustdocflags.is_empty() {
             cargo.command.env("RUSTDOCFLAGS", rustdocflags);
+            cargo.env.push(("RUSTDOCFLAGS".into(), Some(rustdocflags.into())));
         }
 
+        util::record_command_env(&cargo.command, cargo.env);
         cargo.command
     }
 }
@@ -132,6 +132,27 @@ fn build_cross_compile() {
         );
     }
 
+    #[test]
+    fn build_library_cross() {
+        let mut config = configure("build", &["A"], &["B"]);
+        let paths = vec![PathBuf::from("library")];
+        config.cmd = Subcommand::Build { paths: paths.clone() };
+        config.include_default_paths = true;
+        let build = Build::new(config);
+        let mut builder = Builder::new(&build);
+        builder.run_step_descriptions(&Builder::get_step_descriptions(Kind::Build), &paths);
+
+        let a = TargetSelection::from_user("A");
+        let b = TargetSelection::from_user("B");
+        assert!(
+            first(builder.cache.all::<compile::Std>())
+                .contains(&compile::Std { compiler: Compiler { host: a, stage: 1 }, target: b })
+        );
+        // Host tools aren't built, even though they are default steps.
+        assert!(!builder.cache.contains::<tool::Rustbook>());
+        assert!(!builder.cache.contains::<tool::ErrorIndex>());
+    }
+
     #[test]
     fn doc_default() {
         let mut config = configure("doc", &["A"], &["A"]);
@@ -160,6 +181,69 @@ fn doc_default() {
             &[tool::Rustdoc { compiler: Compiler { host: a, stage: 0 } },]
         );
     }
+
+    #[test]
+    fn doc_std_cross_target() {
+        let mut config = configure("doc", &["A"], &["A", "B"]);
+        config.cmd = Subcommand::Doc { paths: vec!["library/std".into()], open: false };
+        let build = Build::new(config);
+        let mut builder = Builder::new(&build);
+        builder.run_step_descriptions(
+            &Builder::get_step_descriptions(Kind::Doc),
+            &["library/std".into()],
+        );
+        let a = TargetSelection::from_user("A");
+        let b = TargetSelection::from_user("B");
+
+        // Each target gets its own docs, documented with the host compiler.
+        assert_eq!(
+            first(builder.cache.all::<doc::Std>()),
+            &[doc::Std { stage: 0, target: a }, doc::Std { stage: 0, target: b },]
+        );
+        assert_eq!(
+            first(builder.cache.all::<compile::Std>()),
+            &[
+                compile::Std { compiler: Compiler { host: a, stage: 0 }, target: a },
+                compile::Std { compiler: Compiler { host: a, stage: 0 }, target: b },
+            ]
+        );
+    }
+
+    #[test]
+    fn check_compiler_crate() {
+        let mut config = configure("check", &["A"], &["A"]);
+        let paths = vec![PathBuf::from("compiler/rustc_middle")];
+        config.cmd = Subcommand::Check { paths: paths.clone(), all_targets: false };
+        let build = Build::new(config);
+        let mut builder = Builder::new(&build);
+        builder.run_step_descriptions(&Builder::get_step_descriptions(Kind::Check), &paths);
+        let a = TargetSelection::from_user("A");
+
+        // Only the crate that was asked for is checked, not the whole compiler.
+        assert_eq!(
+            first(builder.cache.all::<check::Rustc>()),
+            &[check::Rustc { target: a, crates: vec![INTERNER.intern_str("rustc_middle")] },]
+        );
+    }
+
+    #[test]
+    fn explain_std() {
+        let mut config = configure("build", &["A"], &["A"]);
+        config.explain = true;
+        let build = Build::new(config);
+        let builder = Builder::new(&build);
+        builder.run_step_descriptions(
+            &Builder::get_step_descriptions(Kind::Build),
+            &["library/std".into()],
+        );
+
+        // Std is linked into the sysroot for the std selected by the path.
+        let explanations = builder.explanations.borrow();
+        let link = explanations.iter().find(|e| e.starts_with("compile::StdLink")).unwrap();
+        let lines = link.lines().collect::<Vec<_>>();
+        assert!(lines[1].starts_with("    required by compile::Std"));
+        assert_eq!(lines.last(), Some(&"    which was selected by the path `library/std` for A"));
+    }
 }
 
 mod dist {
@@ -171,6 +255,18 @@ fn configure(host: &[&str], target: &[&str]) -> Config {
         Config { stage: 2, ..super::configure("dist", host, target) }
     }
 
+    #[test]
+    fn dist_sign() {
+        let mut config = configure(&["A"], &["A"]);
+        config.cmd = Subcommand::Dist { paths: vec![], sign: true };
+        let build = Build::new(config);
+        let builder = Builder::new(&build);
+        builder.execute_cli();
+
+        assert!(builder.cache.contains::<dist::Rustc>());
+        assert!(builder.cache.contains::<dist::Signatures>());
+    }
+
     #[test]
     fn dist_baseline() {
         let build = Build::new(configure(&["A"], &["A"]));
@@ -210,6 +306,13 @@ fn dist_with_targets() {
             first(builder.cache.all::<dist::Docs>()),
             &[dist::Docs { host: a }, dist::Docs { host: b },]
         );
+        assert_eq!(
+            first(builder.cache.all::<doc::ErrorIndex>()),
+            &[
+                doc::ErrorIndex { compiler: Compiler { host: a, stage: 1 }, target: a },
+                doc::ErrorIndex { compiler: Compiler { host: a, stage: 1 }, target: b },
+            ]
+        );
         assert_eq!(
             first(builder.cache.all::<dist::Mingw>()),
             &[dist::Mingw { host: a }, dist::Mingw { host: b },]
@@ -228,6 +331,38 @@ fn dist_with_targets() {
         assert_eq!(first(builder.cache.all::<dist::Src>()), &[dist::Src]);
     }
 
+    #[test]
+    fn dist_component_by_name() {
+        let build = Build::new(configure(&["A"], &["B"]));
+        let mut builder = Builder::new(&build);
+        let paths = vec![PathBuf::from("rust-std")];
+        builder.run_step_descriptions(&Builder::get_step_descriptions(Kind::Dist), &paths);
+
+        let a = TargetSelection::from_user("A");
+        let b = TargetSelection::from_user("B");
+
+        assert_eq!(
+            first(builder.cache.all::<dist::Std>()),
+            &[dist::Std { compiler: Compiler { host: a, stage: 2 }, target: b },]
+        );
+        assert!(builder.cache.all::<dist::Docs>().is_empty());
+        assert!(builder.cache.all::<dist::Rustc>().is_empty());
+        assert!(builder.cache.all::<dist::Src>().is_empty());
+    }
+
+    #[test]
+    fn dist_mingw_by_name() {
+        let build = Build::new(configure(&["A"], &["A"]));
+        let mut builder = Builder::new(&build);
+        let paths = vec![PathBuf::from("rust-mingw")];
+        builder.run_step_descriptions(&Builder::get_step_descriptions(Kind::Dist), &paths);
+
+        let a = TargetSelection::from_user("A");
+        assert_eq!(first(builder.cache.all::<dist::Mingw>()), &[dist::Mingw { host: a },]);
+        assert!(builder.cache.all::<dist::Rustc>().is_empty());
+        assert!(builder.cache.all::<dist::Std>().is_empty());
+    }
+
     #[test]
     fn dist_with_hosts() {
         let build = Build::new(configure(&["A", "B"], &["A", "B"]));
@@ -484,10 +619,13 @@ fn test_with_no_doc_stage0() {
             test_args: vec![],
             rustc_args: vec![],
             fail_fast: true,
+            rerun_failed: false,
             doc_tests: DocTests::No,
             bless: false,
             compare_mode: None,
             rustfix_coverage: false,
+            gc_sessions: false,
+            force_rerun: false,
             pass: None,
         };
 
@@ -524,10 +662,13 @@ fn test_exclude() {
             test_args: Vec::new(),
             rustc_args: Vec::new(),
             fail_fast: true,
+            rerun_failed: false,
             doc_tests: DocTests::No,
             bless: false,
             compare_mode: None,
             rustfix_coverage: false,
+            gc_sessions: false,
+            force_rerun: false,
             pass: None,
         };
 
@@ -542,6 +683,233 @@ fn test_exclude() {
         assert!(builder.cache.contains::<test::RustdocUi>());
     }
 
+    #[test]
+    fn test_exclude_glob() {
+        let mut config = configure(&["A"], &["A"]);
+        config.exclude = vec!["src/tools/*".into(), "src/test/ui*".into()];
+        config.cmd = Subcommand::Test {
+            paths: Vec::new(),
+            test_args: Vec::new(),
+            rustc_args: Vec::new(),
+            fail_fast: true,
+            rerun_failed: false,
+            doc_tests: DocTests::No,
+            bless: false,
+            compare_mode: None,
+            rustfix_coverage: false,
+            gc_sessions: false,
+            force_rerun: false,
+            pass: None,
+        };
+
+        let build = Build::new(config);
+        let builder = Builder::new(&build);
+        builder.run_step_descriptions(&Builder::get_step_descriptions(Kind::Test), &[]);
+
+        // `src/tools/tidy`, `src/test/ui` and `src/test/ui-fulldeps` match.
+        assert!(!builder.cache.contains::<test::Tidy>());
+        assert!(!builder.cache.contains::<test::Ui>());
+        assert!(!builder.cache.contains::<test::UiFullDeps>());
+
+        // `src/test/rustdoc-ui` doesn't.
+        assert!(builder.cache.contains::<test::RustdocUi>());
+    }
+
+    #[test]
+    fn test_exclude_steps() {
+        let mut config = configure(&["A"], &["A"]);
+        config.exclude = vec!["test::tidy".into(), "test::rustdoc-*".into()];
+        config.cmd = Subcommand::Test {
+            paths: Vec::new(),
+            test_args: Vec::new(),
+            rustc_args: Vec::new(),
+            fail_fast: true,
+            rerun_failed: false,
+            doc_tests: DocTests::No,
+            bless: false,
+            compare_mode: None,
+            rustfix_coverage: false,
+            gc_sessions: false,
+            force_rerun: false,
+            pass: None,
+        };
+
+        let build = Build::new(config);
+        let builder = Builder::new(&build);
+        builder.run_step_descriptions(&Builder::get_step_descriptions(Kind::Test), &[]);
+
+        assert!(!builder.cache.contains::<test::Tidy>());
+        assert!(!builder.cache.contains::<test::RustdocUi>());
+        assert!(!builder.cache.contains::<test::RustdocJSStd>());
+        assert!(builder.cache.contains::<test::Ui>());
+    }
+
+    #[test]
+    fn test_plugin_by_path() {
+        let mut config = configure(&["A"], &["A"]);
+        let plugin = |name: &str, path: &str| crate::config::Plugin {
+            name: name.to_string(),
+            kind: "test".to_string(),
+            paths: vec![path.to_string()],
+            command: PathBuf::from(name),
+            args: Vec::new(),
+            requires: Vec::new(),
+            default: false,
+            only_hosts: false,
+        };
+        config.plugins = vec![plugin("harness", "src/tools/harness"), plugin("other", "other")];
+        let paths = vec![PathBuf::from("src/tools/harness")];
+        config.cmd = Subcommand::Test {
+            paths: paths.clone(),
+            test_args: Vec::new(),
+            rustc_args: Vec::new(),
+            fail_fast: true,
+            rerun_failed: false,
+            doc_tests: DocTests::No,
+            bless: false,
+            compare_mode: None,
+            rustfix_coverage: false,
+            gc_sessions: false,
+            force_rerun: false,
+            pass: None,
+        };
+
+        let build = Build::new(config);
+        let mut builder = Builder::new(&build);
+        builder.run_step_descriptions(&Builder::get_step_descriptions(Kind::Test), &paths);
+
+        let a = TargetSelection::from_user("A");
+        assert_eq!(
+            first(builder.cache.all::<plugin::External>()),
+            &[plugin::External { name: INTERNER.intern_str("harness"), target: a }]
+        );
+        assert!(!builder.cache.contains::<test::Ui>());
+        // Nothing is built for a plugin which requires nothing.
+        assert!(!builder.cache.contains::<compile::Assemble>());
+    }
+
+    #[test]
+    fn test_tools_by_path() {
+        let mut config = configure(&["A"], &["A"]);
+        let paths =
+            vec![PathBuf::from("src/tools/cargo"), PathBuf::from("src/tools/rust-analyzer")];
+        config.cmd = Subcommand::Test {
+            paths: paths.clone(),
+            test_args: Vec::new(),
+            rustc_args: Vec::new(),
+            fail_fast: true,
+            rerun_failed: false,
+            doc_tests: DocTests::No,
+            bless: false,
+            compare_mode: None,
+            rustfix_coverage: false,
+            gc_sessions: false,
+            force_rerun: false,
+            pass: None,
+        };
+
+        let build = Build::new(config);
+        let builder = Builder::new(&build);
+        builder.run_step_descriptions(&Builder::get_step_descriptions(Kind::Test), &paths);
+
+        // Each tool's tests run with the tool built by the same compiler.
+        assert!(builder.cache.contains::<test::Cargo>());
+        assert!(builder.cache.contains::<tool::Cargo>());
+        assert!(builder.cache.contains::<test::RustAnalyzer>());
+        assert!(builder.cache.contains::<tool::RustAnalyzer>());
+        assert!(!builder.cache.contains::<test::Clippy>());
+    }
+
+    #[test]
+    fn bench_tools_by_path() {
+        let mut config = configure(&["A"], &["A"]);
+        let paths = vec![PathBuf::from("src/tools/rustfmt"), PathBuf::from("src/librustdoc")];
+        config.cmd = Subcommand::Bench { paths: paths.clone(), test_args: Vec::new() };
+
+        let build = Build::new(config);
+        let builder = Builder::new(&build);
+        builder.run_step_descriptions(&Builder::get_step_descriptions(Kind::Bench), &paths);
+
+        assert!(builder.cache.contains::<test::BenchRustfmt>());
+        assert!(builder.cache.contains::<test::CrateRustdoc>());
+        assert!(!builder.cache.contains::<test::BenchCargo>());
+        // Benchmarking a tool doesn't run its tests.
+        assert!(!builder.cache.contains::<test::Rustfmt>());
+    }
+
+    #[test]
+    fn miri_library_crate() {
+        let mut config = configure(&["A"], &["A"]);
+        let paths = vec![PathBuf::from("library/alloc")];
+        config.cmd = Subcommand::Miri { paths: paths.clone(), test_args: Vec::new() };
+
+        let build = Build::new(config);
+        let builder = Builder::new(&build);
+        builder.run_step_descriptions(&Builder::get_step_descriptions(Kind::Miri), &paths);
+
+        assert!(builder.cache.contains::<test::CrateMiri>());
+        assert!(builder.cache.contains::<tool::CargoMiri>());
+        // The tests run against the Miri sysroot, not the built std.
+        assert!(!builder.cache.contains::<test::Crate>());
+    }
+
+    #[test]
+    fn run_tool() {
+        let mut config = configure(&["A"], &["A"]);
+        let paths = vec![PathBuf::from("src/tools/rustbook")];
+        config.cmd = Subcommand::Run { paths: paths.clone(), args: vec!["build".into()] };
+
+        let build = Build::new(config);
+        let builder = Builder::new(&build);
+        builder.run_step_descriptions(&Builder::get_step_descriptions(Kind::Run), &paths);
+
+        // The tool is built before it's run.
+        assert!(builder.cache.contains::<run::RunTool>());
+        assert!(builder.cache.contains::<tool::Rustbook>());
+        assert!(!builder.cache.contains::<run::BuildManifest>());
+    }
+
+    #[test]
+    fn install_components_by_name() {
+        let mut config = configure(&["A"], &["A"]);
+        let paths = vec![PathBuf::from("rustc"), PathBuf::from("std"), PathBuf::from("clippy")];
+        config.cmd = Subcommand::Install { paths: paths.clone() };
+        // Clippy is only distributed as one of the extended tools.
+        config.extended = true;
+
+        let build = Build::new(config);
+        let builder = Builder::new(&build);
+        builder.run_step_descriptions(&Builder::get_step_descriptions(Kind::Install), &paths);
+
+        // Only the requested components are installed.
+        assert!(builder.cache.contains::<install::Rustc>());
+        assert!(builder.cache.contains::<install::Std>());
+        assert!(builder.cache.contains::<install::Clippy>());
+        assert!(!builder.cache.contains::<install::Cargo>());
+        assert!(!builder.cache.contains::<install::Docs>());
+        assert!(!builder.cache.contains::<install::Src>());
+    }
+
+    #[test]
+    fn suggested_paths() {
+        let build = Build::new(configure(&["A"], &["A"]));
+        let changed = vec![
+            PathBuf::from("src/test/ui/foo/bar.rs"),
+            PathBuf::from("src/test/ui/foo/bar.stderr"),
+            PathBuf::from("src/tools/rust-analyzer/src/lib.rs"),
+            PathBuf::from("README.md"),
+        ];
+        let suggested = Builder::suggested_paths(&build, Kind::Test, &changed);
+        assert_eq!(
+            suggested.into_iter().collect::<Vec<_>>(),
+            vec![
+                PathBuf::from("src/test/ui/foo"),
+                PathBuf::from("src/test/ui/foo/bar.rs"),
+                PathBuf::from("src/tools/rust-analyzer"),
+            ]
+        );
+    }
+
     #[test]
     fn doc_ci() {
         let mut config = configure(&["A"], &["A"]);
@@ -579,10 +947,13 @@ fn test_docs() {
             test_args: vec![],
             rustc_args: vec![],
             fail_fast: true,
+            rerun_failed: false,
             doc_tests: DocTests::Yes,
             bless: false,
             compare_mode: None,
             rustfix_coverage: false,
+            gc_sessions: false,
+            force_rerun: false,
             pass: None,
         };
         let build = Build::new(config);
@@ -616,3 +987,94 @@ fn test_docs() {
         );
     }
 }
+
+mod step_cache {
+    use super::configure;
+    use crate::builder::*;
+    use crate::step_cache::{fingerprint, is_fresh, record};
+    use filetime::FileTime;
+
+    fn cargo(arg: &str) -> Cargo {
+        let mut cargo = Cargo::new(Command::new("cargo"));
+        cargo.arg(arg);
+        cargo
+    }
+
+    fn touch(path: &Path, seconds: i64) {
+        t!(filetime::set_file_mtime(path, FileTime::from_unix_time(seconds, 0)));
+    }
+
+    #[test]
+    fn fingerprint_invalidation() {
+        let mut config = configure("build", &["A"], &["A"]);
+        config.step_cache = true;
+        let src = config.out.join("step-cache-src");
+        t!(fs::create_dir_all(&src));
+        let source = src.join("lib.rs");
+        t!(fs::write(&source, ""));
+        touch(&source, 1_000_000);
+        // Creating files updates the directory too, which counts as well.
+        touch(&src, 1_000_000);
+        let dep = config.out.join("dep.stamp");
+        t!(fs::write(&dep, ""));
+        touch(&dep, 1_000_000);
+        config.config = config.out.join("config.toml");
+        t!(fs::write(&config.config, ""));
+        let mut build = Build::new(config);
+        // The cache is only used by real runs, but `Build::new` would then
+        // query the compiler about the made up targets.
+        build.config.dry_run = false;
+        let builder = Builder::new(&build);
+
+        let inputs = [src.to_str().unwrap()];
+        let deps = [dep.clone()];
+        let get = |arg| fingerprint(&builder, &cargo(arg), &inputs, &deps).unwrap();
+        let original = get("build");
+        assert_eq!(get("build"), original);
+        assert_ne!(get("check"), original);
+
+        // Touching a source, adding one or rebuilding a dependency all make
+        // the step run again.
+        touch(&source, 2_000_000);
+        let touched = get("build");
+        assert_ne!(touched, original);
+        t!(fs::write(src.join("new.rs"), ""));
+        touch(&src.join("new.rs"), 1_000_000);
+        touch(&src, 1_000_000);
+        let added = get("build");
+        assert_ne!(added, touched);
+        touch(&dep, 2_000_000);
+        let rebuilt = get("build");
+        assert_ne!(rebuilt, added);
+        t!(fs::write(&builder.config.config, "[build]\n"));
+        assert_ne!(get("build"), rebuilt);
+    }
+
+    #[test]
+    fn fresh_stamps() {
+        let mut config = configure("build", &["A"], &["A"]);
+        config.step_cache = true;
+        let mut build = Build::new(config);
+        build.config.dry_run = false;
+        let builder = Builder::new(&build);
+
+        let stamp = builder.out.join("std.stamp");
+        let _ = fs::remove_file(&stamp);
+        record(&builder, &stamp, "0123".to_string());
+        // The stamp itself has to exist too, e.g. after `x.py clean`.
+        assert!(!is_fresh(&builder, &stamp, "0123"));
+        t!(fs::write(&stamp, ""));
+        assert!(is_fresh(&builder, &stamp, "0123"));
+        assert!(!is_fresh(&builder, &stamp, "4567"));
+    }
+
+    #[test]
+    fn disabled() {
+        let mut config = configure("build", &["A"], &["A"]);
+        config.step_cache = true;
+        let build = Build::new(config);
+        let builder = Builder::new(&build);
+        // `configure` makes a dry run, which never uses the cache.
+        assert_eq!(fingerprint(&builder, &cargo("build"), &[], &[]), None);
+    }
+}
@@ -0,0 +1,78 @@
+//! Skipping of expensive default steps on CI based on the files a change
+//! touches.
+//!
+//! With `--ci-change-detection`, the files changed since the merge base with
+//! the upstream branch are listed with the VCS (see `vcs`), and default steps
+//! that appear in `TRIGGERS` are only run if one of those files is below one
+//! of the step's trigger paths. Steps which aren't listed always run, as does
+//! everything if the build system or CI configuration itself changed.
+
+use std::path::{Path, PathBuf};
+
+/// Branch to diff against if `--ci-change-detection` is given without a value.
+pub const DEFAULT_BASE: &str = "origin/master";
+
+/// Changes to any of these paths can affect every step, so nothing is skipped.
+const RUN_EVERYTHING: &[&str] =
+    &["src/bootstrap", "src/ci", "src/stage0.txt", "Cargo.lock", "Cargo.toml", "x.py"];
+
+/// Steps which may be skipped, by the path they are selected with, along with
+/// the paths whose changes cause them to run.
+///
+/// The LLVM-centric test suites only exercise the compiler's code generation,
+/// so, for example, a change confined to `library/` doesn't run them.
+const TRIGGERS: &[(&str, &[&str])] = &[
+    ("src/test/assembly", &["compiler", "src/llvm-project", "src/test/assembly"]),
+    ("src/test/codegen", &["compiler", "src/llvm-project", "src/test/codegen"]),
+    ("src/test/codegen-units", &["compiler", "src/test/codegen-units"]),
+    ("src/test/debuginfo", &["compiler", "src/etc", "src/llvm-project", "src/test/debuginfo"]),
+    ("src/test/run-make", &["compiler", "src/llvm-project", "src/test/run-make"]),
+    ("src/test/run-make-fulldeps", &["compiler", "src/llvm-project", "src/test/run-make-fulldeps"]),
+    ("src/test/rustdoc", &["compiler", "src/librustdoc", "src/test/rustdoc"]),
+    ("src/test/rustdoc-ui", &["compiler", "src/librustdoc", "src/test/rustdoc-ui"]),
+    ("src/tools/linkchecker", &["library", "src/doc", "src/librustdoc", "src/tools/linkchecker"]),
+];
+
+/// Returns whether the default step selected by `is_step` (a test on the
+/// step's paths) can be skipped given the `changed` files.
+pub fn can_skip(is_step: impl Fn(&Path) -> bool, changed: &[PathBuf]) -> bool {
+    if changed.iter().any(|file| RUN_EVERYTHING.iter().any(|p| file.starts_with(p))) {
+        return false;
+    }
+    let triggers = match TRIGGERS.iter().find(|(step, _)| is_step(Path::new(step))) {
+        Some((_, triggers)) => triggers,
+        None => return false,
+    };
+    !changed.iter().any(|file| triggers.iter().any(|p| file.starts_with(p)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn changed(files: &[&str]) -> Vec<PathBuf> {
+        files.iter().map(PathBuf::from).collect()
+    }
+
+    #[test]
+    fn triggers() {
+        let codegen = |path: &Path| path == Path::new("src/test/codegen");
+        assert!(can_skip(codegen, &changed(&["library/std/src/lib.rs"])));
+        assert!(can_skip(codegen, &changed(&[])));
+        assert!(!can_skip(codegen, &changed(&["compiler/rustc_codegen_llvm/src/lib.rs"])));
+        assert!(!can_skip(codegen, &changed(&["library/core", "src/test/codegen/simd.rs"])));
+    }
+
+    #[test]
+    fn unlisted_steps_always_run() {
+        let ui = |path: &Path| path == Path::new("src/test/ui");
+        assert!(!can_skip(ui, &changed(&["library/std/src/lib.rs"])));
+    }
+
+    #[test]
+    fn build_system_changes_run_everything() {
+        let codegen = |path: &Path| path == Path::new("src/test/codegen");
+        assert!(!can_skip(codegen, &changed(&["src/bootstrap/builder.rs"])));
+        assert!(!can_skip(codegen, &changed(&["library/std/src/lib.rs", "Cargo.lock"])));
+    }
+}
@@ -0,0 +1,96 @@
+//! Registry of changes to bootstrap that affect existing configurations.
+//!
+//! Each entry is identified by the number of the pull request that made the
+//! change or, for changes made in this fork, the number of its request.
+//! `config.toml` records the newest id the user has looked at in `change-id`,
+//! and every entry after it is shown to them until they bump it. The two kinds
+//! of ids aren't comparable, so entries are ordered by their position in
+//! `CONFIG_CHANGE_HISTORY` rather than by id.
+//!
+//! If you change the default or meaning of an option, or otherwise make a
+//! change that can break an existing `config.toml`, add an entry at the end of
+//! `CONFIG_CHANGE_HISTORY`.
+
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChangeSeverity {
+    /// Used when the change doesn't require any action from the user.
+    Info,
+    /// Used when the user may have to update their `config.toml`.
+    Warning,
+}
+
+impl fmt::Display for ChangeSeverity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ChangeSeverity::Info => "INFO",
+            ChangeSeverity::Warning => "WARNING",
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ChangeInfo {
+    pub change_id: usize,
+    pub severity: ChangeSeverity,
+    pub summary: &'static str,
+}
+
+/// Changes since version 2 of `changelog-seen`, oldest first.
+pub const CONFIG_CHANGE_HISTORY: &[ChangeInfo] = &[
+    ChangeInfo {
+        change_id: 77473,
+        severity: ChangeSeverity::Info,
+        summary: "`x.py check` needs opt-in to check tests (`--all-targets`).",
+    },
+    ChangeInfo {
+        change_id: 77558,
+        severity: ChangeSeverity::Info,
+        summary: "The default bootstrap profiles are now located at \
+                  `src/bootstrap/defaults/config.$PROFILE.toml`.",
+    },
+    ChangeInfo {
+        change_id: 77703,
+        severity: ChangeSeverity::Warning,
+        summary: "`llvm-libunwind` now accepts `in-tree` (formerly `true`), `system` or `no` \
+                  (formerly `false`).",
+    },
+    ChangeInfo {
+        change_id: 78513,
+        severity: ChangeSeverity::Info,
+        summary: "If Rust is already installed, `build` now defaults to the host of the \
+                  default toolchain.",
+    },
+    ChangeInfo {
+        change_id: 1036,
+        severity: ChangeSeverity::Warning,
+        summary: "Relative paths in `config.toml` are now resolved against the directory \
+                  containing it, rather than the source root or the current directory.",
+    },
+    ChangeInfo {
+        change_id: 1055,
+        severity: ChangeSeverity::Warning,
+        summary: "`rust.deny-warnings` is deprecated in favor of `rust.warnings`, which is \
+                  `\"deny\"` or `\"warn\"`. `deny-warnings = false` still works for now.",
+    },
+];
+
+/// Returns the id of the newest entry in `CONFIG_CHANGE_HISTORY`.
+pub fn latest_change_id() -> usize {
+    CONFIG_CHANGE_HISTORY.last().map_or(0, |change| change.change_id)
+}
+
+/// Returns the changes made after the one with `change_id`. An id without an
+/// entry, such as that of an upstream pull request which didn't change the
+/// configuration, is taken to come before the first entry with a larger id.
+pub fn changes_since(change_id: usize) -> Vec<&'static ChangeInfo> {
+    let history = CONFIG_CHANGE_HISTORY;
+    let start = match history.iter().position(|change| change.change_id == change_id) {
+        Some(i) => i + 1,
+        None => {
+            history.iter().position(|change| change.change_id > change_id).unwrap_or(history.len())
+        }
+    };
+    history[start..].iter().collect()
+}
@@ -6,55 +6,24 @@
 //! print out as part of its version information.
 
 use std::path::Path;
-use std::process::Command;
-
-use build_helper::output;
 
+use crate::vcs::{CommitInfo, Vcs};
 use crate::Build;
 
+/// Version information of a source tree. Despite the name, this also works
+/// with the other version control systems `vcs` knows about.
 pub struct GitInfo {
-    inner: Option<Info>,
-}
-
-struct Info {
-    commit_date: String,
-    sha: String,
-    short_sha: String,
+    inner: Option<CommitInfo>,
 }
 
 impl GitInfo {
     pub fn new(ignore_git: bool, dir: &Path) -> GitInfo {
-        // See if this even begins to look like a git dir
-        if ignore_git || !dir.join(".git").exists() {
-            return GitInfo { inner: None };
-        }
-
-        // Make sure git commands work
-        match Command::new("git").arg("rev-parse").current_dir(dir).output() {
-            Ok(ref out) if out.status.success() => {}
-            _ => return GitInfo { inner: None },
-        }
+        let vcs = if ignore_git { Vcs::None } else { Vcs::detect(dir) };
+        GitInfo::with_vcs(vcs, dir)
+    }
 
-        // Ok, let's scrape some info
-        let ver_date = output(
-            Command::new("git")
-                .current_dir(dir)
-                .arg("log")
-                .arg("-1")
-                .arg("--date=short")
-                .arg("--pretty=format:%cd"),
-        );
-        let ver_hash = output(Command::new("git").current_dir(dir).arg("rev-parse").arg("HEAD"));
-        let short_ver_hash = output(
-            Command::new("git").current_dir(dir).arg("rev-parse").arg("--short=9").arg("HEAD"),
-        );
-        GitInfo {
-            inner: Some(Info {
-                commit_date: ver_date.trim().to_string(),
-                sha: ver_hash.trim().to_string(),
-                short_sha: short_ver_hash.trim().to_string(),
-            }),
-        }
+    pub fn with_vcs(vcs: Vcs, dir: &Path) -> GitInfo {
+        GitInfo { inner: vcs.commit_info(dir) }
     }
 
     pub fn sha(&self) -> Option<&str> {
@@ -2,7 +2,7 @@
 
 use crate::builder::{Builder, Kind, RunConfig, ShouldRun, Step};
 use crate::cache::Interned;
-use crate::compile::{add_to_sysroot, run_cargo, rustc_cargo, rustc_cargo_env, std_cargo};
+use crate::compile::{self, add_to_sysroot, run_cargo, rustc_cargo, rustc_cargo_env, std_cargo};
 use crate::config::TargetSelection;
 use crate::tool::{prepare_tool_cargo, SourceType};
 use crate::INTERNER;
@@ -14,13 +14,19 @@ pub struct Std {
     pub target: TargetSelection,
 }
 
+/// The compiler everything is checked with: the stage0 compiler, unless
+/// `--stage` or `build.check-stage` asks for a later one.
+fn check_compiler(builder: &Builder<'_>) -> Compiler {
+    builder.compiler(builder.top_stage, builder.config.build)
+}
+
 /// Returns args for the subcommand itself (not for cargo)
 fn args(builder: &Builder<'_>) -> Vec<String> {
     fn strings<'a>(arr: &'a [&str]) -> impl Iterator<Item = String> + 'a {
         arr.iter().copied().map(String::from)
     }
 
-    if let Subcommand::Clippy { fix, .. } = builder.config.cmd {
+    if let Subcommand::Clippy { fix, ref lint_levels, .. } = builder.config.cmd {
         // disable the most spammy clippy lints
         let ignored_lints = vec![
             "many_single_char_names", // there are a lot in stdarch
@@ -42,8 +48,13 @@ fn strings<'a>(arr: &'a [&str]) -> impl Iterator<Item = String> + 'a {
                 "--lib", "--bins", "--examples",
             ]));
         }
-        args.extend(strings(&["--", "--cap-lints", "warn"]));
+        args.push("--".to_owned());
+        // Explicitly denied lints have to be able to fail the build.
+        if !lint_levels.iter().any(|level| level.starts_with("-D") || level.starts_with("-F")) {
+            args.extend(strings(&["--cap-lints", "warn"]));
+        }
         args.extend(ignored_lints.iter().map(|lint| format!("-Aclippy::{}", lint)));
+        args.extend(lint_levels.iter().cloned());
         args
     } else {
         vec![]
@@ -73,7 +84,13 @@ fn make_run(run: RunConfig<'_>) {
 
     fn run(self, builder: &Builder<'_>) {
         let target = self.target;
-        let compiler = builder.compiler(0, builder.config.build);
+        let compiler = check_compiler(builder);
+
+        if compiler.stage != 0 {
+            // Unlike the stage0 compiler, a compiler we built only has the
+            // std it needs for build scripts in its sysroot once it's built.
+            builder.ensure(compile::Std { compiler, target: compiler.host });
+        }
 
         let mut cargo = builder.cargo(
             compiler,
@@ -94,9 +111,14 @@ fn run(self, builder: &Builder<'_>) {
             true,
         );
 
-        let libdir = builder.sysroot_libdir(compiler, target);
-        let hostdir = builder.sysroot_libdir(compiler, compiler.host);
-        add_to_sysroot(&builder, &libdir, &hostdir, &libstd_stamp(builder, compiler, target));
+        // The metadata of checked crates would conflict with the libraries
+        // built for the sysroot of later stages, so it's only added to the
+        // sysroot of stage 0, which is never built.
+        if compiler.stage == 0 {
+            let libdir = builder.sysroot_libdir(compiler, target);
+            let hostdir = builder.sysroot_libdir(compiler, compiler.host);
+            add_to_sysroot(&builder, &libdir, &hostdir, &libstd_stamp(builder, compiler, target));
+        }
 
         // Then run cargo again, once we've put the rmeta files for the library
         // crates into the sysroot. This is needed because e.g., core's tests
@@ -137,11 +159,18 @@ fn run(self, builder: &Builder<'_>) {
             );
         }
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Rustc {
     pub target: TargetSelection,
+    /// The compiler crates to check along with their dependencies, or all of
+    /// them if empty.
+    pub crates: Vec<Interned<String>>,
 }
 
 impl Step for Rustc {
@@ -154,7 +183,21 @@ fn should_run(run: ShouldRun<'_>) -> ShouldRun<'_> {
     }
 
     fn make_run(run: RunConfig<'_>) {
-        run.builder.ensure(Rustc { target: run.target });
+        let builder = run.builder;
+        // `x.py check compiler/rustc_middle` only checks the crates that were
+        // asked for, rather than the whole compiler.
+        let crates = builder
+            .in_tree_crates("rustc-main", Some(run.target))
+            .into_iter()
+            .filter(|krate| {
+                builder.paths.iter().any(|path| {
+                    let path = path.strip_prefix(".").unwrap_or(path);
+                    krate.path.ends_with(path)
+                })
+            })
+            .map(|krate| krate.name)
+            .collect();
+        builder.ensure(Rustc { target: run.target, crates });
     }
 
     /// Builds the compiler.
@@ -163,10 +206,17 @@ fn make_run(run: RunConfig<'_>) {
     /// the `compiler` targeting the `target` architecture. The artifacts
     /// created will also be linked into the sysroot directory.
     fn run(self, builder: &Builder<'_>) {
-        let compiler = builder.compiler(0, builder.config.build);
+        let compiler = check_compiler(builder);
         let target = self.target;
 
-        builder.ensure(Std { target });
+        if compiler.stage != 0 {
+            // The compiler crates are checked against the real std of a
+            // compiler we built, which its build scripts need as well.
+            builder.ensure(compile::Std { compiler, target: compiler.host });
+            builder.ensure(compile::Std { compiler, target });
+        } else {
+            builder.ensure(Std { target });
+        }
 
         let mut cargo = builder.cargo(
             compiler,
@@ -180,14 +230,27 @@ fn run(self, builder: &Builder<'_>) {
             cargo.arg("--all-targets");
         }
 
-        // Explicitly pass -p for all compiler krates -- this will force cargo
-        // to also check the tests/benches/examples for these crates, rather
-        // than just the leaf crate.
-        for krate in builder.in_tree_crates("rustc-main", Some(target)) {
-            cargo.arg("-p").arg(krate.name);
+        if self.crates.is_empty() {
+            // Explicitly pass -p for all compiler krates -- this will force cargo
+            // to also check the tests/benches/examples for these crates, rather
+            // than just the leaf crate.
+            for krate in builder.in_tree_crates("rustc-main", Some(target)) {
+                cargo.arg("-p").arg(krate.name);
+            }
+            builder
+                .info(&format!("Checking compiler artifacts ({} -> {})", &compiler.host, target));
+        } else {
+            for krate in &self.crates {
+                cargo.arg("-p").arg(krate);
+            }
+            let crates = self.crates.iter().map(|krate| &krate[..]).collect::<Vec<_>>();
+            builder.info(&format!(
+                "Checking compiler artifacts for {} ({} -> {})",
+                crates.join(", "),
+                &compiler.host,
+                target
+            ));
         }
-
-        builder.info(&format!("Checking compiler artifacts ({} -> {})", &compiler.host, target));
         run_cargo(
             builder,
             cargo,
@@ -201,6 +264,10 @@ fn run(self, builder: &Builder<'_>) {
         let hostdir = builder.sysroot_libdir(compiler, compiler.host);
         add_to_sysroot(&builder, &libdir, &hostdir, &librustc_stamp(builder, compiler, target));
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
@@ -225,11 +292,11 @@ fn make_run(run: RunConfig<'_>) {
     }
 
     fn run(self, builder: &Builder<'_>) {
-        let compiler = builder.compiler(0, builder.config.build);
+        let compiler = check_compiler(builder);
         let target = self.target;
         let backend = self.backend;
 
-        builder.ensure(Rustc { target });
+        builder.ensure(Rustc { target, crates: Vec::new() });
 
         let mut cargo = builder.cargo(
             compiler,
@@ -257,6 +324,10 @@ fn run(self, builder: &Builder<'_>) {
             true,
         );
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
 }
 
 macro_rules! tool_check_step {
@@ -280,10 +351,10 @@ fn make_run(run: RunConfig<'_>) {
             }
 
             fn run(self, builder: &Builder<'_>) {
-                let compiler = builder.compiler(0, builder.config.build);
+                let compiler = check_compiler(builder);
                 let target = self.target;
 
-                builder.ensure(Rustc { target });
+                builder.ensure(Rustc { target, crates: Vec::new() });
 
                 let mut cargo = prepare_tool_cargo(
                     builder,
@@ -331,6 +402,10 @@ fn stamp(
                         .join(format!(".{}-check.stamp", stringify!($name).to_lowercase()))
                 }
             }
+
+            fn target(&self) -> Option<TargetSelection> {
+                Some(self.target)
+            }
         }
     };
 }
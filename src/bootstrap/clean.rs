@@ -3,44 +3,495 @@
 //! Responsible for cleaning out a build directory of all old and stale
 //! artifacts to prepare for a fresh build. Currently doesn't remove the
 //! `build/cache` directory (download cache) or the `build/$target/llvm`
-//! directory unless the `--all` flag is present.
+//! directory unless the `--all` flag is present, and with `--keep-downloads`
+//! and `--keep-llvm` keeps the downloads or LLVM in any case.
+//!
+//! This also implements `x.py gc`, which only removes the parts of the build
+//! directory that haven't been used in a while, and `build.retention`, which
+//! removes the output of older dist runs and stages after successful builds.
+//! It also records how much space the larger parts of the build took up, for
+//! the disk space check before the next build.
 
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
 use std::fs;
 use std::io::{self, ErrorKind};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use build_helper::t;
 
-use crate::Build;
+use crate::artifact_cache;
+use crate::config::TargetSelection;
+use crate::{Build, Subcommand};
+
+/// The directories in the output for a target that hold downloads rather
+/// than build output: the stage0 toolchain, which includes rustfmt, and the
+/// LLVM of `llvm.download-ci-llvm`.
+const TARGET_DOWNLOADS: &[&str] = &["stage0", "ci-llvm"];
+
+/// The file in the build directory with the disk usage of parts of the build.
+const DISK_USAGE: &str = "disk-usage";
+
+/// The file in the output directory of each target which records the hash of
+/// the configuration it was last built with, which `x.py gc` compares to the
+/// current one.
+const CONFIG_HASH: &str = "config-hash";
+
+/// The directories directly in the build directory that hold downloads: the
+/// tarballs of the stage0 toolchain and the Cargo home of `--in-container`,
+/// with its registry cache.
+const DOWNLOADS: &[&str] = &["cache", "cargo-home"];
+
+pub fn clean(
+    build: &Build,
+    all: bool,
+    stage: Option<u32>,
+    targets: &[TargetSelection],
+    llvm: bool,
+    keep_downloads: bool,
+    keep_llvm: bool,
+) {
+    // The directories kept in the output for each target.
+    let mut keep = Vec::new();
+    if keep_downloads {
+        keep.extend_from_slice(TARGET_DOWNLOADS);
+    }
+    if keep_llvm || !all {
+        keep.push("llvm");
+    }
+
+    if stage.is_some() || !targets.is_empty() || llvm {
+        return clean_selected(build, stage, targets, llvm, &keep);
+    }
 
-pub fn clean(build: &Build, all: bool) {
     rm_rf("tmp".as_ref());
 
-    if all {
+    // Intermediate output in `build.tmp-dir` is always removed, but only the
+    // directories we created there, as it may well be shared.
+    if build.tmp_out != build.out {
+        for host in &build.hosts {
+            rm_rf(&build.tmp_out.join(host.triple));
+        }
+    }
+
+    if all && keep.is_empty() {
         rm_rf(&build.out);
+        return;
+    }
+
+    let dirs = if all {
+        // The output for targets that are no longer configured goes entirely,
+        // along with everything else but the kept downloads.
+        let dirs = build.hosts.iter().chain(&build.targets).map(|t| &*t.triple);
+        let dirs = dirs.collect::<Vec<_>>();
+        for entry in build.out.read_dir().into_iter().flatten() {
+            let entry = t!(entry);
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let kept = keep_downloads && DOWNLOADS.contains(&name.as_str());
+            if !kept && !dirs.contains(&name.as_str()) {
+                rm_rf(&entry.path());
+            }
+        }
+        dirs
     } else {
         rm_rf(&build.out.join("tmp"));
         rm_rf(&build.out.join("dist"));
         rm_rf(&build.out.join("bootstrap"));
+        build.hosts.iter().map(|host| &*host.triple).collect()
+    };
+
+    for dir in dirs {
+        let entries = match build.out.join(dir).read_dir() {
+            Ok(iter) => iter,
+            Err(_) => continue,
+        };
 
+        for entry in entries {
+            let entry = t!(entry);
+            if keep.contains(&&*entry.file_name().to_string_lossy()) {
+                continue;
+            }
+            let path = t!(entry.path().canonicalize());
+            rm_rf(&path);
+        }
+    }
+}
+
+/// Removes only part of the output for `targets`, or for the hosts if none
+/// are given: that of one `stage`, LLVM if `llvm` is set, or otherwise
+/// everything, but never the directories in `keep`.
+fn clean_selected(
+    build: &Build,
+    stage: Option<u32>,
+    targets: &[TargetSelection],
+    llvm: bool,
+    keep: &[&str],
+) {
+    let targets = if targets.is_empty() { &build.hosts } else { targets };
+    for target in targets {
+        let dir = build.out.join(target.triple);
+        if llvm {
+            rm_rf(&dir.join("llvm"));
+            continue;
+        }
+        let entries = match dir.read_dir() {
+            Ok(iter) => iter,
+            Err(_) => continue,
+        };
+        for entry in entries {
+            let entry = t!(entry);
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let remove = !keep.contains(&name.as_str())
+                && match stage {
+                    // `stageN` itself as well as e.g. `stageN-std` and `stageN-tools-bin`.
+                    // `--stage 0` keeps the stage0 toolchain with `--keep-downloads`.
+                    Some(stage) => {
+                        let prefix = format!("stage{}", stage);
+                        name == prefix || name.starts_with(&format!("{}-", prefix))
+                    }
+                    None => true,
+                };
+            if remove {
+                rm_rf(&t!(entry.path().canonicalize()));
+            }
+        }
+    }
+}
+
+/// A part of the build directory, as reported by `x.py gc`.
+struct GcItem {
+    path: PathBuf,
+    size: u64,
+    last_used: Option<SystemTime>,
+    /// Whether the item is removed.
+    remove: bool,
+    /// Why the item is stale, if it is.
+    stale: Option<&'static str>,
+}
+
+/// Reports the disk usage of the parts of the build directory, and removes
+/// those that haven't been used in `max_age` days. Nothing is removed with
+/// `--dry-run`.
+///
+/// Each stage's sysroot and Cargo output, LLVM, test output and so on is one
+/// part. The output for targets which are no longer configured, or which was
+/// built with a configuration other than the current one, is reported as
+/// stale. The downloaded stage0 toolchain and bootstrap itself are only
+/// reported, as they are needed to run `x.py` at all.
+pub fn gc(build: &Build, max_age: u64) {
+    let cutoff = SystemTime::now().checked_sub(Duration::from_secs(max_age * 24 * 60 * 60));
+    let configured = |triple: &str| {
+        build
+            .hosts
+            .iter()
+            .chain(&build.targets)
+            .chain(Some(&build.build))
+            .find(|t| &*t.triple == triple)
+    };
+
+    // Parts of the build directory, whether they are always kept, and why
+    // they are stale, if they are.
+    let mut parts = Vec::new();
+    let entries = match build.out.read_dir() {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries {
+        let entry = t!(entry);
+        if !t!(entry.file_type()).is_dir() {
+            continue;
+        }
+        let name = entry.file_name().to_string_lossy().into_owned();
+        match &name[..] {
+            "bootstrap" => parts.push((entry.path(), true, None)),
+            "dist" | "tmp" | "tmp-dry-run" => parts.push((entry.path(), false, None)),
+            "cache" => {
+                for entry in t!(entry.path().read_dir()) {
+                    parts.push((t!(entry).path(), false, None));
+                }
+            }
+            // Don't touch anything we don't recognize.
+            _ if !is_target_dir(&entry.path()) => parts.push((entry.path(), true, None)),
+            triple => {
+                let stale = match configured(triple) {
+                    None => Some("target not configured"),
+                    Some(target) => {
+                        let recorded = fs::read_to_string(entry.path().join(CONFIG_HASH));
+                        let current = artifact_cache::config_hash(&build.config, *target);
+                        match recorded {
+                            Ok(recorded) if recorded.trim() != current => {
+                                Some("built with another configuration")
+                            }
+                            _ => None,
+                        }
+                    }
+                };
+                for entry in t!(entry.path().read_dir()) {
+                    let entry = t!(entry);
+                    if entry.file_name() == CONFIG_HASH {
+                        continue;
+                    }
+                    let keep = entry.file_name() == "stage0" && *build.build.triple == name;
+                    parts.push((entry.path(), keep, stale));
+                }
+            }
+        }
+    }
+    // Only our own directories in `build.tmp-dir`, as in `clean`.
+    if build.tmp_out != build.out {
         for host in &build.hosts {
-            let entries = match build.out.join(host.triple).read_dir() {
-                Ok(iter) => iter,
+            let entries = match build.tmp_out.join(host.triple).read_dir() {
+                Ok(entries) => entries,
                 Err(_) => continue,
             };
-
             for entry in entries {
-                let entry = t!(entry);
-                if entry.file_name().to_str() == Some("llvm") {
-                    continue;
-                }
-                let path = t!(entry.path().canonicalize());
-                rm_rf(&path);
+                parts.push((t!(entry).path(), false, None));
+            }
+        }
+    }
+
+    let mut items = parts
+        .into_iter()
+        .map(|(path, keep, stale)| {
+            let (size, last_used) = usage(&path);
+            let unused = match cutoff {
+                Some(cutoff) => !matches!(last_used, Some(t) if t >= cutoff),
+                None => false,
+            };
+            // Stale output is only removed once it's unused as well, since
+            // the configuration may well be switched back.
+            GcItem { path, size, last_used, remove: !keep && unused, stale }
+        })
+        .collect::<Vec<_>>();
+    items.sort_by_key(|item| Reverse(item.size));
+
+    let now = SystemTime::now();
+    let (mut total, mut freed) = (0, 0);
+    for item in &items {
+        let days = item
+            .last_used
+            .and_then(|t| now.duration_since(t).ok())
+            .map_or(String::from("-"), |age| format!("{}d", age.as_secs() / (24 * 60 * 60)));
+        let reason = match item.stale {
+            Some(stale) if item.remove => format!("unused, {}", stale),
+            Some(stale) => format!("stale: {}", stale),
+            None => String::from("unused"),
+        };
+        let action = match (item.remove, item.stale) {
+            (true, _) if build.config.dry_run => format!("  (would remove: {})", reason),
+            (true, _) => format!("  (removed: {})", reason),
+            (false, Some(_)) => format!("  ({})", reason),
+            (false, None) => String::new(),
+        };
+        println!("{:>10} {:>6}  {}{}", format_size(item.size), days, item.path.display(), action);
+        total += item.size;
+        if item.remove {
+            freed += item.size;
+            if !build.config.dry_run {
+                rm_rf(&item.path);
+            }
+        }
+    }
+    println!(
+        "total: {}, {} {} (parts unused for {} days)",
+        format_size(total),
+        if build.config.dry_run { "would free" } else { "freed" },
+        format_size(freed),
+        max_age
+    );
+}
+
+/// Records the configuration the output for each target was built with, for
+/// `x.py gc`, after a successful build.
+pub fn record_config(build: &Build) {
+    if build.config.dry_run {
+        return;
+    }
+    for target in build.hosts.iter().chain(&build.targets) {
+        let dir = build.out.join(target.triple);
+        if dir.exists() {
+            let hash = artifact_cache::config_hash(&build.config, *target);
+            t!(fs::write(dir.join(CONFIG_HASH), hash));
+        }
+    }
+}
+
+/// Records the total size of `paths` as the disk usage of `part` of the build,
+/// such as `llvm-x86_64-unknown-linux-gnu`, in `build/disk-usage`. The up-front
+/// disk space check estimates what the next build needs from these.
+pub fn record_disk_usage(build: &Build, part: &str, paths: &[PathBuf]) {
+    if build.config.dry_run {
+        return;
+    }
+    let mut recorded = recorded_disk_usage(build);
+    recorded.insert(part.to_string(), paths.iter().map(|path| usage(path).0).sum());
+    let contents = recorded.iter().map(|(part, size)| format!("{} {}\n", part, size));
+    t!(fs::write(build.out.join(DISK_USAGE), contents.collect::<String>()));
+}
+
+/// Returns the disk usage of the parts of the build recorded by
+/// `record_disk_usage`.
+pub fn recorded_disk_usage(build: &Build) -> BTreeMap<String, u64> {
+    let contents = fs::read_to_string(build.out.join(DISK_USAGE)).unwrap_or_default();
+    contents
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(' ');
+            let part = fields.next()?;
+            let size = fields.next()?.parse().ok()?;
+            Some((part.to_string(), size))
+        })
+        .collect()
+}
+
+/// Applies `build.retention` after a successful build that started at
+/// `start`: removes the output of all but the last `dist` runs of `x.py dist`
+/// and, for each target, the superseded stages beyond the `stages` most
+/// recently used ones.
+pub fn apply_retention(build: &Build, start: SystemTime) {
+    let retention = build.config.retention;
+    if build.config.dry_run {
+        return;
+    }
+    if let (Some(keep), Subcommand::Dist { .. }) = (retention.dist, &build.config.cmd) {
+        retain_dist_runs(build, start, keep);
+    }
+    // `x.py check` and friends only write the output of stage 0, so they
+    // don't supersede the stages built before.
+    let checking = matches!(
+        build.config.cmd,
+        Subcommand::Check { .. } | Subcommand::Clippy { .. } | Subcommand::Fix { .. }
+    );
+    if let (Some(keep), false) = (retention.stages, checking) {
+        for entry in build.out.read_dir().into_iter().flatten().flatten() {
+            if is_target_dir(&entry.path()) {
+                retain_stages(build, &entry.path(), start, keep);
             }
         }
     }
 }
 
+/// Removes what was written to `build/dist` before the last `keep` dist runs,
+/// whose start times are recorded in `build/dist-runs`.
+fn retain_dist_runs(build: &Build, start: SystemTime, keep: usize) {
+    let runs_file = build.out.join("dist-runs");
+    let mut runs = fs::read_to_string(&runs_file)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| line.trim().parse::<u64>().ok())
+        .collect::<Vec<_>>();
+    runs.push(t!(start.duration_since(UNIX_EPOCH)).as_secs());
+    let first_kept = runs.len().saturating_sub(keep.max(1));
+    runs.drain(..first_kept);
+    let runs_text = runs.iter().map(|run| format!("{}\n", run)).collect::<String>();
+    t!(fs::write(&runs_file, runs_text));
+
+    let cutoff = UNIX_EPOCH + Duration::from_secs(runs[0]);
+    for entry in build.out.join("dist").read_dir().into_iter().flatten().flatten() {
+        let modified = entry.metadata().and_then(|m| m.modified()).ok();
+        if matches!(modified, Some(modified) if modified < cutoff) {
+            build.info(&format!("removing {} (build.retention.dist)", entry.path().display()));
+            rm_rf(&entry.path());
+        }
+    }
+}
+
+/// Removes the output of superseded stages from `dir`, the output directory of
+/// a target, unless they are among the `keep` most recently used stages.
+///
+/// The stages up to the one built by this run, which started at `start`, are
+/// what it built on, so only the stages above it which this run didn't touch
+/// are superseded. That also keeps the stage0 toolchain.
+fn retain_stages(build: &Build, dir: &Path, start: SystemTime, keep: usize) {
+    // `stageN` itself as well as e.g. `stageN-std` and `stageN-tools-bin`,
+    // grouped by `N`.
+    let mut stages: BTreeMap<u32, Vec<PathBuf>> = BTreeMap::new();
+    for entry in t!(dir.read_dir()) {
+        let entry = t!(entry);
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let stage = match name.strip_prefix("stage") {
+            Some(rest) => rest.split('-').next().and_then(|n| n.parse().ok()),
+            None => None,
+        };
+        if let Some(stage) = stage {
+            stages.entry(stage).or_default().push(entry.path());
+        }
+    }
+
+    let mut by_use = stages
+        .into_iter()
+        .map(|(stage, paths)| {
+            let last_used = paths.iter().filter_map(|path| usage(path).1).max();
+            (stage, paths, last_used)
+        })
+        .collect::<Vec<_>>();
+    by_use.sort_by_key(|(_, _, last_used)| Reverse(*last_used));
+
+    for (stage, paths, last_used) in by_use.into_iter().skip(keep) {
+        let superseded = stage > build.config.stage && !matches!(last_used, Some(t) if t >= start);
+        if !superseded {
+            continue;
+        }
+        for path in &paths {
+            build.info(&format!(
+                "removing {} (stage {} is superseded and not among the {} most recently used)",
+                path.display(),
+                stage,
+                keep
+            ));
+            rm_rf(path);
+        }
+    }
+}
+
+/// Returns whether `dir` looks like the output directory of a target.
+fn is_target_dir(dir: &Path) -> bool {
+    let entries = match dir.read_dir() {
+        Ok(entries) => entries,
+        Err(_) => return false,
+    };
+    entries.flatten().any(|entry| {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        name.starts_with("stage")
+            || ["llvm", "ci-llvm", "native", "doc", "test"].contains(&&name[..])
+    })
+}
+
+/// Returns the total size of the files below `path` and when the most recently
+/// used of them was last modified or accessed.
+fn usage(path: &Path) -> (u64, Option<SystemTime>) {
+    let metadata = match path.symlink_metadata() {
+        Ok(metadata) => metadata,
+        Err(_) => return (0, None),
+    };
+    let mut last_used = metadata.modified().ok();
+    if !metadata.is_dir() {
+        if let Ok(accessed) = metadata.accessed() {
+            last_used = last_used.max(Some(accessed));
+        }
+        return (metadata.len(), last_used);
+    }
+    let mut size = 0;
+    for entry in path.read_dir().into_iter().flatten().flatten() {
+        let (entry_size, entry_last_used) = usage(&entry.path());
+        size += entry_size;
+        last_used = last_used.max(entry_last_used);
+    }
+    (size, last_used)
+}
+
+fn format_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 { format!("{} B", bytes) } else { format!("{:.1} {}", size, UNITS[unit]) }
+}
+
 fn rm_rf(path: &Path) {
     match path.symlink_metadata() {
         Err(e) => {
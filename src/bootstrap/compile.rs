@@ -15,20 +15,26 @@
 use std::path::{Path, PathBuf};
 use std::process::{exit, Command, Stdio};
 use std::str;
+use std::time::Instant;
 
 use build_helper::{output, t, up_to_date};
 use filetime::FileTime;
 use serde::Deserialize;
 
+use crate::artifact_cache;
 use crate::builder::Cargo;
 use crate::builder::{Builder, Kind, RunConfig, ShouldRun, Step};
 use crate::cache::{Interned, INTERNER};
-use crate::config::TargetSelection;
+use crate::clean;
+use crate::config::{is_prebuilt_codegen_backend, TargetSelection};
 use crate::dist;
+use crate::logging;
 use crate::native;
+use crate::priority;
+use crate::step_cache;
 use crate::tool::SourceType;
 use crate::util::{exe, is_dylib, symlink_dir};
-use crate::{Compiler, DependencyType, GitRepo, Mode};
+use crate::{Compiler, DependencyType, GitRepo, Mode, LLD_SELF_CONTAINED_NAMES};
 
 #[derive(Debug, PartialOrd, Ord, Copy, Clone, PartialEq, Eq, Hash)]
 pub struct Std {
@@ -41,7 +47,7 @@ impl Step for Std {
     const DEFAULT: bool = true;
 
     fn should_run(run: ShouldRun<'_>) -> ShouldRun<'_> {
-        run.all_krates("test")
+        run.all_krates("test").path("library")
     }
 
     fn make_run(run: RunConfig<'_>) {
@@ -60,8 +66,10 @@ fn run(self, builder: &Builder<'_>) {
         let target = self.target;
         let compiler = self.compiler;
 
-        if builder.config.keep_stage.contains(&compiler.stage)
-            || builder.config.keep_stage_std.contains(&compiler.stage)
+        let stamp = libstd_stamp(builder, compiler, target);
+        if (builder.config.keep_stage.contains(&compiler.stage)
+            || builder.config.keep_stage_std.contains(&compiler.stage))
+            && can_keep(builder, "libstd", &stamp, compiler, target)
         {
             builder.info("Warning: Using a potentially old libstd. This may not behave well.");
             builder.ensure(StdLink { compiler, target_compiler: compiler, target });
@@ -88,24 +96,44 @@ fn run(self, builder: &Builder<'_>) {
             return;
         }
 
+        // These are part of the sysroot whether std is built or restored from
+        // the artifact cache.
         target_deps.extend(copy_third_party_objects(builder, &compiler, target));
         target_deps.extend(copy_self_contained_objects(builder, &compiler, target));
 
+        let cache_key = artifact_cache::key(builder, "std", compiler, target, STD_INPUTS);
+        if let Some(key) = &cache_key {
+            let dir = builder.cargo_out(compiler, Mode::Std, target).join("artifact-cache");
+            if artifact_cache::fetch_stamped(builder, key, &dir, &stamp, &target_deps) {
+                builder.ensure(StdLink {
+                    compiler: builder.compiler(compiler.stage, builder.config.build),
+                    target_compiler: compiler,
+                    target,
+                });
+                return;
+            }
+        }
+
         let mut cargo = builder.cargo(compiler, Mode::Std, SourceType::InTree, target, "build");
         std_cargo(builder, target, compiler.stage, &mut cargo);
 
-        builder.info(&format!(
-            "Building stage{} std artifacts ({} -> {})",
-            compiler.stage, &compiler.host, target
-        ));
-        run_cargo(
-            builder,
-            cargo,
-            vec![],
-            &libstd_stamp(builder, compiler, target),
-            target_deps,
-            false,
-        );
+        let deps = [builder.rustc(compiler)];
+        let fingerprint = step_cache::fingerprint(builder, &cargo, STD_INPUTS, &deps);
+        if fingerprint.as_ref().map_or(false, |f| step_cache::is_fresh(builder, &stamp, f)) {
+            builder.verbose(&format!("stage{} std for {} is up to date", compiler.stage, target));
+        } else {
+            builder.info(&format!(
+                "Building stage{} std artifacts ({} -> {})",
+                compiler.stage, &compiler.host, target
+            ));
+            run_cargo(builder, cargo, vec![], &stamp, target_deps.clone(), false);
+            if let Some(key) = &cache_key {
+                artifact_cache::store_stamped(builder, key, &stamp, &target_deps);
+            }
+            if let Some(fingerprint) = fingerprint {
+                step_cache::record(builder, &stamp, fingerprint);
+            }
+        }
 
         builder.ensure(StdLink {
             compiler: builder.compiler(compiler.stage, builder.config.build),
@@ -113,6 +141,14 @@ fn run(self, builder: &Builder<'_>) {
             target,
         });
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
+
+    fn stage(&self) -> Option<u32> {
+        Some(self.compiler.stage)
+    }
 }
 
 fn copy_and_stamp(
@@ -337,6 +373,14 @@ fn run(self, builder: &Builder<'_>) {
         let hostdir = builder.sysroot_libdir(target_compiler, compiler.host);
         add_to_sysroot(builder, &libdir, &hostdir, &libstd_stamp(builder, compiler, target));
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
+
+    fn stage(&self) -> Option<u32> {
+        Some(self.compiler.stage)
+    }
 }
 
 /// Copies sanitizer runtime libraries into target libdir.
@@ -458,6 +502,14 @@ fn run(self, builder: &Builder<'_>) -> Vec<(PathBuf, DependencyType)> {
 
         target_deps
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
+
+    fn stage(&self) -> Option<u32> {
+        Some(self.compiler.stage)
+    }
 }
 
 #[derive(Debug, PartialOrd, Ord, Copy, Clone, PartialEq, Eq, Hash)]
@@ -493,7 +545,10 @@ fn run(self, builder: &Builder<'_>) {
 
         builder.ensure(Std { compiler, target });
 
-        if builder.config.keep_stage.contains(&compiler.stage) {
+        let stamp = librustc_stamp(builder, compiler, target);
+        if builder.config.keep_stage.contains(&compiler.stage)
+            && can_keep(builder, "librustc", &stamp, compiler, target)
+        {
             builder.info("Warning: Using a potentially old librustc. This may not behave well.");
             builder.info("Warning: Use `--keep-stage-std` if you want to rebuild the compiler when it changes");
             builder.ensure(RustcLink { compiler, target_compiler: compiler, target });
@@ -519,6 +574,19 @@ fn run(self, builder: &Builder<'_>) {
             target: builder.config.build,
         });
 
+        let cache_key = artifact_cache::key(builder, "rustc", compiler, target, RUSTC_INPUTS);
+        if let Some(key) = &cache_key {
+            let dir = builder.cargo_out(compiler, Mode::Rustc, target).join("artifact-cache");
+            if artifact_cache::fetch_stamped(builder, key, &dir, &stamp, &[]) {
+                builder.ensure(RustcLink {
+                    compiler: builder.compiler(compiler.stage, builder.config.build),
+                    target_compiler: compiler,
+                    target,
+                });
+                return;
+            }
+        }
+
         let mut cargo = builder.cargo(compiler, Mode::Rustc, SourceType::InTree, target, "build");
         rustc_cargo(builder, &mut cargo, target);
 
@@ -557,18 +625,31 @@ fn run(self, builder: &Builder<'_>) {
             ));
         }
 
-        builder.info(&format!(
-            "Building stage{} compiler artifacts ({} -> {})",
-            compiler.stage, &compiler.host, target
-        ));
-        run_cargo(
-            builder,
-            cargo,
-            vec![],
-            &librustc_stamp(builder, compiler, target),
-            vec![],
-            false,
-        );
+        let deps = [
+            builder.rustc(compiler),
+            libstd_stamp(builder, compiler, builder.config.build),
+            libstd_stamp(builder, compiler, target),
+        ];
+        let fingerprint = step_cache::fingerprint(builder, &cargo, RUSTC_INPUTS, &deps);
+        if fingerprint.as_ref().map_or(false, |f| step_cache::is_fresh(builder, &stamp, f)) {
+            builder.verbose(&format!("stage{} rustc for {} is up to date", compiler.stage, target));
+        } else {
+            builder.info(&format!(
+                "Building stage{} compiler artifacts ({} -> {})",
+                compiler.stage, &compiler.host, target
+            ));
+            run_cargo(builder, cargo, vec![], &stamp, vec![], false);
+            let part = format!("stage{}-{}", compiler.stage, compiler.host);
+            let dirs =
+                [builder.stage_out(compiler, Mode::Std), builder.stage_out(compiler, Mode::Rustc)];
+            clean::record_disk_usage(builder, &part, &dirs);
+            if let Some(key) = &cache_key {
+                artifact_cache::store_stamped(builder, key, &stamp, &[]);
+            }
+            if let Some(fingerprint) = fingerprint {
+                step_cache::record(builder, &stamp, fingerprint);
+            }
+        }
 
         builder.ensure(RustcLink {
             compiler: builder.compiler(compiler.stage, builder.config.build),
@@ -576,12 +657,20 @@ fn run(self, builder: &Builder<'_>) {
             target,
         });
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
+
+    fn stage(&self) -> Option<u32> {
+        Some(self.compiler.stage)
+    }
 }
 
 pub fn rustc_cargo(builder: &Builder<'_>, cargo: &mut Cargo, target: TargetSelection) {
     cargo
         .arg("--features")
-        .arg(builder.rustc_features())
+        .arg(builder.rustc_features(target))
         .arg("--manifest-path")
         .arg(builder.src.join("compiler/rustc/Cargo.toml"));
     rustc_cargo_env(builder, cargo, target);
@@ -613,10 +702,16 @@ pub fn rustc_cargo_env(builder: &Builder<'_>, cargo: &mut Cargo, target: TargetS
     }
     if builder.config.rustc_parallel {
         cargo.rustflag("--cfg=parallel_compiler");
+        if let Some(threads) = builder.config.rustc_parallel_threads {
+            cargo.env("CFG_DEFAULT_THREADS", threads.to_string());
+        }
     }
     if builder.config.rust_verify_llvm_ir {
         cargo.env("RUSTC_VERIFY_LLVM_IR", "1");
     }
+    if builder.config.rust_validate_mir_opts {
+        cargo.env("RUSTC_VALIDATE_MIR", "1");
+    }
 
     // Pass down configuration from the LLVM build into the build of
     // rustc_llvm and rustc_codegen_llvm.
@@ -625,7 +720,7 @@ pub fn rustc_cargo_env(builder: &Builder<'_>, cargo: &mut Cargo, target: TargetS
     // build. If we are in a check build we still go ahead here presuming we've
     // detected that LLVM is alreay built and good to go which helps prevent
     // busting caches (e.g. like #71152).
-    if builder.config.llvm_enabled()
+    if builder.config.llvm_enabled(target)
         && (builder.kind != Kind::Check
             || crate::native::prebuilt_llvm_config(builder, target).is_ok())
     {
@@ -661,6 +756,9 @@ pub fn rustc_cargo_env(builder: &Builder<'_>, cargo: &mut Cargo, target: TargetS
         if builder.config.llvm_optimize && !builder.config.llvm_release_debuginfo {
             cargo.env("LLVM_NDEBUG", "1");
         }
+        if let Some(true) = builder.config.llvm_polly {
+            cargo.env("LLVM_POLLY", "1");
+        }
     }
 }
 
@@ -694,6 +792,14 @@ fn run(self, builder: &Builder<'_>) {
             &librustc_stamp(builder, compiler, target),
         );
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
+
+    fn stage(&self) -> Option<u32> {
+        Some(self.compiler.stage)
+    }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
@@ -714,10 +820,13 @@ fn should_run(run: ShouldRun<'_>) -> ShouldRun<'_> {
     }
 
     fn make_run(run: RunConfig<'_>) {
-        for &backend in &run.builder.config.rust_codegen_backends {
+        for &backend in run.builder.config.codegen_backends(run.target) {
             if backend == "llvm" {
                 continue; // Already built as part of rustc
             }
+            if is_prebuilt_codegen_backend(&backend) {
+                continue; // Copied into the sysroot as it is
+            }
 
             run.builder.ensure(CodegenBackend {
                 target: run.target,
@@ -784,6 +893,14 @@ fn run(self, builder: &Builder<'_>) {
         let codegen_backend = codegen_backend.to_str().unwrap();
         t!(fs::write(&stamp, &codegen_backend));
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
+
+    fn stage(&self) -> Option<u32> {
+        Some(self.compiler.stage)
+    }
 }
 
 /// Creates the `codegen-backends` folder for a compiler that's about to be
@@ -814,20 +931,24 @@ fn copy_codegen_backends_to_sysroot(
         return;
     }
 
-    for backend in builder.config.rust_codegen_backends.iter() {
+    for backend in builder.config.codegen_backends(target) {
         if backend == "llvm" {
             continue; // Already built as part of rustc
         }
 
-        let stamp = codegen_backend_stamp(builder, compiler, target, *backend);
-        let dylib = t!(fs::read_to_string(&stamp));
+        let dylib = if is_prebuilt_codegen_backend(backend) {
+            backend.to_string()
+        } else {
+            let stamp = codegen_backend_stamp(builder, compiler, target, *backend);
+            t!(fs::read_to_string(&stamp))
+        };
         let file = Path::new(&dylib);
         let filename = file.file_name().unwrap().to_str().unwrap();
-        // change `librustc_codegen_cranelift-xxxxxx.so` to
-        // `librustc_codegen_cranelift-release.so`
+        // change `librustc_codegen_cranelift-xxxxxx.so` (or a prebuilt
+        // `librustc_codegen_cranelift.so`) to `librustc_codegen_cranelift-release.so`
         let target_filename = {
-            let dash = filename.find('-').unwrap();
             let dot = filename.find('.').unwrap();
+            let dash = filename[..dot].find('-').unwrap_or(dot);
             format!("{}-{}{}", &filename[..dash], builder.rust_release(), &filename[dot..])
         };
         builder.copy(&file, &dst.join(target_filename));
@@ -840,6 +961,49 @@ pub fn libstd_stamp(builder: &Builder<'_>, compiler: Compiler, target: TargetSel
     builder.cargo_out(compiler, Mode::Std, target).join(".libstd.stamp")
 }
 
+/// The paths of the source tree std is built from, for its key in the
+/// artifact cache and its fingerprint in the step cache. `src/bootstrap`
+/// decides the flags it's built with, `src/stage0.txt` the compiler building
+/// stage 0, and the commit of `src/llvm-project` the compiler-rt built into
+/// `compiler_builtins`. The submodules of `library` are included in std with
+/// `#[path]` rather than as crates, so they are listed for the step cache.
+const STD_INPUTS: &[&str] = &[
+    "library",
+    "library/stdarch",
+    "library/backtrace",
+    "Cargo.lock",
+    "src/bootstrap",
+    "src/stage0.txt",
+    "src/llvm-project",
+];
+
+/// The paths of the source tree rustc is built from, for its key in the
+/// artifact cache and its fingerprint in the step cache. Its LLVM is
+/// identified by the commit of the submodule.
+const RUSTC_INPUTS: &[&str] =
+    &["compiler", "library", "Cargo.lock", "src/bootstrap", "src/stage0.txt", "src/llvm-project"];
+
+/// Checks whether `library`, which `--keep-stage` or `--keep-stage-std` asks
+/// to keep, was built before, as recorded by `stamp`. If it wasn't, keeping it
+/// would leave it missing from the sysroot, and using the sysroot would fail
+/// with confusing errors, so it is built after all.
+fn can_keep(
+    builder: &Builder<'_>,
+    library: &str,
+    stamp: &Path,
+    compiler: Compiler,
+    target: TargetSelection,
+) -> bool {
+    if builder.config.dry_run || stamp.exists() {
+        return true;
+    }
+    builder.info(&format!(
+        "Warning: there is no stage{} {} for {} to keep yet, so it is built anyway",
+        compiler.stage, library, target
+    ));
+    false
+}
+
 /// Cargo's output path for librustc in a given stage, compiled by a particular
 /// compiler for the specified target.
 pub fn librustc_stamp(
@@ -929,6 +1093,14 @@ fn run(self, builder: &Builder<'_>) -> Interned<PathBuf> {
 
         INTERNER.intern_path(sysroot)
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.compiler.host)
+    }
+
+    fn stage(&self) -> Option<u32> {
+        Some(self.compiler.stage)
+    }
 }
 
 #[derive(Debug, Copy, PartialOrd, Ord, Clone, PartialEq, Eq, Hash)]
@@ -989,10 +1161,13 @@ fn run(self, builder: &Builder<'_>) -> Compiler {
         // when not performing a full bootstrap).
         builder.ensure(Rustc { compiler: build_compiler, target: target_compiler.host });
 
-        for &backend in builder.config.rust_codegen_backends.iter() {
+        for &backend in builder.config.codegen_backends(target_compiler.host) {
             if backend == "llvm" {
                 continue; // Already built as part of rustc
             }
+            if is_prebuilt_codegen_backend(&backend) {
+                continue; // Copied into the sysroot as it is
+            }
 
             builder.ensure(CodegenBackend {
                 compiler: build_compiler,
@@ -1048,6 +1223,21 @@ fn run(self, builder: &Builder<'_>) -> Compiler {
             let src_exe = exe("lld", target_compiler.host);
             let dst_exe = exe("rust-lld", target_compiler.host);
             builder.copy(&lld_install.join("bin").join(&src_exe), &libdir_bin.join(&dst_exe));
+
+            // rustc puts `bin/self-contained` on the linker's `PATH` when
+            // linking in self-contained mode, so a linker found there is used
+            // over the system one.
+            if builder.config.lld_self_contained {
+                let self_contained_bin = libdir_bin.join("self-contained");
+                t!(fs::create_dir_all(&self_contained_bin));
+                for name in LLD_SELF_CONTAINED_NAMES {
+                    let dst_exe = exe(name, target_compiler.host);
+                    builder.copy(
+                        &lld_install.join("bin").join(&src_exe),
+                        &self_contained_bin.join(&dst_exe),
+                    );
+                }
+            }
         }
 
         // Similarly, copy `llvm-dwp` into libdir for Split DWARF.
@@ -1074,6 +1264,14 @@ fn run(self, builder: &Builder<'_>) -> Compiler {
 
         target_compiler
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target_compiler.host)
+    }
+
+    fn stage(&self) -> Option<u32> {
+        Some(self.target_compiler.stage)
+    }
 }
 
 /// Link some files into a rustc sysroot.
@@ -1266,7 +1464,11 @@ pub fn stream_cargo(
         cargo.arg(arg);
     }
 
-    builder.verbose(&format!("running: {:?}", cargo));
+    builder.verbose_command(&cargo);
+    priority::apply(&mut cargo, builder.priority());
+    let span = logging::exec_span(&cargo);
+    let _enter = span.enter();
+    let start = Instant::now();
     let mut child = match cargo.spawn() {
         Ok(child) => child,
         Err(e) => panic!("failed to execute command: {:?}\nerror: {}", cargo, e),
@@ -1293,6 +1495,7 @@ pub fn stream_cargo(
 
     // Make sure Cargo actually succeeded after we read all of its stdout.
     let status = t!(child.wait());
+    builder.trace_command(&cargo, start);
     if !status.success() {
         eprintln!(
             "command did not execute successfully: {:?}\n\
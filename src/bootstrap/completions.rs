@@ -0,0 +1,308 @@
+//! Generation of shell completion scripts for `x.py completions`.
+//!
+//! The scripts are generated from the same option definitions that
+//! `x.py cli-metadata` describes, along with the paths which select steps of
+//! each subcommand, so they are complete for the checkout they were generated
+//! in.
+
+use std::collections::BTreeSet;
+use std::fmt::Write;
+use std::str::FromStr;
+
+use crate::flags::{OptionMetadata, SubcommandMetadata};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+    Powershell,
+}
+
+impl Shell {
+    pub const ALL: &'static [&'static str] = &["bash", "zsh", "fish", "powershell"];
+}
+
+impl FromStr for Shell {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bash" => Ok(Shell::Bash),
+            "zsh" => Ok(Shell::Zsh),
+            "fish" => Ok(Shell::Fish),
+            "powershell" | "pwsh" => Ok(Shell::Powershell),
+            _ => Err(format!("unknown shell `{}`, expected one of {}", s, Shell::ALL.join(", "))),
+        }
+    }
+}
+
+/// A subcommand along with the paths selecting its steps.
+pub struct Completion<'a> {
+    pub subcommand: &'a SubcommandMetadata,
+    pub paths: Vec<String>,
+}
+
+pub fn generate(shell: Shell, global: &[OptionMetadata], subcommands: &[Completion<'_>]) -> String {
+    match shell {
+        Shell::Bash => bash(global, subcommands),
+        Shell::Zsh => zsh(global, subcommands),
+        Shell::Fish => fish(global, subcommands),
+        Shell::Powershell => powershell(global, subcommands),
+    }
+}
+
+/// Whether an option's value is best completed with file names.
+fn takes_path(opt: &OptionMetadata) -> bool {
+    matches!(opt.value.as_deref(), Some("PATH") | Some("FILE") | Some("DIR"))
+}
+
+fn names(completion: &Completion<'_>) -> Vec<&'static str> {
+    let subcommand = completion.subcommand;
+    let mut names = vec![subcommand.name];
+    names.extend(subcommand.aliases);
+    names
+}
+
+/// The words offered after `subcommand`: its options and paths.
+fn words(global: &[OptionMetadata], completion: &Completion<'_>) -> Vec<String> {
+    let options = global.iter().chain(&completion.subcommand.options);
+    let mut words = options.map(|opt| format!("--{}", opt.long)).collect::<Vec<_>>();
+    words.extend(completion.paths.iter().cloned());
+    words
+}
+
+/// Options taking a value, by their `--long` and `-s`hort spellings.
+fn value_options<'a>(
+    global: &'a [OptionMetadata],
+    subcommands: &'a [Completion<'_>],
+) -> Vec<(Vec<String>, &'a OptionMetadata)> {
+    let mut seen = BTreeSet::new();
+    global
+        .iter()
+        .chain(subcommands.iter().flat_map(|c| &c.subcommand.options))
+        .filter(|opt| opt.value.is_some() && !opt.value_optional && seen.insert(&opt.long))
+        .map(|opt| {
+            let mut spellings = vec![format!("--{}", opt.long)];
+            spellings.extend(opt.short.iter().map(|short| format!("-{}", short)));
+            (spellings, opt)
+        })
+        .collect()
+}
+
+/// Quotes `s` for POSIX shells and fish.
+fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+fn bash(global: &[OptionMetadata], subcommands: &[Completion<'_>]) -> String {
+    let mut out = String::from("_x_py() {\n");
+    out.push_str("    local cur prev cmd i\n");
+    out.push_str("    cur=\"${COMP_WORDS[COMP_CWORD]}\"\n");
+    out.push_str("    prev=\"${COMP_WORDS[COMP_CWORD-1]}\"\n\n");
+
+    out.push_str("    case \"$prev\" in\n");
+    for (spellings, opt) in value_options(global, subcommands) {
+        let action = match &opt.accepted_values {
+            Some(values) => {
+                format!("COMPREPLY=($(compgen -W {} -- \"$cur\"))", quote(&values.join(" ")))
+            }
+            None if takes_path(opt) => String::from("COMPREPLY=($(compgen -f -- \"$cur\"))"),
+            None => String::from("COMPREPLY=()"),
+        };
+        let _ = writeln!(
+            out,
+            "        {})\n            {}\n            return ;;",
+            spellings.join("|"),
+            action
+        );
+    }
+    out.push_str("    esac\n\n");
+
+    let all_names = subcommands.iter().flat_map(names).collect::<Vec<_>>();
+    out.push_str("    for ((i = 1; i < COMP_CWORD; i++)); do\n");
+    out.push_str("        case \"${COMP_WORDS[i]}\" in\n");
+    let _ =
+        writeln!(out, "            {}) cmd=\"${{COMP_WORDS[i]}}\"; break ;;", all_names.join("|"));
+    out.push_str("        esac\n    done\n\n");
+
+    out.push_str("    case \"$cmd\" in\n");
+    let mut top = all_names.iter().map(|name| name.to_string()).collect::<Vec<_>>();
+    top.extend(global.iter().map(|opt| format!("--{}", opt.long)));
+    let _ = writeln!(
+        out,
+        "        \"\")\n            COMPREPLY=($(compgen -W {} -- \"$cur\")) ;;",
+        quote(&top.join(" "))
+    );
+    for completion in subcommands {
+        let _ = writeln!(
+            out,
+            "        {})\n            COMPREPLY=($(compgen -W {} -- \"$cur\")) ;;",
+            names(completion).join("|"),
+            quote(&words(global, completion).join(" "))
+        );
+    }
+    out.push_str("    esac\n}\n\ncomplete -F _x_py x.py\n");
+    out
+}
+
+fn zsh(global: &[OptionMetadata], subcommands: &[Completion<'_>]) -> String {
+    // `_describe` takes `word:description`, with colons in the word escaped.
+    let describe = |word: &str, description: &str| {
+        quote(&format!("{}:{}", word.replace(':', "\\:"), description))
+    };
+    let options = |options: &[OptionMetadata]| {
+        options.iter().map(|opt| describe(&format!("--{}", opt.long), &opt.description)).collect()
+    };
+
+    let mut out = String::from("_x_py() {\n");
+    out.push_str("    local cmd word\n    local -a subcommands options paths\n\n");
+
+    out.push_str("    case $words[CURRENT-1] in\n");
+    for (spellings, opt) in value_options(global, subcommands) {
+        let action = match &opt.accepted_values {
+            Some(values) => format!(
+                "compadd -- {}",
+                values.iter().map(|v| quote(v)).collect::<Vec<_>>().join(" ")
+            ),
+            None if takes_path(opt) => String::from("_files"),
+            None => String::from(":"),
+        };
+        let _ = writeln!(
+            out,
+            "        {})\n            {}\n            return ;;",
+            spellings.join("|"),
+            action
+        );
+    }
+    out.push_str("    esac\n\n");
+
+    let all_names = subcommands.iter().flat_map(names).collect::<Vec<_>>();
+    out.push_str("    for word in ${words[2,CURRENT-1]}; do\n        case $word in\n");
+    let _ = writeln!(out, "            {}) cmd=$word; break ;;", all_names.join("|"));
+    out.push_str("        esac\n    done\n\n");
+
+    let global_options: Vec<String> = options(global);
+    let _ = writeln!(out, "    options=({})", global_options.join(" "));
+    out.push_str("    case $cmd in\n        '')\n");
+    let described = subcommands
+        .iter()
+        .flat_map(|c| {
+            names(c).into_iter().map(move |name| describe(name, c.subcommand.description))
+        })
+        .collect::<Vec<_>>();
+    let _ = writeln!(out, "            subcommands=({})", described.join(" "));
+    out.push_str("            _describe 'subcommand' subcommands ;;\n");
+    for completion in subcommands {
+        let subcommand_options: Vec<String> = options(&completion.subcommand.options);
+        let paths = completion.paths.iter().map(|p| quote(p)).collect::<Vec<_>>();
+        let _ = writeln!(out, "        {})", names(completion).join("|"));
+        let _ = writeln!(out, "            options+=({})", subcommand_options.join(" "));
+        let _ = writeln!(out, "            paths=({}) ;;", paths.join(" "));
+    }
+    out.push_str("    esac\n");
+    out.push_str("    _describe 'option' options\n");
+    out.push_str("    (( $#paths )) && compadd -a paths\n}\n\ncompdef _x_py x.py\n");
+    out
+}
+
+fn fish(global: &[OptionMetadata], subcommands: &[Completion<'_>]) -> String {
+    let option = |out: &mut String, condition: &str, opt: &OptionMetadata| {
+        let _ = write!(out, "complete -c x.py{} -l {}", condition, opt.long);
+        if let Some(short) = &opt.short {
+            let _ = write!(out, " -s {}", short);
+        }
+        if !opt.value_optional {
+            match &opt.accepted_values {
+                Some(values) => {
+                    let _ = write!(out, " -x -a {}", quote(&values.join(" ")));
+                }
+                None if takes_path(opt) => out.push_str(" -r -F"),
+                None if opt.value.is_some() => out.push_str(" -x"),
+                None => {}
+            }
+        }
+        let _ = writeln!(out, " -d {}", quote(&opt.description));
+    };
+
+    let mut out = String::from("complete -c x.py -f\n");
+    for opt in global {
+        option(&mut out, "", opt);
+    }
+    for completion in subcommands {
+        for name in names(completion) {
+            let _ = writeln!(
+                out,
+                "complete -c x.py -n __fish_use_subcommand -a {} -d {}",
+                name,
+                quote(completion.subcommand.description)
+            );
+        }
+        let condition = format!(
+            " -n {}",
+            quote(&format!("__fish_seen_subcommand_from {}", names(completion).join(" ")))
+        );
+        for opt in &completion.subcommand.options {
+            option(&mut out, &condition, opt);
+        }
+        if !completion.paths.is_empty() {
+            let _ = writeln!(
+                out,
+                "complete -c x.py{} -a {}",
+                condition,
+                quote(&completion.paths.join(" "))
+            );
+        }
+    }
+    out
+}
+
+fn powershell(global: &[OptionMetadata], subcommands: &[Completion<'_>]) -> String {
+    // PowerShell single-quoted strings only need their quotes doubled.
+    let quote = |s: &str| format!("'{}'", s.replace('\'', "''"));
+    let list = |words: &[String]| words.iter().map(|w| quote(w)).collect::<Vec<_>>().join(", ");
+
+    let mut out = String::from(
+        "Register-ArgumentCompleter -Native -CommandName 'x.py' -ScriptBlock {\n    \
+         param($wordToComplete, $commandAst, $cursorPosition)\n\n",
+    );
+    let global_options = global.iter().map(|opt| format!("--{}", opt.long)).collect::<Vec<_>>();
+    let _ = writeln!(out, "    $globalOptions = @({})", list(&global_options));
+    out.push_str("    $subcommands = [ordered]@{\n");
+    for completion in subcommands {
+        for name in names(completion) {
+            let _ = writeln!(
+                out,
+                "        {} = {}",
+                quote(name),
+                quote(completion.subcommand.description)
+            );
+        }
+    }
+    out.push_str("    }\n    $words = @{\n");
+    for completion in subcommands {
+        let words = words(global, completion);
+        for name in names(completion) {
+            let _ = writeln!(out, "        {} = @({})", quote(name), list(&words));
+        }
+    }
+    out.push_str(
+        "    }\n\n    $cmd = $commandAst.CommandElements | Select-Object -Skip 1 |
+        Where-Object {
+            $_.Extent.EndOffset -lt $cursorPosition -and $subcommands.Contains($_.ToString())
+        } |
+        Select-Object -First 1
+    if ($cmd) {
+        $candidates = $words[$cmd.ToString()]
+    } else {
+        $candidates = @($subcommands.Keys) + $globalOptions
+    }
+    $candidates | Where-Object { $_ -like \"$wordToComplete*\" } | ForEach-Object {
+        $tooltip = if ($subcommands.Contains($_)) { $subcommands[$_] } else { $_ }
+        [System.Management.Automation.CompletionResult]::new($_, $_, 'ParameterValue', $tooltip)
+    }
+}
+",
+    );
+    out
+}
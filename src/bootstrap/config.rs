@@ -9,13 +9,17 @@
 use std::ffi::OsString;
 use std::fmt;
 use std::fs;
+use std::hash::{Hash, Hasher};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
+use crate::builder::Builder;
 use crate::cache::{Interned, INTERNER};
 pub use crate::flags::Subcommand;
-use crate::flags::{Color, Flags};
-use crate::util::exe;
+use crate::flags::{Color, Flags, Warnings};
+use crate::plugin;
+use crate::util::{self, exe};
+use crate::vcs::Vcs;
 use build_helper::t;
 use merge::Merge;
 use serde::Deserialize;
@@ -30,6 +34,11 @@ macro_rules! check_ci_llvm {
     };
 }
 
+/// The environment variables bootstrap.py always sets for rustbuild, which
+/// `x.py test bootstrap-py` checks by setting `BOOTSTRAP_CHECK_PYTHON_ENV`.
+pub const PYTHON_ENV: &[&str] =
+    &["BOOTSTRAP_PARENT_ID", "BOOTSTRAP_PYTHON", "BUILD_DIR", "RUSTC_BOOTSTRAP"];
+
 /// Global configuration for the entire build and/or bootstrap.
 ///
 /// This structure is derived from a combination of both `config.toml` and
@@ -44,6 +53,7 @@ macro_rules! check_ci_llvm {
 #[derive(Default)]
 pub struct Config {
     pub changelog_seen: Option<usize>,
+    pub change_id: Option<usize>,
     pub ccache: Option<String>,
     /// Call Build::ninja() instead of this.
     pub ninja_in_file: bool,
@@ -61,13 +71,29 @@ pub struct Config {
     pub sanitizers: bool,
     pub profiler: bool,
     pub ignore_git: bool,
+    pub vcs: Option<Vcs>,
     pub exclude: Vec<PathBuf>,
     pub include_default_paths: bool,
+    pub ci_change_detection: Option<String>,
+    pub ci_keep: Vec<PathBuf>,
     pub rustc_error_format: Option<String>,
     pub json_output: bool,
+    pub timings: bool,
+    pub bypass_bootstrap_lock: bool,
     pub test_compare_mode: bool,
     pub llvm_libunwind: Option<LlvmLibunwind>,
     pub color: Color,
+    pub in_container: Option<String>,
+    /// `docker`, `podman` or another compatible command used for `--in-container`
+    pub container_runtime: String,
+    /// Directory or URL of the shared cache of std, rustc and LLVM builds
+    pub artifact_cache: Option<String>,
+    pub artifact_cache_upload: bool,
+    /// Wrapper like sccache which the rustc shim runs the compiler through
+    pub rustc_cache: Option<PathBuf>,
+    /// Skip running cargo for std and rustc when `build/step-cache.json` shows
+    /// their inputs haven't changed
+    pub step_cache: bool,
 
     pub on_fail: Option<String>,
     pub stage: u32,
@@ -77,9 +103,19 @@ pub struct Config {
     // defaults to `config.toml`
     pub config: PathBuf,
     pub jobs: Option<u32>,
+    pub doc_jobs: Option<u32>,
+    pub doc_error_index: bool,
+    pub mdbook_preprocessors: HashMap<String, PathBuf>,
+    /// Commands of `[hooks]` to run before and after the steps they name, by
+    /// the names they're for
+    pub hooks_pre: Vec<(String, String)>,
+    pub hooks_post: Vec<(String, String)>,
+    pub plugins: Vec<Plugin>,
     pub cmd: Subcommand,
     pub incremental: bool,
     pub dry_run: bool,
+    pub json_plan: bool,
+    pub explain: bool,
 
     pub deny_warnings: bool,
     pub backtrace_on_ice: bool,
@@ -101,10 +137,17 @@ pub struct Config {
     pub llvm_use_linker: Option<String>,
     pub llvm_allow_old_toolchain: Option<bool>,
     pub llvm_polly: Option<bool>,
+    pub llvm_plugins: bool,
+    pub llvm_enable_zlib: Option<bool>,
+    pub llvm_build_config: HashMap<String, String>,
+    pub llvm_prebuilt_archive: Option<PathBuf>,
     pub llvm_from_ci: bool,
+    pub llvm_profile_generate: Option<String>,
+    pub llvm_profile_use: Option<String>,
 
     pub use_lld: bool,
     pub lld_enabled: bool,
+    pub lld_self_contained: bool,
     pub llvm_tools_enabled: bool,
 
     pub llvm_cflags: Option<String>,
@@ -118,6 +161,8 @@ pub struct Config {
     pub rust_codegen_units_std: Option<u32>,
     pub rust_debug_assertions: bool,
     pub rust_debug_assertions_std: bool,
+    pub rust_overflow_checks: bool,
+    pub rust_overflow_checks_std: bool,
     pub rust_debug_logging: bool,
     pub rust_debuginfo_level_rustc: u32,
     pub rust_debuginfo_level_std: u32,
@@ -126,14 +171,22 @@ pub struct Config {
     pub rust_run_dsymutil: bool,
     pub rust_rpath: bool,
     pub rustc_parallel: bool,
+    pub rustc_parallel_threads: Option<u32>,
     pub rustc_default_linker: Option<String>,
     pub rust_optimize_tests: bool,
     pub rust_dist_src: bool,
     pub rust_codegen_backends: Vec<Interned<String>>,
+    /// Codegen profile to build the compiler of a given stage with.
+    pub rust_stage_profiles: HashMap<u32, CodegenProfile>,
+    /// Codegen options for single packages, by package name.
+    pub rust_package_overrides: HashMap<String, CodegenProfile>,
     pub rust_verify_llvm_ir: bool,
+    pub rust_validate_mir_opts: bool,
     pub rust_thin_lto_import_instr_limit: Option<u32>,
     pub rust_remap_debuginfo: bool,
     pub rust_new_symbol_mangling: bool,
+    pub rust_randomize_layout: bool,
+    pub rust_layout_seed: Option<u64>,
     pub rust_profile_use: Option<String>,
     pub rust_profile_generate: Option<String>,
 
@@ -152,25 +205,39 @@ pub struct Config {
 
     // libstd features
     pub backtrace: bool, // support for RUST_BACKTRACE
+    pub std_features: Vec<String>,
 
     // misc
     pub low_priority: bool,
+    pub priority: Priority,
+    pub step_priority: HashMap<String, Priority>,
+    pub retention: Retention,
     pub channel: String,
     pub description: Option<String>,
     pub verbose_tests: bool,
     pub save_toolstates: Option<PathBuf>,
     pub print_step_timings: bool,
+    pub io_diagnostics: bool,
+    pub mtime_check: MtimeCheck,
+    pub tmp_dir: Option<PathBuf>,
+    pub min_free_space: u64,
+    pub test_output_max_age: u64,
     pub missing_tools: bool,
 
     // Fallback musl-root for all targets
     pub musl_root: Option<PathBuf>,
     pub prefix: Option<PathBuf>,
+    pub destdir: Option<PathBuf>,
     pub sysconfdir: Option<PathBuf>,
     pub datadir: Option<PathBuf>,
     pub docdir: Option<PathBuf>,
     pub bindir: PathBuf,
     pub libdir: Option<PathBuf>,
     pub mandir: Option<PathBuf>,
+    pub infodir: Option<PathBuf>,
+    pub localstatedir: Option<PathBuf>,
+    pub sbindir: Option<PathBuf>,
+    pub install_validate: bool,
     pub codegen_tests: bool,
     pub nodejs: Option<PathBuf>,
     pub gdb: Option<PathBuf>,
@@ -182,6 +249,7 @@ pub struct Config {
     pub initial_cargo: PathBuf,
     pub initial_rustc: PathBuf,
     pub initial_rustfmt: Option<PathBuf>,
+    pub initial_cargo_clippy: Option<PathBuf>,
     pub out: PathBuf,
 }
 
@@ -211,7 +279,10 @@ fn from_str(value: &str) -> Result<Self, Self::Err> {
     }
 }
 
-#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+/// A target, either built into rustc or defined by a target specification
+/// file. Targets are identified by their triple only, which for a spec file is
+/// its file stem, so that e.g. `[target.<triple>]` applies to both kinds.
+#[derive(Debug, Copy, Clone, Default)]
 pub struct TargetSelection {
     pub triple: Interned<String>,
     file: Option<Interned<String>>,
@@ -227,18 +298,37 @@ pub fn from_user(selection: &str) -> Self {
                 .expect("Target specification file has no file stem")
                 .to_str()
                 .expect("Target specification file stem is not UTF-8");
+            // Cargo and rustc run in various directories, so the spec has to
+            // be passed with an absolute path.
+            let file = t!(path.canonicalize());
+            let file = file.to_str().expect("Target specification path is not UTF-8");
 
-            (triple, Some(selection))
+            (INTERNER.intern_str(triple), Some(INTERNER.intern_str(file)))
         } else {
-            (selection, None)
+            (INTERNER.intern_str(selection), None)
         };
 
-        let triple = INTERNER.intern_str(triple);
-        let file = file.map(|f| INTERNER.intern_str(f));
-
         Self { triple, file }
     }
 
+    /// Copies the target specification file, if any, into `dir`, and returns
+    /// this target using the copy. This keeps the spec used by a build from
+    /// changing behind its back, and lets rustc find it by its triple when
+    /// `dir` is in `RUST_TARGET_PATH`.
+    pub fn with_spec_in(self, dir: &Path) -> Self {
+        let file = match self.file {
+            Some(file) => file,
+            None => return self,
+        };
+        let copy = dir.join(format!("{}.json", self.triple));
+        if Path::new(&*file) != copy {
+            t!(fs::create_dir_all(dir));
+            t!(fs::copy(&*file, &copy));
+        }
+        let copy = copy.to_str().expect("Target specification path is not UTF-8");
+        Self { triple: self.triple, file: Some(INTERNER.intern_str(copy)) }
+    }
+
     pub fn rustc_target_arg(&self) -> &str {
         self.file.as_ref().unwrap_or(&self.triple)
     }
@@ -266,12 +356,123 @@ fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
     }
 }
 
+impl PartialEq for TargetSelection {
+    fn eq(&self, other: &Self) -> bool {
+        self.triple == other.triple
+    }
+}
+
+impl Eq for TargetSelection {}
+
+impl PartialOrd for TargetSelection {
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for TargetSelection {
+    fn cmp(&self, other: &Self) -> cmp::Ordering {
+        self.triple.cmp(&other.triple)
+    }
+}
+
+impl Hash for TargetSelection {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.triple.hash(state)
+    }
+}
+
 impl PartialEq<&str> for TargetSelection {
     fn eq(&self, other: &&str) -> bool {
         self.triple == *other
     }
 }
 
+/// A group of codegen options from `[rust.profiles.<name>]`, `[rust.stage<N>]`
+/// or `[rust.package-overrides.<name>]`, used to build the compiler of some
+/// stage or a single package with different settings than the rest.
+#[derive(Deserialize, Default, Clone, Debug, Merge)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct CodegenProfile {
+    pub opt_level: Option<u32>,
+    pub debuginfo: Option<u32>,
+    pub codegen_units: Option<u32>,
+    pub debug_assertions: Option<bool>,
+    pub overflow_checks: Option<bool>,
+}
+
+/// How to lower the priority of the commands spawned by the build, from
+/// `build.nice` and `build.ionice-class` or `[build.step-priority.<step>]`.
+#[derive(Deserialize, Default, Clone, Copy, Debug, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Priority {
+    pub nice: Option<i32>,
+    pub ionice_class: Option<IoniceClass>,
+}
+
+/// How much older output to keep after successful builds, from
+/// `build.retention`. Everything is kept if a field is `None`.
+#[derive(Deserialize, Default, Clone, Copy, Debug, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Retention {
+    /// Number of `x.py dist` runs whose output is kept in `build/dist`
+    pub dist: Option<usize>,
+    /// Number of most recently used stages kept for each target, among the
+    /// superseded ones
+    pub stages: Option<usize>,
+}
+
+impl Priority {
+    /// Fills in the settings missing from `self` from `fallback`.
+    pub fn or(self, fallback: Priority) -> Priority {
+        Priority {
+            nice: self.nice.or(fallback.nice),
+            ionice_class: self.ionice_class.or(fallback.ionice_class),
+        }
+    }
+}
+
+/// An I/O scheduling class, as in `ionice --class`.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum IoniceClass {
+    BestEffort,
+    Idle,
+}
+
+/// What to do about files with modification times in the future, from
+/// `build.mtime-check`.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum MtimeCheck {
+    Error,
+    Warn,
+    Off,
+}
+
+impl Default for MtimeCheck {
+    fn default() -> Self {
+        MtimeCheck::Warn
+    }
+}
+
+/// A step backed by an external command, declared with `[[plugin]]` and run
+/// by `plugin::External`.
+#[derive(Clone, Debug)]
+pub struct Plugin {
+    pub name: String,
+    /// The subcommand this is a step of, e.g. `test`
+    pub kind: String,
+    /// The paths selecting this step on the command line
+    pub paths: Vec<String>,
+    pub command: PathBuf,
+    pub args: Vec<String>,
+    /// Components built before running, from `plugin::REQUIREMENTS`
+    pub requires: Vec<String>,
+    pub default: bool,
+    pub only_hosts: bool,
+}
+
 /// Per-target configuration stored in the global configuration structure.
 #[derive(Default)]
 pub struct Target {
@@ -287,11 +488,13 @@ pub struct Target {
     pub ndk: Option<PathBuf>,
     pub sanitizers: Option<bool>,
     pub profiler: Option<bool>,
+    pub codegen_backends: Option<Vec<Interned<String>>>,
     pub crt_static: Option<bool>,
     pub musl_root: Option<PathBuf>,
     pub musl_libdir: Option<PathBuf>,
     pub wasi_root: Option<PathBuf>,
     pub qemu_rootfs: Option<PathBuf>,
+    pub runner: Option<String>,
     pub no_std: bool,
 }
 
@@ -313,19 +516,36 @@ pub fn from_triple(triple: &str) -> Self {
 #[serde(deny_unknown_fields, rename_all = "kebab-case")]
 struct TomlConfig {
     changelog_seen: Option<usize>,
+    change_id: Option<usize>,
     build: Option<Build>,
     install: Option<Install>,
     llvm: Option<Llvm>,
     rust: Option<Rust>,
     target: Option<HashMap<String, TomlTarget>>,
     dist: Option<Dist>,
+    doc: Option<Doc>,
+    hooks: Option<Hooks>,
+    plugin: Option<Vec<TomlPlugin>>,
     profile: Option<String>,
 }
 
 impl Merge for TomlConfig {
     fn merge(
         &mut self,
-        TomlConfig { build, install, llvm, rust, dist, target, profile: _, changelog_seen: _ }: Self,
+        TomlConfig {
+            build,
+            install,
+            llvm,
+            rust,
+            dist,
+            doc,
+            hooks,
+            plugin,
+            target,
+            profile: _,
+            changelog_seen: _,
+            change_id: _,
+        }: Self,
     ) {
         fn do_merge<T: Merge>(x: &mut Option<T>, y: Option<T>) {
             if let Some(new) = y {
@@ -341,6 +561,11 @@ fn do_merge<T: Merge>(x: &mut Option<T>, y: Option<T>) {
         do_merge(&mut self.llvm, llvm);
         do_merge(&mut self.rust, rust);
         do_merge(&mut self.dist, dist);
+        do_merge(&mut self.doc, doc);
+        do_merge(&mut self.hooks, hooks);
+        if let Some(plugin) = plugin {
+            self.plugin.get_or_insert_with(Vec::new).extend(plugin);
+        }
         assert!(target.is_none(), "merging target-specific config is not currently supported");
     }
 }
@@ -357,10 +582,12 @@ struct Build {
     cargo: Option<String>,
     rustc: Option<String>,
     rustfmt: Option<PathBuf>,
+    cargo_clippy: Option<PathBuf>,
     docs: Option<bool>,
     compiler_docs: Option<bool>,
     submodules: Option<bool>,
     fast_submodules: Option<bool>,
+    vcs: Option<String>,
     gdb: Option<String>,
     nodejs: Option<String>,
     python: Option<String>,
@@ -374,15 +601,34 @@ struct Build {
     profiler: Option<bool>,
     cargo_native_static: Option<bool>,
     low_priority: Option<bool>,
+    nice: Option<i32>,
+    ionice_class: Option<IoniceClass>,
+    step_priority: Option<HashMap<String, Priority>>,
+    retention: Option<Retention>,
     configure_args: Option<Vec<String>>,
     local_rebuild: Option<bool>,
     print_step_timings: Option<bool>,
+    io_diagnostics: Option<bool>,
+    mtime_check: Option<MtimeCheck>,
+    tmp_dir: Option<String>,
+    min_free_space: Option<u64>,
+    container_runtime: Option<String>,
+    artifact_cache: Option<String>,
+    artifact_cache_upload: Option<bool>,
+    rustc_cache: Option<String>,
+    step_cache: Option<bool>,
+    test_output_max_age: Option<u64>,
+    // These are only used by bootstrap.py
+    patch_binaries_for_nix: Option<bool>,
+    bootstrap_cache_path: Option<String>,
     doc_stage: Option<u32>,
     build_stage: Option<u32>,
     test_stage: Option<u32>,
     install_stage: Option<u32>,
     dist_stage: Option<u32>,
     bench_stage: Option<u32>,
+    check_stage: Option<u32>,
+    miri_stage: Option<u32>,
 }
 
 /// TOML representation of various global install decisions.
@@ -390,6 +636,7 @@ struct Build {
 #[serde(deny_unknown_fields, rename_all = "kebab-case")]
 struct Install {
     prefix: Option<String>,
+    destdir: Option<String>,
     sysconfdir: Option<String>,
     docdir: Option<String>,
     bindir: Option<String>,
@@ -397,9 +644,12 @@ struct Install {
     mandir: Option<String>,
     datadir: Option<String>,
 
-    // standard paths, currently unused
+    // standard paths, which nothing is currently installed to
     infodir: Option<String>,
     localstatedir: Option<String>,
+    sbindir: Option<String>,
+
+    validate: Option<bool>,
 }
 
 /// TOML representation of how the LLVM build is configured.
@@ -428,7 +678,13 @@ struct Llvm {
     use_linker: Option<String>,
     allow_old_toolchain: Option<bool>,
     polly: Option<bool>,
+    plugins: Option<bool>,
+    enable_zlib: Option<bool>,
+    build_config: Option<HashMap<String, String>>,
+    prebuilt_archive: Option<String>,
     download_ci_llvm: Option<StringOrBool>,
+    profile_generate: Option<String>,
+    profile_use: Option<String>,
 }
 
 #[derive(Deserialize, Default, Clone, Merge)]
@@ -442,6 +698,37 @@ struct Dist {
     compression_formats: Option<Vec<String>>,
 }
 
+/// TOML representation of how documentation is generated.
+#[derive(Deserialize, Default, Clone, Merge)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct Doc {
+    jobs: Option<u32>,
+    error_index: Option<bool>,
+    mdbook_preprocessors: Option<HashMap<String, String>>,
+}
+
+/// TOML representation of the commands run before and after steps.
+#[derive(Deserialize, Default, Clone, Merge)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct Hooks {
+    pre: Option<HashMap<String, String>>,
+    post: Option<HashMap<String, String>>,
+}
+
+/// TOML representation of a step backed by an external command.
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct TomlPlugin {
+    name: String,
+    kind: String,
+    paths: Vec<String>,
+    command: String,
+    args: Option<Vec<String>>,
+    requires: Option<Vec<String>>,
+    default: Option<bool>,
+    only_hosts: Option<bool>,
+}
+
 #[derive(Deserialize)]
 #[serde(untagged)]
 enum StringOrBool {
@@ -465,16 +752,19 @@ struct Rust {
     codegen_units_std: Option<u32>,
     debug_assertions: Option<bool>,
     debug_assertions_std: Option<bool>,
+    overflow_checks: Option<bool>,
+    overflow_checks_std: Option<bool>,
     debug_logging: Option<bool>,
-    debuginfo_level: Option<u32>,
-    debuginfo_level_rustc: Option<u32>,
-    debuginfo_level_std: Option<u32>,
-    debuginfo_level_tools: Option<u32>,
-    debuginfo_level_tests: Option<u32>,
+    debuginfo_level: Option<TomlDebuginfoLevel>,
+    debuginfo_level_rustc: Option<TomlDebuginfoLevel>,
+    debuginfo_level_std: Option<TomlDebuginfoLevel>,
+    debuginfo_level_tools: Option<TomlDebuginfoLevel>,
+    debuginfo_level_tests: Option<TomlDebuginfoLevel>,
     run_dsymutil: Option<bool>,
     backtrace: Option<bool>,
+    std_features: Option<Vec<String>>,
     incremental: Option<bool>,
-    parallel_compiler: Option<bool>,
+    parallel_compiler: Option<TomlParallelCompiler>,
     default_linker: Option<String>,
     channel: Option<String>,
     description: Option<String>,
@@ -487,12 +777,15 @@ struct Rust {
     dist_src: Option<bool>,
     save_toolstates: Option<String>,
     codegen_backends: Option<Vec<String>>,
-    lld: Option<bool>,
+    lld: Option<TomlLld>,
     use_lld: Option<bool>,
     llvm_tools: Option<bool>,
+    /// Deprecated in favor of `warnings`.
     deny_warnings: Option<bool>,
+    warnings: Option<Warnings>,
     backtrace_on_ice: Option<bool>,
     verify_llvm_ir: Option<bool>,
+    validate_mir_opts: Option<bool>,
     thin_lto_import_instr_limit: Option<u32>,
     remap_debuginfo: Option<bool>,
     jemalloc: Option<bool>,
@@ -500,8 +793,63 @@ struct Rust {
     llvm_libunwind: Option<String>,
     control_flow_guard: Option<bool>,
     new_symbol_mangling: Option<bool>,
+    randomize_layout: Option<bool>,
+    layout_seed: Option<u64>,
     profile_generate: Option<String>,
     profile_use: Option<String>,
+    profiles: Option<HashMap<String, CodegenProfile>>,
+    stage1_profile: Option<String>,
+    stage2_profile: Option<String>,
+    stage1: Option<CodegenProfile>,
+    stage2: Option<CodegenProfile>,
+    package_overrides: Option<HashMap<String, CodegenProfile>>,
+}
+
+/// A debuginfo level in `config.toml`, given either as the number passed to
+/// `-Cdebuginfo` or by name.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TomlDebuginfoLevel {
+    Numeric(u32),
+    Named(String),
+}
+
+impl TomlDebuginfoLevel {
+    /// Returns the `-Cdebuginfo` value for this level of the `option` setting.
+    fn level(&self, option: &str) -> u32 {
+        match self {
+            TomlDebuginfoLevel::Numeric(n) => *n,
+            TomlDebuginfoLevel::Named(name) => match &name[..] {
+                "none" => 0,
+                // Level 1 is line tables only, and there is no level that
+                // emits less than that while still keeping line information.
+                "line-directives-only" | "line-tables-only" => 1,
+                "full" => 2,
+                _ => panic!(
+                    "invalid value `{}` for rust.{}, expected 0, 1, 2, \"none\", \
+                     \"line-directives-only\", \"line-tables-only\" or \"full\"",
+                    name, option
+                ),
+            },
+        }
+    }
+}
+
+/// The `rust.lld` setting, either a boolean or `"self-contained"`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TomlLld {
+    Enabled(bool),
+    Mode(String),
+}
+
+/// The `rust.parallel-compiler` setting, either a boolean or the default number
+/// of threads of the parallel compiler.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TomlParallelCompiler {
+    Enabled(bool),
+    Threads(u32),
 }
 
 /// TOML representation of how each build target is configured.
@@ -518,11 +866,13 @@ struct TomlTarget {
     android_ndk: Option<String>,
     sanitizers: Option<bool>,
     profiler: Option<bool>,
+    codegen_backends: Option<Vec<String>>,
     crt_static: Option<bool>,
     musl_root: Option<String>,
     musl_libdir: Option<String>,
     wasi_root: Option<String>,
     qemu_rootfs: Option<String>,
+    runner: Option<String>,
     no_std: Option<bool>,
 }
 
@@ -550,7 +900,9 @@ pub fn default_opts() -> Config {
         config.submodules = true;
         config.fast_submodules = true;
         config.docs = true;
+        config.doc_error_index = true;
         config.rust_rpath = true;
+        config.install_validate = true;
         config.channel = "dev".to_string();
         config.codegen_tests = true;
         config.ignore_git = false;
@@ -558,12 +910,22 @@ pub fn default_opts() -> Config {
         config.rust_codegen_backends = vec![INTERNER.intern_str("llvm")];
         config.deny_warnings = true;
         config.missing_tools = false;
+        config.test_output_max_age = 7;
+        config.min_free_space = util::MIN_FREE_SPACE;
+        config.container_runtime = "docker".to_string();
 
         // set by build.rs
         config.build = TargetSelection::from_user(&env!("BUILD_TRIPLE"));
         let manifest_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
         // Undo `src/bootstrap`
         config.src = manifest_dir.parent().unwrap().parent().unwrap().to_owned();
+        if env::var_os("BOOTSTRAP_CHECK_PYTHON_ENV").is_some() {
+            let missing =
+                PYTHON_ENV.iter().filter(|var| env::var_os(var).is_none()).collect::<Vec<_>>();
+            if !missing.is_empty() {
+                panic!("\n\nbootstrap.py did not set {:?}\n\n", missing);
+            }
+        }
         config.out = Config::path_from_python("BUILD_DIR");
 
         config.initial_cargo = PathBuf::from(env!("CARGO"));
@@ -578,6 +940,8 @@ pub fn parse(args: &[String]) -> Config {
         let mut config = Config::default_opts();
         config.exclude = flags.exclude;
         config.include_default_paths = flags.include_default_paths;
+        config.ci_change_detection = flags.ci_change_detection;
+        config.ci_keep = flags.ci_keep;
         config.rustc_error_format = flags.rustc_error_format;
         config.json_output = flags.json_output;
         config.on_fail = flags.on_fail;
@@ -585,15 +949,36 @@ pub fn parse(args: &[String]) -> Config {
         config.cmd = flags.cmd;
         config.incremental = flags.incremental;
         config.dry_run = flags.dry_run;
+        config.json_plan = flags.json_plan;
+        config.explain = flags.explain;
+        config.timings = flags.timings;
+        config.bypass_bootstrap_lock = flags.bypass_bootstrap_lock;
         config.keep_stage = flags.keep_stage;
         config.keep_stage_std = flags.keep_stage_std;
         config.bindir = "bin".into(); // default
-        config.color = flags.color;
-        if let Some(value) = flags.deny_warnings {
-            config.deny_warnings = value;
+        config.color = match flags.color {
+            // Due to use of stamp/docker, the output stream of rustbuild is
+            // not a TTY in CI, but the logs are displayed with colors.
+            Color::Auto if crate::CiEnv::current() != crate::CiEnv::None => Color::Always,
+            color => color,
+        };
+        config.in_container = flags.in_container;
+        if let Some(deny) = flags.warnings.deny() {
+            config.deny_warnings = deny;
         }
 
-        if config.dry_run {
+        if let Subcommand::Test { ref mut paths, rerun_failed: true, .. } = config.cmd {
+            let failed = fs::read_to_string(config.out.join(crate::test::FAILED_TESTS));
+            let failed = failed.unwrap_or_default();
+            if failed.trim().is_empty() && paths.is_empty() {
+                println!("No failed tests were recorded by the previous `x.py test`");
+                std::process::exit(0);
+            }
+            paths.extend(failed.lines().map(PathBuf::from));
+        }
+
+        // `x.py gc --dry-run` reports on the actual build directory.
+        if config.dry_run && !matches!(config.cmd, Subcommand::Gc { .. }) {
             let dir = config.out.join("tmp-dry-run");
             t!(fs::create_dir_all(&dir));
             config.out = dir;
@@ -615,7 +1000,14 @@ pub fn parse(args: &[String]) -> Config {
             }
         };
 
-        let mut toml = flags.config.as_deref().map(get_toml).unwrap_or_else(TomlConfig::default);
+        // Mirrors bootstrap.py, which has already read the same file.
+        let config_file = env::var_os("RUST_BOOTSTRAP_CONFIG")
+            .filter(|path| !path.is_empty())
+            .map(PathBuf::from)
+            .or(flags.config)
+            .or_else(|| find_config(&config.src))
+            .map(|path| if path.exists() { path } else { config.src.join(path) });
+        let mut toml = config_file.as_deref().map(get_toml).unwrap_or_else(TomlConfig::default);
         if let Some(include) = &toml.profile {
             let mut include_path = config.src.clone();
             include_path.push("src");
@@ -627,12 +1019,36 @@ pub fn parse(args: &[String]) -> Config {
         }
 
         config.changelog_seen = toml.changelog_seen;
-        if let Some(cfg) = flags.config {
+        config.change_id = toml.change_id;
+        // Relative paths in the config file are relative to the directory it is
+        // in, so that it can be moved together with the files it refers to.
+        let config_dir = match &config_file {
+            Some(cfg) => t!(env::current_dir()).join(cfg).parent().unwrap().to_path_buf(),
+            None => config.src.clone(),
+        };
+        if let Some(cfg) = config_file {
             config.config = cfg;
         }
 
         let build = toml.build.unwrap_or_default();
 
+        // Where the hosts and targets come from, for `warn_on_target_overrides`.
+        let from_user = |triples: &Vec<String>| {
+            triples.iter().map(|t| TargetSelection::from_user(t)).collect::<Vec<_>>()
+        };
+        let file_hosts = build.host.as_ref().map(from_user).unwrap_or_default();
+        let file_targets = build.target.as_ref().map(from_user).unwrap_or_default();
+        let host_source = match (&flags.host, &build.host) {
+            (Some(_), _) => "--host",
+            (None, Some(_)) => "build.host",
+            (None, None) => "the build triple",
+        };
+        let target_source = match (&flags.target, &build.target) {
+            (Some(_), _) => "--target",
+            (None, Some(_)) => "build.target",
+            (None, None) => "the hosts",
+        };
+
         config.hosts = if let Some(arg_host) = flags.host {
             arg_host
         } else if let Some(file_host) = build.host {
@@ -650,22 +1066,30 @@ pub fn parse(args: &[String]) -> Config {
             config.hosts.clone()
         };
 
-        config.nodejs = build.nodejs.map(PathBuf::from);
-        config.gdb = build.gdb.map(PathBuf::from);
-        config.python = build.python.map(PathBuf::from);
+        config.nodejs = build.nodejs.map(|p| config_program(&config_dir, p));
+        config.gdb = build.gdb.map(|p| config_program(&config_dir, p));
+        config.python = build.python.map(|p| config_program(&config_dir, p));
         set(&mut config.low_priority, build.low_priority);
+        config.priority = Priority { nice: build.nice, ionice_class: build.ionice_class };
+        config.step_priority = build.step_priority.unwrap_or_default();
+        let priorities = Some(&config.priority).into_iter().chain(config.step_priority.values());
+        if let Some(nice) = priorities.filter_map(|p| p.nice).find(|n| !(0..=19).contains(n)) {
+            panic!("invalid nice value {} in config.toml, expected a value from 0 to 19", nice);
+        }
         set(&mut config.compiler_docs, build.compiler_docs);
         set(&mut config.docs, build.docs);
         set(&mut config.submodules, build.submodules);
         set(&mut config.fast_submodules, build.fast_submodules);
+        config.vcs = build.vcs.as_deref().map(Vcs::from_config);
         set(&mut config.locked_deps, build.locked_deps);
         set(&mut config.vendor, build.vendor);
         set(&mut config.full_bootstrap, build.full_bootstrap);
         set(&mut config.extended, build.extended);
         config.tools = build.tools;
-        if build.rustfmt.is_some() {
-            config.initial_rustfmt = build.rustfmt;
+        if let Some(rustfmt) = build.rustfmt {
+            config.initial_rustfmt = Some(config_program(&config_dir, rustfmt));
         }
+        config.initial_cargo_clippy = build.cargo_clippy.map(|p| config_program(&config_dir, p));
         set(&mut config.verbose, build.verbose);
         set(&mut config.sanitizers, build.sanitizers);
         set(&mut config.profiler, build.profiler);
@@ -673,6 +1097,25 @@ pub fn parse(args: &[String]) -> Config {
         set(&mut config.configure_args, build.configure_args);
         set(&mut config.local_rebuild, build.local_rebuild);
         set(&mut config.print_step_timings, build.print_step_timings);
+        set(&mut config.io_diagnostics, build.io_diagnostics);
+        set(&mut config.mtime_check, build.mtime_check);
+        config.tmp_dir = build.tmp_dir.map(|p| config_path(&config_dir, p));
+        if let Some(mib) = build.min_free_space {
+            config.min_free_space = mib << 20;
+        }
+        set(&mut config.container_runtime, build.container_runtime);
+        config.artifact_cache = build.artifact_cache.map(|cache| {
+            if cache.contains("://") {
+                cache
+            } else {
+                config_path(&config_dir, cache).to_string_lossy().into_owned()
+            }
+        });
+        set(&mut config.artifact_cache_upload, build.artifact_cache_upload);
+        config.rustc_cache = build.rustc_cache.map(|p| config_program(&config_dir, p));
+        set(&mut config.step_cache, build.step_cache);
+        set(&mut config.retention, build.retention);
+        set(&mut config.test_output_max_age, build.test_output_max_age);
 
         // See https://github.com/rust-lang/compiler-team/issues/326
         config.stage = match config.cmd {
@@ -680,16 +1123,22 @@ pub fn parse(args: &[String]) -> Config {
             Subcommand::Build { .. } => flags.stage.or(build.build_stage).unwrap_or(1),
             Subcommand::Test { .. } => flags.stage.or(build.test_stage).unwrap_or(1),
             Subcommand::Bench { .. } => flags.stage.or(build.bench_stage).unwrap_or(2),
+            Subcommand::Miri { .. } => flags.stage.or(build.miri_stage).unwrap_or(1),
             Subcommand::Dist { .. } => flags.stage.or(build.dist_stage).unwrap_or(2),
             Subcommand::Install { .. } => flags.stage.or(build.install_stage).unwrap_or(2),
+            Subcommand::Check { .. } | Subcommand::Clippy { .. } | Subcommand::Fix { .. } => {
+                flags.stage.or(build.check_stage).unwrap_or(0)
+            }
             // These are all bootstrap tools, which don't depend on the compiler.
             // The stage we pass shouldn't matter, but use 0 just in case.
             Subcommand::Clean { .. }
-            | Subcommand::Check { .. }
-            | Subcommand::Clippy { .. }
-            | Subcommand::Fix { .. }
+            | Subcommand::Gc { .. }
             | Subcommand::Run { .. }
+            | Subcommand::ExportLlvm { .. }
             | Subcommand::Setup { .. }
+            | Subcommand::Uninstall
+            | Subcommand::Suggest { .. }
+            | Subcommand::CiSmoke { .. }
             | Subcommand::Format { .. } => flags.stage.unwrap_or(0),
         };
 
@@ -701,6 +1150,7 @@ pub fn parse(args: &[String]) -> Config {
                 | Subcommand::Doc { .. }
                 | Subcommand::Build { .. }
                 | Subcommand::Bench { .. }
+                | Subcommand::Miri { .. }
                 | Subcommand::Dist { .. }
                 | Subcommand::Install { .. } => {
                     assert_eq!(
@@ -710,11 +1160,16 @@ pub fn parse(args: &[String]) -> Config {
                     );
                 }
                 Subcommand::Clean { .. }
+                | Subcommand::Gc { .. }
                 | Subcommand::Check { .. }
                 | Subcommand::Clippy { .. }
                 | Subcommand::Fix { .. }
                 | Subcommand::Run { .. }
+                | Subcommand::ExportLlvm { .. }
                 | Subcommand::Setup { .. }
+                | Subcommand::Uninstall
+                | Subcommand::Suggest { .. }
+                | Subcommand::CiSmoke { .. }
                 | Subcommand::Format { .. } => {}
             }
         }
@@ -722,19 +1177,26 @@ pub fn parse(args: &[String]) -> Config {
         config.verbose = cmp::max(config.verbose, flags.verbose);
 
         if let Some(install) = toml.install {
-            config.prefix = install.prefix.map(PathBuf::from);
+            config.prefix = install.prefix.map(|p| config_path(&config_dir, p));
+            config.destdir = install.destdir.map(|p| config_path(&config_dir, p));
             config.sysconfdir = install.sysconfdir.map(PathBuf::from);
             config.datadir = install.datadir.map(PathBuf::from);
             config.docdir = install.docdir.map(PathBuf::from);
             set(&mut config.bindir, install.bindir.map(PathBuf::from));
             config.libdir = install.libdir.map(PathBuf::from);
             config.mandir = install.mandir.map(PathBuf::from);
+            config.infodir = install.infodir.map(PathBuf::from);
+            config.localstatedir = install.localstatedir.map(PathBuf::from);
+            config.sbindir = install.sbindir.map(PathBuf::from);
+            set(&mut config.install_validate, install.validate);
         }
 
         // We want the llvm-skip-rebuild flag to take precedence over the
         // skip-rebuild config.toml option so we store it separately
         // so that we can infer the right value
         let mut llvm_skip_rebuild = flags.llvm_skip_rebuild;
+        let mut llvm_profile_generate = flags.llvm_profile_generate;
+        let mut llvm_profile_use = flags.llvm_profile_use;
 
         // Store off these values as options because if they're not provided
         // we'll infer default values for them later
@@ -742,6 +1204,8 @@ pub fn parse(args: &[String]) -> Config {
         let mut debug = None;
         let mut debug_assertions = None;
         let mut debug_assertions_std = None;
+        let mut overflow_checks = None;
+        let mut overflow_checks_std = None;
         let mut debug_logging = None;
         let mut debuginfo_level = None;
         let mut debuginfo_level_rustc = None;
@@ -762,6 +1226,8 @@ pub fn parse(args: &[String]) -> Config {
             set(&mut config.ninja_in_file, llvm.ninja);
             llvm_assertions = llvm.assertions;
             llvm_skip_rebuild = llvm_skip_rebuild.or(llvm.skip_rebuild);
+            llvm_profile_generate = llvm_profile_generate.or(llvm.profile_generate.clone());
+            llvm_profile_use = llvm_profile_use.or(llvm.profile_use.clone());
             set(&mut config.llvm_optimize, llvm.optimize);
             set(&mut config.llvm_thin_lto, llvm.thin_lto);
             set(&mut config.llvm_release_debuginfo, llvm.release_debuginfo);
@@ -781,6 +1247,11 @@ pub fn parse(args: &[String]) -> Config {
             config.llvm_use_linker = llvm.use_linker.clone();
             config.llvm_allow_old_toolchain = llvm.allow_old_toolchain;
             config.llvm_polly = llvm.polly;
+            set(&mut config.llvm_plugins, llvm.plugins);
+            config.llvm_enable_zlib = llvm.enable_zlib;
+            config.llvm_build_config = llvm.build_config.clone().unwrap_or_default();
+            config.llvm_prebuilt_archive =
+                llvm.prebuilt_archive.as_ref().map(|p| config_path(&config_dir, p));
             config.llvm_from_ci = match llvm.download_ci_llvm {
                 Some(StringOrBool::String(s)) => {
                     assert!(s == "if-available", "unknown option `{}` for download-ci-llvm", s);
@@ -815,6 +1286,12 @@ pub fn parse(args: &[String]) -> Config {
                 check_ci_llvm!(llvm.use_linker);
                 check_ci_llvm!(llvm.allow_old_toolchain);
                 check_ci_llvm!(llvm.polly);
+                check_ci_llvm!(llvm.plugins);
+                check_ci_llvm!(llvm.enable_zlib);
+                check_ci_llvm!(llvm.build_config);
+                check_ci_llvm!(llvm.prebuilt_archive);
+                check_ci_llvm!(llvm.profile_generate);
+                check_ci_llvm!(llvm.profile_use);
 
                 // CI-built LLVM is shared
                 config.llvm_link_shared = true;
@@ -826,22 +1303,36 @@ pub fn parse(args: &[String]) -> Config {
                 // step) with each stage.
                 config.llvm_link_shared = true;
             }
+
+            if config.llvm_plugins {
+                // Plugins resolve LLVM symbols against the process that loads
+                // them, which only works if rustc links to a shared LLVM rather
+                // than carrying its own private static copy.
+                config.llvm_link_shared = true;
+            }
         }
 
         if let Some(rust) = toml.rust {
             debug = rust.debug;
             debug_assertions = rust.debug_assertions;
             debug_assertions_std = rust.debug_assertions_std;
+            overflow_checks = rust.overflow_checks;
+            overflow_checks_std = rust.overflow_checks_std;
             debug_logging = rust.debug_logging;
-            debuginfo_level = rust.debuginfo_level;
-            debuginfo_level_rustc = rust.debuginfo_level_rustc;
-            debuginfo_level_std = rust.debuginfo_level_std;
-            debuginfo_level_tools = rust.debuginfo_level_tools;
-            debuginfo_level_tests = rust.debuginfo_level_tests;
+            debuginfo_level = rust.debuginfo_level.map(|l| l.level("debuginfo-level"));
+            debuginfo_level_rustc =
+                rust.debuginfo_level_rustc.map(|l| l.level("debuginfo-level-rustc"));
+            debuginfo_level_std = rust.debuginfo_level_std.map(|l| l.level("debuginfo-level-std"));
+            debuginfo_level_tools =
+                rust.debuginfo_level_tools.map(|l| l.level("debuginfo-level-tools"));
+            debuginfo_level_tests =
+                rust.debuginfo_level_tests.map(|l| l.level("debuginfo-level-tests"));
             config.rust_run_dsymutil = rust.run_dsymutil.unwrap_or(false);
             optimize = rust.optimize;
             ignore_git = rust.ignore_git;
             set(&mut config.rust_new_symbol_mangling, rust.new_symbol_mangling);
+            set(&mut config.rust_randomize_layout, rust.randomize_layout);
+            config.rust_layout_seed = rust.layout_seed;
             set(&mut config.rust_optimize_tests, rust.optimize_tests);
             set(&mut config.codegen_tests, rust.codegen_tests);
             set(&mut config.rust_rpath, rust.rpath);
@@ -851,6 +1342,7 @@ pub fn parse(args: &[String]) -> Config {
                 .llvm_libunwind
                 .map(|v| v.parse().expect("failed to parse rust.llvm-libunwind"));
             set(&mut config.backtrace, rust.backtrace);
+            set(&mut config.std_features, rust.std_features);
             set(&mut config.channel, rust.channel);
             config.description = rust.description;
             set(&mut config.rust_dist_src, rust.dist_src);
@@ -860,28 +1352,82 @@ pub fn parse(args: &[String]) -> Config {
                 config.incremental = true;
             }
             set(&mut config.use_lld, rust.use_lld);
-            set(&mut config.lld_enabled, rust.lld);
+            match rust.lld {
+                Some(TomlLld::Enabled(enabled)) => config.lld_enabled = enabled,
+                Some(TomlLld::Mode(mode)) if mode == "self-contained" => {
+                    config.lld_enabled = true;
+                    config.lld_self_contained = true;
+                }
+                Some(TomlLld::Mode(mode)) => panic!(
+                    "invalid value `{}` for rust.lld, expected true, false or \"self-contained\"",
+                    mode
+                ),
+                None => {}
+            }
             set(&mut config.llvm_tools_enabled, rust.llvm_tools);
-            config.rustc_parallel = rust.parallel_compiler.unwrap_or(false);
+            match rust.parallel_compiler {
+                Some(TomlParallelCompiler::Enabled(enabled)) => config.rustc_parallel = enabled,
+                Some(TomlParallelCompiler::Threads(0)) => {
+                    panic!(
+                        "rust.parallel-compiler must be a boolean or a number of threads above 0"
+                    )
+                }
+                Some(TomlParallelCompiler::Threads(threads)) => {
+                    config.rustc_parallel = true;
+                    config.rustc_parallel_threads = Some(threads);
+                }
+                None => {}
+            }
             config.rustc_default_linker = rust.default_linker;
-            config.musl_root = rust.musl_root.map(PathBuf::from);
-            config.save_toolstates = rust.save_toolstates.map(PathBuf::from);
-            set(&mut config.deny_warnings, flags.deny_warnings.or(rust.deny_warnings));
+            config.musl_root = rust.musl_root.map(|p| config_path(&config_dir, p));
+            config.save_toolstates = rust.save_toolstates.map(|p| config_path(&config_dir, p));
+            if rust.deny_warnings.is_some() {
+                eprintln!(
+                    "warning: `rust.deny-warnings` is deprecated, set `rust.warnings` to \
+                     \"deny\" or \"warn\" instead"
+                );
+            }
+            let file_warnings = rust.warnings.and_then(Warnings::deny).or(rust.deny_warnings);
+            set(&mut config.deny_warnings, flags.warnings.deny().or(file_warnings));
             set(&mut config.backtrace_on_ice, rust.backtrace_on_ice);
             set(&mut config.rust_verify_llvm_ir, rust.verify_llvm_ir);
+            set(&mut config.rust_validate_mir_opts, rust.validate_mir_opts);
             config.rust_thin_lto_import_instr_limit = rust.thin_lto_import_instr_limit;
             set(&mut config.rust_remap_debuginfo, rust.remap_debuginfo);
             set(&mut config.control_flow_guard, rust.control_flow_guard);
 
             if let Some(ref backends) = rust.codegen_backends {
                 config.rust_codegen_backends =
-                    backends.iter().map(|s| INTERNER.intern_str(s)).collect();
+                    backends.iter().map(|s| codegen_backend(&config_dir, s)).collect();
             }
 
             config.rust_codegen_units = rust.codegen_units.map(threads_from_config);
             config.rust_codegen_units_std = rust.codegen_units_std.map(threads_from_config);
             config.rust_profile_use = flags.rust_profile_use.or(rust.profile_use);
             config.rust_profile_generate = flags.rust_profile_generate.or(rust.profile_generate);
+
+            // Options given directly in `[rust.stageN]` win over the ones
+            // from the named profile selected for that stage.
+            let profiles = rust.profiles.unwrap_or_default();
+            let stages =
+                vec![(1, rust.stage1_profile, rust.stage1), (2, rust.stage2_profile, rust.stage2)];
+            for (stage, name, overrides) in stages {
+                let named = name.map(|name| {
+                    profiles.get(&name).cloned().unwrap_or_else(|| {
+                        panic!("rust.stage{}-profile refers to unknown profile `{}`", stage, name)
+                    })
+                });
+                let profile = match (overrides, named) {
+                    (Some(mut overrides), Some(named)) => {
+                        overrides.merge(named);
+                        overrides
+                    }
+                    (Some(profile), None) | (None, Some(profile)) => profile,
+                    (None, None) => continue,
+                };
+                config.rust_stage_profiles.insert(stage, profile);
+            }
+            config.rust_package_overrides = rust.package_overrides.unwrap_or_default();
         } else {
             config.rust_profile_use = flags.rust_profile_use;
             config.rust_profile_generate = flags.rust_profile_generate;
@@ -892,33 +1438,42 @@ pub fn parse(args: &[String]) -> Config {
                 let mut target = Target::from_triple(&triple);
 
                 if let Some(ref s) = cfg.llvm_config {
-                    target.llvm_config = Some(config.src.join(s));
+                    target.llvm_config = Some(config_path(&config_dir, s));
                 }
                 if let Some(ref s) = cfg.llvm_filecheck {
-                    target.llvm_filecheck = Some(config.src.join(s));
+                    target.llvm_filecheck = Some(config_path(&config_dir, s));
                 }
                 if let Some(ref s) = cfg.android_ndk {
-                    target.ndk = Some(config.src.join(s));
+                    target.ndk = Some(config_path(&config_dir, s));
                 }
                 if let Some(s) = cfg.no_std {
                     target.no_std = s;
                 }
-                target.cc = cfg.cc.map(PathBuf::from);
-                target.cxx = cfg.cxx.map(PathBuf::from);
-                target.ar = cfg.ar.map(PathBuf::from);
-                target.ranlib = cfg.ranlib.map(PathBuf::from);
-                target.linker = cfg.linker.map(PathBuf::from);
+                target.cc = cfg.cc.map(|p| config_program(&config_dir, p));
+                target.cxx = cfg.cxx.map(|p| config_program(&config_dir, p));
+                target.ar = cfg.ar.map(|p| config_program(&config_dir, p));
+                target.ranlib = cfg.ranlib.map(|p| config_program(&config_dir, p));
+                target.linker = cfg.linker.map(|p| config_program(&config_dir, p));
                 target.crt_static = cfg.crt_static;
-                target.musl_root = cfg.musl_root.map(PathBuf::from);
-                target.musl_libdir = cfg.musl_libdir.map(PathBuf::from);
-                target.wasi_root = cfg.wasi_root.map(PathBuf::from);
-                target.qemu_rootfs = cfg.qemu_rootfs.map(PathBuf::from);
+                target.musl_root = cfg.musl_root.map(|p| config_path(&config_dir, p));
+                target.musl_libdir = cfg.musl_libdir.map(|p| config_path(&config_dir, p));
+                target.wasi_root = cfg.wasi_root.map(|p| config_path(&config_dir, p));
+                target.qemu_rootfs = cfg.qemu_rootfs.map(|p| config_path(&config_dir, p));
+                target.runner = cfg.runner;
                 target.sanitizers = cfg.sanitizers;
                 target.profiler = cfg.profiler;
+                target.codegen_backends = cfg.codegen_backends.map(|backends| {
+                    backends.iter().map(|s| codegen_backend(&config_dir, s)).collect()
+                });
 
                 config.target_config.insert(TargetSelection::from_user(&triple), target);
             }
         }
+        warn_on_target_overrides(
+            &config,
+            (host_source, &file_hosts),
+            (target_source, &file_targets),
+        );
 
         if config.llvm_from_ci {
             let triple = &config.build.triple;
@@ -935,14 +1490,47 @@ pub fn parse(args: &[String]) -> Config {
         }
 
         if let Some(t) = toml.dist {
-            config.dist_sign_folder = t.sign_folder.map(PathBuf::from);
-            config.dist_gpg_password_file = t.gpg_password_file.map(PathBuf::from);
+            config.dist_sign_folder = t.sign_folder.map(|p| config_path(&config_dir, p));
+            config.dist_gpg_password_file =
+                t.gpg_password_file.map(|p| config_path(&config_dir, p));
             config.dist_upload_addr = t.upload_addr;
             config.dist_compression_formats = t.compression_formats;
+            check_dist_compression(&config);
             set(&mut config.rust_dist_src, t.src_tarball);
             set(&mut config.missing_tools, t.missing_tools);
         }
 
+        if let Some(doc) = toml.doc {
+            config.doc_jobs = doc.jobs.map(threads_from_config);
+            set(&mut config.doc_error_index, doc.error_index);
+            for (name, command) in doc.mdbook_preprocessors.unwrap_or_default() {
+                config.mdbook_preprocessors.insert(name, config_program(&config_dir, command));
+            }
+        }
+
+        if let Some(hooks) = toml.hooks {
+            config.hooks_pre = hooks.pre.unwrap_or_default().into_iter().collect();
+            config.hooks_post = hooks.post.unwrap_or_default().into_iter().collect();
+            // Run the hooks of a step in a predictable order.
+            config.hooks_pre.sort();
+            config.hooks_post.sort();
+        }
+
+        for plugin in toml.plugin.unwrap_or_default() {
+            let plugin = Plugin {
+                name: plugin.name,
+                kind: plugin.kind,
+                paths: plugin.paths,
+                command: config_program(&config_dir, plugin.command),
+                args: plugin.args.unwrap_or_default(),
+                requires: plugin.requires.unwrap_or_default(),
+                default: plugin.default.unwrap_or(false),
+                only_hosts: plugin.only_hosts.unwrap_or(false),
+            };
+            check_plugin(&config, &plugin);
+            config.plugins.push(plugin);
+        }
+
         config.initial_rustfmt = config.initial_rustfmt.or_else({
             let build = config.build;
             let initial_rustc = &config.initial_rustc;
@@ -960,6 +1548,13 @@ pub fn parse(args: &[String]) -> Config {
         // default values for all options that we haven't otherwise stored yet.
 
         config.llvm_skip_rebuild = llvm_skip_rebuild.unwrap_or(false);
+        assert!(
+            llvm_profile_generate.is_none() || llvm_profile_use.is_none(),
+            "setting llvm.profile-generate and llvm.profile-use together is not supported: \
+             a build can't both generate and use PGO profiles of LLVM."
+        );
+        config.llvm_profile_generate = llvm_profile_generate;
+        config.llvm_profile_use = llvm_profile_use;
 
         let default = false;
         config.llvm_assertions = llvm_assertions.unwrap_or(default);
@@ -971,6 +1566,9 @@ pub fn parse(args: &[String]) -> Config {
         config.rust_debug_assertions = debug_assertions.unwrap_or(default);
         config.rust_debug_assertions_std =
             debug_assertions_std.unwrap_or(config.rust_debug_assertions);
+        config.rust_overflow_checks = overflow_checks.unwrap_or(config.rust_debug_assertions);
+        config.rust_overflow_checks_std =
+            overflow_checks_std.unwrap_or(config.rust_debug_assertions_std);
 
         config.rust_debug_logging = debug_logging.unwrap_or(config.rust_debug_assertions);
 
@@ -987,7 +1585,8 @@ pub fn parse(args: &[String]) -> Config {
         config.rust_debuginfo_level_tests = debuginfo_level_tests.unwrap_or(0);
 
         let default = config.channel == "dev";
-        config.ignore_git = ignore_git.unwrap_or(default);
+        // Without version control there's no commit information to use either.
+        config.ignore_git = ignore_git.unwrap_or(default) || config.vcs == Some(Vcs::None);
 
         config
     }
@@ -1029,10 +1628,6 @@ pub fn sanitizers_enabled(&self, target: TargetSelection) -> bool {
         self.target_config.get(&target).map(|t| t.sanitizers).flatten().unwrap_or(self.sanitizers)
     }
 
-    pub fn any_sanitizers_enabled(&self) -> bool {
-        self.target_config.values().any(|t| t.sanitizers == Some(true)) || self.sanitizers
-    }
-
     pub fn profiler_enabled(&self, target: TargetSelection) -> bool {
         self.target_config.get(&target).map(|t| t.profiler).flatten().unwrap_or(self.profiler)
     }
@@ -1041,11 +1636,81 @@ pub fn any_profiler_enabled(&self) -> bool {
         self.target_config.values().any(|t| t.profiler == Some(true)) || self.profiler
     }
 
-    pub fn llvm_enabled(&self) -> bool {
-        self.rust_codegen_backends.contains(&INTERNER.intern_str("llvm"))
+    /// Returns the codegen backends to build for `target`, which is the
+    /// target's own `codegen-backends` if set and `rust.codegen-backends`
+    /// otherwise.
+    pub fn codegen_backends(&self, target: TargetSelection) -> &[Interned<String>] {
+        self.target_config
+            .get(&target)
+            .and_then(|t| t.codegen_backends.as_deref())
+            .unwrap_or(&self.rust_codegen_backends)
+    }
+
+    pub fn llvm_enabled(&self, target: TargetSelection) -> bool {
+        self.codegen_backends(target).contains(&INTERNER.intern_str("llvm"))
+    }
+
+    pub fn any_llvm_enabled(&self) -> bool {
+        let llvm = INTERNER.intern_str("llvm");
+        self.target_config
+            .values()
+            .any(|t| matches!(&t.codegen_backends, Some(backends) if backends.contains(&llvm)))
+            || self.rust_codegen_backends.contains(&llvm)
+    }
+}
+
+/// Returns whether a `codegen-backends` entry is the path of a prebuilt backend
+/// dylib rather than the name of a backend in `compiler/rustc_codegen_$name`.
+/// As with rustc's `-Z codegen-backend`, anything containing a `.` is a path.
+pub fn is_prebuilt_codegen_backend(backend: &str) -> bool {
+    backend.contains('.')
+}
+
+/// Interns a `codegen-backends` entry, resolving the path of prebuilt backends.
+fn codegen_backend(config_dir: &Path, backend: &str) -> Interned<String> {
+    if is_prebuilt_codegen_backend(backend) {
+        INTERNER.intern_string(config_path(config_dir, backend).to_str().unwrap().to_string())
+    } else {
+        INTERNER.intern_str(backend)
+    }
+}
+
+/// Resolves a path from `config.toml` against `config_dir`, the directory the
+/// file is in. Absolute paths are passed through.
+fn config_path(config_dir: &Path, path: impl AsRef<Path>) -> PathBuf {
+    config_dir.join(path)
+}
+
+/// Like `config_path`, but leaves bare program names like `"gdb"` as they are,
+/// to be looked up in `PATH`.
+fn config_program(config_dir: &Path, program: impl AsRef<Path>) -> PathBuf {
+    let program = program.as_ref();
+    if program.components().count() > 1 {
+        config_path(config_dir, program)
+    } else {
+        program.to_path_buf()
     }
 }
 
+/// Finds the `config.toml` to use when none was given explicitly, like
+/// `find_config_toml` in bootstrap.py: the first one in the current directory
+/// and, if it's inside the source tree, each of its parents up to the source
+/// root, or else the one in the source root.
+fn find_config(src: &Path) -> Option<PathBuf> {
+    let mut candidates = Vec::new();
+    if let Ok(cwd) = env::current_dir() {
+        if cwd.starts_with(src) {
+            candidates.extend(
+                cwd.ancestors().take_while(|dir| dir.starts_with(src)).map(Path::to_path_buf),
+            );
+        } else {
+            candidates.push(cwd);
+        }
+    }
+    candidates.push(src.to_path_buf());
+    candidates.into_iter().map(|dir| dir.join("config.toml")).find(|path| path.exists())
+}
+
 fn set<T>(field: &mut T, val: Option<T>) {
     if let Some(v) = val {
         *field = v;
@@ -1058,3 +1723,139 @@ fn threads_from_config(v: u32) -> u32 {
         n => n,
     }
 }
+
+/// Prints where the hosts and targets come from if `--host` or `--target`
+/// drop ones listed in config.toml, or add targets which are configured
+/// nowhere, so that the resulting selection doesn't come as a surprise.
+/// `hosts` and `targets` are the source of each list along with the entries
+/// config.toml has for it.
+fn warn_on_target_overrides(
+    config: &Config,
+    hosts: (&str, &[TargetSelection]),
+    targets: (&str, &[TargetSelection]),
+) {
+    let mut dropped = Vec::new();
+    let mut unconfigured = false;
+    for &((source, file), list, key) in
+        &[(hosts, &config.hosts, "build.host"), (targets, &config.targets, "build.target")]
+    {
+        if !source.starts_with("--") {
+            continue;
+        }
+        let missing = file.iter().filter(|t| !list.contains(t));
+        dropped.extend(missing.map(|t| format!("{}: {}", key, t)));
+        unconfigured |= list.iter().any(|t| {
+            *t != config.build
+                && !config.target_config.contains_key(t)
+                && !hosts.1.contains(t)
+                && !targets.1.contains(t)
+        });
+    }
+    if dropped.is_empty() && !unconfigured {
+        return;
+    }
+
+    eprintln!("warning: the hosts and targets given on the command line differ from config.toml");
+    for &((source, _), list, name) in
+        &[(hosts, &config.hosts, "hosts"), (targets, &config.targets, "targets")]
+    {
+        eprintln!("  {} (from {}):", name, source);
+        for target in list {
+            let section = if config.target_config.contains_key(target) {
+                format!("[target.{}] configured", target.triple)
+            } else {
+                format!("no [target.{}] section", target.triple)
+            };
+            eprintln!("    {:<40} {}", target.to_string(), section);
+        }
+    }
+    if !dropped.is_empty() {
+        eprintln!("  not built, although listed in config.toml:");
+        for entry in &dropped {
+            eprintln!("    {}", entry);
+        }
+    }
+}
+
+fn check_plugin(config: &Config, plugin: &Plugin) {
+    if config.plugins.iter().any(|p| p.name == plugin.name) {
+        panic!("\n\nplugin `{}` is declared more than once\n\n", plugin.name);
+    }
+    if Builder::kind_for_paths(&plugin.kind).is_none() {
+        panic!(
+            "\n\nplugin `{}` has the unsupported kind `{}`, expected one of build, doc, test, \
+             bench, miri, dist or install\n\n",
+            plugin.name, plugin.kind
+        );
+    }
+    if plugin.paths.is_empty() {
+        panic!("\n\nplugin `{}` claims no paths\n\n", plugin.name);
+    }
+    for requirement in &plugin.requires {
+        if !plugin::REQUIREMENTS.contains(&&requirement[..]) {
+            panic!(
+                "\n\nplugin `{}` requires `{}`, expected one of {:?}\n\n",
+                plugin.name,
+                requirement,
+                plugin::REQUIREMENTS
+            );
+        }
+    }
+}
+
+/// The compression formats the rust-installer in `src/tools/rust-installer`
+/// can generate dist tarballs with.
+const DIST_COMPRESSION_FORMATS: &[&str] = &["gz", "xz"];
+
+fn check_dist_compression(config: &Config) {
+    for format in config.dist_compression_formats.iter().flatten() {
+        if !DIST_COMPRESSION_FORMATS.contains(&&format[..]) {
+            panic!(
+                "\n\nunsupported dist.compression-formats entry `{}`, expected one of {:?}\n\n",
+                format, DIST_COMPRESSION_FORMATS
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relative_paths() {
+        let dir = Path::new("/home/user/rust");
+        assert_eq!(config_path(dir, "build/llvm"), PathBuf::from("/home/user/rust/build/llvm"));
+        assert_eq!(config_path(dir, "../llvm"), PathBuf::from("/home/user/rust/../llvm"));
+        assert_eq!(config_path(dir, "/opt/llvm"), PathBuf::from("/opt/llvm"));
+    }
+
+    #[test]
+    fn relative_programs() {
+        let dir = Path::new("/home/user/rust");
+        assert_eq!(config_program(dir, "gdb"), PathBuf::from("gdb"));
+        assert_eq!(config_program(dir, "bin/gdb"), PathBuf::from("/home/user/rust/bin/gdb"));
+        assert_eq!(config_program(dir, "/usr/bin/gdb"), PathBuf::from("/usr/bin/gdb"));
+    }
+
+    fn debuginfo_level(value: &str) -> u32 {
+        let rust: Rust = toml::from_str(&format!("debuginfo-level = {}", value)).unwrap();
+        rust.debuginfo_level.unwrap().level("debuginfo-level")
+    }
+
+    #[test]
+    fn named_debuginfo_levels() {
+        assert_eq!(debuginfo_level("0"), 0);
+        assert_eq!(debuginfo_level("2"), 2);
+        assert_eq!(debuginfo_level("\"none\""), 0);
+        assert_eq!(debuginfo_level("\"line-directives-only\""), 1);
+        assert_eq!(debuginfo_level("\"line-tables-only\""), 1);
+        assert_eq!(debuginfo_level("\"full\""), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid value `limited` for rust.debuginfo-level")]
+    fn unknown_debuginfo_level() {
+        debuginfo_level("\"limited\"");
+    }
+}
@@ -45,6 +45,9 @@ o("local-rebuild", "build.local-rebuild", "assume local-rust matches the current
 o("llvm-static-stdcpp", "llvm.static-libstdcpp", "statically link to libstdc++ for LLVM")
 o("llvm-link-shared", "llvm.link-shared", "prefer shared linking to LLVM (llvm-config --link-shared)")
 o("rpath", "rust.rpath", "build rpaths into rustc itself")
+o("llvm-polly", "llvm.polly", "build LLVM with the Polly optimizer")
+o("llvm-plugins", "llvm.plugins", "build LLVM with support for loading pass plugins")
+o("llvm-zlib", "llvm.enable-zlib", "build LLVM with zlib (fails if zlib isn't found)")
 o("llvm-version-check", "llvm.version-check", "check if the LLVM version is supported, build anyway")
 o("codegen-tests", "rust.codegen-tests", "run the src/test/codegen tests")
 o("option-checking", None, "complain about unrecognized options in this configure script")
@@ -60,6 +63,7 @@ o("lld", "rust.lld", "build lld")
 o("missing-tools", "dist.missing-tools", "allow failures when building tools")
 o("use-libcxx", "llvm.use-libcxx", "build LLVM with libc++")
 o("control-flow-guard", "rust.control-flow-guard", "Enable Control Flow Guard")
+o("install-validate", "install.validate", "check the installed toolchain at the end of x.py install")
 
 v("llvm-cflags", "llvm.cflags", "build LLVM with these extra compiler flags")
 v("llvm-cxxflags", "llvm.cxxflags", "build LLVM with these extra compiler flags")
@@ -73,6 +77,7 @@ o("optimize", "rust.optimize", "build optimized rust code")
 o("optimize-llvm", "llvm.optimize", "build optimized LLVM")
 o("llvm-assertions", "llvm.assertions", "build LLVM with assertions")
 o("debug-assertions", "rust.debug-assertions", "build with debugging assertions")
+o("overflow-checks", "rust.overflow-checks", "build with overflow checks")
 o("llvm-release-debuginfo", "llvm.release-debuginfo", "build LLVM with debugger metadata")
 v("debuginfo-level", "rust.debuginfo-level", "debuginfo level for Rust code")
 v("debuginfo-level-rustc", "rust.debuginfo-level-rustc", "debuginfo level for the compiler")
@@ -82,6 +87,7 @@ v("debuginfo-level-tests", "rust.debuginfo-level-tests", "debuginfo level for th
 v("save-toolstates", "rust.save-toolstates", "save build and test status of external tools into this file")
 
 v("prefix", "install.prefix", "set installation prefix")
+v("destdir", "install.destdir", "stage the installation below this directory")
 v("localstatedir", "install.localstatedir", "local state directory")
 v("datadir", "install.datadir", "install data")
 v("sysconfdir", "install.sysconfdir", "install system configuration files")
@@ -90,6 +96,7 @@ v("libdir", "install.libdir", "install libraries")
 v("mandir", "install.mandir", "install man pages in PATH")
 v("docdir", "install.docdir", "install documentation in PATH")
 v("bindir", "install.bindir", "install binaries")
+v("sbindir", "install.sbindir", "install system administration binaries")
 
 v("llvm-root", None, "set LLVM root")
 v("llvm-config", None, "set path to llvm-config")
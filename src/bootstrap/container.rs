@@ -0,0 +1,129 @@
+//! Implementation of `x.py --in-container`.
+//!
+//! Runs the requested command again with x.py inside a container, so that
+//! builds can be reproduced in the environment of a CI job. The source tree
+//! and configuration are mounted at the same paths they have on this machine,
+//! so paths in `config.toml` keep working, while the build directory is
+//! replaced with one kept separately for each image, as the artifacts of the
+//! host and of the container usually can't be mixed.
+
+use std::env;
+use std::fs;
+use std::process::Command;
+
+use build_helper::t;
+
+use crate::util;
+use crate::Build;
+
+/// Options of x.py which are set again for the container and therefore not
+/// forwarded. All of them take a value.
+const REPLACED_OPTIONS: &[&str] = &["--in-container", "--config", "--jobs", "-j"];
+
+/// Runs the command bootstrap was invoked with in `image`, which is either
+/// the name of one of the CI images in `src/ci/docker` or an image the
+/// container runtime can pull.
+pub fn run_in_container(build: &Build, image: &str) {
+    let runtime = &build.config.container_runtime;
+    let image = prepare_image(build, image);
+
+    let build_dir = build.out.join("container").join(sanitize(&image));
+    t!(fs::create_dir_all(&build_dir));
+
+    let mut cmd = Command::new(runtime);
+    cmd.arg("run").arg("--rm").arg("--init").arg("--interactive");
+    if util::stdout_is_tty() {
+        cmd.arg("--tty");
+    }
+    cmd.arg("--volume").arg(format!("{}:{}", build.src.display(), build.src.display()));
+    cmd.arg("--volume").arg(format!("{}:{}", build_dir.display(), build.out.display()));
+    if build.config.config.exists() && !build.config.config.starts_with(&build.src) {
+        let config = t!(fs::canonicalize(&build.config.config));
+        cmd.arg("--volume").arg(format!("{}:{}:ro", config.display(), config.display()));
+    }
+    if let Some(user) = owner(build) {
+        cmd.arg("--user").arg(user);
+    }
+    cmd.arg("--workdir").arg(&build.src);
+    // Keep the crates.io index and downloaded crates with the build directory,
+    // as the user we run as may have no home directory in the image.
+    cmd.arg("--env").arg(format!("CARGO_HOME={}", build.out.join("cargo-home").display()));
+    cmd.arg(&image);
+
+    cmd.arg("python3").arg(build.src.join("x.py"));
+    cmd.args(forwarded_args(&env::args().skip(1).collect::<Vec<_>>()));
+    cmd.arg("--jobs").arg(build.jobs().to_string());
+    if build.config.config.exists() {
+        cmd.arg("--config").arg(t!(fs::canonicalize(&build.config.config)));
+    }
+
+    build.info(&format!("Running in container {}", image));
+    if build.config.dry_run {
+        println!("would run: {:?}", cmd);
+        return;
+    }
+    build.run(&mut cmd);
+}
+
+/// Builds `image` if it is one of the CI images for this machine's
+/// architecture, returning the name to run it by.
+fn prepare_image(build: &Build, image: &str) -> String {
+    let docker_dir = build.src.join("src/ci/docker");
+    let dockerfile =
+        docker_dir.join(format!("host-{}", env::consts::ARCH)).join(image).join("Dockerfile");
+    if !dockerfile.exists() {
+        return image.to_string();
+    }
+
+    let tag = format!("rust-ci-{}", image);
+    build.info(&format!("Building container image {} from {}", tag, dockerfile.display()));
+    let mut cmd = Command::new(&build.config.container_runtime);
+    cmd.arg("build").arg("--rm").arg("-t").arg(&tag).arg("-f").arg(&dockerfile).arg(&docker_dir);
+    build.run(&mut cmd);
+    tag
+}
+
+/// Returns the arguments to pass on to x.py in the container, which are
+/// `args` without the options in `REPLACED_OPTIONS`.
+fn forwarded_args(args: &[String]) -> Vec<String> {
+    let mut forwarded = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            forwarded.push(arg.clone());
+            forwarded.extend(args.cloned());
+            break;
+        }
+        if REPLACED_OPTIONS.contains(&arg.as_str()) {
+            args.next();
+            continue;
+        }
+        let joined = REPLACED_OPTIONS.iter().any(|opt| {
+            let prefix = if opt.starts_with("--") { format!("{}=", opt) } else { opt.to_string() };
+            arg.starts_with(&prefix)
+        });
+        if !joined {
+            forwarded.push(arg.clone());
+        }
+    }
+    forwarded
+}
+
+/// Makes `image` usable as a directory name.
+fn sanitize(image: &str) -> String {
+    image.chars().map(|c| if c.is_ascii_alphanumeric() || c == '.' { c } else { '-' }).collect()
+}
+
+/// Returns the `uid:gid` owning the source tree, so that the files written
+/// by the container are owned by the user running x.py.
+#[cfg(unix)]
+fn owner(build: &Build) -> Option<String> {
+    use std::os::unix::fs::MetadataExt;
+    let metadata = t!(fs::metadata(&build.src));
+    Some(format!("{}:{}", metadata.uid(), metadata.gid()))
+}
+
+#[cfg(not(unix))]
+fn owner(_build: &Build) -> Option<String> {
+    None
+}
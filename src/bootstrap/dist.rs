@@ -10,19 +10,22 @@
 
 use std::env;
 use std::fs;
+use std::io::Write;
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::{self, Command, Stdio};
 
-use build_helper::{output, t};
+use build_helper::{output, t, up_to_date};
 
 use crate::builder::{Builder, RunConfig, ShouldRun, Step};
 use crate::cache::{Interned, INTERNER};
 use crate::compile;
 use crate::config::TargetSelection;
+use crate::doc;
+use crate::native;
 use crate::tarball::{GeneratedTarball, OverlayKind, Tarball};
 use crate::tool::{self, Tool};
 use crate::util::{exe, is_dylib, timeit};
-use crate::{Compiler, DependencyType, Mode, LLVM_TOOLS};
+use crate::{Compiler, DependencyType, Mode, LLD_SELF_CONTAINED_NAMES, LLVM_TOOLS};
 use time::{self, Timespec};
 
 pub fn pkgname(builder: &Builder<'_>, component: &str) -> String {
@@ -55,7 +58,7 @@ impl Step for Docs {
     const DEFAULT: bool = true;
 
     fn should_run(run: ShouldRun<'_>) -> ShouldRun<'_> {
-        run.path("src/doc")
+        run.paths(&["src/doc", "rust-docs"])
     }
 
     fn make_run(run: RunConfig<'_>) {
@@ -69,6 +72,14 @@ fn run(self, builder: &Builder<'_>) -> Option<GeneratedTarball> {
             return None;
         }
         builder.default_doc(None);
+        // The error index is only documented for hosts by default, but it
+        // doesn't depend on the target, so ship it with every target's docs,
+        // generated by the same compiler as the build triple's.
+        if builder.config.doc_error_index {
+            let build = builder.config.build;
+            let compiler = builder.compiler_for(builder.top_stage, build, build);
+            builder.ensure(doc::ErrorIndex { compiler, target: host });
+        }
 
         let dest = "share/doc/rust/html";
 
@@ -78,6 +89,10 @@ fn run(self, builder: &Builder<'_>) -> Option<GeneratedTarball> {
         tarball.add_file(&builder.src.join("src/doc/robots.txt"), dest, 0o644);
         Some(tarball.generate())
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.host)
+    }
 }
 
 #[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
@@ -90,7 +105,7 @@ impl Step for RustcDocs {
     const DEFAULT: bool = true;
 
     fn should_run(run: ShouldRun<'_>) -> ShouldRun<'_> {
-        run.path("src/librustc")
+        run.paths(&["src/librustc", "rustc-docs"])
     }
 
     fn make_run(run: RunConfig<'_>) {
@@ -110,6 +125,10 @@ fn run(self, builder: &Builder<'_>) -> Option<GeneratedTarball> {
         tarball.add_dir(&builder.compiler_doc_out(host), "share/doc/rust/html/rustc");
         Some(tarball.generate())
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.host)
+    }
 }
 
 fn find_files(files: &[&str], path: &[PathBuf]) -> Vec<PathBuf> {
@@ -271,7 +290,9 @@ impl Step for Mingw {
     const DEFAULT: bool = true;
 
     fn should_run(run: ShouldRun<'_>) -> ShouldRun<'_> {
-        run.never()
+        // Not selected by any path of the source tree, but by default as
+        // before, and by the name of its component.
+        run.never().path("rust-mingw")
     }
 
     fn make_run(run: RunConfig<'_>) {
@@ -299,6 +320,10 @@ fn run(self, builder: &Builder<'_>) -> Option<GeneratedTarball> {
 
         Some(tarball.generate())
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.host)
+    }
 }
 
 #[derive(Debug, PartialOrd, Ord, Copy, Clone, Hash, PartialEq, Eq)]
@@ -312,7 +337,7 @@ impl Step for Rustc {
     const ONLY_HOSTS: bool = true;
 
     fn should_run(run: ShouldRun<'_>) -> ShouldRun<'_> {
-        run.path("src/librustc")
+        run.paths(&["src/librustc", "rustc"])
     }
 
     fn make_run(run: RunConfig<'_>) {
@@ -403,6 +428,15 @@ fn prepare_image(builder: &Builder<'_>, compiler: Compiler, image: &Path) {
                 let exe = exe("rust-lld", compiler.host);
                 builder.copy(&src_dir.join(&exe), &dst_dir.join(&exe));
             }
+            if builder.config.lld_self_contained {
+                let src_dir = src_dir.join("self-contained");
+                let dst_dir = dst_dir.join("self-contained");
+                t!(fs::create_dir_all(&dst_dir));
+                for name in LLD_SELF_CONTAINED_NAMES {
+                    let exe = exe(name, compiler.host);
+                    builder.copy(&src_dir.join(&exe), &dst_dir.join(&exe));
+                }
+            }
 
             // Copy over llvm-dwp if it's there
             let exe = exe("rust-llvm-dwp", compiler.host);
@@ -456,6 +490,14 @@ fn prepare_image(builder: &Builder<'_>, compiler: Compiler, image: &Path) {
             cp("README.md");
         }
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.compiler.host)
+    }
+
+    fn stage(&self) -> Option<u32> {
+        Some(self.compiler.stage)
+    }
 }
 
 #[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
@@ -520,6 +562,10 @@ fn run(self, builder: &Builder<'_>) {
             cp_debugger_script("lldb_commands")
         }
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.host)
+    }
 }
 
 fn skip_host_target_lib(builder: &Builder<'_>, compiler: Compiler) -> bool {
@@ -559,7 +605,7 @@ impl Step for Std {
     const DEFAULT: bool = true;
 
     fn should_run(run: ShouldRun<'_>) -> ShouldRun<'_> {
-        run.path("library/std")
+        run.paths(&["library/std", "rust-std"])
     }
 
     fn make_run(run: RunConfig<'_>) {
@@ -592,6 +638,14 @@ fn run(self, builder: &Builder<'_>) -> Option<GeneratedTarball> {
 
         Some(tarball.generate())
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
+
+    fn stage(&self) -> Option<u32> {
+        Some(self.compiler.stage)
+    }
 }
 
 #[derive(Debug, PartialOrd, Ord, Copy, Clone, Hash, PartialEq, Eq)]
@@ -651,6 +705,14 @@ fn run(self, builder: &Builder<'_>) -> Option<GeneratedTarball> {
 
         Some(tarball.generate())
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
+
+    fn stage(&self) -> Option<u32> {
+        Some(self.compiler.stage)
+    }
 }
 
 #[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
@@ -665,7 +727,7 @@ impl Step for Analysis {
 
     fn should_run(run: ShouldRun<'_>) -> ShouldRun<'_> {
         let builder = run.builder;
-        run.path("analysis").default_condition(builder.config.extended)
+        run.paths(&["analysis", "rust-analysis"]).default_condition(builder.config.extended)
     }
 
     fn make_run(run: RunConfig<'_>) {
@@ -704,6 +766,14 @@ fn run(self, builder: &Builder<'_>) -> Option<GeneratedTarball> {
         tarball.add_dir(src, format!("lib/rustlib/{}/analysis", target.triple));
         Some(tarball.generate())
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
+
+    fn stage(&self) -> Option<u32> {
+        Some(self.compiler.stage)
+    }
 }
 
 /// Use the `builder` to make a filtered copy of `base`/X for X in (`src_dirs` - `exclude_dirs`) to
@@ -801,7 +871,7 @@ impl Step for Src {
     const ONLY_HOSTS: bool = true;
 
     fn should_run(run: ShouldRun<'_>) -> ShouldRun<'_> {
-        run.path("src")
+        run.paths(&["src", "rust-src"])
     }
 
     fn make_run(run: RunConfig<'_>) {
@@ -854,7 +924,7 @@ impl Step for PlainSourceTarball {
 
     fn should_run(run: ShouldRun<'_>) -> ShouldRun<'_> {
         let builder = run.builder;
-        run.path("src").default_condition(builder.config.rust_dist_src)
+        run.paths(&["src", "rustc-src"]).default_condition(builder.config.rust_dist_src)
     }
 
     fn make_run(run: RunConfig<'_>) {
@@ -986,6 +1056,14 @@ fn run(self, builder: &Builder<'_>) -> GeneratedTarball {
 
         tarball.generate()
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
+
+    fn stage(&self) -> Option<u32> {
+        Some(self.compiler.stage)
+    }
 }
 
 #[derive(Debug, PartialOrd, Ord, Copy, Clone, Hash, PartialEq, Eq)]
@@ -999,7 +1077,7 @@ impl Step for Rls {
     const ONLY_HOSTS: bool = true;
 
     fn should_run(run: ShouldRun<'_>) -> ShouldRun<'_> {
-        run.path("rls")
+        run.paths(&["rls", "rls-preview"])
     }
 
     fn make_run(run: RunConfig<'_>) {
@@ -1032,6 +1110,14 @@ fn run(self, builder: &Builder<'_>) -> Option<GeneratedTarball> {
         tarball.add_legal_and_readme_to("share/doc/rls");
         Some(tarball.generate())
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
+
+    fn stage(&self) -> Option<u32> {
+        Some(self.compiler.stage)
+    }
 }
 
 #[derive(Debug, PartialOrd, Ord, Copy, Clone, Hash, PartialEq, Eq)]
@@ -1045,7 +1131,7 @@ impl Step for RustAnalyzer {
     const ONLY_HOSTS: bool = true;
 
     fn should_run(run: ShouldRun<'_>) -> ShouldRun<'_> {
-        run.path("rust-analyzer")
+        run.paths(&["rust-analyzer", "rust-analyzer-preview"])
     }
 
     fn make_run(run: RunConfig<'_>) {
@@ -1081,6 +1167,14 @@ fn run(self, builder: &Builder<'_>) -> Option<GeneratedTarball> {
         tarball.add_legal_and_readme_to("share/doc/rust-analyzer");
         Some(tarball.generate())
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
+
+    fn stage(&self) -> Option<u32> {
+        Some(self.compiler.stage)
+    }
 }
 
 #[derive(Debug, PartialOrd, Ord, Copy, Clone, Hash, PartialEq, Eq)]
@@ -1094,7 +1188,7 @@ impl Step for Clippy {
     const ONLY_HOSTS: bool = true;
 
     fn should_run(run: ShouldRun<'_>) -> ShouldRun<'_> {
-        run.path("clippy")
+        run.paths(&["clippy", "clippy-preview"])
     }
 
     fn make_run(run: RunConfig<'_>) {
@@ -1131,6 +1225,14 @@ fn run(self, builder: &Builder<'_>) -> GeneratedTarball {
         tarball.add_legal_and_readme_to("share/doc/clippy");
         tarball.generate()
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
+
+    fn stage(&self) -> Option<u32> {
+        Some(self.compiler.stage)
+    }
 }
 
 #[derive(Debug, PartialOrd, Ord, Copy, Clone, Hash, PartialEq, Eq)]
@@ -1144,7 +1246,7 @@ impl Step for Miri {
     const ONLY_HOSTS: bool = true;
 
     fn should_run(run: ShouldRun<'_>) -> ShouldRun<'_> {
-        run.path("miri")
+        run.paths(&["miri", "miri-preview"])
     }
 
     fn make_run(run: RunConfig<'_>) {
@@ -1184,6 +1286,14 @@ fn run(self, builder: &Builder<'_>) -> Option<GeneratedTarball> {
         tarball.add_legal_and_readme_to("share/doc/miri");
         Some(tarball.generate())
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
+
+    fn stage(&self) -> Option<u32> {
+        Some(self.compiler.stage)
+    }
 }
 
 #[derive(Debug, PartialOrd, Ord, Copy, Clone, Hash, PartialEq, Eq)]
@@ -1197,7 +1307,7 @@ impl Step for Rustfmt {
     const ONLY_HOSTS: bool = true;
 
     fn should_run(run: ShouldRun<'_>) -> ShouldRun<'_> {
-        run.path("rustfmt")
+        run.paths(&["rustfmt", "rustfmt-preview"])
     }
 
     fn make_run(run: RunConfig<'_>) {
@@ -1236,6 +1346,14 @@ fn run(self, builder: &Builder<'_>) -> Option<GeneratedTarball> {
         tarball.add_legal_and_readme_to("share/doc/rustfmt");
         Some(tarball.generate())
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
+
+    fn stage(&self) -> Option<u32> {
+        Some(self.compiler.stage)
+    }
 }
 
 #[derive(Debug, PartialOrd, Ord, Copy, Clone, Hash, PartialEq, Eq)]
@@ -1252,7 +1370,7 @@ impl Step for Extended {
 
     fn should_run(run: ShouldRun<'_>) -> ShouldRun<'_> {
         let builder = run.builder;
-        run.path("extended").default_condition(builder.config.extended)
+        run.paths(&["extended", "rust"]).default_condition(builder.config.extended)
     }
 
     fn make_run(run: RunConfig<'_>) {
@@ -1771,6 +1889,14 @@ fn filter(contents: &str, marker: &str) -> String {
             }
         }
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
+
+    fn stage(&self) -> Option<u32> {
+        Some(self.stage)
+    }
 }
 
 fn add_env(builder: &Builder<'_>, cmd: &mut Command, target: TargetSelection) {
@@ -1874,7 +2000,7 @@ impl Step for LlvmTools {
     const ONLY_HOSTS: bool = true;
 
     fn should_run(run: ShouldRun<'_>) -> ShouldRun<'_> {
-        run.path("llvm-tools")
+        run.paths(&["llvm-tools", "llvm-tools-preview"])
     }
 
     fn make_run(run: RunConfig<'_>) {
@@ -1893,6 +2019,9 @@ fn run(self, builder: &Builder<'_>) -> Option<GeneratedTarball> {
             }
         }
 
+        // This may be a cross target that nothing else needed LLVM for yet.
+        builder.ensure(native::Llvm { target });
+
         let mut tarball = Tarball::new(builder, "llvm-tools", &target.triple);
         tarball.set_overlay(OverlayKind::LLVM);
         tarball.is_preview(true);
@@ -1913,6 +2042,10 @@ fn run(self, builder: &Builder<'_>) -> Option<GeneratedTarball> {
 
         Some(tarball.generate())
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
 }
 
 // Tarball intended for internal consumption to ease rustc/std development.
@@ -1947,6 +2080,8 @@ fn run(self, builder: &Builder<'_>) -> Option<GeneratedTarball> {
             }
         }
 
+        builder.ensure(native::Llvm { target });
+
         let mut tarball = Tarball::new(builder, "rust-dev", &target.triple);
         tarball.set_overlay(OverlayKind::LLVM);
 
@@ -1975,8 +2110,27 @@ fn run(self, builder: &Builder<'_>) -> Option<GeneratedTarball> {
         // compiler libraries.
         maybe_install_llvm(builder, target, &tarball.image_dir().join("lib"));
 
+        // Polly is not an LLVM component, so neither of the above picks up its
+        // archives. Ship them alongside so that rustc_llvm can link against
+        // them when Polly was enabled for this LLVM.
+        if let Some(true) = builder.config.llvm_polly {
+            let src_libdir = builder.llvm_out(target).join("lib");
+            for lib in &["Polly", "PollyISL"] {
+                let name = if target.contains("msvc") {
+                    format!("{}.lib", lib)
+                } else {
+                    format!("lib{}.a", lib)
+                };
+                tarball.add_file(src_libdir.join(name), "lib", 0o644);
+            }
+        }
+
         Some(tarball.generate())
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
 }
 
 /// Tarball containing a prebuilt version of the build-manifest tool, intented to be used by the
@@ -2008,6 +2162,10 @@ fn run(self, builder: &Builder<'_>) -> GeneratedTarball {
         tarball.add_file(&build_manifest, "bin", 0o755);
         tarball.generate()
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
 }
 
 /// Tarball containing artifacts necessary to reproduce the build of rustc.
@@ -2040,4 +2198,97 @@ fn run(self, builder: &Builder<'_>) -> Self::Output {
         tarball.add_file(path, ".", 0o644);
         Some(tarball.generate())
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
+}
+
+/// Signatures and checksums of the artifacts of `x.py dist --sign`.
+///
+/// Every file in `dist.sign-folder`, or in the dist directory if that isn't
+/// set, gets a detached gpg signature and a SHA-256 checksum in the dist
+/// directory, named after it with `.asc` and `.sha256` appended. This step is
+/// ensured after all others of the invocation, so their artifacts are covered.
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+pub struct Signatures;
+
+impl Step for Signatures {
+    type Output = ();
+    const ONLY_HOSTS: bool = true;
+
+    fn should_run(run: ShouldRun<'_>) -> ShouldRun<'_> {
+        run.never()
+    }
+
+    fn run(self, builder: &Builder<'_>) {
+        let dist = distdir(builder);
+        let folder = builder.config.dist_sign_folder.clone().unwrap_or_else(|| dist.clone());
+        builder.info(&format!("Signing the artifacts in {}", folder.display()));
+        if builder.config.dry_run {
+            return;
+        }
+
+        // gpg reads the password up to the first newline.
+        let password = builder.config.dist_gpg_password_file.as_ref().map(|path| {
+            let password = t!(fs::read_to_string(path), path);
+            format!("{}\n", password.trim_end())
+        });
+        t!(fs::create_dir_all(&dist));
+        let mut artifacts = t!(fs::read_dir(&folder))
+            .map(|entry| t!(entry).path())
+            .filter(|path| path.is_file())
+            .filter(|path| {
+                let extension = path.extension().and_then(|e| e.to_str());
+                extension != Some("asc") && extension != Some("sha256")
+            })
+            .collect::<Vec<_>>();
+        artifacts.sort();
+
+        for artifact in &artifacts {
+            let name = artifact.file_name().unwrap().to_string_lossy();
+            let signature = dist.join(format!("{}.asc", name));
+            let checksum = dist.join(format!("{}.sha256", name));
+            if up_to_date(artifact, &signature) && up_to_date(artifact, &checksum) {
+                continue;
+            }
+            builder.verbose(&format!("signing {}", artifact.display()));
+            sign(builder, artifact, &signature, password.as_deref());
+
+            // Written like `sha256sum` does, so that it can check the file.
+            let mut cmd = if cfg!(target_os = "macos") {
+                let mut cmd = Command::new("shasum");
+                cmd.arg("-a").arg("256");
+                cmd
+            } else {
+                Command::new("sha256sum")
+            };
+            builder.create(&checksum, &output(cmd.arg(&*name).current_dir(&folder)));
+        }
+        builder.info(&format!("Signed {} artifacts into {}", artifacts.len(), dist.display()));
+    }
+}
+
+/// Writes an ASCII-armored detached signature of `artifact` to `signature`
+/// with the default gpg key, unlocking it with `password` if there is one.
+fn sign(builder: &Builder<'_>, artifact: &Path, signature: &Path, password: Option<&str>) {
+    let mut cmd = Command::new("gpg");
+    cmd.arg("--batch").arg("--yes").arg("--armor").arg("--detach-sign");
+    cmd.arg("--output").arg(signature);
+    if password.is_some() {
+        cmd.arg("--pinentry-mode").arg("loopback").arg("--passphrase-fd").arg("0");
+        cmd.stdin(Stdio::piped());
+    }
+    cmd.arg(artifact);
+    builder.verbose(&format!("running: {:?}", cmd));
+
+    let mut child = t!(cmd.spawn());
+    if let Some(password) = password {
+        t!(child.stdin.take().unwrap().write_all(password.as_bytes()));
+    }
+    let status = t!(child.wait());
+    if !status.success() {
+        eprintln!("error: failed to sign {}: gpg exited with {}", artifact.display(), status);
+        process::exit(1);
+    }
 }
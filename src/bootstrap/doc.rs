@@ -11,16 +11,18 @@
 use std::fs;
 use std::io;
 use std::path::{Path, PathBuf};
+use std::process::Command;
 
 use crate::Mode;
 use build_helper::{t, up_to_date};
 
-use crate::builder::{Builder, Compiler, RunConfig, ShouldRun, Step};
+use crate::builder::{Builder, Compiler, Kind, RunConfig, ShouldRun, Step};
 use crate::cache::{Interned, INTERNER};
 use crate::compile;
 use crate::config::{Config, TargetSelection};
+use crate::sanity::Finder;
 use crate::tool::{self, prepare_tool_cargo, SourceType, Tool};
-use crate::util::symlink_dir;
+use crate::util::{exe, symlink_dir};
 
 macro_rules! book {
     ($($name:ident, $path:expr, $book_name:expr;)+) => {
@@ -50,7 +52,13 @@ fn run(self, builder: &Builder<'_>) {
                     target: self.target,
                     name: INTERNER.intern_str($book_name),
                     src: INTERNER.intern_path(builder.src.join($path)),
-                })
+                });
+                let index = builder.doc_out(self.target).join($book_name).join("index.html");
+                report(builder, &index, is_explicit_request(builder, $path));
+            }
+
+            fn target(&self) -> Option<TargetSelection> {
+                Some(self.target)
             }
         }
         )+
@@ -69,12 +77,18 @@ fn run(self, builder: &Builder<'_>) {
     RustdocBook, "src/doc/rustdoc", "rustdoc";
 );
 
-fn open(builder: &Builder<'_>, path: impl AsRef<Path>) {
-    if builder.config.dry_run || !builder.config.cmd.open() {
+/// Prints where the docs built by a step start for `x.py doc`, or opens them
+/// instead if they were `requested` on the command line along with `--open`.
+fn report(builder: &Builder<'_>, path: impl AsRef<Path>, requested: bool) {
+    if builder.config.dry_run || builder.kind != Kind::Doc {
         return;
     }
 
     let path = path.as_ref();
+    if !requested || !builder.config.cmd.open() {
+        builder.info(&format!("Docs are at {}", path.display()));
+        return;
+    }
     builder.info(&format!("Opening doc {}", path.display()));
     if let Err(err) = opener::open(path) {
         builder.info(&format!("{}\n", err));
@@ -122,7 +136,13 @@ fn run(self, builder: &Builder<'_>) {
             target: self.target,
             name: INTERNER.intern_str("unstable-book"),
             src: INTERNER.intern_path(builder.md_doc_out(self.target).join("unstable-book")),
-        })
+        });
+        let index = builder.doc_out(self.target).join("unstable-book").join("index.html");
+        report(builder, &index, is_explicit_request(builder, "src/doc/unstable-book"));
+    }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
     }
 }
 
@@ -154,15 +174,131 @@ fn run(self, builder: &Builder<'_>) {
         let out = out.join(name);
         let index = out.join("index.html");
         let rustbook = builder.tool_exe(Tool::Rustbook);
-        let mut rustbook_cmd = builder.tool_cmd(Tool::Rustbook);
+        let rustbook_cmd = builder.tool_cmd(Tool::Rustbook);
         if builder.config.dry_run || up_to_date(&src, &index) && up_to_date(&rustbook, &index) {
             return;
         }
         builder.info(&format!("Rustbook ({}) - {}", target, name));
+        let mut cmd = book_command(builder, &src, rustbook_cmd);
         let _ = fs::remove_dir_all(&out);
 
-        builder.run(rustbook_cmd.arg("build").arg(&src).arg("-d").arg(out));
+        builder.run(cmd.arg("build").arg(&src).arg("-d").arg(out));
+    }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
+}
+
+/// Preprocessors that are part of mdBook itself.
+const BUILTIN_PREPROCESSORS: &[&str] = &["links", "index"];
+
+/// Returns the command building the book in `src` the way its `book.toml`
+/// expects, which is `rustbook` unless the book requires another version of
+/// mdBook. mdBook itself only warns about a missing preprocessor and renders
+/// the book without it, so every preprocessor has to be found.
+///
+/// A book can require a version of mdBook with `rust.mdbook-version`, which
+/// the one `rustbook` is built with has to start with, or else it's installed
+/// into the build directory and used instead. Each preprocessor the book
+/// declares is run from the path given in `doc.mdbook-preprocessors`, which
+/// is passed on to mdBook in the command's environment, or from its
+/// `command`, or as `mdbook-<name>`. The latter is installed like mdBook if
+/// it isn't in `PATH`, at the version given in `rust.preprocessor-versions`.
+fn book_command(builder: &Builder<'_>, src: &Path, rustbook: Command) -> Command {
+    let book_toml = src.join("book.toml");
+    let book: toml::Value = match fs::read_to_string(&book_toml) {
+        Ok(contents) => t!(toml::from_str(&contents), &book_toml),
+        Err(_) => return rustbook,
+    };
+    let rust = book.get("rust");
+
+    let mut cmd = rustbook;
+    let required = rust.and_then(|rust| rust.get("mdbook-version"));
+    if let Some(required) = required.and_then(|version| version.as_str()) {
+        let linked = mdbook_version(builder);
+        let matches = required.split('.').zip(linked.split('.')).all(|(r, l)| r == l)
+            && required.split('.').count() <= linked.split('.').count();
+        if !matches {
+            cmd = Command::new(install_book_tool(builder, "mdbook", Some(required)));
+        }
+    }
+
+    let preprocessors = book.get("preprocessor").and_then(|p| p.as_table());
+    for (name, preprocessor) in preprocessors.into_iter().flatten() {
+        if BUILTIN_PREPROCESSORS.contains(&&name[..]) {
+            continue;
+        }
+        // mdBook reads overrides of `book.toml` from `MDBOOK_*` variables.
+        let var = format!("MDBOOK_PREPROCESSOR__{}__COMMAND", name.to_uppercase());
+        let var = var.replace('-', "_");
+        let command = if let Some(path) = builder.config.mdbook_preprocessors.get(name) {
+            cmd.env(&var, path);
+            path.to_string_lossy().into_owned()
+        } else if let Some(command) = preprocessor.get("command").and_then(|c| c.as_str()) {
+            command.to_owned()
+        } else {
+            let program = format!("mdbook-{}", name);
+            if Finder::new().maybe_have(&program).is_some() {
+                program
+            } else {
+                let versions = rust.and_then(|rust| rust.get("preprocessor-versions"));
+                let version = versions.and_then(|v| v.get(name)).and_then(|v| v.as_str());
+                let path = install_book_tool(builder, &program, version);
+                cmd.env(&var, &path);
+                path.to_string_lossy().into_owned()
+            }
+        };
+        // The command may include arguments.
+        let program = command.split_whitespace().next().unwrap_or_default();
+        if !Path::new(program).is_file() && Finder::new().maybe_have(program).is_none() {
+            panic!(
+                "\n\ncouldn't find `{}`, the `{}` mdBook preprocessor of {}\n\
+                 help: install it, or set its path in `doc.mdbook-preprocessors`\n\n",
+                program,
+                name,
+                book_toml.display()
+            );
+        }
+    }
+    cmd
+}
+
+/// Installs the binary of the crates.io package `krate` into the build
+/// directory with `cargo install`, unless it's already there, and returns its
+/// path. `version` is a prefix of the version to install, like `0.4`, and the
+/// latest version is installed without it.
+fn install_book_tool(builder: &Builder<'_>, krate: &str, version: Option<&str>) -> PathBuf {
+    let build = builder.config.build;
+    let root = builder.out.join(&*build.triple).join("book-tools");
+    let root = root.join(format!("{}-{}", krate, version.unwrap_or("latest")));
+    let exe = root.join("bin").join(exe(krate, build));
+    if exe.exists() {
+        return exe;
+    }
+    builder.info(&format!("Installing {} {}", krate, version.unwrap_or("")));
+    let mut cargo = Command::new(&builder.initial_cargo);
+    cargo.env("RUSTC", &builder.initial_rustc);
+    cargo.arg("install").arg(krate).arg("--locked").arg("--root").arg(&root);
+    if let Some(version) = version {
+        // `=0.4` matches any 0.4.x, like the prefix does.
+        cargo.arg("--version").arg(format!("={}", version));
     }
+    builder.run(&mut cargo);
+    exe
+}
+
+/// Returns the version of mdBook that `rustbook` is built with, according to
+/// `Cargo.lock`.
+fn mdbook_version(builder: &Builder<'_>) -> String {
+    let lockfile = builder.src.join("Cargo.lock");
+    let lock: toml::Value = t!(toml::from_str(&t!(fs::read_to_string(&lockfile))), &lockfile);
+    let packages = lock.get("package").and_then(|p| p.as_array()).into_iter().flatten();
+    packages
+        .filter(|package| package.get("name").and_then(|n| n.as_str()) == Some("mdbook"))
+        .find_map(|package| package.get("version").and_then(|v| v.as_str()))
+        .unwrap_or_else(|| panic!("no mdbook package in {}", lockfile.display()))
+        .to_owned()
 }
 
 #[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
@@ -229,11 +365,16 @@ fn run(self, builder: &Builder<'_>) {
             invoke_rustdoc(builder, compiler, target, path);
         }
 
-        if is_explicit_request(builder, "src/doc/book") {
-            let out = builder.doc_out(target);
-            let index = out.join("book").join("index.html");
-            open(builder, &index);
-        }
+        let index = builder.doc_out(target).join("book").join("index.html");
+        report(builder, &index, is_explicit_request(builder, "src/doc/book"));
+    }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
+
+    fn stage(&self) -> Option<u32> {
+        Some(self.compiler.stage)
     }
 }
 
@@ -375,14 +516,20 @@ fn run(self, builder: &Builder<'_>) {
 
         // We open doc/index.html as the default if invoked as `x.py doc --open`
         // with no particular explicit doc requested (e.g. library/core).
-        if builder.paths.is_empty() || is_explicit_request(builder, "src/doc") {
-            let index = out.join("index.html");
-            open(builder, &index);
-        }
+        let requested = builder.paths.is_empty() || is_explicit_request(builder, "src/doc");
+        report(builder, out.join("index.html"), requested);
+    }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
+
+    fn stage(&self) -> Option<u32> {
+        Some(self.compiler.stage)
     }
 }
 
-#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+#[derive(Ord, PartialOrd, Debug, Copy, Clone, Hash, PartialEq, Eq)]
 pub struct Std {
     pub stage: u32,
     pub target: TargetSelection,
@@ -418,55 +565,90 @@ fn run(self, builder: &Builder<'_>) {
 
         t!(fs::copy(builder.src.join("src/doc/rust.css"), out.join("rust.css")));
 
-        let run_cargo_rustdoc_for = |package: &str| {
-            let mut cargo =
-                builder.cargo(compiler, Mode::Std, SourceType::InTree, target, "rustdoc");
-            compile::std_cargo(builder, target, compiler.stage, &mut cargo);
-
-            cargo
-                .arg("-p")
-                .arg(package)
-                .arg("--")
-                .arg("--markdown-css")
-                .arg("rust.css")
-                .arg("--markdown-no-toc")
-                .arg("-Z")
-                .arg("unstable-options")
-                .arg("--resource-suffix")
-                .arg(&builder.version)
-                .arg("--index-page")
-                .arg(&builder.src.join("src/doc/index.md"));
+        let mut cargo = builder.cargo(compiler, Mode::Std, SourceType::InTree, target, "doc");
+        compile::std_cargo(builder, target, compiler.stage, &mut cargo);
+        cargo.rustdocflag("--markdown-css");
+        cargo.rustdocflag("rust.css");
+        cargo.rustdocflag("--markdown-no-toc");
+        cargo.rustdocflag("-Zunstable-options");
+        cargo.rustdocflag("--resource-suffix");
+        cargo.rustdocflag(&builder.version);
+        cargo.rustdocflag("--index-page");
+        cargo.rustdocflag(builder.src.join("src/doc/index.md").to_str().unwrap());
+        cargo.arg("--no-deps");
 
-            builder.run(&mut cargo.into());
-        };
         // Only build the following crates. While we could just iterate over the
         // folder structure, that would also build internal crates that we do
         // not want to show in documentation. These crates will later be visited
         // by the rustc step, so internal documentation will show them.
         //
-        // Note that the order here is important! The crates need to be
-        // processed starting from the leaves, otherwise rustdoc will not
-        // create correct links between crates because rustdoc depends on the
-        // existence of the output directories to know if it should be a local
-        // or remote link.
+        // They are documented by a single cargo invocation, so that cargo can
+        // run rustdoc on them in parallel (see `doc.jobs`). rustdoc decides
+        // whether to link to another crate locally based on the existence of
+        // its output directory, so all of them are created up front.
         let krates = ["core", "alloc", "std", "proc_macro", "test"];
         for krate in &krates {
-            run_cargo_rustdoc_for(krate);
+            t!(fs::create_dir_all(out_dir.join(krate)));
+            cargo.arg("-p").arg(krate);
         }
+        builder.run(&mut cargo.into());
         builder.cp_r(&out_dir, &out);
+        target_index(builder);
 
         // Look for library/std, library/core etc in the `x.py doc` arguments and
         // open the corresponding rendered docs.
+        let mut reported = false;
         for path in builder.paths.iter().map(components_simplified) {
-            if path.get(0) == Some(&"library") {
+            if path.get(0) == Some(&"library") && path.len() > 1 {
                 let requested_crate = &path[1];
                 if krates.contains(&requested_crate) {
                     let index = out.join(requested_crate).join("index.html");
-                    open(builder, &index);
+                    report(builder, &index, true);
+                    reported = true;
                 }
             }
         }
+        if !reported {
+            report(builder, out.join("std").join("index.html"), false);
+        }
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
+
+    fn stage(&self) -> Option<u32> {
+        Some(self.stage)
+    }
+}
+
+/// Writes `doc/index.html` in the build directory, which links to the standard
+/// library docs of every target they have been built for, as the available
+/// APIs differ between targets.
+fn target_index(builder: &Builder<'_>) {
+    if builder.config.dry_run {
+        return;
+    }
+    let mut targets = t!(fs::read_dir(&builder.out))
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().join("doc/std/index.html").is_file())
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+    targets.sort();
+
+    let mut html = String::from(
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n\
+         <title>The Rust Standard Library by target</title>\n</head>\n<body>\n\
+         <h1>The Rust Standard Library by target</h1>\n<ul>\n",
+    );
+    for target in &targets {
+        html.push_str(&format!("<li><a href=\"../{0}/doc/std/index.html\">{0}</a></li>\n", target));
+    }
+    html.push_str("</ul>\n</body>\n</html>\n");
+
+    let dir = builder.out.join("doc");
+    builder.create_dir(&dir);
+    builder.create(&dir.join("index.html"), &html);
 }
 
 #[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
@@ -509,9 +691,12 @@ fn run(self, builder: &Builder<'_>) {
         let out = builder.compiler_doc_out(target);
         t!(fs::create_dir_all(&out));
 
-        // Build rustc.
+        // `cargo doc` only checks the compiler crates, so they don't have to
+        // be built before they are documented. The standard library does, as
+        // proc macros are run while documenting the crates using them.
         let compiler = builder.compiler(stage, builder.config.build);
-        builder.ensure(compile::Rustc { compiler, target });
+        builder.ensure(compile::Std { compiler, target: builder.config.build });
+        builder.ensure(compile::Std { compiler, target });
 
         // This uses a shared directory so that librustdoc documentation gets
         // correctly built and merged with the rustc documentation. This is
@@ -556,6 +741,15 @@ fn run(self, builder: &Builder<'_>) {
         }
 
         builder.run(&mut cargo.into());
+        report(builder, out.join("index.html"), is_explicit_request(builder, "compiler/rustc"));
+    }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
+
+    fn stage(&self) -> Option<u32> {
+        Some(self.stage)
     }
 }
 
@@ -631,6 +825,16 @@ fn run(self, builder: &Builder<'_>) {
         cargo.rustdocflag("--enable-index-page");
         cargo.rustdocflag("-Zunstable-options");
         builder.run(&mut cargo.into());
+        let index = out.join("rustdoc").join("index.html");
+        report(builder, &index, is_explicit_request(builder, "src/tools/rustdoc"));
+    }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
+
+    fn stage(&self) -> Option<u32> {
+        Some(self.stage)
     }
 }
 
@@ -647,7 +851,8 @@ impl Step for ErrorIndex {
 
     fn should_run(run: ShouldRun<'_>) -> ShouldRun<'_> {
         let builder = run.builder;
-        run.path("src/tools/error_index_generator").default_condition(builder.config.docs)
+        run.path("src/tools/error_index_generator")
+            .default_condition(builder.config.docs && builder.config.doc_error_index)
     }
 
     fn make_run(run: RunConfig<'_>) {
@@ -660,7 +865,7 @@ fn make_run(run: RunConfig<'_>) {
         run.builder.ensure(ErrorIndex { compiler, target });
     }
 
-    /// Generates the HTML rendered error-index by running the
+    /// Generates the HTML and markdown rendered error-index by running the
     /// `error_index_generator` tool.
     fn run(self, builder: &Builder<'_>) {
         builder.info(&format!("Documenting error index ({})", self.target));
@@ -670,8 +875,23 @@ fn run(self, builder: &Builder<'_>) {
         index.arg("html");
         index.arg(out.join("error-index.html"));
         index.arg(&builder.version);
+        builder.run(&mut index);
 
+        let mut index = tool::ErrorIndex::command(builder, self.compiler);
+        index.arg("markdown");
+        index.arg(out.join("error-index.md"));
         builder.run(&mut index);
+
+        let requested = is_explicit_request(builder, "src/tools/error_index_generator");
+        report(builder, out.join("error-index.html"), requested);
+    }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
+
+    fn stage(&self) -> Option<u32> {
+        Some(self.compiler.stage)
     }
 }
 
@@ -709,6 +929,10 @@ fn run(self, builder: &Builder<'_>) {
 
         builder.run(&mut cmd);
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
 }
 
 fn symlink_dir_force(config: &Config, src: &Path, dst: &Path) -> io::Result<()> {
@@ -794,10 +1018,15 @@ fn run(self, builder: &Builder<'_>) {
             name: INTERNER.intern_str("rustc"),
             src: INTERNER.intern_path(out_base),
         });
-        if is_explicit_request(builder, "src/doc/rustc") {
-            let out = builder.doc_out(self.target);
-            let index = out.join("rustc").join("index.html");
-            open(builder, &index);
-        }
+        let index = builder.doc_out(self.target).join("rustc").join("index.html");
+        report(builder, &index, is_explicit_request(builder, "src/doc/rustc"));
+    }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
+
+    fn stage(&self) -> Option<u32> {
+        Some(self.compiler.stage)
     }
 }
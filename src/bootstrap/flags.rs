@@ -3,18 +3,26 @@
 //! This module implements the command-line parsing of the build system which
 //! has various flags to configure how it's run.
 
+use std::collections::BTreeMap;
 use std::env;
-use std::path::PathBuf;
+use std::fs;
+use std::iter;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
 use std::process;
 
 use build_helper::t;
 use getopts::Options;
+use serde::{Deserialize, Serialize};
 
 use crate::builder::Builder;
+use crate::change_detection;
+use crate::completions::{self, Completion, Shell};
 use crate::config::{Config, TargetSelection};
-use crate::setup::Profile;
+use crate::setup::{GitHook, Profile};
 use crate::{Build, DocTests};
 
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Color {
     Always,
     Never,
@@ -40,6 +48,46 @@ fn from_str(s: &str) -> Result<Self, Self::Err> {
     }
 }
 
+/// Whether to deny warnings, from `--warnings` or `rust.warnings`.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Warnings {
+    Deny,
+    Warn,
+    /// Deny them unless config.toml says otherwise.
+    Default,
+}
+
+impl Default for Warnings {
+    fn default() -> Self {
+        Self::Default
+    }
+}
+
+impl std::str::FromStr for Warnings {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "deny" => Ok(Self::Deny),
+            "warn" => Ok(Self::Warn),
+            "default" => Ok(Self::Default),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Warnings {
+    /// Whether to pass `-Dwarnings`, or `None` for `Default`.
+    pub fn deny(self) -> Option<bool> {
+        match self {
+            Warnings::Deny => Some(true),
+            Warnings::Warn => Some(false),
+            Warnings::Default => None,
+        }
+    }
+}
+
 /// Deserialized version of all flags for this compile.
 pub struct Flags {
     pub verbose: usize, // number of -v args; each extra -v after the first is passed to Cargo
@@ -56,21 +104,34 @@ pub struct Flags {
     pub incremental: bool,
     pub exclude: Vec<PathBuf>,
     pub include_default_paths: bool,
+    pub ci_change_detection: Option<String>,
+    pub ci_keep: Vec<PathBuf>,
     pub rustc_error_format: Option<String>,
     pub json_output: bool,
     pub dry_run: bool,
+    /// Whether to print the steps of a dry run as JSON
+    pub json_plan: bool,
+    /// Whether to print why each step runs
+    pub explain: bool,
+    /// Whether to write a Chrome trace of the build to `build/trace.json`
+    pub timings: bool,
     pub color: Color,
+    /// Container image to run the command in instead of on this machine
+    pub in_container: Option<String>,
+    /// Whether to build without taking the lock on the build directory
+    pub bypass_bootstrap_lock: bool,
 
-    // This overrides the deny-warnings configuration option,
-    // which passes -Dwarnings to the compiler invocations.
-    //
-    // true => deny, false => warn
-    pub deny_warnings: Option<bool>,
+    // This overrides the warnings and deny-warnings configuration options,
+    // which pass -Dwarnings to the compiler invocations.
+    pub warnings: Warnings,
 
     pub llvm_skip_rebuild: Option<bool>,
 
     pub rust_profile_use: Option<String>,
     pub rust_profile_generate: Option<String>,
+
+    pub llvm_profile_use: Option<String>,
+    pub llvm_profile_generate: Option<String>,
 }
 
 pub enum Subcommand {
@@ -86,12 +147,17 @@ pub enum Subcommand {
     Clippy {
         fix: bool,
         paths: Vec<PathBuf>,
+        /// Lint level flags such as `-Dclippy::all`, in the order they were
+        /// given, as later ones override earlier ones.
+        lint_levels: Vec<String>,
     },
     Fix {
         paths: Vec<PathBuf>,
     },
     Format {
         check: bool,
+        /// Whether to format every file rather than only the modified ones.
+        all: bool,
     },
     Doc {
         paths: Vec<PathBuf>,
@@ -106,27 +172,71 @@ pub enum Subcommand {
         test_args: Vec<String>,
         rustc_args: Vec<String>,
         fail_fast: bool,
+        /// Whether to also run the tests that failed in the previous run
+        rerun_failed: bool,
         doc_tests: DocTests,
         rustfix_coverage: bool,
+        /// Whether to remove stale compiletest output before running tests
+        gc_sessions: bool,
+        /// Whether to run tests even if they passed and are up to date
+        force_rerun: bool,
     },
     Bench {
         paths: Vec<PathBuf>,
         test_args: Vec<String>,
     },
+    Miri {
+        paths: Vec<PathBuf>,
+        test_args: Vec<String>,
+    },
     Clean {
         all: bool,
+        /// Only remove the output of this stage
+        stage: Option<u32>,
+        /// Only remove the output for these targets
+        targets: Vec<TargetSelection>,
+        /// Only remove the LLVM build
+        llvm: bool,
+        /// Keep the downloaded stage0 toolchain, CI LLVM and Cargo registry
+        keep_downloads: bool,
+        /// Keep the LLVM build, even with `--all`
+        keep_llvm: bool,
+    },
+    Gc {
+        /// Number of days after which unused parts of the build directory
+        /// are removed
+        max_age: u64,
     },
     Dist {
         paths: Vec<PathBuf>,
+        /// Whether to sign the artifacts with gpg
+        sign: bool,
     },
     Install {
         paths: Vec<PathBuf>,
     },
+    Uninstall,
+    Suggest {
+        /// Whether to run the suggested commands rather than only print them
+        run: bool,
+    },
+    CiSmoke {
+        /// Number of minutes after which the run is stopped and fails
+        budget: u64,
+    },
     Run {
         paths: Vec<PathBuf>,
+        /// Arguments to pass to the tool being run
+        args: Vec<String>,
+    },
+    ExportLlvm {
+        paths: Vec<PathBuf>,
     },
     Setup {
-        profile: Profile,
+        /// The profile to create a `config.toml` for, or `None` for `x.py
+        /// setup hooks`, which only installs `hook`
+        profile: Option<Profile>,
+        hook: GitHook,
     },
 }
 
@@ -136,119 +246,84 @@ fn default() -> Subcommand {
     }
 }
 
+/// All subcommands, with their abbreviations and a short description.
+const SUBCOMMANDS: &[(&str, &[&str], &str)] = &[
+    ("build", &["b"], "Compile either the compiler or libraries"),
+    ("check", &["c"], "Compile either the compiler or libraries, using cargo check"),
+    ("clippy", &[], "Run clippy (uses rustup/cargo-installed clippy binary)"),
+    ("fix", &[], "Run cargo fix"),
+    ("fmt", &[], "Run rustfmt"),
+    ("test", &["t"], "Build and run some test suites"),
+    ("bench", &[], "Build and run some benchmarks"),
+    ("miri", &[], "Run library tests under Miri to check for undefined behavior"),
+    ("doc", &[], "Build documentation"),
+    ("clean", &[], "Clean out build directories"),
+    ("gc", &[], "Show the disk usage of the build directory and remove unused parts"),
+    ("dist", &[], "Build distribution artifacts"),
+    ("install", &[], "Install distribution artifacts"),
+    ("uninstall", &[], "Remove the files installed by `x.py install`"),
+    ("run", &["r"], "Run tools contained in this repository"),
+    ("suggest", &[], "Suggest what to check, test and document for the modified files"),
+    ("ci-smoke", &[], "Run a quick, time-limited selection of builds and tests"),
+    ("export-llvm", &[], "Package the in-tree LLVM build for `llvm.prebuilt-archive`"),
+    ("setup", &[], "Create a config.toml (making it easier to use `x.py` itself), or git hooks"),
+    ("cli-metadata", &[], "Describe the subcommands and options of x.py, e.g. as JSON"),
+    ("completions", &[], "Print a completion script for bash, zsh, fish or powershell"),
+];
+
+fn is_subcommand(s: &str) -> bool {
+    SUBCOMMANDS.iter().any(|(name, aliases, _)| *name == s || aliases.contains(&s))
+}
+
+/// File in the root of the source tree defining invocations that can be run
+/// as `x.py @<name>`.
+const ALIASES_FILE: &str = ".bootstrap-aliases.toml";
+
+/// Default of `x.py gc --max-age`, in days.
+const DEFAULT_MAX_AGE: u64 = 30;
+/// Default of `x.py ci-smoke --budget`, in minutes.
+const DEFAULT_BUDGET: u64 = 60;
+
+/// The stage `subcommand` runs at unless `--stage` or the matching
+/// `build.*-stage` option says otherwise, as `Config::parse` decides.
+fn default_stage(subcommand: &str) -> u32 {
+    match subcommand {
+        "build" | "b" | "test" | "t" | "miri" => 1,
+        "bench" | "dist" | "install" => 2,
+        _ => 0,
+    }
+}
+
 impl Flags {
     pub fn parse(args: &[String]) -> Flags {
-        let mut subcommand_help = String::from(
-            "\
-Usage: x.py <subcommand> [options] [<paths>...]
-
-Subcommands:
-    build, b    Compile either the compiler or libraries
-    check, c    Compile either the compiler or libraries, using cargo check
-    clippy      Run clippy (uses rustup/cargo-installed clippy binary)
-    fix         Run cargo fix
-    fmt         Run rustfmt
-    test, t     Build and run some test suites
-    bench       Build and run some benchmarks
-    doc         Build documentation
-    clean       Clean out build directories
-    dist        Build distribution artifacts
-    install     Install distribution artifacts
-    run, r      Run tools contained in this repository
-    setup       Create a config.toml (making it easier to use `x.py` itself)
-
-To learn more about a subcommand, run `./x.py <subcommand> -h`",
+        let args = &expand_alias(args)[..];
+        let mut subcommand_help =
+            String::from("Usage: x.py <subcommand> [options] [<paths>...]\n\nSubcommands:\n");
+        let names = SUBCOMMANDS
+            .iter()
+            .map(|(name, aliases, _)| {
+                iter::once(name).chain(aliases.iter()).copied().collect::<Vec<_>>().join(", ")
+            })
+            .collect::<Vec<_>>();
+        let width = names.iter().map(|names| names.len()).max().unwrap_or(0);
+        for (names, (_, _, description)) in names.iter().zip(SUBCOMMANDS) {
+            subcommand_help.push_str(&format!("    {:<2$} {}\n", names, description, width));
+        }
+        subcommand_help.push_str(
+            "
+To learn more about a subcommand, run `./x.py <subcommand> -h`.
+To run an alias defined in `.bootstrap-aliases.toml`, run `./x.py @<alias>`,
+or `./x.py @` to list them.",
         );
 
-        let mut opts = Options::new();
-        // Options common to all subcommands
-        opts.optflagmulti("v", "verbose", "use verbose output (-vv for very verbose)");
-        opts.optflag("i", "incremental", "use incremental compilation");
-        opts.optopt("", "config", "TOML configuration file for build", "FILE");
-        opts.optopt("", "build", "build target of the stage0 compiler", "BUILD");
-        opts.optmulti("", "host", "host targets to build", "HOST");
-        opts.optmulti("", "target", "target targets to build", "TARGET");
-        opts.optmulti("", "exclude", "build paths to exclude", "PATH");
-        opts.optflag(
-            "",
-            "include-default-paths",
-            "include default paths in addition to the provided ones",
-        );
-        opts.optopt("", "on-fail", "command to run on failure", "CMD");
-        opts.optflag("", "dry-run", "dry run; don't build anything");
-        opts.optopt(
-            "",
-            "stage",
-            "stage to build (indicates compiler to use/test, e.g., stage 0 uses the \
-             bootstrap compiler, stage 1 the stage 0 rustc artifacts, etc.)",
-            "N",
-        );
-        opts.optmulti(
-            "",
-            "keep-stage",
-            "stage(s) to keep without recompiling \
-            (pass multiple times to keep e.g., both stages 0 and 1)",
-            "N",
-        );
-        opts.optmulti(
-            "",
-            "keep-stage-std",
-            "stage(s) of the standard library to keep without recompiling \
-            (pass multiple times to keep e.g., both stages 0 and 1)",
-            "N",
-        );
-        opts.optopt("", "src", "path to the root of the rust checkout", "DIR");
-        let j_msg = format!(
-            "number of jobs to run in parallel; \
-             defaults to {} (this host's logical CPU count)",
-            num_cpus::get()
-        );
-        opts.optopt("j", "jobs", &j_msg, "JOBS");
-        opts.optflag("h", "help", "print this help message");
-        opts.optopt(
-            "",
-            "warnings",
-            "if value is deny, will deny warnings, otherwise use default",
-            "VALUE",
-        );
-        opts.optopt("", "error-format", "rustc error format", "FORMAT");
-        opts.optflag("", "json-output", "use message-format=json");
-        opts.optopt("", "color", "whether to use color in cargo and rustc output", "STYLE");
-        opts.optopt(
-            "",
-            "llvm-skip-rebuild",
-            "whether rebuilding llvm should be skipped \
-             a VALUE of TRUE indicates that llvm will not be rebuilt \
-             VALUE overrides the skip-rebuild option in config.toml.",
-            "VALUE",
-        );
-        opts.optopt("", "rust-profile-generate", "rustc error format", "FORMAT");
-        opts.optopt("", "rust-profile-use", "rustc error format", "FORMAT");
+        let mut opts = common_options();
 
         // We can't use getopt to parse the options until we have completed specifying which
         // options are valid, but under the current implementation, some options are conditional on
         // the subcommand. Therefore we must manually identify the subcommand first, so that we can
         // complete the definition of the options.  Then we can use the getopt::Matches object from
         // there on out.
-        let subcommand = args.iter().find(|&s| {
-            (s == "build")
-                || (s == "b")
-                || (s == "check")
-                || (s == "c")
-                || (s == "clippy")
-                || (s == "fix")
-                || (s == "fmt")
-                || (s == "test")
-                || (s == "t")
-                || (s == "bench")
-                || (s == "doc")
-                || (s == "clean")
-                || (s == "dist")
-                || (s == "install")
-                || (s == "run")
-                || (s == "r")
-                || (s == "setup")
-        });
+        let subcommand = args.iter().find(|s| is_subcommand(s));
         let subcommand = match subcommand {
             Some(s) => s,
             None => {
@@ -261,65 +336,7 @@ pub fn parse(args: &[String]) -> Flags {
             }
         };
 
-        // Some subcommands get extra options
-        match subcommand.as_str() {
-            "test" | "t" => {
-                opts.optflag("", "no-fail-fast", "Run all tests regardless of failure");
-                opts.optmulti(
-                    "",
-                    "test-args",
-                    "extra arguments to be passed for the test tool being used \
-                        (e.g. libtest, compiletest or rustdoc)",
-                    "ARGS",
-                );
-                opts.optmulti(
-                    "",
-                    "rustc-args",
-                    "extra options to pass the compiler when running tests",
-                    "ARGS",
-                );
-                opts.optflag("", "no-doc", "do not run doc tests");
-                opts.optflag("", "doc", "only run doc tests");
-                opts.optflag("", "bless", "update all stderr/stdout files of failing ui tests");
-                opts.optopt(
-                    "",
-                    "compare-mode",
-                    "mode describing what file the actual ui output will be compared to",
-                    "COMPARE MODE",
-                );
-                opts.optopt(
-                    "",
-                    "pass",
-                    "force {check,build,run}-pass tests to this mode.",
-                    "check | build | run",
-                );
-                opts.optflag(
-                    "",
-                    "rustfix-coverage",
-                    "enable this to generate a Rustfix coverage file, which is saved in \
-                        `/<build_base>/rustfix_missing_coverage.txt`",
-                );
-            }
-            "check" | "c" => {
-                opts.optflag("", "all-targets", "Check all targets");
-            }
-            "bench" => {
-                opts.optmulti("", "test-args", "extra arguments", "ARGS");
-            }
-            "clippy" => {
-                opts.optflag("", "fix", "automatically apply lint suggestions");
-            }
-            "doc" => {
-                opts.optflag("", "open", "open the docs in a browser");
-            }
-            "clean" => {
-                opts.optflag("", "all", "clean all build artifacts");
-            }
-            "fmt" => {
-                opts.optflag("", "check", "check formatting instead of applying.");
-            }
-            _ => {}
-        };
+        add_subcommand_options(&mut opts, subcommand);
 
         // fn usage()
         let usage = |exit_code: i32, opts: &Options, verbose: bool, subcommand_help: &str| -> ! {
@@ -332,7 +349,17 @@ pub fn parse(args: &[String]) -> Flags {
 
                 let maybe_rules_help = Builder::get_help(&build, subcommand.as_str());
                 extra_help.push_str(maybe_rules_help.unwrap_or_default().as_str());
-            } else if !(subcommand.as_str() == "clean" || subcommand.as_str() == "fmt") {
+            } else if !matches!(
+                subcommand.as_str(),
+                "clean"
+                    | "gc"
+                    | "fmt"
+                    | "uninstall"
+                    | "suggest"
+                    | "ci-smoke"
+                    | "cli-metadata"
+                    | "completions"
+            ) {
                 extra_help.push_str(
                     format!("Run `./x.py {} -h -v` to see a list of available paths.", subcommand)
                         .as_str(),
@@ -416,6 +443,13 @@ pub fn parse(args: &[String]) -> Flags {
         ./x.py check library/core
         ./x.py check library/core library/proc_macro
 
+    A path to a compiler crate only checks that crate and the crates it depends on, rather than the
+    whole compiler, which is quicker when working on one crate:
+
+        ./x.py check compiler/rustc_middle
+
+    Tests, benchmarks and examples are only checked when `--all-targets` is passed.
+
     If no arguments are passed then the complete artifacts are compiled: std, test, and rustc. Note
     also that since we use `cargo check`, by default this will automatically enable incremental
     compilation, so there's no need to pass it separately, though it won't hurt. We also completely
@@ -431,7 +465,12 @@ pub fn parse(args: &[String]) -> Flags {
     and/or artifacts to run clippy against. For example:
 
         ./x.py clippy library/core
-        ./x.py clippy library/core library/proc_macro",
+        ./x.py clippy library/core library/proc_macro
+
+    Lint levels can be set like with rustc, and are applied in the order they
+    are given. Lints are capped at warnings unless one is denied or forbidden:
+
+        ./x.py clippy compiler/rustc_middle -A clippy::all -D clippy::correctness",
                 );
             }
             "fix" => {
@@ -453,7 +492,12 @@ pub fn parse(args: &[String]) -> Flags {
     fails if it is not. For example:
 
         ./x.py fmt
-        ./x.py fmt --check",
+        ./x.py fmt --check
+
+    Only the files changed since the current branch forked from `origin/master`, including
+    uncommitted changes, are formatted. Pass `--all` to format every file instead:
+
+        ./x.py fmt --all",
                 );
             }
             "test" | "t" => {
@@ -467,7 +511,13 @@ pub fn parse(args: &[String]) -> Flags {
         ./x.py test library/std --test-args hash_map
         ./x.py test library/std --stage 0 --no-doc
         ./x.py test src/test/ui --bless
+        ./x.py test tidy --bless
         ./x.py test src/test/ui --compare-mode nll
+        ./x.py test src/test/ui --gc-sessions
+        ./x.py test --rerun-failed
+
+    The compiletest tests that fail are listed in `build/failed-tests.txt`, and `--rerun-failed`
+    runs them again along with any paths that are passed.
 
     Note that `test src/test/* --stage N` does NOT depend on `build compiler/rustc --stage N`;
     just like `build library/std --stage N` it tests the compiler produced by the previous
@@ -484,6 +534,38 @@ pub fn parse(args: &[String]) -> Flags {
         ./x.py test --stage 1",
                 );
             }
+            "bench" => {
+                subcommand_help.push_str(
+                    "\n
+Arguments:
+    This subcommand accepts a number of paths to library crates or tools to
+    build with the bench profile and run `cargo bench` for. Arguments given
+    with `--test-args` are passed on to the benchmark harness. For example:
+
+        ./x.py bench library/core --test-args fmt
+        ./x.py bench compiler/rustc_data_structures --stage 1
+        ./x.py bench src/tools/rustfmt
+
+    If no arguments are passed then the benchmarks of the standard library,
+    the compiler and rustdoc are run.",
+                );
+            }
+            "miri" => {
+                subcommand_help.push_str(
+                    "\n
+Arguments:
+    This subcommand accepts a number of paths to library crates whose tests
+    should be run under Miri, to check them for undefined behavior. Miri and
+    cargo-miri are built from `src/tools/miri` with the compiler of the chosen
+    stage, and the Miri sysroot is built from the sources in `library`, so no
+    separate toolchain is needed. Flags for Miri itself are taken from the
+    MIRIFLAGS environment variable. For example:
+
+        ./x.py miri library/alloc
+        ./x.py miri library/core --test-args slice
+        MIRIFLAGS=-Zmiri-disable-isolation ./x.py miri library/std --stage 2",
+                );
+            }
             "doc" => {
                 subcommand_help.push_str(
                     "\n
@@ -511,7 +593,150 @@ pub fn parse(args: &[String]) -> Flags {
 
         ./x.py run src/tools/expand-yaml-anchors
 
-    At least a tool needs to be called.",
+    At least a tool needs to be called. The tools in `src/tools` that bootstrap
+    itself uses are built with the stage 0 compiler, if they're out of date, and
+    run with the arguments given with `--args`:
+
+        ./x.py run src/tools/rustbook --args \"build src/doc/rustc\"",
+                );
+            }
+            "clean" => {
+                subcommand_help.push_str(
+                    "\n
+Arguments:
+    This subcommand removes the build output for the hosts, except for LLVM,
+    which takes long to rebuild, or everything with `--all`. To remove less,
+    pass `--stage` to only remove that stage's output, `--target` to only
+    remove the output in the given targets' directories, or `--llvm` to only
+    remove LLVM. `--keep-downloads` keeps the downloaded stage0 toolchain,
+    LLVM from CI and their tarballs, along with the Cargo registry of
+    `--in-container`, and `--keep-llvm` keeps LLVM even with `--all`.
+    For example:
+
+        ./x.py clean --stage 1
+        ./x.py clean --target aarch64-unknown-linux-gnu
+        ./x.py clean --llvm
+        ./x.py clean --all --keep-downloads --keep-llvm",
+                );
+            }
+            "gc" => {
+                subcommand_help.push_str(
+                    "\n
+Arguments:
+    This subcommand lists the parts of the build directory, such as each stage's
+    sysroot and Cargo output, LLVM and test output, with their size and how many
+    days ago they were last used. Parts that weren't used in `--max-age` days
+    (30 by default) are removed. The output for targets that are no longer
+    configured, or that was built with another configuration, is marked as
+    stale. Pass `--dry-run` to only see what would be removed. For example:
+
+        ./x.py gc --dry-run
+        ./x.py gc --max-age 7",
+                );
+            }
+            "dist" => {
+                subcommand_help.push_str(
+                    "\n
+Arguments:
+    This subcommand builds the dist tarballs in build/dist. Without arguments,
+    all components enabled in config.toml are built for the configured hosts
+    and targets. To build only some of them, pass their component names, such
+    as `rust-std`, `rustc`, `rust-docs`, `cargo` or `clippy-preview`, along with
+    `--target` for the target components or `--host` for the host ones. What
+    they depend on is built as needed. For example:
+
+        ./x.py dist rust-std --target aarch64-unknown-linux-gnu
+        ./x.py dist rustc cargo --host x86_64-unknown-linux-musl
+
+    With `--sign`, every artifact in build/dist, or in `dist.sign-folder` if it
+    is set, also gets a detached gpg signature (`.asc`) and a SHA-256 checksum
+    (`.sha256`) in build/dist. The default gpg key is used, unlocked with the
+    password in `dist.gpg-password-file` if it is set.
+
+        ./x.py dist --sign",
+                );
+            }
+            "install" => {
+                subcommand_help.push_str(
+                    "\n
+Arguments:
+    This subcommand installs the toolchain into the configured `install.prefix`
+    (and `DESTDIR`, if set): the compiler, the standard library and the docs,
+    and with `build.extended` also cargo, the tools and the sources. To only
+    install some components, pass their names, such as `rustc`, `std`, `docs`,
+    `cargo`, `clippy`, `rustfmt` or `src`, or the names of their dist
+    tarballs. For example:
+
+        ./x.py install
+        ./x.py install rustc std clippy
+        DESTDIR=/tmp/rust ./x.py install rust-std --target aarch64-unknown-linux-gnu",
+                );
+            }
+            "uninstall" => {
+                subcommand_help.push_str(
+                    "\n
+Arguments:
+    This subcommand removes the files that `x.py install` installed into the
+    configured `install.prefix` (and `DESTDIR`, if set), as recorded in
+    `rustlib/x-install-manifest` in the install libdir, or in `rust/` in
+    `install.localstatedir` if that is set. Files that were changed
+    since they were installed are left alone. For example:
+
+        ./x.py uninstall
+        DESTDIR=/tmp/rust ./x.py uninstall",
+                );
+            }
+            "suggest" => {
+                subcommand_help.push_str(
+                    "\n
+Arguments:
+    This subcommand lists the files modified since the merge base with
+    `origin/master`, including uncommitted changes, and prints the `check`,
+    `test` and `doc` commands for the paths that cover them. Pass `--run` to
+    also run those commands. For example:
+
+        ./x.py suggest
+        ./x.py suggest --run",
+                );
+            }
+            "ci-smoke" => {
+                subcommand_help.push_str(
+                    "\n
+Arguments:
+    This subcommand runs tidy, builds the stage 1 standard library and runs the
+    parser UI tests, which catches most breakage without running the full test
+    suite, e.g. as the first job of a CI pipeline. It fails if this takes more
+    than `--budget` minutes (60 by default). For example:
+
+        ./x.py ci-smoke
+        ./x.py ci-smoke --budget 45",
+                );
+            }
+            "export-llvm" => {
+                subcommand_help.push_str(
+                    "\n
+Arguments:
+    This subcommand builds LLVM for the build triple, if needed, and packages it
+    into `build/dist`. The archive's name identifies the LLVM submodule commit
+    and the `[llvm]` configuration it was built with, and another checkout with
+    the same commit and configuration can use it by setting
+    `llvm.prebuilt-archive` to the directory containing it. For example:
+
+        ./x.py export-llvm",
+                );
+            }
+            "completions" => {
+                subcommand_help.push_str(
+                    "\n
+Arguments:
+    This subcommand prints a completion script for the given shell, which is one
+    of bash, zsh, fish or powershell. Besides subcommands and options, it
+    completes the paths selecting steps in this checkout, so regenerate it when
+    they change. For example:
+
+        source <(./x.py completions bash)
+        ./x.py completions fish > ~/.config/fish/completions/x.py.fish
+        ./x.py completions powershell | Out-String | Invoke-Expression",
                 );
             }
             "setup" => {
@@ -527,7 +752,15 @@ pub fn parse(args: &[String]) -> Flags {
     The profile is optional and you will be prompted interactively if it is not given.
     The following profiles are available:
 
-{}",
+{}
+
+    Passing `hooks` instead installs a git hook which checks formatting and runs
+    tidy, so that changes failing those checks on CI aren't pushed. The hook is
+    `pre-push` unless `pre-commit` is passed after `hooks`, and x.py keeps its
+    script up to date:
+
+        ./x.py setup hooks
+        ./x.py setup hooks pre-commit",
                     Profile::all_for_help("        ").trim_end()
                 ));
             }
@@ -544,12 +777,36 @@ pub fn parse(args: &[String]) -> Flags {
             usage(0, &opts, verbose, &subcommand_help);
         }
 
+        if subcommand == "cli-metadata" {
+            print_cli_metadata(matches.opt_present("json"));
+            process::exit(0);
+        }
+
+        if subcommand == "completions" {
+            let shell = match &paths[..] {
+                [shell] => shell.to_string_lossy().parse().unwrap_or_else(|err| {
+                    println!("\n{}\n", err);
+                    usage(1, &opts, verbose, &subcommand_help);
+                }),
+                _ => {
+                    println!("\ncompletions takes exactly one shell\n");
+                    usage(1, &opts, verbose, &subcommand_help);
+                }
+            };
+            print_completions(shell);
+            process::exit(0);
+        }
+
         let cmd = match subcommand.as_str() {
             "build" | "b" => Subcommand::Build { paths },
             "check" | "c" => {
                 Subcommand::Check { paths, all_targets: matches.opt_present("all-targets") }
             }
-            "clippy" => Subcommand::Clippy { paths, fix: matches.opt_present("fix") },
+            "clippy" => Subcommand::Clippy {
+                paths,
+                fix: matches.opt_present("fix"),
+                lint_levels: clippy_lint_levels(&matches),
+            },
             "fix" => Subcommand::Fix { paths },
             "test" | "t" => Subcommand::Test {
                 paths,
@@ -559,7 +816,10 @@ pub fn parse(args: &[String]) -> Flags {
                 test_args: matches.opt_strs("test-args"),
                 rustc_args: matches.opt_strs("rustc-args"),
                 fail_fast: !matches.opt_present("no-fail-fast"),
+                rerun_failed: matches.opt_present("rerun-failed"),
                 rustfix_coverage: matches.opt_present("rustfix-coverage"),
+                gc_sessions: matches.opt_present("gc-sessions"),
+                force_rerun: matches.opt_present("force-rerun"),
                 doc_tests: if matches.opt_present("doc") {
                     DocTests::Only
                 } else if matches.opt_present("no-doc") {
@@ -569,6 +829,13 @@ pub fn parse(args: &[String]) -> Flags {
                 },
             },
             "bench" => Subcommand::Bench { paths, test_args: matches.opt_strs("test-args") },
+            "miri" => {
+                if paths.is_empty() {
+                    println!("\nmiri requires at least a path!\n");
+                    usage(1, &opts, verbose, &subcommand_help);
+                }
+                Subcommand::Miri { paths, test_args: matches.opt_strs("test-args") }
+            }
             "doc" => Subcommand::Doc { paths, open: matches.opt_present("open") },
             "clean" => {
                 if !paths.is_empty() {
@@ -576,17 +843,99 @@ pub fn parse(args: &[String]) -> Flags {
                     usage(1, &opts, verbose, &subcommand_help);
                 }
 
-                Subcommand::Clean { all: matches.opt_present("all") }
+                let all = matches.opt_present("all");
+                let stage = matches
+                    .opt_str("stage")
+                    .map(|j| j.parse().expect("`stage` should be a number"));
+                let targets = split(&matches.opt_strs("target"))
+                    .into_iter()
+                    .map(|x| TargetSelection::from_user(&x))
+                    .collect::<Vec<_>>();
+                let llvm = matches.opt_present("llvm");
+                if all && (stage.is_some() || !targets.is_empty() || llvm) {
+                    println!("\n--all can't be combined with --stage, --target or --llvm\n");
+                    usage(1, &opts, verbose, &subcommand_help);
+                }
+                if llvm && stage.is_some() {
+                    println!("\n--llvm can't be combined with --stage\n");
+                    usage(1, &opts, verbose, &subcommand_help);
+                }
+                let keep_downloads = matches.opt_present("keep-downloads");
+                let keep_llvm = matches.opt_present("keep-llvm");
+                if llvm && keep_llvm {
+                    println!("\n--llvm can't be combined with --keep-llvm\n");
+                    usage(1, &opts, verbose, &subcommand_help);
+                }
+                Subcommand::Clean { all, stage, targets, llvm, keep_downloads, keep_llvm }
+            }
+            "gc" => {
+                if !paths.is_empty() {
+                    println!("\ngc does not take a path argument\n");
+                    usage(1, &opts, verbose, &subcommand_help);
+                }
+                let max_age = matches.opt_str("max-age").map_or(DEFAULT_MAX_AGE, |age| {
+                    age.parse().unwrap_or_else(|_| {
+                        println!("\n--max-age must be a number of days\n");
+                        usage(1, &opts, verbose, &subcommand_help);
+                    })
+                });
+                Subcommand::Gc { max_age }
             }
-            "fmt" => Subcommand::Format { check: matches.opt_present("check") },
-            "dist" => Subcommand::Dist { paths },
+            "fmt" => Subcommand::Format {
+                check: matches.opt_present("check"),
+                all: matches.opt_present("all"),
+            },
+            "dist" => Subcommand::Dist { paths, sign: matches.opt_present("sign") },
             "install" => Subcommand::Install { paths },
+            "uninstall" => {
+                if !paths.is_empty() {
+                    println!("\nuninstall does not take a path argument\n");
+                    usage(1, &opts, verbose, &subcommand_help);
+                }
+                Subcommand::Uninstall
+            }
+            "suggest" => {
+                if !paths.is_empty() {
+                    println!("\nsuggest does not take a path argument\n");
+                    usage(1, &opts, verbose, &subcommand_help);
+                }
+                Subcommand::Suggest { run: matches.opt_present("run") }
+            }
+            "ci-smoke" => {
+                if !paths.is_empty() {
+                    println!("\nci-smoke does not take a path argument\n");
+                    usage(1, &opts, verbose, &subcommand_help);
+                }
+                let budget = matches.opt_str("budget").map_or(DEFAULT_BUDGET, |budget| {
+                    budget.parse().unwrap_or_else(|_| {
+                        println!("\n--budget must be a number of minutes\n");
+                        usage(1, &opts, verbose, &subcommand_help);
+                    })
+                });
+                Subcommand::CiSmoke { budget }
+            }
             "run" | "r" => {
                 if paths.is_empty() {
                     println!("\nrun requires at least a path!\n");
                     usage(1, &opts, verbose, &subcommand_help);
                 }
-                Subcommand::Run { paths }
+                Subcommand::Run { paths, args: matches.opt_strs("args") }
+            }
+            "export-llvm" => Subcommand::ExportLlvm { paths },
+            "setup" if paths.first().map_or(false, |path| path == Path::new("hooks")) => {
+                if paths.len() > 2 {
+                    println!("\nat most one git hook can be passed to setup hooks\n");
+                    usage(1, &opts, verbose, &subcommand_help);
+                }
+                let hook = match paths.get(1) {
+                    Some(hook) => hook.to_string_lossy().parse().unwrap_or_else(|err| {
+                        eprintln!("error: {}", err);
+                        eprintln!("help: the available hooks are `pre-push` and `pre-commit`");
+                        std::process::exit(1);
+                    }),
+                    None => GitHook::PrePush,
+                };
+                Subcommand::Setup { profile: None, hook }
             }
             "setup" => {
                 let profile = if paths.len() > 1 {
@@ -606,13 +955,18 @@ pub fn parse(args: &[String]) -> Flags {
                 } else {
                     t!(crate::setup::interactive_path())
                 };
-                Subcommand::Setup { profile }
+                Subcommand::Setup { profile: Some(profile), hook: GitHook::PrePush }
             }
             _ => {
                 usage(1, &opts, verbose, &subcommand_help);
             }
         };
 
+        if matches.opt_present("json") && !matches.opt_present("dry-run") {
+            println!("\n--json requires --dry-run\n");
+            usage(1, &opts, verbose, &subcommand_help);
+        }
+
         if let Subcommand::Check { .. } = &cmd {
             if matches.opt_str("stage").is_some() {
                 println!("--stage not supported for x.py check, always treated as stage 0");
@@ -630,6 +984,10 @@ pub fn parse(args: &[String]) -> Flags {
             verbose: matches.opt_count("verbose"),
             stage: matches.opt_str("stage").map(|j| j.parse().expect("`stage` should be a number")),
             dry_run: matches.opt_present("dry-run"),
+            json_plan: matches.opt_present("json"),
+            explain: matches.opt_present("explain"),
+            timings: matches.opt_present("timings"),
+            bypass_bootstrap_lock: matches.opt_present("bypass-bootstrap-lock"),
             on_fail: matches.opt_str("on-fail"),
             rustc_error_format: matches.opt_str("error-format"),
             json_output: matches.opt_present("json-output"),
@@ -667,20 +1025,36 @@ pub fn parse(args: &[String]) -> Flags {
             jobs: matches.opt_str("jobs").map(|j| j.parse().expect("`jobs` should be a number")),
             cmd,
             incremental: matches.opt_present("incremental"),
-            exclude: split(&matches.opt_strs("exclude"))
+            exclude: split(&[matches.opt_strs("exclude"), matches.opt_strs("skip")].concat())
                 .into_iter()
                 .map(|p| p.into())
                 .collect::<Vec<_>>(),
             include_default_paths: matches.opt_present("include-default-paths"),
-            deny_warnings: parse_deny_warnings(&matches),
+            ci_change_detection: if matches.opt_present("ci-change-detection") {
+                Some(
+                    matches
+                        .opt_str("ci-change-detection")
+                        .unwrap_or_else(|| change_detection::DEFAULT_BASE.to_string()),
+                )
+            } else {
+                None
+            },
+            ci_keep: split(&matches.opt_strs("ci-keep"))
+                .into_iter()
+                .map(|p| p.into())
+                .collect::<Vec<_>>(),
+            warnings: parse_warnings(&matches),
             llvm_skip_rebuild: matches.opt_str("llvm-skip-rebuild").map(|s| s.to_lowercase()).map(
                 |s| s.parse::<bool>().expect("`llvm-skip-rebuild` should be either true or false"),
             ),
             color: matches
                 .opt_get_default("color", Color::Auto)
                 .expect("`color` should be `always`, `never`, or `auto`"),
+            in_container: matches.opt_str("in-container"),
             rust_profile_use: matches.opt_str("rust-profile-use"),
             rust_profile_generate: matches.opt_str("rust-profile-generate"),
+            llvm_profile_use: matches.opt_str("llvm-profile-use"),
+            llvm_profile_generate: matches.opt_str("llvm-profile-generate"),
         }
     }
 }
@@ -688,13 +1062,24 @@ pub fn parse(args: &[String]) -> Flags {
 impl Subcommand {
     pub fn test_args(&self) -> Vec<&str> {
         match *self {
-            Subcommand::Test { ref test_args, .. } | Subcommand::Bench { ref test_args, .. } => {
+            Subcommand::Test { ref test_args, .. }
+            | Subcommand::Bench { ref test_args, .. }
+            | Subcommand::Miri { ref test_args, .. } => {
                 test_args.iter().flat_map(|s| s.split_whitespace()).collect()
             }
             _ => Vec::new(),
         }
     }
 
+    pub fn run_args(&self) -> Vec<&str> {
+        match *self {
+            Subcommand::Run { ref args, .. } => {
+                args.iter().flat_map(|s| s.split_whitespace()).collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
     pub fn rustc_args(&self) -> Vec<&str> {
         match *self {
             Subcommand::Test { ref rustc_args, .. } => {
@@ -732,6 +1117,20 @@ pub fn rustfix_coverage(&self) -> bool {
         }
     }
 
+    pub fn gc_sessions(&self) -> bool {
+        match *self {
+            Subcommand::Test { gc_sessions, .. } => gc_sessions,
+            _ => false,
+        }
+    }
+
+    pub fn force_rerun(&self) -> bool {
+        match *self {
+            Subcommand::Test { force_rerun, .. } => force_rerun,
+            _ => false,
+        }
+    }
+
     pub fn compare_mode(&self) -> Option<&str> {
         match *self {
             Subcommand::Test { ref compare_mode, .. } => compare_mode.as_ref().map(|s| &s[..]),
@@ -752,20 +1151,551 @@ pub fn open(&self) -> bool {
             _ => false,
         }
     }
+
+    pub fn sign(&self) -> bool {
+        match *self {
+            Subcommand::Dist { sign, .. } => sign,
+            _ => false,
+        }
+    }
+}
+
+/// A command line option, as described by `x.py cli-metadata`.
+#[derive(Serialize)]
+pub struct OptionMetadata {
+    pub short: Option<String>,
+    pub long: String,
+    pub description: String,
+    /// Placeholder for the option's value, if it takes one.
+    pub value: Option<String>,
+    /// Whether the value may be left out.
+    pub value_optional: bool,
+    /// Whether the option may be given more than once.
+    pub multiple: bool,
+    /// The values the option accepts, if there's a fixed set of them.
+    pub accepted_values: Option<Vec<String>>,
+    /// The value used when the option isn't given, if it takes a value.
+    pub default: Option<String>,
+}
+
+/// `getopts::Options` that also records the options defined on it, so that
+/// `x.py cli-metadata` can describe them.
+struct CliOptions {
+    opts: Options,
+    metadata: Vec<OptionMetadata>,
+}
+
+impl CliOptions {
+    fn new() -> CliOptions {
+        CliOptions { opts: Options::new(), metadata: Vec::new() }
+    }
+
+    fn record(&mut self, short: &str, long: &str, desc: &str, value: Option<&str>, multiple: bool) {
+        // Hints like `check | build | run` list the accepted values.
+        let accepted_values = value
+            .filter(|hint| hint.contains(" | "))
+            .map(|hint| hint.split(" | ").map(str::to_string).collect());
+        self.metadata.push(OptionMetadata {
+            short: if short.is_empty() { None } else { Some(short.to_string()) },
+            long: long.to_string(),
+            description: desc.to_string(),
+            value: value.map(str::to_string),
+            value_optional: false,
+            multiple,
+            accepted_values,
+            default: None,
+        });
+    }
+
+    /// Records `value` as the default of the option defined last.
+    fn set_default(&mut self, value: &str) {
+        self.metadata.last_mut().unwrap().default = Some(value.to_string());
+    }
+
+    fn optflag(&mut self, short: &str, long: &str, desc: &str) {
+        self.opts.optflag(short, long, desc);
+        self.record(short, long, desc, None, false);
+    }
+
+    fn optflagmulti(&mut self, short: &str, long: &str, desc: &str) {
+        self.opts.optflagmulti(short, long, desc);
+        self.record(short, long, desc, None, true);
+    }
+
+    fn optopt(&mut self, short: &str, long: &str, desc: &str, hint: &str) {
+        self.opts.optopt(short, long, desc, hint);
+        self.record(short, long, desc, Some(hint), false);
+    }
+
+    fn optmulti(&mut self, short: &str, long: &str, desc: &str, hint: &str) {
+        self.opts.optmulti(short, long, desc, hint);
+        self.record(short, long, desc, Some(hint), true);
+    }
+
+    fn optflagopt(&mut self, short: &str, long: &str, desc: &str, hint: &str) {
+        self.opts.optflagopt(short, long, desc, hint);
+        self.record(short, long, desc, Some(hint), false);
+        self.metadata.last_mut().unwrap().value_optional = true;
+    }
+}
+
+impl Deref for CliOptions {
+    type Target = Options;
+
+    fn deref(&self) -> &Options {
+        &self.opts
+    }
+}
+
+/// Options common to all subcommands.
+fn common_options() -> CliOptions {
+    let mut opts = CliOptions::new();
+    opts.optflagmulti("v", "verbose", "use verbose output (-vv for very verbose)");
+    opts.optflag("i", "incremental", "use incremental compilation");
+    opts.optopt("", "config", "TOML configuration file for build", "FILE");
+    opts.optopt("", "build", "build target of the stage0 compiler", "BUILD");
+    opts.optmulti("", "host", "host targets to build", "HOST");
+    opts.optmulti("", "target", "target targets to build", "TARGET");
+    opts.optmulti(
+        "",
+        "exclude",
+        "build paths to exclude, which may be glob patterns like 'src/tools/*', or steps \
+         like 'test::tidy' or 'doc::*'",
+        "PATH",
+    );
+    opts.optmulti("", "skip", "same as --exclude", "PATH");
+    opts.optflag(
+        "",
+        "include-default-paths",
+        "include default paths in addition to the provided ones",
+    );
+    opts.optflagopt(
+        "",
+        "ci-change-detection",
+        "skip default steps not affected by the files changed since the merge base \
+         with BASE (default: origin/master)",
+        "BASE",
+    );
+    opts.optmulti("", "ci-keep", "never skip PATH because of change detection", "PATH");
+    opts.optopt(
+        "",
+        "on-fail",
+        "command to run when rustc fails, or `write-script` to write scripts to \
+         build/on-fail running failed commands again",
+        "CMD",
+    );
+    opts.optflag("", "dry-run", "dry run; don't build anything");
+    opts.optflag(
+        "",
+        "json",
+        "with --dry-run, print the steps that would run as JSON; \
+         with cli-metadata, print the metadata as JSON",
+    );
+    opts.optflag(
+        "",
+        "timings",
+        "write the times each step and command took to build/trace.json, \
+         which can be opened in chrome://tracing or Perfetto",
+    );
+    opts.optflag(
+        "",
+        "explain",
+        "print why each step runs: the steps that required it, up to the one \
+         selected by a path or run by default",
+    );
+    opts.optflag(
+        "",
+        "bypass-bootstrap-lock",
+        "don't take the lock on the build directory, even if another x.py \
+         invocation is using it",
+    );
+    opts.optopt(
+        "",
+        "stage",
+        "stage to build (indicates compiler to use/test, e.g., stage 0 uses the \
+         bootstrap compiler, stage 1 the stage 0 rustc artifacts, etc.)",
+        "N",
+    );
+    opts.optmulti(
+        "",
+        "keep-stage",
+        "stage(s) to keep without recompiling \
+        (pass multiple times to keep e.g., both stages 0 and 1)",
+        "N",
+    );
+    opts.optmulti(
+        "",
+        "keep-stage-std",
+        "stage(s) of the standard library to keep without recompiling \
+        (pass multiple times to keep e.g., both stages 0 and 1)",
+        "N",
+    );
+    opts.optopt("", "src", "path to the root of the rust checkout", "DIR");
+    let j_msg = format!(
+        "number of jobs to run in parallel; \
+         defaults to {} (this host's logical CPU count)",
+        num_cpus::get()
+    );
+    opts.optopt("j", "jobs", &j_msg, "JOBS");
+    opts.set_default(&num_cpus::get().to_string());
+    opts.optflag("h", "help", "print this help message");
+    opts.optopt(
+        "",
+        "warnings",
+        "whether warnings fail the build, overriding rust.warnings in config.toml",
+        "deny | warn | default",
+    );
+    opts.set_default("default");
+    opts.optopt("", "error-format", "rustc error format", "FORMAT");
+    opts.optflag("", "json-output", "use message-format=json");
+    opts.optopt(
+        "",
+        "color",
+        "whether to color the output of bootstrap and the tools it runs",
+        "always | never | auto",
+    );
+    opts.set_default("auto");
+    opts.optopt(
+        "",
+        "in-container",
+        "run this command in a container, either one of the CI images in \
+         src/ci/docker or any other image",
+        "IMAGE",
+    );
+    opts.optopt(
+        "",
+        "llvm-skip-rebuild",
+        "whether rebuilding llvm should be skipped \
+         a VALUE of TRUE indicates that llvm will not be rebuilt \
+         VALUE overrides the skip-rebuild option in config.toml.",
+        "VALUE",
+    );
+    opts.optopt("", "rust-profile-generate", "rustc error format", "FORMAT");
+    opts.optopt("", "rust-profile-use", "rustc error format", "FORMAT");
+    opts.optopt(
+        "",
+        "llvm-profile-generate",
+        "build LLVM instrumented to write PGO profiles into DIR, \
+         overriding llvm.profile-generate in config.toml",
+        "DIR",
+    );
+    opts.optopt(
+        "",
+        "llvm-profile-use",
+        "build LLVM optimized with the merged PGO profile FILE, \
+         overriding llvm.profile-use in config.toml",
+        "FILE",
+    );
+    opts
+}
+
+/// Adds the options specific to `subcommand` to `opts`.
+fn add_subcommand_options(opts: &mut CliOptions, subcommand: &str) {
+    match subcommand {
+        "test" | "t" => {
+            opts.optflag("", "no-fail-fast", "Run all tests regardless of failure");
+            opts.optflag("", "rerun-failed", "run the tests that failed in the previous run");
+            opts.optflag(
+                "",
+                "force-rerun",
+                "run tests even if they passed before and nothing they depend on changed",
+            );
+            opts.optmulti(
+                "",
+                "test-args",
+                "extra arguments to be passed for the test tool being used \
+                    (e.g. libtest, compiletest or rustdoc)",
+                "ARGS",
+            );
+            opts.optmulti(
+                "",
+                "rustc-args",
+                "extra options to pass the compiler when running tests",
+                "ARGS",
+            );
+            opts.optflag("", "no-doc", "do not run doc tests");
+            opts.optflag("", "doc", "only run doc tests");
+            opts.optflag(
+                "",
+                "bless",
+                "update the expected output of failing tests, like the stderr/stdout files of \
+                 ui tests, instead of failing",
+            );
+            opts.optopt(
+                "",
+                "compare-mode",
+                "mode describing what file the actual ui output will be compared to",
+                "COMPARE MODE",
+            );
+            opts.optopt(
+                "",
+                "pass",
+                "force {check,build,run}-pass tests to this mode.",
+                "check | build | run",
+            );
+            opts.optflag(
+                "",
+                "rustfix-coverage",
+                "enable this to generate a Rustfix coverage file, which is saved in \
+                    `/<build_base>/rustfix_missing_coverage.txt`",
+            );
+            opts.optflag(
+                "",
+                "gc-sessions",
+                "remove test output older than `build.test-output-max-age` days \
+                    before running test suites",
+            );
+        }
+        "check" | "c" => {
+            opts.optflag("", "all-targets", "Check all targets");
+        }
+        "bench" | "miri" => {
+            opts.optmulti("", "test-args", "extra arguments", "ARGS");
+        }
+        "clippy" => {
+            opts.optflag("", "fix", "automatically apply lint suggestions");
+            opts.optmulti("A", "allow", "set lint allowed", "LINT");
+            opts.optmulti("W", "warn", "set lint warnings", "LINT");
+            opts.optmulti("D", "deny", "set lint denied", "LINT");
+            opts.optmulti("F", "forbid", "set lint forbidden", "LINT");
+        }
+        "doc" => {
+            opts.optflag("", "open", "open the docs in a browser");
+        }
+        "dist" => {
+            opts.optflag("", "sign", "sign the artifacts with gpg and write their checksums");
+        }
+        "clean" => {
+            opts.optflag("", "all", "clean all build artifacts");
+            opts.optflag("", "llvm", "only clean the LLVM build");
+            opts.optflag("", "keep-downloads", "keep the downloaded toolchain, LLVM and crates");
+            opts.optflag("", "keep-llvm", "keep the LLVM build, even with --all");
+        }
+        "gc" => {
+            opts.optopt("", "max-age", "remove parts unused for this many days", "DAYS");
+            opts.set_default(&DEFAULT_MAX_AGE.to_string());
+        }
+        "run" | "r" => {
+            opts.optmulti("", "args", "arguments to pass to the tool being run", "ARGS");
+        }
+        "suggest" => {
+            opts.optflag("", "run", "run the suggested commands");
+        }
+        "ci-smoke" => {
+            opts.optopt("", "budget", "fail if the run takes longer than this", "MINUTES");
+            opts.set_default(&DEFAULT_BUDGET.to_string());
+        }
+        "fmt" => {
+            opts.optflag("", "check", "check formatting instead of applying.");
+            opts.optflag("", "all", "format all files, not only the modified ones.");
+        }
+        _ => {}
+    };
+}
+
+/// A subcommand, as described by `x.py cli-metadata`.
+#[derive(Serialize)]
+pub struct SubcommandMetadata {
+    pub name: &'static str,
+    pub aliases: &'static [&'static str],
+    pub description: &'static str,
+    /// The stage it runs at unless `--stage`, config.toml or CI change it.
+    pub default_stage: u32,
+    pub options: Vec<OptionMetadata>,
+}
+
+fn subcommand_metadata() -> Vec<SubcommandMetadata> {
+    SUBCOMMANDS
+        .iter()
+        .map(|&(name, aliases, description)| {
+            let mut opts = CliOptions::new();
+            add_subcommand_options(&mut opts, name);
+            let default_stage = default_stage(name);
+            SubcommandMetadata { name, aliases, description, default_stage, options: opts.metadata }
+        })
+        .collect()
+}
+
+/// Prints all subcommands and options of x.py, for tools wrapping it.
+fn print_cli_metadata(json: bool) {
+    let global_options = common_options().metadata;
+    let subcommands = subcommand_metadata();
+
+    if json {
+        let metadata = serde_json::json!({
+            "global_options": global_options,
+            "subcommands": subcommands,
+        });
+        println!("{}", t!(serde_json::to_string_pretty(&metadata)));
+        return;
+    }
+
+    let print_options = |options: &[OptionMetadata]| {
+        for opt in options {
+            let value = opt.value.as_ref().map(|v| format!(" <{}>", v)).unwrap_or_default();
+            let default = opt.default.as_ref().map(|d| format!(" (default: {})", d));
+            let default = default.unwrap_or_default();
+            println!("    --{}{}{}\n        {}", opt.long, value, default, opt.description);
+        }
+    };
+    println!("Global options:");
+    print_options(&global_options);
+    for subcommand in &subcommands {
+        println!(
+            "\n{}: {} (stage {} by default)",
+            subcommand.name, subcommand.description, subcommand.default_stage
+        );
+        print_options(&subcommand.options);
+    }
+}
+
+/// Prints a completion script for `shell`, which also completes the paths
+/// selecting steps in this checkout.
+fn print_completions(shell: Shell) {
+    let config = Config::parse(&["build".to_string()]);
+    let build = Build::new(config);
+
+    let global_options = common_options().metadata;
+    let subcommands = subcommand_metadata();
+    let completions = subcommands
+        .iter()
+        .map(|subcommand| {
+            let paths = Builder::step_paths(&build, subcommand.name);
+            let paths = paths.iter().map(|path| path.display().to_string()).collect();
+            Completion { subcommand, paths }
+        })
+        .collect::<Vec<_>>();
+    print!("{}", completions::generate(shell, &global_options, &completions));
+}
+
+/// Expands a leading `@<name>` argument into the invocation it's an alias for
+/// in `.bootstrap-aliases.toml`, keeping any arguments following it. A bare `@`
+/// lists the defined aliases.
+fn expand_alias(args: &[String]) -> Vec<String> {
+    let name = match args.first().and_then(|arg| arg.strip_prefix('@')) {
+        Some(name) => name,
+        None => return args.to_vec(),
+    };
+
+    // Undo `src/bootstrap`
+    let src = Path::new(env!("CARGO_MANIFEST_DIR")).parent().unwrap().parent().unwrap();
+    let path = src.join(ALIASES_FILE);
+    let contents = fs::read_to_string(&path).unwrap_or_else(|_| {
+        eprintln!("error: aliases are defined in {}, which doesn't exist", ALIASES_FILE);
+        process::exit(1);
+    });
+    let aliases: BTreeMap<String, String> = toml::from_str(&contents).unwrap_or_else(|e| {
+        eprintln!("error: failed to parse {}: {}", ALIASES_FILE, e);
+        process::exit(1);
+    });
+
+    // Validate all aliases up front so mistakes surface whichever one is used.
+    if let Err(e) = validate_aliases(&aliases) {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+
+    if name.is_empty() {
+        println!("Aliases defined in {}:", ALIASES_FILE);
+        for (alias, invocation) in &aliases {
+            println!("    @{:<20} {}", alias, invocation);
+        }
+        process::exit(0);
+    }
+
+    apply_alias(&aliases, name, &args[1..]).unwrap_or_else(|| {
+        eprintln!("error: no alias `{}` in {}; run `x.py @` to list them", name, ALIASES_FILE);
+        process::exit(1);
+    })
+}
+
+/// Checks that every alias starts with a subcommand and doesn't refer to
+/// another alias.
+fn validate_aliases(aliases: &BTreeMap<String, String>) -> Result<(), String> {
+    for (alias, invocation) in aliases {
+        let mut words = invocation.split_whitespace();
+        match words.next() {
+            Some(first) if is_subcommand(first) => {}
+            _ => {
+                return Err(format!(
+                    "alias `{}` in {} must start with a subcommand, found `{}`",
+                    alias, ALIASES_FILE, invocation
+                ));
+            }
+        }
+        if words.any(|word| word.starts_with('@')) {
+            return Err(format!("alias `{}` in {} refers to another alias", alias, ALIASES_FILE));
+        }
+    }
+    Ok(())
+}
+
+/// Replaces the alias `name` by its invocation followed by `rest`, or returns
+/// `None` if there's no such alias.
+fn apply_alias(
+    aliases: &BTreeMap<String, String>,
+    name: &str,
+    rest: &[String],
+) -> Option<Vec<String>> {
+    let invocation = aliases.get(name)?;
+    Some(invocation.split_whitespace().map(str::to_string).chain(rest.iter().cloned()).collect())
 }
 
 fn split(s: &[String]) -> Vec<String> {
     s.iter().flat_map(|s| s.split(',')).filter(|s| !s.is_empty()).map(|s| s.to_string()).collect()
 }
 
-fn parse_deny_warnings(matches: &getopts::Matches) -> Option<bool> {
-    match matches.opt_str("warnings").as_deref() {
-        Some("deny") => Some(true),
-        Some("warn") => Some(false),
-        Some(value) => {
-            eprintln!(r#"invalid value for --warnings: {:?}, expected "warn" or "deny""#, value,);
-            process::exit(1);
+/// Returns the lint level flags passed to `x.py clippy` in the order they
+/// were given, which getopts doesn't keep across different options.
+fn clippy_lint_levels(matches: &getopts::Matches) -> Vec<String> {
+    let mut levels = Vec::new();
+    for &(flag, name) in &[("-A", "allow"), ("-W", "warn"), ("-D", "deny"), ("-F", "forbid")] {
+        for (pos, lint) in matches.opt_strs_pos(name) {
+            levels.push((pos, format!("{}{}", flag, lint)));
         }
-        None => None,
+    }
+    levels.sort_by_key(|&(pos, _)| pos);
+    levels.into_iter().map(|(_, level)| level).collect()
+}
+
+fn parse_warnings(matches: &getopts::Matches) -> Warnings {
+    match matches.opt_str("warnings") {
+        Some(value) => value.parse().unwrap_or_else(|_| {
+            eprintln!(
+                r#"invalid value for --warnings: {:?}, expected "deny", "warn" or "default""#,
+                value
+            );
+            process::exit(1);
+        }),
+        None => Warnings::Default,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn aliases(entries: &[(&str, &str)]) -> BTreeMap<String, String> {
+        entries.iter().map(|&(name, alias)| (name.to_string(), alias.to_string())).collect()
+    }
+
+    #[test]
+    fn expand_aliases() {
+        let aliases = aliases(&[("ui", "test src/test/ui --stage 1"), ("docs", "doc")]);
+        let rest = ["--bless".to_string()];
+        assert_eq!(
+            apply_alias(&aliases, "ui", &rest).unwrap(),
+            ["test", "src/test/ui", "--stage", "1", "--bless"]
+        );
+        assert_eq!(apply_alias(&aliases, "docs", &[]).unwrap(), ["doc"]);
+        assert_eq!(apply_alias(&aliases, "dosc", &rest), None);
+    }
+
+    #[test]
+    fn invalid_aliases() {
+        assert!(validate_aliases(&aliases(&[("ui", "t src/test/ui")])).is_ok());
+        assert!(validate_aliases(&aliases(&[("ui", "src/test/ui")])).is_err());
+        assert!(validate_aliases(&aliases(&[("empty", "")])).is_err());
+        assert!(validate_aliases(&aliases(&[("all", "test @ui")])).is_err());
     }
 }
@@ -1,11 +1,18 @@
 //! Runs rustfmt on the repository.
+//!
+//! This needs the `fmt` feature of bootstrap, whose dependencies take a while
+//! to build, so x.py only enables it once a command needs it.
 
 use crate::Build;
-use build_helper::{output, t};
-use ignore::WalkBuilder;
-use std::path::Path;
-use std::process::{Command, Stdio};
 
+// Everything but `Build` is only used with the `fmt` feature.
+#[cfg(feature = "fmt")]
+use {
+    crate::change_detection, build_helper::t, ignore::WalkBuilder, std::collections::HashSet,
+    std::path::Path, std::process::Command,
+};
+
+#[cfg(feature = "fmt")]
 fn rustfmt(src: &Path, rustfmt: &Path, path: &Path, check: bool) {
     let mut cmd = Command::new(&rustfmt);
     // avoid the submodule config paths from coming into play,
@@ -31,12 +38,25 @@ fn rustfmt(src: &Path, rustfmt: &Path, path: &Path, check: bool) {
     }
 }
 
+#[cfg(feature = "fmt")]
 #[derive(serde::Deserialize)]
 struct RustfmtConfig {
     ignore: Vec<String>,
 }
 
-pub fn format(build: &Build, check: bool) {
+#[cfg(not(feature = "fmt"))]
+pub fn format(_build: &Build, _check: bool, _all: bool) {
+    eprintln!("error: formatting needs bootstrap to be built with its `fmt` feature");
+    eprintln!("help: x.py enables it when running `x.py fmt` or `x.py test`");
+    std::process::exit(1);
+}
+
+/// Formats the Rust files of the source tree, or checks that they are
+/// formatted if `check` is set. Unless `all` is set, only the files modified
+/// since the current branch forked from upstream are formatted, if the VCS
+/// can tell which those are.
+#[cfg(feature = "fmt")]
+pub fn format(build: &Build, check: bool, all: bool) {
     if build.config.dry_run {
         return;
     }
@@ -56,61 +76,45 @@ pub fn format(build: &Build, check: bool) {
     for ignore in rustfmt_config.ignore {
         ignore_fmt.add(&format!("!{}", ignore)).expect(&ignore);
     }
-    let git_available = match Command::new("git")
-        .arg("--version")
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .status()
-    {
-        Ok(status) => status.success(),
-        Err(_) => false,
-    };
-    if git_available {
-        let in_working_tree = match Command::new("git")
-            .arg("rev-parse")
-            .arg("--is-inside-work-tree")
-            .stdout(Stdio::null())
-            .stderr(Stdio::null())
-            .status()
-        {
-            Ok(status) => status.success(),
-            Err(_) => false,
-        };
-        if in_working_tree {
-            let untracked_paths_output = output(
-                Command::new("git")
-                    .arg("status")
-                    .arg("--porcelain")
-                    .arg("--untracked-files=normal"),
-            );
-            let untracked_paths = untracked_paths_output
-                .lines()
-                .filter(|entry| entry.starts_with("??"))
-                .map(|entry| {
-                    entry.split(' ').nth(1).expect("every git status entry should list a path")
-                });
+    match build.vcs.untracked_files(&build.src) {
+        Some(untracked_paths) => {
             for untracked_path in untracked_paths {
                 eprintln!("skip untracked path {} during rustfmt invocations", untracked_path);
                 ignore_fmt.add(&format!("!{}", untracked_path)).expect(&untracked_path);
             }
-        } else {
-            eprintln!("Not in git tree. Skipping git-aware format checks");
         }
-    } else {
-        eprintln!("Could not find usable git. Skipping git-aware format checks");
+        None => eprintln!("Could not query version control. Skipping VCS-aware format checks"),
     }
     let ignore_fmt = ignore_fmt.build().unwrap();
+    let src = &build.src;
+
+    let modified: Option<HashSet<_>> = if all {
+        None
+    } else {
+        match build.vcs.modified_files(src, change_detection::DEFAULT_BASE) {
+            Some(files) => Some(files.iter().map(|file| src.join(file)).collect()),
+            None => {
+                eprintln!(
+                    "Could not find the files modified since {}. Formatting all files",
+                    change_detection::DEFAULT_BASE
+                );
+                None
+            }
+        }
+    };
 
     let rustfmt_path = build.config.initial_rustfmt.as_ref().unwrap_or_else(|| {
         eprintln!("./x.py fmt is not supported on this channel");
         std::process::exit(1);
     });
-    let src = &build.src;
+    let modified = &modified;
     let walker = WalkBuilder::new(src).types(matcher).overrides(ignore_fmt).build_parallel();
     walker.run(|| {
         Box::new(move |entry| {
             let entry = t!(entry);
-            if entry.file_type().map_or(false, |t| t.is_file()) {
+            if entry.file_type().map_or(false, |t| t.is_file())
+                && modified.as_ref().map_or(true, |modified| modified.contains(entry.path()))
+            {
                 rustfmt(src, &rustfmt_path, &entry.path(), check);
             }
             ignore::WalkState::Continue
@@ -0,0 +1,102 @@
+//! The commands of the `[hooks]` section of `config.toml`.
+//!
+//! Packagers post-processing the artifacts of x.py, e.g. to sign tarballs,
+//! used to wrap it in scripts which had to guess which steps ran and where
+//! their output ended up. Instead, `hooks.pre` and `hooks.post` map names of
+//! steps, as taken by `--exclude` (like `dist::rustc` or `test::ui`), to
+//! shell commands which are run from the source directory right before and
+//! right after each step of that name runs, by `sh -c`, or `cmd /C` on
+//! Windows. The commands get the step in environment variables:
+//!
+//! - `BOOTSTRAP_HOOK`: `pre` or `post`
+//! - `BOOTSTRAP_HOOK_STEP`: the name of the step, e.g. `dist::Rustc`
+//! - `BOOTSTRAP_HOOK_DESCRIPTION`: its `Debug` representation
+//! - `BOOTSTRAP_HOOK_TARGET` and `BOOTSTRAP_HOOK_STAGE`: its target and the
+//!   stage of its compiler, if it has them
+//! - `BOOTSTRAP_HOOK_OUTPUTS`: for `post`, the files or directories produced
+//!   by the step, like the tarballs of `dist` steps, as a list of paths
+//!   separated like `PATH`
+//! - `BOOTSTRAP_BUILD_DIR`: the build directory
+//!
+//! A failing hook fails the build.
+
+use std::any::Any;
+use std::env;
+use std::path::PathBuf;
+use std::process::Command;
+
+use build_helper::t;
+
+use crate::builder::{self, Builder, Step};
+use crate::cache::Interned;
+use crate::tarball::GeneratedTarball;
+
+/// Runs the hooks of `step` before it runs.
+pub fn run_pre<S: Step>(builder: &Builder<'_>, step: &S) {
+    run(builder, "pre", &builder.config.hooks_pre, step, Vec::new());
+}
+
+/// Runs the hooks of `step` after it ran and produced `output`.
+pub fn run_post<S: Step>(builder: &Builder<'_>, step: &S, output: &S::Output) {
+    run(builder, "post", &builder.config.hooks_post, step, outputs(output));
+}
+
+fn run<S: Step>(
+    builder: &Builder<'_>,
+    phase: &str,
+    hooks: &[(String, String)],
+    step: &S,
+    outputs: Vec<PathBuf>,
+) {
+    if hooks.is_empty() || builder.config.dry_run {
+        return;
+    }
+    let name = builder::step_name::<S>();
+    let description = format!("{:?}", step);
+    let hooks = hooks.iter().filter(|(filter, _)| builder::step_matches(name, filter));
+    for (filter, command) in hooks {
+        builder.info(&format!("Running {} hook of {} for `{}`: {}", phase, name, filter, command));
+        let mut cmd = if cfg!(windows) {
+            let mut cmd = Command::new("cmd");
+            cmd.arg("/C");
+            cmd
+        } else {
+            let mut cmd = Command::new("sh");
+            cmd.arg("-c");
+            cmd
+        };
+        cmd.arg(command).current_dir(&builder.src);
+        cmd.env("BOOTSTRAP_HOOK", phase)
+            .env("BOOTSTRAP_HOOK_STEP", name)
+            .env("BOOTSTRAP_HOOK_DESCRIPTION", &description)
+            .env("BOOTSTRAP_BUILD_DIR", &builder.out);
+        if let Some(target) = step.target() {
+            cmd.env("BOOTSTRAP_HOOK_TARGET", &*target.triple);
+        }
+        if let Some(stage) = step.stage() {
+            cmd.env("BOOTSTRAP_HOOK_STAGE", stage.to_string());
+        }
+        if !outputs.is_empty() {
+            cmd.env("BOOTSTRAP_HOOK_OUTPUTS", t!(env::join_paths(&outputs)));
+        }
+        builder.run(&mut cmd);
+    }
+}
+
+/// The paths among the output of a step. Steps return what later steps need
+/// from them, which is often the path of what they built.
+fn outputs(output: &dyn Any) -> Vec<PathBuf> {
+    if let Some(path) = output.downcast_ref::<PathBuf>() {
+        vec![path.clone()]
+    } else if let Some(path) = output.downcast_ref::<Interned<PathBuf>>() {
+        vec![path.to_path_buf()]
+    } else if let Some(tarball) = output.downcast_ref::<GeneratedTarball>() {
+        vec![tarball.tarball().to_path_buf()]
+    } else if let Some(Some(tarball)) = output.downcast_ref::<Option<GeneratedTarball>>() {
+        vec![tarball.tarball().to_path_buf()]
+    } else if let Some(paths) = output.downcast_ref::<Vec<PathBuf>>() {
+        paths.clone()
+    } else {
+        Vec::new()
+    }
+}
@@ -1,18 +1,22 @@
 //! Implementation of the install aspects of the compiler.
 //!
 //! This module is responsible for installing the standard library,
-//! compiler, and documentation.
+//! compiler, and documentation, for checking that the installed toolchain
+//! works, and for removing it again with `x.py uninstall`.
 
+use std::collections::{BTreeMap, BTreeSet};
 use std::env;
 use std::fs;
-use std::path::{Component, PathBuf};
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
 use std::process::Command;
 
 use build_helper::t;
 
 use crate::dist::{self, sanitize_sh};
 use crate::tarball::GeneratedTarball;
-use crate::Compiler;
+use crate::util::{dylib_path_var, exe};
+use crate::{Build, Compiler};
 
 use crate::builder::{Builder, RunConfig, ShouldRun, Step};
 use crate::config::{Config, TargetSelection};
@@ -34,36 +38,332 @@ fn install_sh(
     let libdir = prefix.join(default_path(&builder.config.libdir, "lib"));
     let bindir = prefix.join(&builder.config.bindir); // Default in config.rs
 
+    let infodir = prefix.join(default_path(&builder.config.infodir, "share/info"));
+    let sbindir = prefix.join(default_path(&builder.config.sbindir, "sbin"));
+
     let empty_dir = builder.out.join("tmp/empty_dir");
     t!(fs::create_dir_all(&empty_dir));
 
+    let config = &builder.config;
     let mut cmd = Command::new("sh");
     cmd.current_dir(&empty_dir)
         .arg(sanitize_sh(&tarball.decompressed_output().join("install.sh")))
-        .arg(format!("--prefix={}", prepare_dir(prefix)))
-        .arg(format!("--sysconfdir={}", prepare_dir(sysconfdir)))
-        .arg(format!("--datadir={}", prepare_dir(datadir)))
-        .arg(format!("--docdir={}", prepare_dir(docdir)))
-        .arg(format!("--bindir={}", prepare_dir(bindir)))
-        .arg(format!("--libdir={}", prepare_dir(libdir)))
-        .arg(format!("--mandir={}", prepare_dir(mandir)))
+        .arg(format!("--prefix={}", prepare_dir(config, prefix.clone())))
+        .arg(format!("--sysconfdir={}", prepare_dir(config, sysconfdir)))
+        .arg(format!("--datadir={}", prepare_dir(config, datadir.clone())))
+        .arg(format!("--docdir={}", prepare_dir(config, docdir)))
+        .arg(format!("--bindir={}", prepare_dir(config, bindir)))
+        .arg(format!("--libdir={}", prepare_dir(config, libdir.clone())))
+        .arg(format!("--mandir={}", prepare_dir(config, mandir)))
         .arg("--disable-ldconfig");
     builder.run(&mut cmd);
     t!(fs::remove_dir_all(&empty_dir));
+
+    if !config.dry_run {
+        let libdir = with_destdir(config, libdir);
+        // `install.sh` has no options for these, so it puts system binaries
+        // and GNU info pages in `<prefix>/sbin` and `<datadir>/info`.
+        let moves = [
+            (with_destdir(config, prefix.join("sbin")), with_destdir(config, sbindir)),
+            (with_destdir(config, datadir.join("info")), with_destdir(config, infodir)),
+        ];
+        relocate_installed(&libdir, tarball, &moves);
+        record_installed(config, &libdir, &install_manifest_path(config, &prefix), tarball);
+    }
+}
+
+/// Moves the files `install.sh` installed from `tarball` below the first
+/// directory of each pair in `moves` to the second one, and updates the
+/// component manifests of `install.sh`, which its `uninstall.sh` reads, to
+/// match.
+fn relocate_installed(libdir: &Path, tarball: &GeneratedTarball, moves: &[(PathBuf, PathBuf)]) {
+    let rustlib = libdir.join("rustlib");
+    let components = t!(fs::read_to_string(tarball.decompressed_output().join("components")));
+    for component in components.lines() {
+        let component_manifest = rustlib.join(format!("manifest-{}", component));
+        let mut contents = String::new();
+        for line in t!(fs::read_to_string(&component_manifest)).lines() {
+            let (kind, path) = match line.find(':') {
+                Some(i) => (&line[..=i], Path::new(&line[i + 1..])),
+                None => (line, Path::new("")),
+            };
+            let moved = moves.iter().filter(|(from, to)| from != to).find_map(|(from, to)| {
+                path.strip_prefix(from).ok().map(|relative| to.join(relative))
+            });
+            match moved {
+                Some(dst) => {
+                    t!(fs::create_dir_all(dst.parent().unwrap()));
+                    t!(fs::rename(path, &dst));
+                    contents.push_str(&format!("{}{}\n", kind, dst.display()));
+                }
+                None => contents.push_str(&format!("{}\n", line)),
+            }
+        }
+        t!(fs::write(&component_manifest, contents));
+    }
+}
+
+/// Returns where the install manifest of `prefix` is kept: in the
+/// `install.localstatedir` directory if that is set, as it's state of the
+/// installation, or else in the `rustlib` directory of the install libdir.
+fn install_manifest_path(config: &Config, prefix: &Path) -> PathBuf {
+    match &config.localstatedir {
+        Some(localstatedir) => {
+            with_destdir(config, prefix.join(localstatedir)).join("rust").join(INSTALL_MANIFEST)
+        }
+        None => {
+            let libdir = prefix.join(default_path(&config.libdir, "lib"));
+            with_destdir(config, libdir).join("rustlib").join(INSTALL_MANIFEST)
+        }
+    }
+}
+
+/// File listing everything `x.py install` put into the prefix, for
+/// `x.py uninstall`. See `install_manifest_path` for where it's kept.
+///
+/// Each line is either `file <hash> <path>`, with the hash of the file's
+/// contents when it was installed, or `dir <path>`. The paths are where the
+/// files end up, without the `DESTDIR` they were staged in, so the manifest
+/// can be shipped by packages built from a staged install.
+const INSTALL_MANIFEST: &str = "x-install-manifest";
+
+/// Adds the files installed from `tarball` to the install manifest.
+///
+/// `install.sh` already lists the files of each component it installs in
+/// `rustlib/manifest-<component>`, so those lists are what gets recorded,
+/// along with the installer's own bookkeeping files.
+fn record_installed(
+    config: &Config,
+    libdir: &Path,
+    manifest_path: &Path,
+    tarball: &GeneratedTarball,
+) {
+    let rustlib = libdir.join("rustlib");
+    let mut manifest = read_install_manifest(manifest_path);
+    let mut dirs = BTreeSet::new();
+
+    let mut installed = vec![
+        rustlib.join("components"),
+        rustlib.join("rust-installer-version"),
+        rustlib.join("uninstall.sh"),
+    ];
+    let components = t!(fs::read_to_string(tarball.decompressed_output().join("components")));
+    for component in components.lines() {
+        let component_manifest = rustlib.join(format!("manifest-{}", component));
+        for line in t!(fs::read_to_string(&component_manifest)).lines() {
+            if let Some(file) = line.strip_prefix("file:") {
+                installed.push(file.into());
+            } else if let Some(dir) = line.strip_prefix("dir:") {
+                dirs.insert(PathBuf::from(dir));
+                t!(walk_dir(Path::new(dir), &mut installed, &mut dirs));
+            }
+        }
+        installed.push(component_manifest);
+    }
+
+    for dir in dirs {
+        manifest.insert(without_destdir(config, &dir), None);
+    }
+    for path in installed {
+        if path.is_file() {
+            let hash = t!(hash_file(&path));
+            manifest.insert(without_destdir(config, &path), Some(hash));
+        }
+    }
+
+    let mut contents = String::new();
+    for (path, hash) in &manifest {
+        match hash {
+            Some(hash) => contents.push_str(&format!("file {:016x} {}\n", hash, path.display())),
+            None => contents.push_str(&format!("dir {}\n", path.display())),
+        }
+    }
+    t!(fs::create_dir_all(manifest_path.parent().unwrap()));
+    t!(fs::write(manifest_path, contents));
+}
+
+/// Collects the files below `dir` into `files`, and its subdirectories into
+/// `dirs`.
+fn walk_dir(dir: &Path, files: &mut Vec<PathBuf>, dirs: &mut BTreeSet<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            dirs.insert(path.clone());
+            walk_dir(&path, files, dirs)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Reads an install manifest, mapping each installed path to the hash of its
+/// contents, or to `None` for directories.
+fn read_install_manifest(path: &Path) -> BTreeMap<PathBuf, Option<u64>> {
+    let mut manifest = BTreeMap::new();
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(_) => return manifest,
+    };
+    for line in contents.lines() {
+        if let Some(dir) = line.strip_prefix("dir ") {
+            manifest.insert(dir.into(), None);
+        } else if let Some(file) = line.strip_prefix("file ") {
+            let mut parts = file.splitn(2, ' ');
+            match (parts.next().and_then(|hash| u64::from_str_radix(hash, 16).ok()), parts.next()) {
+                (Some(hash), Some(file)) => manifest.insert(file.into(), Some(hash)),
+                _ => panic!("invalid line in {}: {}", path.display(), line),
+            };
+        }
+    }
+    manifest
+}
+
+/// Hashes the contents of `path` with 64-bit FNV-1a, which is stable across
+/// builds of bootstrap, unlike `DefaultHasher`.
+fn hash_file(path: &Path) -> io::Result<u64> {
+    let mut file = fs::File::open(path)?;
+    let mut buf = [0; 64 * 1024];
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            return Ok(hash);
+        }
+        for byte in &buf[..n] {
+            hash = (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
+
+/// Removes what `x.py install` recorded in the install manifest of the
+/// configured prefix. Files changed since they were installed are kept, and
+/// directories are only removed once they are empty.
+pub fn uninstall(build: &Build) {
+    let config = &build.config;
+    let prefix = default_path(&config.prefix, "/usr/local");
+    let manifest_path = install_manifest_path(config, &prefix);
+    if !manifest_path.exists() {
+        eprintln!(
+            "error: no {} found, so there is nothing `x.py install` installed to {}",
+            manifest_path.display(),
+            with_destdir(config, prefix).display()
+        );
+        std::process::exit(1);
+    }
+    let manifest = read_install_manifest(&manifest_path);
+
+    // The standard install directories stay, even if they end up empty.
+    let keep_dirs = [
+        default_path(&config.sysconfdir, "/etc"),
+        default_path(&config.datadir, "share"),
+        default_path(&config.docdir, "share/doc"),
+        default_path(&config.mandir, "share/man"),
+        default_path(&config.libdir, "lib"),
+        default_path(&config.infodir, "share/info"),
+        default_path(&config.sbindir, "sbin"),
+        config.bindir.clone(),
+        PathBuf::new(),
+    ]
+    .iter()
+    .map(|dir| with_destdir(config, prefix.join(dir)))
+    .collect::<BTreeSet<_>>();
+
+    let mut removed = 0;
+    let mut changed = Vec::new();
+    let mut dirs = BTreeSet::new();
+    for (path, hash) in &manifest {
+        let path = with_destdir(config, path.clone());
+        let hash = match hash {
+            Some(hash) => *hash,
+            None => {
+                dirs.insert(path);
+                continue;
+            }
+        };
+        match hash_file(&path) {
+            Ok(current) if current == hash => {
+                build.verbose(&format!("removing {}", path.display()));
+                if !config.dry_run {
+                    t!(fs::remove_file(&path));
+                }
+                removed += 1;
+                dirs.extend(
+                    path.ancestors()
+                        .skip(1)
+                        .take_while(|dir| !keep_dirs.contains(*dir))
+                        .filter(|dir| keep_dirs.iter().any(|keep| dir.starts_with(keep)))
+                        .map(Path::to_path_buf),
+                );
+            }
+            Ok(_) => changed.push(path),
+            // Already removed.
+            Err(_) => {}
+        }
+    }
+    if !config.dry_run {
+        // Children sort after their parents, so this removes them first.
+        for dir in dirs.iter().rev() {
+            let _ = fs::remove_dir(dir);
+        }
+        if changed.is_empty() {
+            t!(fs::remove_file(&manifest_path));
+            if config.localstatedir.is_some() {
+                let _ = fs::remove_dir(manifest_path.parent().unwrap());
+            }
+        }
+    }
+
+    println!("Removed {} installed files from {}", removed, with_destdir(config, prefix).display());
+    if !changed.is_empty() {
+        println!(
+            "warning: kept {} files that were changed since they were installed:",
+            changed.len()
+        );
+        for path in changed {
+            println!("    {}", path.display());
+        }
+    }
 }
 
 fn default_path(config: &Option<PathBuf>, default: &str) -> PathBuf {
     PathBuf::from(config.as_ref().cloned().unwrap_or_else(|| PathBuf::from(default)))
 }
 
-fn prepare_dir(mut path: PathBuf) -> String {
+fn prepare_dir(config: &Config, path: PathBuf) -> String {
+    sanitize_sh(&with_destdir(config, path))
+}
+
+/// Returns the directory the installation is staged in, if any: the `DESTDIR`
+/// environment variable, or else `install.destdir`.
+fn destdir(config: &Config) -> Option<PathBuf> {
+    env::var_os("DESTDIR")
+        .filter(|destdir| !destdir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| config.destdir.clone())
+}
+
+/// Maps a staged path back to where it ends up once the staging directory is
+/// gone, undoing `with_destdir`.
+fn without_destdir(config: &Config, path: &Path) -> PathBuf {
+    let destdir = match destdir(config) {
+        Some(destdir) => absolute(destdir),
+        None => return path.to_path_buf(),
+    };
+    match path.strip_prefix(&destdir) {
+        Ok(relative) => Path::new("/").join(relative),
+        Err(_) => path.to_path_buf(),
+    }
+}
+
+fn with_destdir(config: &Config, mut path: PathBuf) -> PathBuf {
     // The DESTDIR environment variable is a standard way to install software in a subdirectory
     // while keeping the original directory structure, even if the prefix or other directories
     // contain absolute paths.
     //
     // More information on the environment variable is available here:
     // https://www.gnu.org/prep/standards/html_node/DESTDIR.html
-    if let Some(destdir) = env::var_os("DESTDIR").map(PathBuf::from) {
+    if let Some(destdir) = destdir(config) {
         let without_destdir = path.clone();
         path = destdir;
         // Custom .join() which ignores disk roots.
@@ -77,18 +377,34 @@ fn prepare_dir(mut path: PathBuf) -> String {
     // The installation command is not executed from the current directory, but from a temporary
     // directory. To prevent relative paths from breaking this converts relative paths to absolute
     // paths. std::fs::canonicalize is not used as that requires the path to actually be present.
+    absolute(path)
+}
+
+fn absolute(mut path: PathBuf) -> PathBuf {
     if path.is_relative() {
         path = std::env::current_dir().expect("failed to get the current directory").join(path);
         assert!(path.is_absolute(), "could not make the path relative");
     }
+    path
+}
 
-    sanitize_sh(&path)
+/// Exits with an error if `component`, which is only distributed as part of
+/// the extended set of tools, is installed without `build.extended`.
+fn require_extended(builder: &Builder<'_>, component: &str) {
+    if !builder.config.extended {
+        eprintln!(
+            "error: installing {} requires `build.extended = true` in config.toml",
+            component
+        );
+        std::process::exit(1);
+    }
 }
 
 macro_rules! install {
     (($sel:ident, $builder:ident, $_config:ident),
        $($name:ident,
        $path:expr,
+       names: $names:expr,
        $default_cond:expr,
        only_hosts: $only_hosts:expr,
        $run_item:block $(, $c:ident)*;)+) => {
@@ -115,7 +431,11 @@ impl Step for $name {
 
             fn should_run(run: ShouldRun<'_>) -> ShouldRun<'_> {
                 let $_config = &run.builder.config;
-                run.path($path).default_condition($default_cond)
+                // The component can also be selected by its name, to only
+                // install some of them.
+                let mut paths = vec![$path];
+                paths.extend_from_slice($names);
+                run.paths(&paths).default_condition($default_cond)
             }
 
             fn make_run(run: RunConfig<'_>) {
@@ -128,16 +448,24 @@ fn make_run(run: RunConfig<'_>) {
             fn run($sel, $builder: &Builder<'_>) {
                 $run_item
             }
+
+            fn target(&self) -> Option<TargetSelection> {
+                Some(self.target)
+            }
+
+            fn stage(&self) -> Option<u32> {
+                Some(self.compiler.stage)
+            }
         })+
     }
 }
 
 install!((self, builder, _config),
-    Docs, "src/doc", _config.docs, only_hosts: false, {
+    Docs, "src/doc", names: &["docs", "rust-docs"], _config.docs, only_hosts: false, {
         let tarball = builder.ensure(dist::Docs { host: self.target }).expect("missing docs");
         install_sh(builder, "docs", self.compiler.stage, Some(self.target), &tarball);
     };
-    Std, "library/std", true, only_hosts: false, {
+    Std, "library/std", names: &["std", "rust-std"], true, only_hosts: false, {
         for target in &builder.targets {
             let tarball = builder.ensure(dist::Std {
                 compiler: self.compiler,
@@ -146,11 +474,12 @@ fn run($sel, $builder: &Builder<'_>) {
             install_sh(builder, "std", self.compiler.stage, Some(*target), &tarball);
         }
     };
-    Cargo, "cargo", Self::should_build(_config), only_hosts: true, {
+    Cargo, "cargo", names: &[], Self::should_build(_config), only_hosts: true, {
         let tarball = builder.ensure(dist::Cargo { compiler: self.compiler, target: self.target });
         install_sh(builder, "cargo", self.compiler.stage, Some(self.target), &tarball);
     };
-    Rls, "rls", Self::should_build(_config), only_hosts: true, {
+    Rls, "rls", names: &[], Self::should_build(_config), only_hosts: true, {
+        require_extended(builder, "rls");
         if let Some(tarball) = builder.ensure(dist::Rls { compiler: self.compiler, target: self.target }) {
             install_sh(builder, "rls", self.compiler.stage, Some(self.target), &tarball);
         } else {
@@ -159,17 +488,21 @@ fn run($sel, $builder: &Builder<'_>) {
             );
         }
     };
-    RustAnalyzer, "rust-analyzer", Self::should_build(_config), only_hosts: true, {
+    RustAnalyzer, "rust-analyzer", names: &["rust-analyzer-preview"],
+        Self::should_build(_config), only_hosts: true, {
+        require_extended(builder, "rust-analyzer");
         let tarball = builder
             .ensure(dist::RustAnalyzer { compiler: self.compiler, target: self.target })
             .expect("missing rust-analyzer");
         install_sh(builder, "rust-analyzer", self.compiler.stage, Some(self.target), &tarball);
     };
-    Clippy, "clippy", Self::should_build(_config), only_hosts: true, {
+    Clippy, "clippy", names: &["clippy-preview"], Self::should_build(_config), only_hosts: true, {
+        require_extended(builder, "clippy");
         let tarball = builder.ensure(dist::Clippy { compiler: self.compiler, target: self.target });
         install_sh(builder, "clippy", self.compiler.stage, Some(self.target), &tarball);
     };
-    Miri, "miri", Self::should_build(_config), only_hosts: true, {
+    Miri, "miri", names: &["miri-preview"], Self::should_build(_config), only_hosts: true, {
+        require_extended(builder, "miri");
         if let Some(tarball) = builder.ensure(dist::Miri { compiler: self.compiler, target: self.target }) {
             install_sh(builder, "miri", self.compiler.stage, Some(self.target), &tarball);
         } else {
@@ -178,7 +511,8 @@ fn run($sel, $builder: &Builder<'_>) {
             );
         }
     };
-    Rustfmt, "rustfmt", Self::should_build(_config), only_hosts: true, {
+    Rustfmt, "rustfmt", names: &["rustfmt-preview"],
+        Self::should_build(_config), only_hosts: true, {
         if let Some(tarball) = builder.ensure(dist::Rustfmt {
             compiler: self.compiler,
             target: self.target
@@ -190,7 +524,9 @@ fn run($sel, $builder: &Builder<'_>) {
             );
         }
     };
-    Analysis, "analysis", Self::should_build(_config), only_hosts: false, {
+    Analysis, "analysis", names: &["rust-analysis"],
+        Self::should_build(_config), only_hosts: false, {
+        require_extended(builder, "analysis");
         let tarball = builder.ensure(dist::Analysis {
             // Find the actual compiler (handling the full bootstrap option) which
             // produced the save-analysis data because that data isn't copied
@@ -200,7 +536,7 @@ fn run($sel, $builder: &Builder<'_>) {
         }).expect("missing analysis");
         install_sh(builder, "analysis", self.compiler.stage, Some(self.target), &tarball);
     };
-    Rustc, "src/librustc", true, only_hosts: true, {
+    Rustc, "src/librustc", names: &["rustc"], true, only_hosts: true, {
         let tarball = builder.ensure(dist::Rustc {
             compiler: builder.compiler(builder.top_stage, self.target),
         });
@@ -221,7 +557,7 @@ impl Step for Src {
     fn should_run(run: ShouldRun<'_>) -> ShouldRun<'_> {
         let config = &run.builder.config;
         let cond = config.extended && config.tools.as_ref().map_or(true, |t| t.contains("src"));
-        run.path("src").default_condition(cond)
+        run.paths(&["src", "rust-src"]).default_condition(cond)
     }
 
     fn make_run(run: RunConfig<'_>) {
@@ -232,4 +568,132 @@ fn run(self, builder: &Builder<'_>) {
         let tarball = builder.ensure(dist::Src);
         install_sh(builder, "src", self.stage, None, &tarball);
     }
+
+    fn stage(&self) -> Option<u32> {
+        Some(self.stage)
+    }
+}
+
+/// Checks the toolchain installed by the steps above, before anything picks up
+/// a broken prefix: `rustc` has to run and build a working hello world against
+/// the installed sysroot, and `cargo` has to run if it was installed.
+///
+/// The installed binaries are run without the dynamic library path bootstrap
+/// itself runs with, so this also covers their rpaths.
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+pub struct Validate {
+    pub target: TargetSelection,
+}
+
+impl Step for Validate {
+    type Output = ();
+    const DEFAULT: bool = true;
+    const ONLY_HOSTS: bool = true;
+
+    fn should_run(run: ShouldRun<'_>) -> ShouldRun<'_> {
+        let validate = run.builder.config.install_validate;
+        run.path("validate").default_condition(validate)
+    }
+
+    fn make_run(run: RunConfig<'_>) {
+        run.builder.ensure(Validate { target: run.target });
+    }
+
+    fn run(self, builder: &Builder<'_>) {
+        if builder.config.dry_run {
+            return;
+        }
+        if self.target != builder.config.build {
+            builder.info(&format!("skipping install check for {}: can't run it here", self.target));
+            return;
+        }
+        builder.info(&format!("Validating installed toolchain ({})", self.target));
+
+        let prefix = default_path(&builder.config.prefix, "/usr/local");
+        let bindir = with_destdir(&builder.config, prefix.join(&builder.config.bindir));
+        let prefix = with_destdir(&builder.config, prefix);
+        let rustc = bindir.join(exe("rustc", self.target));
+        let cargo = bindir.join(exe("cargo", self.target));
+
+        let dir = builder.out.join(&*self.target.triple).join("install-check");
+        let _ = fs::remove_dir_all(&dir);
+        t!(fs::create_dir_all(&dir));
+        let hello = dir.join("hello.rs");
+        let hello_exe = dir.join(exe("hello", self.target));
+        t!(fs::write(&hello, "fn main() { println!(\"Hello, world!\"); }\n"));
+
+        let mut checks: Vec<(&str, Result<(), String>)> = Vec::new();
+        checks.push((
+            "rustc --version",
+            installed_output(Command::new(&rustc).arg("--version")).map(drop),
+        ));
+        checks.push((
+            "rustc sysroot",
+            installed_output(Command::new(&rustc).arg("--print").arg("sysroot")).and_then(
+                |sysroot| {
+                    let found = fs::canonicalize(&sysroot).unwrap_or_else(|_| sysroot.into());
+                    let expected = fs::canonicalize(&prefix).unwrap_or_else(|_| prefix.clone());
+                    if found == expected {
+                        Ok(())
+                    } else {
+                        Err(format!("expected {}, found {}", expected.display(), found.display()))
+                    }
+                },
+            ),
+        ));
+        checks.push((
+            "hello world",
+            installed_output(Command::new(&rustc).arg(&hello).arg("-o").arg(&hello_exe))
+                .and_then(|_| installed_output(&mut Command::new(&hello_exe)))
+                .and_then(|stdout| {
+                    if stdout == "Hello, world!" {
+                        Ok(())
+                    } else {
+                        Err(format!("unexpected output: {:?}", stdout))
+                    }
+                }),
+        ));
+        if cargo.exists() {
+            checks.push((
+                "cargo --version",
+                installed_output(Command::new(&cargo).arg("--version")).map(drop),
+            ));
+        }
+
+        let mut failed = false;
+        for (name, result) in &checks {
+            match result {
+                Ok(()) => builder.info(&format!("  {} ... ok", name)),
+                Err(e) => {
+                    builder.info(&format!("  {} ... FAILED\n    {}", name, e));
+                    failed = true;
+                }
+            }
+        }
+        if failed {
+            eprintln!("\nerror: the toolchain installed in {} is broken", prefix.display());
+            std::process::exit(1);
+        }
+        t!(fs::remove_dir_all(&dir));
+    }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
+}
+
+/// Runs a binary from the installed toolchain, without the library path
+/// bootstrap runs with, and returns its trimmed stdout.
+fn installed_output(cmd: &mut Command) -> Result<String, String> {
+    cmd.env_remove(dylib_path_var());
+    let output = cmd.output().map_err(|e| format!("failed to run {:?}: {}", cmd, e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "{:?} exited with {}: {}",
+            cmd,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
 }
@@ -104,49 +104,69 @@
 //! also check out the `src/bootstrap/README.md` file for more information.
 
 use std::cell::{Cell, RefCell};
+use std::collections::hash_map::DefaultHasher;
 use std::collections::{HashMap, HashSet};
 use std::env;
+use std::ffi::OsString;
 use std::fs::{self, File, OpenOptions};
+use std::hash::{Hash, Hasher};
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 use std::process::{self, Command};
 use std::slice;
 use std::str;
+use std::time::{Instant, SystemTime};
 
 #[cfg(unix)]
 use std::os::unix::fs::symlink as symlink_file;
 #[cfg(windows)]
 use std::os::windows::fs::symlink_file;
 
-use build_helper::{mtime, output, run, run_suppressed, t, try_run, try_run_suppressed};
+use build_helper::{mtime, output, t, try_run, try_run_suppressed};
 use filetime::FileTime;
 
 use crate::config::{LlvmLibunwind, TargetSelection};
 use crate::util::{exe, libdir, CiEnv};
 
+mod artifact_cache;
 mod builder;
 mod cache;
 mod cc_detect;
+mod change_detection;
+pub mod change_tracker;
 mod channel;
 mod check;
 mod clean;
 mod compile;
+mod completions;
 mod config;
+mod container;
 mod dist;
 mod doc;
 mod flags;
 mod format;
+mod hooks;
 mod install;
+mod lock;
+mod logging;
 mod metadata;
 mod native;
+mod picker;
+mod plugin;
+mod priority;
 mod run;
 mod sanity;
 mod setup;
+mod smoke;
+mod step_cache;
+mod suggest;
 mod tarball;
 mod test;
 mod tool;
 mod toolstate;
+mod trace;
 pub mod util;
+mod vcs;
 
 #[cfg(windows)]
 mod job;
@@ -167,7 +187,7 @@ pub unsafe fn setup(_build: &mut crate::Build) {}
 
 use crate::cache::{Interned, INTERNER};
 pub use crate::config::Config;
-pub use crate::flags::Subcommand;
+pub use crate::flags::{Color, Subcommand};
 
 const LLVM_TOOLS: &[&str] = &[
     "llvm-cov",      // used to generate coverage report
@@ -185,6 +205,16 @@ pub unsafe fn setup(_build: &mut crate::Build) {}
     "opt",           // used to optimize LLVM bytecode
 ];
 
+/// The names LLD is copied under into the `self-contained` directory of the
+/// sysroot's `bin` with `rust.lld = "self-contained"`. LLD picks its flavor
+/// from the name it's invoked with.
+const LLD_SELF_CONTAINED_NAMES: &[&str] = &[
+    "rust-lld", // invoked directly with `-C linker=rust-lld`
+    "ld.lld",   // ELF, found by `cc -fuse-ld=lld`
+    "ld64.lld", // Mach-O
+    "lld-link", // COFF, MSVC-compatible
+];
+
 pub const VERSION: usize = 2;
 
 /// A structure representing a Rust compiler.
@@ -233,6 +263,10 @@ pub struct Build {
     // Properties derived from the above configuration
     src: PathBuf,
     out: PathBuf,
+    // Root of the intermediate Cargo output, either `out` or `build.tmp-dir`
+    tmp_out: PathBuf,
+    // Version control system `src` is checked out with
+    vcs: vcs::Vcs,
     rust_info: channel::GitInfo,
     cargo_info: channel::GitInfo,
     rls_info: channel::GitInfo,
@@ -267,10 +301,24 @@ pub struct Build {
     crates: HashMap<Interned<String>, Crate>,
     is_sudo: bool,
     ci_env: CiEnv,
+    // Files changed on this branch, if `--ci-change-detection` was passed
+    changed_files: Option<Vec<PathBuf>>,
     delayed_failures: RefCell<Vec<String>>,
+    // Tests that failed in a `--no-fail-fast` run, for the summary at the end
+    failed_tests: RefCell<Vec<test::FailedTest>>,
     prerelease_version: Cell<Option<u32>>,
     tool_artifacts:
         RefCell<HashMap<TargetSelection, HashMap<String, (&'static str, PathBuf, Vec<String>)>>>,
+    // The names of the steps currently running, innermost last
+    running_steps: RefCell<Vec<&'static str>>,
+    // Paths picked instead of ones matching no step, so that the dry run and
+    // the real run don't both ask
+    picked_paths: RefCell<HashMap<PathBuf, PathBuf>>,
+    // Targets the dry run found LLVM has to be built for, which the real run
+    // builds concurrently
+    llvm_to_build: RefCell<Vec<TargetSelection>>,
+    // Steps and commands run so far, if `--timings` was passed
+    trace: Option<trace::Trace>,
 }
 
 #[derive(Debug)]
@@ -346,10 +394,18 @@ impl Build {
     /// line and the filesystem `config`.
     ///
     /// By default all build output will be placed in the current directory.
-    pub fn new(config: Config) -> Build {
+    pub fn new(mut config: Config) -> Build {
+        logging::init();
         let src = config.src.clone();
         let out = config.out.clone();
 
+        // Build with copies of custom target specs, see `TargetSelection::with_spec_in`.
+        let specs = out.join("target-specs");
+        config.build = config.build.with_spec_in(&specs);
+        for target in config.hosts.iter_mut().chain(config.targets.iter_mut()) {
+            *target = target.with_spec_in(&specs);
+        }
+
         let is_sudo = match env::var_os("SUDO_USER") {
             Some(sudo_user) => match env::var_os("USER") {
                 Some(user) => user != sudo_user,
@@ -358,8 +414,10 @@ pub fn new(config: Config) -> Build {
             None => false,
         };
 
+        let vcs = config.vcs.unwrap_or_else(|| vcs::Vcs::detect(&src));
         let ignore_git = config.ignore_git;
-        let rust_info = channel::GitInfo::new(ignore_git, &src);
+        let rust_info =
+            channel::GitInfo::with_vcs(if ignore_git { vcs::Vcs::None } else { vcs }, &src);
         let cargo_info = channel::GitInfo::new(ignore_git, &src.join("src/tools/cargo"));
         let rls_info = channel::GitInfo::new(ignore_git, &src.join("src/tools/rls"));
         let rust_analyzer_info =
@@ -368,8 +426,9 @@ pub fn new(config: Config) -> Build {
         let miri_info = channel::GitInfo::new(ignore_git, &src.join("src/tools/miri"));
         let rustfmt_info = channel::GitInfo::new(ignore_git, &src.join("src/tools/rustfmt"));
 
-        // we always try to use git for LLVM builds
-        let in_tree_llvm_info = channel::GitInfo::new(false, &src.join("src/llvm-project"));
+        // we always try to use git for LLVM builds, unless there's no VCS at all
+        let in_tree_llvm_info =
+            channel::GitInfo::new(vcs == vcs::Vcs::None, &src.join("src/llvm-project"));
 
         let initial_target_libdir_str = if config.dry_run {
             "/dummy/lib/path/to/lib/".to_string()
@@ -399,6 +458,35 @@ pub fn new(config: Config) -> Build {
             .unwrap()
             .to_path_buf();
 
+        let tmp_out = match &config.tmp_dir {
+            Some(dir) if config.dry_run => dir.clone(),
+            Some(dir) => match util::check_tmp_dir(dir) {
+                Ok(()) => dir.clone(),
+                Err(e) => {
+                    println!(
+                        "warning: not using build.tmp-dir `{}`: {}; falling back to `{}`",
+                        dir.display(),
+                        e,
+                        out.display()
+                    );
+                    out.clone()
+                }
+            },
+            None => out.clone(),
+        };
+
+        let changed_files = config.ci_change_detection.as_ref().and_then(|base| {
+            let files = vcs.changed_files(&src, base);
+            if files.is_none() {
+                println!(
+                    "warning: couldn't determine the files changed since `{}`; \
+                     not skipping any steps",
+                    base
+                );
+            }
+            files
+        });
+
         let version = std::fs::read_to_string(src.join("src").join("version"))
             .expect("failed to read src/version");
         let version = version.trim();
@@ -416,12 +504,19 @@ pub fn new(config: Config) -> Build {
             build: config.build,
             hosts: config.hosts.clone(),
             targets: config.targets.clone(),
+            trace: if config.timings {
+                Some(trace::Trace::new(out.join("trace.json")))
+            } else {
+                None
+            },
 
             config,
             version: version.to_string(),
             src,
             out,
+            tmp_out,
 
+            vcs,
             rust_info,
             cargo_info,
             rls_info,
@@ -437,11 +532,22 @@ pub fn new(config: Config) -> Build {
             crates: HashMap::new(),
             is_sudo,
             ci_env: CiEnv::current(),
+            changed_files,
             delayed_failures: RefCell::new(Vec::new()),
+            failed_tests: RefCell::new(Vec::new()),
             prerelease_version: Cell::new(None),
             tool_artifacts: Default::default(),
+            running_steps: RefCell::new(Vec::new()),
+            picked_paths: RefCell::new(HashMap::new()),
+            llvm_to_build: RefCell::new(Vec::new()),
         };
 
+        // The toolchain and sources are inspected by the bootstrap running in
+        // the container instead.
+        if build.config.in_container.is_some() {
+            return build;
+        }
+
         build.verbose("finding compilers");
         cc_detect::find(&mut build);
         build.verbose("running sanity check");
@@ -478,16 +584,61 @@ pub fn build(&mut self) {
             job::setup(self);
         }
 
-        if let Subcommand::Format { check } = self.config.cmd {
-            return format::format(self, check);
+        if let Some(image) = &self.config.in_container {
+            return container::run_in_container(self, image);
         }
 
-        if let Subcommand::Clean { all } = self.config.cmd {
-            return clean::clean(self, all);
+        // At `-vv` and with `--on-fail=write-script`, the shims print or
+        // write their commands with the variables bootstrap changed in this
+        // environment.
+        let write_script = self.config.on_fail.as_deref() == Some("write-script");
+        if (self.is_verbose_than(1) || write_script) && !self.config.dry_run {
+            util::record_parent_env(&self.out.join("tmp").join("parent-env"));
         }
 
-        if let Subcommand::Setup { profile } = &self.config.cmd {
-            return setup::setup(&self.config.src, *profile);
+        let _lock = lock::acquire(self);
+
+        if let Subcommand::Format { check, all } = self.config.cmd {
+            return format::format(self, check, all);
+        }
+
+        if let Subcommand::Test { .. } = self.config.cmd {
+            if !self.config.dry_run {
+                let _ = fs::remove_file(self.out.join(test::FAILED_TESTS));
+                let _ = fs::remove_file(self.out.join(test::FAILURE_SUMMARY));
+            }
+        }
+
+        if let Subcommand::Clean { all, stage, ref targets, llvm, keep_downloads, keep_llvm } =
+            self.config.cmd
+        {
+            return clean::clean(self, all, stage, targets, llvm, keep_downloads, keep_llvm);
+        }
+
+        if let Subcommand::Gc { max_age } = self.config.cmd {
+            return clean::gc(self, max_age);
+        }
+
+        if let Subcommand::Setup { profile, hook } = self.config.cmd {
+            return match profile {
+                Some(profile) => setup::setup(&self.config.src, profile),
+                None => setup::setup_hooks(hook),
+            };
+        }
+        if !self.config.dry_run {
+            setup::update_git_hooks(&self.config.src);
+        }
+
+        if let Subcommand::Uninstall = self.config.cmd {
+            return install::uninstall(self);
+        }
+
+        if let Subcommand::Suggest { run } = self.config.cmd {
+            return suggest::suggest(self, run);
+        }
+
+        if let Subcommand::CiSmoke { budget } = self.config.cmd {
+            return smoke::ci_smoke(self, budget);
         }
 
         {
@@ -499,29 +650,48 @@ pub fn build(&mut self) {
             }
         }
 
-        if !self.config.dry_run {
-            {
-                self.config.dry_run = true;
-                let builder = builder::Builder::new(&self);
-                builder.execute_cli();
-            }
-            self.config.dry_run = false;
+        let start = SystemTime::now();
+        // Everything is first run as a dry run, which `--json` and `--explain`
+        // describe, before running it for real unless `--dry-run` was given.
+        let dry_run = self.config.dry_run;
+        {
+            self.config.dry_run = true;
             let builder = builder::Builder::new(&self);
             builder.execute_cli();
-        } else {
+            if self.config.json_plan {
+                builder.print_plan();
+            }
+            if self.config.explain {
+                builder.print_explanations();
+            }
+        }
+        if !dry_run {
+            self.config.dry_run = false;
             let builder = builder::Builder::new(&self);
             builder.execute_cli();
         }
 
+        if let Some(trace) = &self.trace {
+            println!("Build trace written to {}", trace.finish().display());
+        }
+
         // Check for postponed failures from `test --no-fail-fast`.
         let failures = self.delayed_failures.borrow();
         if failures.len() > 0 {
-            println!("\n{} command(s) did not execute successfully:\n", failures.len());
+            let msg = format!("{} command(s) did not execute successfully:", failures.len());
+            println!("\n{}\n", self.paint(31, &msg));
             for failure in failures.iter() {
                 println!("  - {}\n", failure);
             }
+            test::print_failure_summary(self);
             process::exit(1);
         }
+
+        clean::record_config(self);
+        if let Subcommand::Dist { .. } = self.config.cmd {
+            clean::record_disk_usage(self, "dist", &[self.out.join("dist")]);
+        }
+        clean::apply_retention(self, start);
     }
 
     /// Clear out `dir` if `input` is newer.
@@ -545,29 +715,35 @@ fn clear_if_dirty(&self, dir: &Path, input: &Path) -> bool {
     /// Gets the space-separated set of activated features for the standard
     /// library.
     fn std_features(&self, target: TargetSelection) -> String {
-        let mut features = "panic-unwind".to_string();
+        let mut features = vec!["panic-unwind"];
 
         match self.config.llvm_libunwind.unwrap_or_default() {
-            LlvmLibunwind::InTree => features.push_str(" llvm-libunwind"),
-            LlvmLibunwind::System => features.push_str(" system-llvm-libunwind"),
+            LlvmLibunwind::InTree => features.push("llvm-libunwind"),
+            LlvmLibunwind::System => features.push("system-llvm-libunwind"),
             LlvmLibunwind::No => {}
         }
         if self.config.backtrace {
-            features.push_str(" backtrace");
+            features.push("backtrace");
         }
         if self.config.profiler_enabled(target) {
-            features.push_str(" profiler");
+            features.push("profiler");
         }
-        features
+        for feature in &self.config.std_features {
+            match feature.strip_prefix('-') {
+                Some(disabled) => features.retain(|f| *f != disabled),
+                None => features.push(feature),
+            }
+        }
+        features.join(" ")
     }
 
     /// Gets the space-separated set of activated features for the compiler.
-    fn rustc_features(&self) -> String {
+    fn rustc_features(&self, target: TargetSelection) -> String {
         let mut features = String::new();
         if self.config.jemalloc {
             features.push_str("jemalloc");
         }
-        if self.config.llvm_enabled() {
+        if self.config.llvm_enabled(target) {
             features.push_str(" llvm");
         }
 
@@ -601,7 +777,8 @@ fn tools_dir(&self, compiler: Compiler) -> PathBuf {
     /// Returns the root directory for all output generated in a particular
     /// stage when running with a particular host compiler.
     ///
-    /// The mode indicates what the root directory is for.
+    /// The mode indicates what the root directory is for. This lives under
+    /// `build.tmp-dir` if one is configured.
     fn stage_out(&self, compiler: Compiler, mode: Mode) -> PathBuf {
         let suffix = match mode {
             Mode::Std => "-std",
@@ -610,7 +787,37 @@ fn stage_out(&self, compiler: Compiler, mode: Mode) -> PathBuf {
             Mode::ToolBootstrap => "-bootstrap-tools",
             Mode::ToolStd | Mode::ToolRustc => "-tools",
         };
-        self.out.join(&*compiler.host.triple).join(format!("stage{}{}", compiler.stage, suffix))
+        self.tmp_out.join(&*compiler.host.triple).join(format!("stage{}{}", compiler.stage, suffix))
+    }
+
+    /// Stops the build if either output directory is about to run out of space.
+    fn check_free_space(&self) {
+        if self.config.dry_run {
+            return;
+        }
+        for dir in &[&self.out, &self.tmp_out] {
+            if let Some(avail) = util::free_space(dir) {
+                self.require_free_space(dir, avail, "stopping the build");
+            }
+        }
+    }
+
+    /// Exits with an error about `action` if `avail`, the free space in `dir`,
+    /// is below `build.min-free-space`.
+    fn require_free_space(&self, dir: &Path, avail: u64, action: &str) {
+        if avail < self.config.min_free_space {
+            eprintln!(
+                "error: only {} MiB of disk space left in {}, {}",
+                avail >> 20,
+                dir.display(),
+                action
+            );
+            eprintln!(
+                "help: free up some space, for example with `x.py gc`, or lower \
+                 `build.min-free-space` in config.toml"
+            );
+            process::exit(1);
+        }
     }
 
     /// Returns the root output directory for all Cargo output in a given stage,
@@ -632,6 +839,15 @@ fn lld_out(&self, target: TargetSelection) -> PathBuf {
         self.out.join(&*target.triple).join("lld")
     }
 
+    /// Value of `RUST_TARGET_PATH` which lets rustc find the custom target
+    /// specs copied by `Build::new` by their triple, even when it's only passed
+    /// the triple, as by build scripts or compiletest.
+    fn rust_target_path(&self) -> OsString {
+        let mut paths = vec![self.out.join("target-specs")];
+        paths.extend(env::var_os("RUST_TARGET_PATH").iter().flat_map(env::split_paths));
+        t!(env::join_paths(paths))
+    }
+
     /// Output directory for all documentation for a target
     fn doc_out(&self, target: TargetSelection) -> PathBuf {
         self.out.join(&*target.triple).join("doc")
@@ -686,6 +902,9 @@ fn llvm_filecheck(&self, target: TargetSelection) -> PathBuf {
                     filecheck
                 }
             }
+        } else if self.llvm_out(self.config.build).join(native::PREBUILT_LLVM_KEY).exists() {
+            // An imported LLVM ships FileCheck in its install tree.
+            self.llvm_out(self.config.build).join("bin").join(exe("FileCheck", target))
         } else {
             let base = self.llvm_out(self.config.build).join("build");
             let base = if !self.ninja() && self.config.build.contains("msvc") {
@@ -738,8 +957,17 @@ fn run(&self, cmd: &mut Command) {
         if self.config.dry_run {
             return;
         }
-        self.verbose(&format!("running: {:?}", cmd));
-        run(cmd)
+        priority::apply(cmd, self.priority());
+        self.verbose_command(cmd);
+        let span = logging::exec_span(cmd);
+        let _enter = span.enter();
+        let start = Instant::now();
+        let success = try_run(cmd);
+        self.trace_command(cmd, start);
+        if !success {
+            self.write_failure_script(cmd);
+            process::exit(1);
+        }
     }
 
     /// Runs a command, printing out nice contextual information if it fails.
@@ -747,8 +975,17 @@ fn run_quiet(&self, cmd: &mut Command) {
         if self.config.dry_run {
             return;
         }
-        self.verbose(&format!("running: {:?}", cmd));
-        run_suppressed(cmd)
+        priority::apply(cmd, self.priority());
+        self.verbose_command(cmd);
+        let span = logging::exec_span(cmd);
+        let _enter = span.enter();
+        let start = Instant::now();
+        let success = try_run_suppressed(cmd);
+        self.trace_command(cmd, start);
+        if !success {
+            self.write_failure_script(cmd);
+            process::exit(1);
+        }
     }
 
     /// Runs a command, printing out nice contextual information if it fails.
@@ -758,8 +995,17 @@ fn try_run(&self, cmd: &mut Command) -> bool {
         if self.config.dry_run {
             return true;
         }
-        self.verbose(&format!("running: {:?}", cmd));
-        try_run(cmd)
+        priority::apply(cmd, self.priority());
+        self.verbose_command(cmd);
+        let span = logging::exec_span(cmd);
+        let _enter = span.enter();
+        let start = Instant::now();
+        let success = try_run(cmd);
+        if !success {
+            self.write_failure_script(cmd);
+        }
+        self.trace_command(cmd, start);
+        success
     }
 
     /// Runs a command, printing out nice contextual information if it fails.
@@ -769,17 +1015,76 @@ fn try_run_quiet(&self, cmd: &mut Command) -> bool {
         if self.config.dry_run {
             return true;
         }
-        self.verbose(&format!("running: {:?}", cmd));
-        try_run_suppressed(cmd)
+        priority::apply(cmd, self.priority());
+        self.verbose_command(cmd);
+        let span = logging::exec_span(cmd);
+        let _enter = span.enter();
+        let start = Instant::now();
+        let success = try_run_suppressed(cmd);
+        if !success {
+            self.write_failure_script(cmd);
+        }
+        self.trace_command(cmd, start);
+        success
+    }
+
+    /// Prints that `cmd` is run at `-v`, and at `-vv` as a shell command which
+    /// runs it with the variables bootstrap set, like the shims of rustc and
+    /// rustdoc print theirs.
+    fn verbose_command(&self, cmd: &Command) {
+        if self.is_verbose_than(1) {
+            self.verbose(&format!("running: {}", util::reproduction(cmd, &[])));
+        } else {
+            self.verbose(&format!("running: {:?}", cmd));
+        }
+    }
+
+    /// With `--on-fail=write-script`, writes a script to `build/on-fail`
+    /// running `cmd`, which failed, again, like the rustc shim does for rustc.
+    /// The name of the script is made unique by a hash of the command.
+    fn write_failure_script(&self, cmd: &Command) {
+        if self.config.on_fail.as_deref() != Some("write-script") {
+            return;
+        }
+        let mut hasher = DefaultHasher::new();
+        format!("{:?}", cmd).hash(&mut hasher);
+        let name = format!("{}-{:016x}", util::program_name(cmd), hasher.finish());
+        let script = util::write_reproduction_script(
+            &self.out.join("on-fail"),
+            &name,
+            cmd,
+            &[],
+            "the command failed",
+        );
+        println!("Wrote a script running the failed command to {}", script.display());
+    }
+
+    /// Adds `cmd`, which ran from `start` until now, to the `--timings` trace.
+    fn trace_command(&self, cmd: &Command, start: Instant) {
+        if let Some(trace) = &self.trace {
+            trace.command(cmd, start);
+        }
+    }
+
+    /// The priority to run the commands of the currently running step with:
+    /// that of the innermost running step with a `[build.step-priority]`
+    /// entry, falling back to `build.nice` and `build.ionice-class`.
+    fn priority(&self) -> config::Priority {
+        let steps = self.running_steps.borrow();
+        let step = steps.iter().rev().find_map(|step| self.config.step_priority.get(*step));
+        step.cloned().unwrap_or_default().or(self.config.priority)
     }
 
     pub fn is_verbose(&self) -> bool {
         self.verbosity > 0
     }
 
-    /// Prints a message if this build is configured in verbose mode.
+    /// Prints a message if this build is configured in verbose mode, or logs
+    /// it if `BOOTSTRAP_LOG` is set.
     fn verbose(&self, msg: &str) {
-        if self.is_verbose() {
+        if logging::enabled() {
+            logging::verbose(0, msg);
+        } else if self.is_verbose() {
             println!("{}", msg);
         }
     }
@@ -788,9 +1093,12 @@ pub fn is_verbose_than(&self, level: usize) -> bool {
         self.verbosity > level
     }
 
-    /// Prints a message if this build is configured in more verbose mode than `level`.
+    /// Prints a message if this build is configured in more verbose mode than `level`,
+    /// or logs it if `BOOTSTRAP_LOG` is set.
     fn verbose_than(&self, level: usize, msg: &str) {
-        if self.is_verbose_than(level) {
+        if logging::enabled() {
+            logging::verbose(level, msg);
+        } else if self.is_verbose_than(level) {
             println!("{}", msg);
         }
     }
@@ -802,6 +1110,38 @@ fn info(&self, msg: &str) {
         println!("{}", msg);
     }
 
+    /// Passes `--color` on to `cmd`, which is cargo or compiletest, so that
+    /// their output and that of the rustc they run is colored like
+    /// bootstrap's own messages.
+    fn forward_color(&self, cmd: &mut Command) {
+        match self.config.color {
+            Color::Always => {
+                // The explicit `TERM=xterm` environment is needed for
+                // `--color always` to actually work in CI. This env var was
+                // lost when compiling through the Makefile. Very strange.
+                if self.ci_env != CiEnv::None {
+                    cmd.env("TERM", "xterm");
+                }
+                cmd.arg("--color=always");
+            }
+            Color::Never => {
+                cmd.arg("--color=never");
+            }
+            Color::Auto => {}
+        }
+    }
+
+    /// Makes `msg` bold and colored with the ANSI `color` code, unless
+    /// bootstrap's messages aren't colored.
+    fn paint(&self, color: u8, msg: &str) -> String {
+        let colored = match self.config.color {
+            Color::Always => true,
+            Color::Never => false,
+            Color::Auto => util::stdout_is_tty(),
+        };
+        if colored { format!("\x1b[1;{}m{}\x1b[0m", color, msg) } else { msg.to_string() }
+    }
+
     /// Returns the number of parallel jobs that have been configured for this
     /// build.
     fn jobs(&self) -> u32 {
@@ -955,9 +1295,19 @@ fn no_std(&self, target: TargetSelection) -> Option<bool> {
     /// Returns `true` if the target will be tested using the `remote-test-client`
     /// and `remote-test-server` binaries.
     fn remote_tested(&self, target: TargetSelection) -> bool {
-        self.qemu_rootfs(target).is_some()
-            || target.contains("android")
-            || env::var_os("TEST_DEVICE_ADDR").is_some()
+        self.runner(target).is_none()
+            && (self.qemu_rootfs(target).is_some()
+                || target.contains("android")
+                || env::var_os("TEST_DEVICE_ADDR").is_some())
+    }
+
+    /// Returns the command that test binaries for `target` are run with, if one
+    /// was configured. The build triple always runs them directly.
+    fn runner(&self, target: TargetSelection) -> Option<&str> {
+        if target == self.config.build {
+            return None;
+        }
+        self.config.target_config.get(&target).and_then(|t| t.runner.as_deref())
     }
 
     /// Returns the root of the "rootfs" image that this target will be using,
@@ -1011,37 +1361,29 @@ fn force_use_stage1(&self, compiler: Compiler, target: TargetSelection) -> bool
     fn release(&self, num: &str) -> String {
         match &self.config.channel[..] {
             "stable" => num.to_string(),
-            "beta" => {
-                if self.rust_info.is_git() {
-                    format!("{}-beta.{}", num, self.beta_prerelease_version())
-                } else {
-                    format!("{}-beta", num)
-                }
-            }
+            "beta" => match self.beta_prerelease_version() {
+                Some(n) => format!("{}-beta.{}", num, n),
+                None => format!("{}-beta", num),
+            },
             "nightly" => format!("{}-nightly", num),
             _ => format!("{}-dev", num),
         }
     }
 
-    fn beta_prerelease_version(&self) -> u32 {
+    /// Returns the number of merge commits since we branched off master,
+    /// which is our beta number, or `None` if that isn't known, for example
+    /// in a source tarball or a shallow clone.
+    fn beta_prerelease_version(&self) -> Option<u32> {
         if let Some(s) = self.prerelease_version.get() {
-            return s;
-        }
-
-        // Figure out how many merge commits happened since we branched off master.
-        // That's our beta number!
-        // (Note that we use a `..` range, not the `...` symmetric difference.)
-        let count = output(
-            Command::new("git")
-                .arg("rev-list")
-                .arg("--count")
-                .arg("--merges")
-                .arg("refs/remotes/origin/master..HEAD")
-                .current_dir(&self.src),
-        );
-        let n = count.trim().parse().unwrap();
+            return Some(s);
+        }
+        if !self.rust_info.is_git() {
+            return None;
+        }
+
+        let n = self.vcs.merges_since(&self.src, "origin/master")?;
         self.prerelease_version.set(Some(n));
-        n
+        Some(n)
     }
 
     /// Returns the value of `release` above for Rust itself.
@@ -1145,7 +1487,10 @@ fn in_tree_crates(&self, root: &str, target: Option<TargetSelection>) -> Vec<&Cr
                         || target
                             .map(|t| self.config.profiler_enabled(t))
                             .unwrap_or_else(|| self.config.any_profiler_enabled()))
-                    && (dep != "rustc_codegen_llvm" || self.config.llvm_enabled())
+                    && (dep != "rustc_codegen_llvm"
+                        || target
+                            .map(|t| self.config.llvm_enabled(t))
+                            .unwrap_or_else(|| self.config.any_llvm_enabled()))
                 {
                     list.push(*dep);
                 }
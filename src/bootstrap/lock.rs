@@ -0,0 +1,121 @@
+//! The lock on the build directory.
+//!
+//! Two x.py invocations building into the same directory overwrite each
+//! other's stamps and race in cargo's target directories, which breaks the
+//! build in ways that are hard to trace back. So every invocation that builds
+//! something holds an exclusive lock on `build/lock` while it runs. When the
+//! lock is taken, an interactive invocation waits for it, and one that isn't
+//! run from a terminal fails with the PID of the holder, which is written to
+//! the lock file. `--bypass-bootstrap-lock` skips the lock altogether.
+
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::process;
+
+use build_helper::t;
+
+use crate::util;
+use crate::Build;
+
+/// Set to the path of the lock file while it is held, so that x.py
+/// invocations nested in the build, like those of `x.py suggest --run`,
+/// don't wait for the invocation that runs them.
+const HELD_VAR: &str = "BOOTSTRAP_BUILD_LOCK";
+
+/// The held lock, which is released when this is dropped.
+pub struct BuildLock {
+    _file: File,
+}
+
+/// Takes the lock on the build directory of `build`, unless it's bypassed,
+/// this is a dry run, or the lock is already held by an x.py invocation
+/// this one was run by.
+pub fn acquire(build: &Build) -> Option<BuildLock> {
+    if build.config.bypass_bootstrap_lock || build.config.dry_run {
+        return None;
+    }
+    let path = build.out.join("lock");
+    if env::var_os(HELD_VAR).map_or(false, |held| Path::new(&held) == path) {
+        return None;
+    }
+
+    t!(fs::create_dir_all(&build.out));
+    let mut file = t!(OpenOptions::new().read(true).write(true).create(true).open(&path));
+    if !t!(lock(&file, false)) {
+        let mut pid = String::new();
+        let _ = file.read_to_string(&mut pid);
+        let holder = match pid.trim() {
+            "" => "another x.py invocation".to_string(),
+            pid => format!("x.py invocation with PID {}", pid),
+        };
+        if !util::stdout_is_tty() {
+            println!("error: the build directory {} is in use by {}", build.out.display(), holder);
+            println!(
+                "help: wait for it to finish, or pass `--bypass-bootstrap-lock` if the two \
+                 invocations don't build the same things"
+            );
+            process::exit(1);
+        }
+        println!("Waiting for {} to release {}", holder, path.display());
+        t!(lock(&file, true));
+    }
+
+    t!(file.set_len(0));
+    t!(file.seek(SeekFrom::Start(0)));
+    t!(write!(file, "{}", process::id()));
+    env::set_var(HELD_VAR, &path);
+    Some(BuildLock { _file: file })
+}
+
+/// Locks `file` exclusively, returning whether it was locked. If `wait` is
+/// false this returns immediately when another process holds the lock.
+#[cfg(unix)]
+fn lock(file: &File, wait: bool) -> io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    let operation = if wait { libc::LOCK_EX } else { libc::LOCK_EX | libc::LOCK_NB };
+    if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+        return Ok(true);
+    }
+    let err = io::Error::last_os_error();
+    if err.raw_os_error() == Some(libc::EWOULDBLOCK) { Ok(false) } else { Err(err) }
+}
+
+/// Like the `flock` of Unix, but with `LockFileEx`, which locks a range of
+/// bytes of the file. The lock is released by Windows when the handle is
+/// closed, including when the process exits without dropping `BuildLock`.
+#[cfg(windows)]
+fn lock(file: &File, wait: bool) -> io::Result<bool> {
+    use std::mem;
+    use std::os::windows::io::AsRawHandle;
+
+    use winapi::shared::winerror::ERROR_LOCK_VIOLATION;
+    use winapi::um::fileapi::LockFileEx;
+    use winapi::um::minwinbase::{LOCKFILE_EXCLUSIVE_LOCK, LOCKFILE_FAIL_IMMEDIATELY, OVERLAPPED};
+
+    let mut flags = LOCKFILE_EXCLUSIVE_LOCK;
+    if !wait {
+        flags |= LOCKFILE_FAIL_IMMEDIATELY;
+    }
+    // Locks on Windows are mandatory, so lock a byte far past the PID, which
+    // has to stay readable for other invocations.
+    let mut overlapped: OVERLAPPED = unsafe { mem::zeroed() };
+    unsafe {
+        overlapped.u.s_mut().OffsetHigh = 1;
+    }
+    let locked = unsafe { LockFileEx(file.as_raw_handle() as _, flags, 0, 1, 0, &mut overlapped) };
+    if locked != 0 {
+        return Ok(true);
+    }
+    let err = io::Error::last_os_error();
+    if err.raw_os_error() == Some(ERROR_LOCK_VIOLATION as i32) { Ok(false) } else { Err(err) }
+}
+
+/// Other platforms have no file locks x.py can use, so their invocations
+/// aren't serialized.
+#[cfg(not(any(unix, windows)))]
+fn lock(_file: &File, _wait: bool) -> io::Result<bool> {
+    Ok(true)
+}
@@ -0,0 +1,154 @@
+//! Structured logging with `tracing`, enabled by `BOOTSTRAP_LOG`.
+//!
+//! `-v` and `-vv` print everything bootstrap does as one stream of lines,
+//! which is hard to make sense of when debugging it. With `BOOTSTRAP_LOG`
+//! set to a filter in the syntax of `RUST_LOG`, like
+//! `BOOTSTRAP_LOG=step=debug,exec=trace`, what bootstrap does is logged to
+//! stderr instead, under these targets:
+//!
+//! - `step`: a span for each step that runs, with its name and `Debug`
+//!   representation, at debug level, and an event for each step whose
+//!   result is reused, at trace level
+//! - `exec`: a span for each command run, including cargo, at trace level
+//! - `verbose`: the messages of `-v` at debug level, and of `-vv` at trace
+//!   level, which are only printed through `BOOTSTRAP_LOG` while it's set
+//!
+//! Spans are logged when they close, with the time spent in them. With
+//! `BOOTSTRAP_LOG_FORMAT=json`, each event is logged as a line of JSON which
+//! includes the spans it's in.
+//!
+//! This needs the `logging` feature of bootstrap, whose dependencies take a
+//! while to build, so x.py only enables it once `BOOTSTRAP_LOG` is set.
+//! Without it, the spans are stand-ins that log nothing.
+
+use std::env;
+use std::fmt;
+use std::process::{self, Command};
+
+// Everything else is only used with the `logging` feature.
+#[cfg(feature = "logging")]
+use {
+    crate::util,
+    std::io,
+    std::sync::atomic::{AtomicBool, Ordering},
+    tracing_subscriber::{fmt::format::FmtSpan, EnvFilter},
+};
+
+#[cfg(feature = "logging")]
+pub use tracing::Span;
+
+/// Stands in for `tracing::Span` without the `logging` feature.
+#[cfg(not(feature = "logging"))]
+pub struct Span;
+
+#[cfg(not(feature = "logging"))]
+impl Span {
+    pub fn enter(&self) -> &Span {
+        self
+    }
+
+    pub fn in_scope<T>(&self, f: impl FnOnce() -> T) -> T {
+        f()
+    }
+}
+
+#[cfg(feature = "logging")]
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Returns the filter of `BOOTSTRAP_LOG`, if logging was asked for.
+fn filter() -> Option<String> {
+    env::var("BOOTSTRAP_LOG").ok().filter(|filter| !filter.is_empty())
+}
+
+/// Starts logging if `BOOTSTRAP_LOG` is set. Only the first call has any
+/// effect, as the subscriber is global.
+#[cfg(feature = "logging")]
+pub fn init() {
+    let filter = match filter() {
+        Some(filter) => filter,
+        None => return,
+    };
+    let filter = match EnvFilter::try_new(&filter) {
+        Ok(filter) => filter,
+        Err(e) => {
+            eprintln!("error: invalid BOOTSTRAP_LOG `{}`: {}", filter, e);
+            process::exit(1);
+        }
+    };
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_span_events(FmtSpan::CLOSE)
+        .with_writer(io::stderr);
+    let installed = match env::var("BOOTSTRAP_LOG_FORMAT").as_deref() {
+        Ok("json") => subscriber.json().try_init().is_ok(),
+        _ => subscriber.try_init().is_ok(),
+    };
+    if installed {
+        ENABLED.store(true, Ordering::Relaxed);
+    }
+}
+
+#[cfg(not(feature = "logging"))]
+pub fn init() {
+    if filter().is_some() {
+        eprintln!("error: BOOTSTRAP_LOG needs bootstrap to be built with its `logging` feature");
+        eprintln!("help: x.py enables it when BOOTSTRAP_LOG is set");
+        process::exit(1);
+    }
+}
+
+/// Whether messages go through `tracing` rather than being printed.
+#[cfg(feature = "logging")]
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+#[cfg(not(feature = "logging"))]
+pub fn enabled() -> bool {
+    false
+}
+
+/// The span of running `cmd`.
+#[cfg(feature = "logging")]
+pub fn exec_span(cmd: &Command) -> Span {
+    tracing::trace_span!(target: "exec", "exec", program = %util::program_name(cmd), command = ?cmd)
+}
+
+#[cfg(not(feature = "logging"))]
+pub fn exec_span(_cmd: &Command) -> Span {
+    Span
+}
+
+/// The span of running the step `name`, whose `Debug` representation is
+/// `step`.
+#[cfg(feature = "logging")]
+pub fn step_span(name: &str, step: &dyn fmt::Debug) -> Span {
+    tracing::debug_span!(target: "step", "step", name = name, step = ?step)
+}
+
+#[cfg(not(feature = "logging"))]
+pub fn step_span(_name: &str, _step: &dyn fmt::Debug) -> Span {
+    Span
+}
+
+/// Logs that the result of the step `name` is reused.
+#[cfg(feature = "logging")]
+pub fn step_cached(name: &str, step: &dyn fmt::Debug) {
+    tracing::trace!(target: "step", name = name, step = ?step, "cached");
+}
+
+#[cfg(not(feature = "logging"))]
+pub fn step_cached(_name: &str, _step: &dyn fmt::Debug) {}
+
+/// Logs a message of `-v`, or of `-vv` and up if `level` is above 0.
+#[cfg(feature = "logging")]
+pub fn verbose(level: usize, msg: &str) {
+    if level == 0 {
+        tracing::debug!(target: "verbose", "{}", msg);
+    } else {
+        tracing::trace!(target: "verbose", "{}", msg);
+    }
+}
+
+#[cfg(not(feature = "logging"))]
+pub fn verbose(_level: usize, _msg: &str) {}
@@ -8,18 +8,26 @@
 //! LLVM and compiler-rt are essentially just wired up to everything else to
 //! ensure that they're always in place if needed.
 
+use std::cmp;
 use std::env;
 use std::env::consts::EXE_EXTENSION;
 use std::ffi::OsString;
 use std::fs::{self, File};
 use std::io;
+use std::mem;
+use std::panic;
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use build_helper::{output, t};
 
+use crate::artifact_cache;
 use crate::builder::{Builder, RunConfig, ShouldRun, Step};
+use crate::clean;
 use crate::config::TargetSelection;
+use crate::priority;
 use crate::util::{self, exe};
 use crate::GitRepo;
 use build_helper::up_to_date;
@@ -62,8 +70,27 @@ pub fn prebuilt_llvm_config(
 
     let build_llvm_config = llvm_config_ret_dir.join(exe("llvm-config", builder.config.build));
 
+    // Switching between the phases of PGO rebuilds LLVM, which with
+    // `--keep-stage` is all that's rebuilt between them.
     let stamp = out_dir.join("llvm-finished-building");
-    let stamp = HashStamp::new(stamp, builder.in_tree_llvm_info.sha());
+    let stamp_hash =
+        builder.in_tree_llvm_info.sha().map(|sha| format!("{}{}", sha, pgo_key(builder)));
+    let stamp = HashStamp::new(stamp, stamp_hash.as_deref());
+
+    // An LLVM imported from a prebuilt archive has no build directory, so its
+    // `llvm-config` lives in the install tree instead. It's only reused as
+    // long as it still matches the configuration; otherwise fall back to
+    // importing or building a fresh one.
+    let prebuilt_key = out_dir.join(PREBUILT_LLVM_KEY);
+    if let Ok(key) = fs::read_to_string(&prebuilt_key) {
+        if stamp.is_done() && Some(key) == llvm_archive_key(builder, target) {
+            return Ok(out_dir.join("bin").join(exe("llvm-config", target)));
+        }
+        if !builder.config.dry_run {
+            t!(stamp.remove());
+            t!(fs::remove_file(&prebuilt_key));
+        }
+    }
 
     if builder.config.llvm_skip_rebuild && stamp.path.exists() {
         builder.info(
@@ -111,228 +138,503 @@ fn make_run(run: RunConfig<'_>) {
 
     /// Compile LLVM for `target`.
     fn run(self, builder: &Builder<'_>) -> PathBuf {
-        let target = self.target;
-        let target_native = if self.target.starts_with("riscv") {
-            // RISC-V target triples in Rust is not named the same as C compiler target triples.
-            // This converts Rust RISC-V target triples to C compiler triples.
-            let idx = target.triple.find('-').unwrap();
+        match configure_llvm(builder, self.target, builder.jobs()) {
+            LlvmConfigured::Prebuilt(llvm_config) => llvm_config,
+            LlvmConfigured::NeedsBuild(mut build) => {
+                let _time = util::timeit(&builder);
+                let start = Instant::now();
+                cmake_build(builder, &mut build.cfg);
+                record_build_time(builder, self.target, builder.jobs(), start.elapsed());
+                record_disk_usage(builder, self.target);
+                t!(build.stamp.write());
+                cache_llvm(builder, self.target);
+                build.llvm_config
+            }
+        }
+    }
 
-            format!("riscv{}{}", &target.triple[5..7], &target.triple[idx..])
-        } else {
-            target.to_string()
-        };
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
+}
 
-        let Meta { stamp, build_llvm_config, out_dir, root } =
-            match prebuilt_llvm_config(builder, target) {
-                Ok(p) => return p,
-                Err(m) => m,
-            };
+/// A build of LLVM that has been configured, but not run yet.
+struct LlvmBuild {
+    cfg: cmake::Config,
+    stamp: HashStamp,
+    llvm_config: PathBuf,
+}
 
-        if builder.config.llvm_link_shared
-            && (target.contains("windows") || target.contains("apple-darwin"))
-        {
-            panic!("shared linking to LLVM is not currently supported on {}", target.triple);
-        }
+/// What `configure_llvm` found LLVM for a target to need.
+enum LlvmConfigured {
+    /// Nothing, as it's up to date, imported, or configured not to be built
+    /// by us, or this is a dry run; its `llvm-config` is at this path.
+    Prebuilt(PathBuf),
+    /// Running this build.
+    NeedsBuild(LlvmBuild),
+}
 
-        builder.info(&format!("Building LLVM for {}", target));
-        t!(stamp.remove());
-        let _time = util::timeit(&builder);
-        t!(fs::create_dir_all(&out_dir));
+/// Configures the build of LLVM for `target` with `jobs` parallel jobs, if it
+/// needs to be built.
+fn configure_llvm(builder: &Builder<'_>, target: TargetSelection, jobs: u32) -> LlvmConfigured {
+    let target_native = if target.starts_with("riscv") {
+        // RISC-V target triples in Rust is not named the same as C compiler target triples.
+        // This converts Rust RISC-V target triples to C compiler triples.
+        let idx = target.triple.find('-').unwrap();
 
-        // http://llvm.org/docs/CMake.html
-        let mut cfg = cmake::Config::new(builder.src.join(root));
+        format!("riscv{}{}", &target.triple[5..7], &target.triple[idx..])
+    } else {
+        target.to_string()
+    };
 
-        let profile = match (builder.config.llvm_optimize, builder.config.llvm_release_debuginfo) {
-            (false, _) => "Debug",
-            (true, false) => "Release",
-            (true, true) => "RelWithDebInfo",
+    let Meta { stamp, build_llvm_config, out_dir, root } =
+        match prebuilt_llvm_config(builder, target) {
+            Ok(p) => return LlvmConfigured::Prebuilt(p),
+            Err(m) => m,
         };
 
-        // NOTE: remember to also update `config.toml.example` when changing the
-        // defaults!
-        let llvm_targets = match &builder.config.llvm_targets {
-            Some(s) => s,
-            None => {
-                "AArch64;ARM;Hexagon;MSP430;Mips;NVPTX;PowerPC;RISCV;\
-                     Sparc;SystemZ;WebAssembly;X86"
-            }
-        };
+    if let Some(llvm_config) = import_llvm(builder, target, &stamp) {
+        return LlvmConfigured::Prebuilt(llvm_config);
+    }
 
-        let llvm_exp_targets = match builder.config.llvm_experimental_targets {
-            Some(ref s) => s,
-            None => "AVR",
-        };
+    if builder.config.llvm_link_shared
+        && (target.contains("windows") || target.contains("apple-darwin"))
+    {
+        panic!("shared linking to LLVM is not currently supported on {}", target.triple);
+    }
 
-        let assertions = if builder.config.llvm_assertions { "ON" } else { "OFF" };
+    builder.info(&format!("Building LLVM for {}", target));
+    t!(stamp.remove());
+    t!(fs::create_dir_all(&out_dir));
 
-        cfg.out_dir(&out_dir)
-            .profile(profile)
-            .define("LLVM_ENABLE_ASSERTIONS", assertions)
-            .define("LLVM_TARGETS_TO_BUILD", llvm_targets)
-            .define("LLVM_EXPERIMENTAL_TARGETS_TO_BUILD", llvm_exp_targets)
-            .define("LLVM_INCLUDE_EXAMPLES", "OFF")
-            .define("LLVM_INCLUDE_TESTS", "OFF")
-            .define("LLVM_INCLUDE_DOCS", "OFF")
-            .define("LLVM_INCLUDE_BENCHMARKS", "OFF")
-            .define("LLVM_ENABLE_TERMINFO", "OFF")
-            .define("LLVM_ENABLE_LIBEDIT", "OFF")
-            .define("LLVM_ENABLE_BINDINGS", "OFF")
-            .define("LLVM_ENABLE_Z3_SOLVER", "OFF")
-            .define("LLVM_PARALLEL_COMPILE_JOBS", builder.jobs().to_string())
-            .define("LLVM_TARGET_ARCH", target_native.split('-').next().unwrap())
-            .define("LLVM_DEFAULT_TARGET_TRIPLE", target_native);
-
-        if target != "aarch64-apple-darwin" {
-            cfg.define("LLVM_ENABLE_ZLIB", "ON");
-        } else {
-            cfg.define("LLVM_ENABLE_ZLIB", "OFF");
+    // http://llvm.org/docs/CMake.html
+    let mut cfg = cmake::Config::new(builder.src.join(root));
+
+    let profile = match (builder.config.llvm_optimize, builder.config.llvm_release_debuginfo) {
+        (false, _) => "Debug",
+        (true, false) => "Release",
+        (true, true) => "RelWithDebInfo",
+    };
+
+    // NOTE: remember to also update `config.toml.example` when changing the
+    // defaults!
+    let llvm_targets = match &builder.config.llvm_targets {
+        Some(s) => s,
+        None => {
+            "AArch64;ARM;Hexagon;MSP430;Mips;NVPTX;PowerPC;RISCV;\
+                 Sparc;SystemZ;WebAssembly;X86"
         }
+    };
 
-        // Are we compiling for iOS/tvOS?
-        if target.contains("apple-ios") || target.contains("apple-tvos") {
-            // These two defines prevent CMake from automatically trying to add a MacOSX sysroot, which leads to a compiler error.
-            cfg.define("CMAKE_OSX_SYSROOT", "/");
-            cfg.define("CMAKE_OSX_DEPLOYMENT_TARGET", "");
-            // Prevent cmake from adding -bundle to CFLAGS automatically, which leads to a compiler error because "-bitcode_bundle" also gets added.
-            cfg.define("LLVM_ENABLE_PLUGINS", "OFF");
-            // Zlib fails to link properly, leading to a compiler error.
+    let llvm_exp_targets = match builder.config.llvm_experimental_targets {
+        Some(ref s) => s,
+        None => "AVR",
+    };
+
+    let assertions = if builder.config.llvm_assertions { "ON" } else { "OFF" };
+
+    cfg.out_dir(&out_dir)
+        .profile(profile)
+        .define("LLVM_ENABLE_ASSERTIONS", assertions)
+        .define("LLVM_TARGETS_TO_BUILD", llvm_targets)
+        .define("LLVM_EXPERIMENTAL_TARGETS_TO_BUILD", llvm_exp_targets)
+        .define("LLVM_INCLUDE_EXAMPLES", "OFF")
+        .define("LLVM_INCLUDE_TESTS", "OFF")
+        .define("LLVM_INCLUDE_DOCS", "OFF")
+        .define("LLVM_INCLUDE_BENCHMARKS", "OFF")
+        .define("LLVM_ENABLE_TERMINFO", "OFF")
+        .define("LLVM_ENABLE_LIBEDIT", "OFF")
+        .define("LLVM_ENABLE_BINDINGS", "OFF")
+        .define("LLVM_ENABLE_Z3_SOLVER", "OFF")
+        .define("LLVM_PARALLEL_COMPILE_JOBS", jobs.to_string())
+        .define("LLVM_TARGET_ARCH", target_native.split('-').next().unwrap())
+        .define("LLVM_DEFAULT_TARGET_TRIPLE", target_native);
+
+    // An explicit setting uses `FORCE_ON` so that CMake fails loudly if
+    // the library can't be found, instead of silently building without
+    // it and producing different artifacts depending on the builder.
+    match builder.config.llvm_enable_zlib {
+        Some(true) => {
+            cfg.define("LLVM_ENABLE_ZLIB", "FORCE_ON");
+        }
+        Some(false) => {
             cfg.define("LLVM_ENABLE_ZLIB", "OFF");
         }
+        None if target != "aarch64-apple-darwin" => {
+            cfg.define("LLVM_ENABLE_ZLIB", "ON");
+        }
+        None => {
+            cfg.define("LLVM_ENABLE_ZLIB", "OFF");
+        }
+    }
 
-        if builder.config.llvm_thin_lto {
-            cfg.define("LLVM_ENABLE_LTO", "Thin");
-            if !target.contains("apple") {
-                cfg.define("LLVM_ENABLE_LLD", "ON");
-            }
+    // Setting both is rejected when loading config.toml.
+    match (&builder.config.llvm_profile_generate, &builder.config.llvm_profile_use) {
+        (Some(dir), _) => {
+            cfg.define("LLVM_BUILD_INSTRUMENTED", "IR");
+            cfg.define("LLVM_BUILD_RUNTIME", "No");
+            cfg.define("LLVM_PROFILE_DATA_DIR", dir);
         }
+        (None, Some(file)) => {
+            cfg.define("LLVM_PROFDATA_FILE", file);
+        }
+        (None, None) => {}
+    }
 
-        // This setting makes the LLVM tools link to the dynamic LLVM library,
-        // which saves both memory during parallel links and overall disk space
-        // for the tools. We don't do this on every platform as it doesn't work
-        // equally well everywhere.
-        //
-        // If we're not linking rustc to a dynamic LLVM, though, then don't link
-        // tools to it.
-        if builder.llvm_link_tools_dynamically(target) && builder.config.llvm_link_shared {
-            cfg.define("LLVM_LINK_LLVM_DYLIB", "ON");
+    // Are we compiling for iOS/tvOS?
+    if target.contains("apple-ios") || target.contains("apple-tvos") {
+        // These two defines prevent CMake from automatically trying to add a MacOSX sysroot, which leads to a compiler error.
+        cfg.define("CMAKE_OSX_SYSROOT", "/");
+        cfg.define("CMAKE_OSX_DEPLOYMENT_TARGET", "");
+        // Prevent cmake from adding -bundle to CFLAGS automatically, which leads to a compiler error because "-bitcode_bundle" also gets added.
+        cfg.define("LLVM_ENABLE_PLUGINS", "OFF");
+        // Zlib fails to link properly, leading to a compiler error.
+        if builder.config.llvm_enable_zlib == Some(true) {
+            panic!("llvm.enable-zlib is not supported when targeting iOS or tvOS");
         }
+        cfg.define("LLVM_ENABLE_ZLIB", "OFF");
+    }
 
-        // For distribution we want the LLVM tools to be *statically* linked to libstdc++
-        if builder.config.llvm_tools_enabled {
-            if !target.contains("msvc") {
-                if target.contains("apple") {
-                    cfg.define("CMAKE_EXE_LINKER_FLAGS", "-static-libstdc++");
-                } else {
-                    cfg.define("CMAKE_EXE_LINKER_FLAGS", "-Wl,-Bsymbolic -static-libstdc++");
-                }
-            }
+    if builder.config.llvm_thin_lto {
+        cfg.define("LLVM_ENABLE_LTO", "Thin");
+        if !target.contains("apple") {
+            cfg.define("LLVM_ENABLE_LLD", "ON");
         }
+    }
 
-        if target.starts_with("riscv") {
-            // In RISC-V, using C++ atomics require linking to `libatomic` but the LLVM build
-            // system check cannot detect this. Therefore it is set manually here.
-            if !builder.config.llvm_tools_enabled {
-                cfg.define("CMAKE_EXE_LINKER_FLAGS", "-latomic");
+    if builder.config.llvm_plugins {
+        cfg.define("LLVM_ENABLE_PLUGINS", "ON");
+        cfg.define("LLVM_EXPORT_SYMBOLS_FOR_PLUGINS", "ON");
+    }
+
+    // This setting makes the LLVM tools link to the dynamic LLVM library,
+    // which saves both memory during parallel links and overall disk space
+    // for the tools. We don't do this on every platform as it doesn't work
+    // equally well everywhere.
+    //
+    // If we're not linking rustc to a dynamic LLVM, though, then don't link
+    // tools to it.
+    if builder.llvm_link_tools_dynamically(target) && builder.config.llvm_link_shared {
+        cfg.define("LLVM_LINK_LLVM_DYLIB", "ON");
+    }
+
+    // For distribution we want the LLVM tools to be *statically* linked to libstdc++
+    if builder.config.llvm_tools_enabled {
+        if !target.contains("msvc") {
+            if target.contains("apple") {
+                cfg.define("CMAKE_EXE_LINKER_FLAGS", "-static-libstdc++");
             } else {
-                cfg.define("CMAKE_EXE_LINKER_FLAGS", "-latomic -static-libstdc++");
+                cfg.define("CMAKE_EXE_LINKER_FLAGS", "-Wl,-Bsymbolic -static-libstdc++");
             }
-            cfg.define("CMAKE_SHARED_LINKER_FLAGS", "-latomic");
         }
+    }
 
-        if target.contains("msvc") {
-            cfg.define("LLVM_USE_CRT_DEBUG", "MT");
-            cfg.define("LLVM_USE_CRT_RELEASE", "MT");
-            cfg.define("LLVM_USE_CRT_RELWITHDEBINFO", "MT");
-            cfg.static_crt(true);
+    if target.starts_with("riscv") {
+        // In RISC-V, using C++ atomics require linking to `libatomic` but the LLVM build
+        // system check cannot detect this. Therefore it is set manually here.
+        if !builder.config.llvm_tools_enabled {
+            cfg.define("CMAKE_EXE_LINKER_FLAGS", "-latomic");
+        } else {
+            cfg.define("CMAKE_EXE_LINKER_FLAGS", "-latomic -static-libstdc++");
         }
+        cfg.define("CMAKE_SHARED_LINKER_FLAGS", "-latomic");
+    }
 
-        if target.starts_with("i686") {
-            cfg.define("LLVM_BUILD_32_BITS", "ON");
-        }
+    if target.contains("msvc") {
+        cfg.define("LLVM_USE_CRT_DEBUG", "MT");
+        cfg.define("LLVM_USE_CRT_RELEASE", "MT");
+        cfg.define("LLVM_USE_CRT_RELWITHDEBINFO", "MT");
+        cfg.static_crt(true);
+    }
 
-        let mut enabled_llvm_projects = Vec::new();
+    if target.starts_with("i686") {
+        cfg.define("LLVM_BUILD_32_BITS", "ON");
+    }
 
-        if util::forcing_clang_based_tests() {
-            enabled_llvm_projects.push("clang");
-            enabled_llvm_projects.push("compiler-rt");
-        }
+    let mut enabled_llvm_projects = Vec::new();
 
-        if let Some(true) = builder.config.llvm_polly {
-            enabled_llvm_projects.push("polly");
-        }
+    if util::forcing_clang_based_tests() {
+        enabled_llvm_projects.push("clang");
+        enabled_llvm_projects.push("compiler-rt");
+    }
+
+    if let Some(true) = builder.config.llvm_polly {
+        enabled_llvm_projects.push("polly");
+        // Link Polly statically into libLLVM and the LLVM tools rather
+        // than building it as a loadable module, so that rustc can pick
+        // it up through `-Cllvm-args=-polly` without any plugin loading.
+        cfg.define("LLVM_POLLY_LINK_INTO_TOOLS", "ON");
+    }
 
-        // We want libxml to be disabled.
-        // See https://github.com/rust-lang/rust/pull/50104
-        cfg.define("LLVM_ENABLE_LIBXML2", "OFF");
+    // We want libxml to be disabled.
+    // See https://github.com/rust-lang/rust/pull/50104
+    cfg.define("LLVM_ENABLE_LIBXML2", "OFF");
 
-        if !enabled_llvm_projects.is_empty() {
-            enabled_llvm_projects.sort();
-            enabled_llvm_projects.dedup();
-            cfg.define("LLVM_ENABLE_PROJECTS", enabled_llvm_projects.join(";"));
-        }
+    if !enabled_llvm_projects.is_empty() {
+        enabled_llvm_projects.sort();
+        enabled_llvm_projects.dedup();
+        cfg.define("LLVM_ENABLE_PROJECTS", enabled_llvm_projects.join(";"));
+    }
 
-        if let Some(num_linkers) = builder.config.llvm_link_jobs {
-            if num_linkers > 0 {
-                cfg.define("LLVM_PARALLEL_LINK_JOBS", num_linkers.to_string());
-            }
+    if let Some(num_linkers) = builder.config.llvm_link_jobs {
+        if num_linkers > 0 {
+            cfg.define("LLVM_PARALLEL_LINK_JOBS", num_linkers.to_string());
         }
+    }
 
-        // http://llvm.org/docs/HowToCrossCompileLLVM.html
-        if target != builder.config.build {
-            builder.ensure(Llvm { target: builder.config.build });
-            // FIXME: if the llvm root for the build triple is overridden then we
-            //        should use llvm-tblgen from there, also should verify that it
-            //        actually exists most of the time in normal installs of LLVM.
-            let host_bin = builder.llvm_out(builder.config.build).join("bin");
-            cfg.define("CMAKE_CROSSCOMPILING", "True");
-            cfg.define("LLVM_TABLEGEN", host_bin.join("llvm-tblgen").with_extension(EXE_EXTENSION));
-            cfg.define("LLVM_NM", host_bin.join("llvm-nm").with_extension(EXE_EXTENSION));
-            cfg.define(
-                "LLVM_CONFIG_PATH",
-                host_bin.join("llvm-config").with_extension(EXE_EXTENSION),
+    // http://llvm.org/docs/HowToCrossCompileLLVM.html
+    if target != builder.config.build {
+        let host_llvm_config = builder.ensure(Llvm { target: builder.config.build });
+        // Reuse the build triple's tablegen and friends rather than
+        // building them again for every cross target. These come from
+        // wherever the build triple's LLVM lives, which need not be our
+        // own build of it.
+        let host_bin = if builder.config.dry_run {
+            builder.llvm_out(builder.config.build).join("bin")
+        } else {
+            PathBuf::from(output(Command::new(&host_llvm_config).arg("--bindir")).trim())
+        };
+        let host_tblgen = host_bin.join("llvm-tblgen").with_extension(EXE_EXTENSION);
+        if !builder.config.dry_run && !host_tblgen.exists() {
+            panic!(
+                "cross-compiling LLVM for {} requires llvm-tblgen for the build triple, \
+                 but it was not found at {}",
+                target,
+                host_tblgen.display()
             );
         }
+        cfg.define("CMAKE_CROSSCOMPILING", "True");
+        cfg.define("LLVM_TABLEGEN", host_tblgen);
+        cfg.define("LLVM_NM", host_bin.join("llvm-nm").with_extension(EXE_EXTENSION));
+        cfg.define("LLVM_CONFIG_PATH", host_bin.join("llvm-config").with_extension(EXE_EXTENSION));
+    }
 
-        if let Some(ref suffix) = builder.config.llvm_version_suffix {
-            // Allow version-suffix="" to not define a version suffix at all.
-            if !suffix.is_empty() {
-                cfg.define("LLVM_VERSION_SUFFIX", suffix);
-            }
-        } else if builder.config.channel == "dev" {
-            // Changes to a version suffix require a complete rebuild of the LLVM.
-            // To avoid rebuilds during a time of version bump, don't include rustc
-            // release number on the dev channel.
-            cfg.define("LLVM_VERSION_SUFFIX", "-rust-dev");
-        } else {
-            let suffix = format!("-rust-{}-{}", builder.version, builder.config.channel);
+    if let Some(ref suffix) = builder.config.llvm_version_suffix {
+        // Allow version-suffix="" to not define a version suffix at all.
+        if !suffix.is_empty() {
             cfg.define("LLVM_VERSION_SUFFIX", suffix);
         }
+    } else if builder.config.channel == "dev" {
+        // Changes to a version suffix require a complete rebuild of the LLVM.
+        // To avoid rebuilds during a time of version bump, don't include rustc
+        // release number on the dev channel.
+        cfg.define("LLVM_VERSION_SUFFIX", "-rust-dev");
+    } else {
+        let suffix = format!("-rust-{}-{}", builder.version, builder.config.channel);
+        cfg.define("LLVM_VERSION_SUFFIX", suffix);
+    }
 
-        if let Some(ref linker) = builder.config.llvm_use_linker {
-            cfg.define("LLVM_USE_LINKER", linker);
-        }
+    if let Some(ref linker) = builder.config.llvm_use_linker {
+        cfg.define("LLVM_USE_LINKER", linker);
+    }
+
+    if let Some(true) = builder.config.llvm_allow_old_toolchain {
+        cfg.define("LLVM_TEMPORARILY_ALLOW_OLD_TOOLCHAIN", "YES");
+    }
+
+    configure_cmake(builder, target, &mut cfg, true, jobs);
+
+    // Apply `llvm.build-config` last, so it can override anything above.
+    for (key, val) in &builder.config.llvm_build_config {
+        cfg.define(key, val);
+    }
+
+    // FIXME: we don't actually need to build all LLVM tools and all LLVM
+    //        libraries here, e.g., we just want a few components and a few
+    //        tools. Figure out how to filter them down and only build the right
+    //        tools and libs on all platforms.
 
-        if let Some(true) = builder.config.llvm_allow_old_toolchain {
-            cfg.define("LLVM_TEMPORARILY_ALLOW_OLD_TOOLCHAIN", "YES");
+    if builder.config.dry_run {
+        builder.llvm_to_build.borrow_mut().push(target);
+        return LlvmConfigured::Prebuilt(build_llvm_config);
+    }
+
+    LlvmConfigured::NeedsBuild(LlvmBuild { cfg, stamp, llvm_config: build_llvm_config })
+}
+
+/// Name of the file in the LLVM output directory recording the key of the
+/// prebuilt archive it was imported from.
+pub const PREBUILT_LLVM_KEY: &str = "llvm-prebuilt-key";
+
+/// Identifies the PGO phase LLVM is built for, which is appended to the hash
+/// in its stamp and to its archive key.
+fn pgo_key(builder: &Builder<'_>) -> String {
+    match (&builder.config.llvm_profile_generate, &builder.config.llvm_profile_use) {
+        (Some(dir), _) => format!("-profile-generate={}", dir),
+        (None, Some(file)) => format!("-profile-use={}", file),
+        (None, None) => String::new(),
+    }
+}
+
+/// Returns the key identifying LLVM builds for `target` that are
+/// interchangeable with the one the current configuration would produce, or
+/// `None` if the LLVM submodule commit can't be determined or bootstrap was
+/// built without its `artifact-cache` feature.
+fn llvm_archive_key(builder: &Builder<'_>, target: TargetSelection) -> Option<String> {
+    let sha = builder.in_tree_llvm_info.sha()?;
+    let config = &builder.config;
+
+    let mut build_config = config.llvm_build_config.iter().collect::<Vec<_>>();
+    build_config.sort();
+    let options = [
+        format!("{:?}", target),
+        format!("{:?}", config.channel),
+        format!("{:?}", config.llvm_assertions),
+        format!("{:?}", config.llvm_optimize),
+        format!("{:?}", config.llvm_thin_lto),
+        format!("{:?}", config.llvm_release_debuginfo),
+        format!("{:?}", config.llvm_static_stdcpp),
+        format!("{:?}", config.llvm_link_shared),
+        format!("{:?}", config.llvm_targets),
+        format!("{:?}", config.llvm_experimental_targets),
+        format!("{:?}", config.llvm_version_suffix),
+        format!("{:?}", config.llvm_polly),
+        format!("{:?}", config.llvm_plugins),
+        format!("{:?}", config.llvm_enable_zlib),
+        format!("{:?}", config.llvm_use_libcxx),
+        format!("{:?}", config.llvm_cflags),
+        format!("{:?}", config.llvm_cxxflags),
+        format!("{:?}", config.llvm_ldflags),
+        format!("{:?}", build_config),
+        pgo_key(builder),
+    ];
+    let hash = artifact_cache::shared_hash(options.join("\0").as_bytes())?;
+    Some(format!("{}-{}", &sha[..12], hash))
+}
+
+/// File name of the prebuilt LLVM archive for `target` with the given key.
+fn llvm_archive_name(key: &str, target: TargetSelection) -> String {
+    format!("llvm-{}-{}.tar.xz", key, target.triple)
+}
+
+/// Imports LLVM for `target` from the `llvm.prebuilt-archive` directory or
+/// the artifact cache, if either has an archive matching the current
+/// configuration, returning the path to its `llvm-config`.
+fn import_llvm(
+    builder: &Builder<'_>,
+    target: TargetSelection,
+    stamp: &HashStamp,
+) -> Option<PathBuf> {
+    // Cross-compiled LLVM is configured against the build triple's LLVM, so
+    // only that one can be swapped out for a prebuilt copy.
+    if target != builder.config.build {
+        return None;
+    }
+    let key = llvm_archive_key(builder, target)?;
+    let name = llvm_archive_name(&key, target);
+    let local = builder.config.llvm_prebuilt_archive.as_ref().map(|dir| dir.join(&name));
+    let archive = match local.filter(|archive| archive.exists()) {
+        Some(archive) => archive,
+        None => {
+            if let Some(dir) = &builder.config.llvm_prebuilt_archive {
+                builder.info(&format!(
+                    "No prebuilt LLVM matching this configuration in {}",
+                    dir.display()
+                ));
+            }
+            crate::artifact_cache::fetch(builder, &name)?
         }
+    };
+
+    let out_dir = builder.llvm_out(target);
+    builder.info(&format!("Importing prebuilt LLVM for {} from {}", target, archive.display()));
+    if !builder.config.dry_run {
+        t!(stamp.remove());
+        t!(fs::create_dir_all(&out_dir));
+        builder.run(Command::new("tar").arg("-xJf").arg(&archive).arg("-C").arg(&out_dir));
+        t!(fs::write(out_dir.join(PREBUILT_LLVM_KEY), &key));
+        t!(stamp.write());
+    }
+    Some(out_dir.join("bin").join(exe("llvm-config", target)))
+}
+
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+pub struct ExportLlvm {
+    pub target: TargetSelection,
+}
+
+impl Step for ExportLlvm {
+    type Output = ();
+    const DEFAULT: bool = true;
+    const ONLY_HOSTS: bool = true;
+
+    fn should_run(run: ShouldRun<'_>) -> ShouldRun<'_> {
+        run.path("src/llvm-project")
+    }
 
-        configure_cmake(builder, target, &mut cfg, true);
+    fn make_run(run: RunConfig<'_>) {
+        run.builder.ensure(ExportLlvm { target: run.builder.config.build });
+    }
+
+    /// Packages the LLVM built for `target` into an archive that other
+    /// checkouts can import through `llvm.prebuilt-archive`.
+    fn run(self, builder: &Builder<'_>) {
+        let target = self.target;
+        if !builder.is_rust_llvm(target) || builder.config.llvm_from_ci {
+            panic!("export-llvm requires LLVM to be built from the in-tree sources");
+        }
+        if !cfg!(feature = "artifact-cache") {
+            panic!("export-llvm requires bootstrap to be built with its `artifact-cache` feature");
+        }
+        let key = match llvm_archive_key(builder, target) {
+            Some(key) => key,
+            None => panic!("export-llvm requires the LLVM submodule to be checked out with git"),
+        };
 
-        // FIXME: we don't actually need to build all LLVM tools and all LLVM
-        //        libraries here, e.g., we just want a few components and a few
-        //        tools. Figure out how to filter them down and only build the right
-        //        tools and libs on all platforms.
+        builder.ensure(Llvm { target });
 
+        let dest = crate::dist::distdir(builder).join(llvm_archive_name(&key, target));
+        builder.info(&format!("Exporting LLVM for {} to {}", target, dest.display()));
         if builder.config.dry_run {
-            return build_llvm_config;
+            return;
         }
 
-        cfg.build();
+        pack_llvm(builder, target, &dest);
+    }
 
-        t!(stamp.write());
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
+}
+
+/// Packs the LLVM built for `target` into the archive `dest`.
+fn pack_llvm(builder: &Builder<'_>, target: TargetSelection, dest: &Path) {
+    let out_dir = builder.llvm_out(target);
+    // FileCheck isn't installed by LLVM, but the test suites need it.
+    let filecheck = builder.llvm_filecheck(target);
+    if filecheck.exists() && !filecheck.starts_with(out_dir.join("bin")) {
+        builder.copy(&filecheck, &out_dir.join("bin").join(exe("FileCheck", target)));
+    }
+
+    t!(fs::create_dir_all(dest.parent().unwrap()));
+    let mut cmd = Command::new("tar");
+    cmd.arg("-cJf").arg(dest).arg("-C").arg(&out_dir);
+    for dir in &["bin", "include", "lib", "share"] {
+        if out_dir.join(dir).exists() {
+            cmd.arg(dir);
+        }
+    }
+    builder.run(&mut cmd);
+}
 
-        build_llvm_config
+/// Stores the LLVM just built for `target` in the artifact cache, if uploads
+/// to it are enabled.
+fn cache_llvm(builder: &Builder<'_>, target: TargetSelection) {
+    if builder.config.artifact_cache.is_none()
+        || !builder.config.artifact_cache_upload
+        || target != builder.config.build
+    {
+        return;
     }
+    let key = match llvm_archive_key(builder, target) {
+        Some(key) => key,
+        None => return,
+    };
+    let name = llvm_archive_name(&key, target);
+    let archive = builder.out.join("tmp").join("artifact-cache").join(&name);
+    pack_llvm(builder, target, &archive);
+    crate::artifact_cache::store(builder, &name, &archive);
+    t!(fs::remove_file(&archive));
 }
 
 fn check_llvm_version(builder: &Builder<'_>, llvm_config: &Path) {
@@ -355,11 +657,129 @@ fn check_llvm_version(builder: &Builder<'_>, llvm_config: &Path) {
     panic!("\n\nbad LLVM version: {}, need >=9.0\n\n", version)
 }
 
+/// Builds LLVM for all of `targets` at once, splitting the jobs of the build
+/// between them, rather than one after the other as the steps needing each
+/// of them run. Multi-target builds like those of `dist` otherwise spend
+/// most of the LLVM builds linking on a single core.
+///
+/// The builds for cross targets use the tablegen of the build triple, so
+/// LLVM for the build triple is built on its own first.
+///
+/// The jobs are split by how long each build took the last time, so that the
+/// longest one, which decides when all of them are done, gets the most and is
+/// started first. Builds that weren't timed yet count as average ones.
+///
+/// Only LLVM is built concurrently: the other steps run one after the other
+/// from `Builder::ensure`, as they share the state of the builder, which
+/// isn't thread-safe, and most of them run cargo, which already uses all the
+/// jobs. The LLVM builds are cmake invocations which only need their
+/// configuration, so they can run on threads of their own.
+pub fn build_llvm_concurrently(builder: &Builder<'_>, targets: &[TargetSelection]) {
+    let mut targets = targets.to_vec();
+    targets.sort();
+    targets.dedup();
+    targets.retain(|target| *target != builder.config.build);
+    if builder.config.dry_run || targets.len() < 2 {
+        return;
+    }
+
+    builder.running_steps.borrow_mut().push("native::Llvm");
+    let times = targets.iter().map(|&target| recorded_build_time(builder, target));
+    let times = times.collect::<Vec<_>>();
+    let timed = times.iter().flatten().collect::<Vec<_>>();
+    let average = timed.iter().copied().sum::<u64>() / cmp::max(1, timed.len() as u64);
+    let mut targets = targets
+        .into_iter()
+        .zip(times.iter().map(|time| cmp::max(1, time.unwrap_or(average))))
+        .collect::<Vec<_>>();
+    targets.sort_by_key(|&(_, time)| cmp::Reverse(time));
+    let total = targets.iter().map(|&(_, time)| time).sum::<u64>();
+    let builds = targets
+        .into_iter()
+        .filter_map(|(target, time)| {
+            let jobs = cmp::max(1, (u64::from(builder.jobs()) * time / total) as u32);
+            match configure_llvm(builder, target, jobs) {
+                LlvmConfigured::Prebuilt(_) => None,
+                LlvmConfigured::NeedsBuild(build) => Some((target, jobs, build)),
+            }
+        })
+        .collect::<Vec<_>>();
+    if builds.len() > 1 {
+        let names = builds.iter().map(|(target, _, _)| target.to_string()).collect::<Vec<_>>();
+        builder.info(&format!("Building LLVM for {} concurrently", names.join(", ")));
+    }
+
+    let _time = util::timeit(&builder);
+    let start = Instant::now();
+    let priority = builder.priority();
+    let threads = builds
+        .into_iter()
+        .map(|(target, jobs, build)| {
+            let thread = thread::spawn(move || {
+                priority::run_with(priority, move || {
+                    let mut build = build;
+                    let start = Instant::now();
+                    build.cfg.build();
+                    (build, start.elapsed())
+                })
+            });
+            (target, jobs, thread)
+        })
+        .collect::<Vec<_>>();
+    for (target, jobs, thread) in threads {
+        let (build, elapsed) = thread.join().unwrap_or_else(|e| panic::resume_unwind(e));
+        record_build_time(builder, target, jobs, elapsed);
+        record_disk_usage(builder, target);
+        t!(build.stamp.write());
+    }
+    if let Some(trace) = &builder.trace {
+        trace.cmake("native::Llvm", start);
+    }
+    builder.running_steps.borrow_mut().pop();
+}
+
+/// Name of the file in the LLVM output directory recording how long its last
+/// build took, in seconds times the jobs it had, to split the jobs of
+/// concurrent builds by.
+const LLVM_BUILD_TIME: &str = "llvm-build-time";
+
+fn record_build_time(builder: &Builder<'_>, target: TargetSelection, jobs: u32, time: Duration) {
+    let path = builder.llvm_out(target).join(LLVM_BUILD_TIME);
+    t!(fs::write(path, (time.as_secs() * u64::from(jobs)).to_string()));
+}
+
+fn recorded_build_time(builder: &Builder<'_>, target: TargetSelection) -> Option<u64> {
+    let path = builder.llvm_out(target).join(LLVM_BUILD_TIME);
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+fn record_disk_usage(builder: &Builder<'_>, target: TargetSelection) {
+    let part = format!("llvm-{}", target);
+    clean::record_disk_usage(builder, &part, &[builder.llvm_out(target)]);
+}
+
+/// Runs `cfg.build()` with the priority configured for the running step.
+fn cmake_build(builder: &Builder<'_>, cfg: &mut cmake::Config) {
+    // The build may run on a thread of its own, which has to own `cfg`.
+    let owned = mem::replace(cfg, cmake::Config::new(""));
+    let start = Instant::now();
+    *cfg = priority::run_with(builder.priority(), move || {
+        let mut cfg = owned;
+        cfg.build();
+        cfg
+    });
+    if let Some(trace) = &builder.trace {
+        let step = builder.running_steps.borrow().last().copied().unwrap_or("unknown step");
+        trace.cmake(step, start);
+    }
+}
+
 fn configure_cmake(
     builder: &Builder<'_>,
     target: TargetSelection,
     cfg: &mut cmake::Config,
     use_compiler_launcher: bool,
+    jobs: u32,
 ) {
     // Do not print installation messages for up-to-date files.
     // LLVM and LLD builds can produce a lot of those and hit CI limits on log size.
@@ -465,7 +885,7 @@ fn configure_cmake(
             .define("CMAKE_ASM_COMPILER", sanitize_cc(cc));
     }
 
-    cfg.build_arg("-j").build_arg(builder.jobs().to_string());
+    cfg.build_arg("-j").build_arg(jobs.to_string());
     let mut cflags = builder.cflags(target, GitRepo::Llvm).join(" ");
     if let Some(ref s) = builder.config.llvm_cflags {
         cflags.push_str(&format!(" {}", s));
@@ -557,7 +977,7 @@ fn run(self, builder: &Builder<'_>) -> PathBuf {
         t!(fs::create_dir_all(&out_dir));
 
         let mut cfg = cmake::Config::new(builder.src.join("src/llvm-project/lld"));
-        configure_cmake(builder, target, &mut cfg, true);
+        configure_cmake(builder, target, &mut cfg, true, builder.jobs());
 
         // This is an awful, awful hack. Discovered when we migrated to using
         // clang-cl to compile LLVM/LLD it turns out that LLD, when built out of
@@ -607,11 +1027,15 @@ fn run(self, builder: &Builder<'_>) -> PathBuf {
         // for standalone builds.
         cfg.define("CMAKE_CXX_STANDARD", "14");
 
-        cfg.build();
+        cmake_build(builder, &mut cfg);
 
         t!(File::create(&done_stamp));
         out_dir
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
@@ -677,6 +1101,10 @@ fn run(self, builder: &Builder<'_>) {
             .file(builder.src.join("src/test/auxiliary/rust_test_helpers.c"))
             .compile("rust_test_helpers");
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
@@ -747,19 +1175,23 @@ fn run(self, builder: &Builder<'_>) -> Self::Output {
         // Unfortunately sccache currently lacks support to build them successfully.
         // Disable compiler launcher on Darwin targets to avoid potential issues.
         let use_compiler_launcher = !self.target.contains("apple-darwin");
-        configure_cmake(builder, self.target, &mut cfg, use_compiler_launcher);
+        configure_cmake(builder, self.target, &mut cfg, use_compiler_launcher, builder.jobs());
 
         t!(fs::create_dir_all(&out_dir));
         cfg.out_dir(out_dir);
 
         for runtime in &runtimes {
             cfg.build_target(&runtime.cmake_target);
-            cfg.build();
+            cmake_build(builder, &mut cfg);
         }
         t!(stamp.write());
 
         runtimes
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -773,7 +1205,7 @@ pub struct SanitizerRuntime {
 }
 
 /// Returns sanitizers available on a given target.
-fn supported_sanitizers(
+pub fn supported_sanitizers(
     out_dir: &Path,
     target: TargetSelection,
     channel: &str,
@@ -0,0 +1,129 @@
+//! Choosing a step path when the one passed to x.py matches no step.
+//!
+//! Typos in paths like `src/test/uii` used to stop x.py with a panic, after
+//! it had possibly spent a while on other paths. Instead, the paths of the
+//! steps that come closest to it are offered: on a terminal, one of them can
+//! be chosen to run instead, and otherwise they're printed as suggestions.
+
+use std::cmp;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use build_helper::t;
+
+use crate::util;
+
+/// The number of paths offered at most.
+const MAX_CHOICES: usize = 10;
+
+/// Asks which of `candidates`, the paths of the steps that can be run, to
+/// run instead of `typed`, which matches none of them. Exits if none is
+/// chosen or x.py isn't run interactively.
+pub fn choose(typed: &Path, candidates: &[PathBuf]) -> PathBuf {
+    let choices = closest(typed, candidates);
+    println!("error: no step matches the path `{}`", typed.display());
+    if choices.is_empty() {
+        println!("help: run with `-h -v` to see the paths of the steps");
+        process::exit(1);
+    }
+    if !util::stdin_is_tty() || !util::stdout_is_tty() {
+        for choice in &choices {
+            println!("help: did you mean `{}`?", choice.display());
+        }
+        process::exit(1);
+    }
+
+    println!("Did you mean one of these?");
+    for (i, choice) in choices.iter().enumerate() {
+        println!("{:>4}) {}", i + 1, choice.display());
+    }
+    loop {
+        print!("Choose one to run instead (1-{}), or press enter to stop: ", choices.len());
+        t!(io::stdout().flush());
+        let mut input = String::new();
+        t!(io::stdin().read_line(&mut input));
+        let input = input.trim();
+        if input.is_empty() {
+            process::exit(1);
+        }
+        match input.parse::<usize>() {
+            Ok(n) if n >= 1 && n <= choices.len() => return choices[n - 1].clone(),
+            _ => println!("error: `{}` isn't one of the numbers above", input),
+        }
+    }
+}
+
+/// Returns the candidates close enough to `typed` to be offered, closest
+/// first.
+fn closest(typed: &Path, candidates: &[PathBuf]) -> Vec<PathBuf> {
+    let typed = typed.to_string_lossy().replace('\\', "/");
+    let typed = typed.trim_end_matches('/');
+    let mut scored = candidates
+        .iter()
+        .filter_map(|candidate| Some((distance(typed, candidate)?, candidate)))
+        .collect::<Vec<_>>();
+    scored.sort_by(|(a, a_path), (b, b_path)| {
+        a.cmp(b).then(a_path.as_os_str().len().cmp(&b_path.as_os_str().len()))
+    });
+    let mut choices = scored.into_iter().map(|(_, path)| path.clone()).collect::<Vec<_>>();
+    choices.dedup();
+    choices.truncate(MAX_CHOICES);
+    choices
+}
+
+/// How far `candidate` is from `typed`, which has forward slashes, or `None`
+/// if they have too little in common. `typed` is compared to as many trailing
+/// components of `candidate` as it has, as paths are matched by suffix.
+fn distance(typed: &str, candidate: &Path) -> Option<usize> {
+    let candidate = candidate.to_string_lossy().replace('\\', "/");
+    if candidate.contains(typed) {
+        return Some(0);
+    }
+    let components = typed.split('/').count();
+    let tail = candidate.rsplit('/').take(components).collect::<Vec<_>>();
+    let tail = tail.into_iter().rev().collect::<Vec<_>>().join("/");
+    let distance = edit_distance(typed, &tail);
+    if distance <= cmp::max(2, typed.len() / 3) { Some(distance) } else { None }
+}
+
+/// The Levenshtein distance between `a` and `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+    for (i, a) in a.chars().enumerate() {
+        let mut previous = row[0];
+        row[0] = i + 1;
+        for j in 0..b.len() {
+            let substituted = previous + if a == b[j] { 0 } else { 1 };
+            previous = row[j + 1];
+            row[j + 1] = cmp::min(substituted, cmp::min(row[j], row[j + 1]) + 1);
+        }
+    }
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn closest_paths() {
+        let candidates = ["src/test/ui", "src/test/ui-fulldeps", "src/tools/tidy", "library/std"]
+            .iter()
+            .map(PathBuf::from)
+            .collect::<Vec<_>>();
+        assert_eq!(closest(Path::new("src/test/uii"), &candidates), [PathBuf::from("src/test/ui")]);
+        assert_eq!(closest(Path::new("tdy"), &candidates)[0], PathBuf::from("src/tools/tidy"));
+        assert_eq!(closest(Path::new("ui"), &candidates)[0], PathBuf::from("src/test/ui"));
+        assert!(closest(Path::new("compiler/rustc_parse"), &candidates).is_empty());
+    }
+
+    #[test]
+    fn edit_distances() {
+        assert_eq!(edit_distance("tidy", "tidy"), 0);
+        assert_eq!(edit_distance("tdiy", "tidy"), 2);
+        assert_eq!(edit_distance("uii", "ui"), 1);
+        assert_eq!(edit_distance("", "std"), 3);
+    }
+}
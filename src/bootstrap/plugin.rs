@@ -0,0 +1,126 @@
+//! Steps declared with `[[plugin]]` in `config.toml`.
+//!
+//! Organizations with their own tools, like an internal test harness or a
+//! signing service, want x.py to run them with the toolchain it builds
+//! without forking bootstrap. A plugin is an external command which runs as a
+//! step of the subcommand of its `kind`: it's selected by the paths it claims
+//! like the built-in steps, or by default with `default = true`, and runs once
+//! for each target (or each host, with `only-hosts = true`) after the
+//! components it `requires` are built. The command is run from the source
+//! directory with the test arguments given after `--`, and gets the step in
+//! environment variables:
+//!
+//! - `BOOTSTRAP_PLUGIN`: the name of the plugin
+//! - `BOOTSTRAP_TARGET` and `BOOTSTRAP_STAGE`: the target it runs for and the
+//!   stage of the compiler built for it
+//! - `BOOTSTRAP_SRC_DIR` and `BOOTSTRAP_BUILD_DIR`
+//! - `RUSTC` and `SYSROOT`, `RUSTDOC`, `CARGO` and `LLVM_CONFIG`, for each of
+//!   `rustc`, `rustdoc`, `cargo` and `llvm` it requires
+//!
+//! Requiring `std` builds the standard library for the target. Plugins are
+//! all steps of the type `plugin::External`, so `--exclude plugin::*` skips
+//! them.
+
+use std::path::Path;
+use std::process::Command;
+
+use crate::builder::{Builder, RunConfig, ShouldRun, Step};
+use crate::cache::{Interned, INTERNER};
+use crate::compile;
+use crate::config::{Plugin, TargetSelection};
+use crate::native;
+use crate::tool;
+
+/// The components plugins can require to be built before they run.
+pub const REQUIREMENTS: &[&str] = &["std", "rustc", "rustdoc", "cargo", "llvm"];
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct External {
+    pub name: Interned<String>,
+    pub target: TargetSelection,
+}
+
+/// The plugins which are steps of the subcommand being run.
+fn plugins<'a>(builder: &'a Builder<'_>) -> impl Iterator<Item = &'a Plugin> + 'a {
+    let kind = builder.kind;
+    builder.config.plugins.iter().filter(move |p| Builder::kind_for_paths(&p.kind) == Some(kind))
+}
+
+impl Step for External {
+    type Output = ();
+    const DEFAULT: bool = true;
+
+    fn should_run(run: ShouldRun<'_>) -> ShouldRun<'_> {
+        let builder = run.builder;
+        if plugins(builder).next().is_none() {
+            return run.never();
+        }
+        let mut run = run.default_condition(plugins(builder).any(|p| p.default));
+        for plugin in plugins(builder) {
+            run = run.paths(&plugin.paths.iter().map(|p| &**p).collect::<Vec<_>>());
+        }
+        run
+    }
+
+    fn make_run(run: RunConfig<'_>) {
+        let builder = run.builder;
+        // All plugins share this step's description, so find the ones which
+        // claim the path it was selected by.
+        let claiming =
+            plugins(builder).filter(|p| p.paths.iter().any(|path| Path::new(path) == run.path));
+        for plugin in claiming {
+            if builder.paths.is_empty() && !plugin.default {
+                continue;
+            }
+            if plugin.only_hosts && !builder.hosts.contains(&run.target) {
+                continue;
+            }
+            let name = INTERNER.intern_str(&plugin.name);
+            builder.ensure(External { name, target: run.target });
+        }
+    }
+
+    fn run(self, builder: &Builder<'_>) {
+        let plugin = builder.config.plugins.iter().find(|p| p.name == *self.name).unwrap();
+        // Only assemble a compiler if the plugin requires something built by
+        // one, so that e.g. a signing plugin doesn't build rustc.
+        let compiler = || builder.compiler(builder.top_stage, builder.config.build);
+
+        let mut cmd = Command::new(&plugin.command);
+        cmd.args(&plugin.args).args(builder.config.cmd.test_args()).current_dir(&builder.src);
+        cmd.env("BOOTSTRAP_PLUGIN", &plugin.name)
+            .env("BOOTSTRAP_TARGET", &*self.target.triple)
+            .env("BOOTSTRAP_STAGE", builder.top_stage.to_string())
+            .env("BOOTSTRAP_SRC_DIR", &builder.src)
+            .env("BOOTSTRAP_BUILD_DIR", &builder.out);
+        for requirement in &plugin.requires {
+            match &requirement[..] {
+                "std" => builder.ensure(compile::Std { compiler: compiler(), target: self.target }),
+                "rustc" => {
+                    cmd.env("RUSTC", builder.rustc(compiler()));
+                    cmd.env("SYSROOT", &*builder.sysroot(compiler()));
+                }
+                "rustdoc" => {
+                    cmd.env("RUSTDOC", builder.rustdoc(compiler()));
+                }
+                "cargo" => {
+                    let target = builder.config.build;
+                    let cargo = builder.ensure(tool::Cargo { compiler: compiler(), target });
+                    cmd.env("CARGO", cargo);
+                }
+                "llvm" => {
+                    let llvm_config = builder.ensure(native::Llvm { target: builder.config.build });
+                    cmd.env("LLVM_CONFIG", llvm_config);
+                }
+                _ => unreachable!("`{}` is checked when loading config.toml", requirement),
+            }
+        }
+
+        builder.info(&format!("Running plugin {} for {}", plugin.name, self.target));
+        builder.run(&mut cmd);
+    }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
+}
@@ -0,0 +1,98 @@
+//! Lowering the CPU and I/O priority of the commands spawned by the build, as
+//! configured with `build.nice`, `build.ionice-class` and
+//! `[build.step-priority.<step>]`.
+//!
+//! Commands run through `Build::run` and its variants, and cargo run through
+//! `compile::stream_cargo`, get their priority set right before they are
+//! executed. LLVM and the other native libraries are
+//! built by the `cmake` crate, which spawns its commands itself, so those
+//! builds run on a thread of their own whose priority the commands inherit.
+//! Only Linux has per-thread priorities, so elsewhere they keep the priority
+//! of bootstrap itself. Nothing is changed on Windows.
+
+use std::panic;
+use std::process::Command;
+use std::thread;
+
+use crate::config::Priority;
+
+/// Makes `cmd` run with `priority`.
+pub fn apply(cmd: &mut Command, priority: Priority) {
+    if priority != Priority::default() {
+        imp::apply(cmd, priority);
+    }
+}
+
+/// Runs `f` with `priority`, which the processes it spawns inherit.
+pub fn run_with<T, F>(priority: Priority, f: F) -> T
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    if priority == Priority::default() || !cfg!(target_os = "linux") {
+        return f();
+    }
+    let thread = thread::spawn(move || {
+        unsafe { imp::set(priority) };
+        f()
+    });
+    thread.join().unwrap_or_else(|e| panic::resume_unwind(e))
+}
+
+#[cfg(all(unix, not(target_os = "haiku")))]
+mod imp {
+    use std::os::unix::process::CommandExt;
+    use std::process::Command;
+
+    use crate::config::Priority;
+
+    pub fn apply(cmd: &mut Command, priority: Priority) {
+        // Failing to lower the priority isn't worth failing the build over,
+        // so errors are ignored here and in `set`.
+        unsafe {
+            cmd.pre_exec(move || {
+                set(priority);
+                Ok(())
+            });
+        }
+    }
+
+    /// Sets the priority of the calling thread on Linux, and of the whole
+    /// process elsewhere.
+    pub unsafe fn set(priority: Priority) {
+        if let Some(nice) = priority.nice {
+            libc::setpriority(libc::PRIO_PROCESS as _, 0, nice);
+        }
+        if let Some(class) = priority.ionice_class {
+            set_ionice_class(class);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    unsafe fn set_ionice_class(class: crate::config::IoniceClass) {
+        use crate::config::IoniceClass;
+
+        // See `ioprio_set(2)`: the class is stored above the 13 bits holding
+        // the level within it, of which 7 is the lowest.
+        const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+        let ioprio: libc::c_int = match class {
+            IoniceClass::BestEffort => 2 << 13 | 7,
+            IoniceClass::Idle => 3 << 13,
+        };
+        libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio);
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    unsafe fn set_ionice_class(_class: crate::config::IoniceClass) {}
+}
+
+#[cfg(not(all(unix, not(target_os = "haiku"))))]
+mod imp {
+    use std::process::Command;
+
+    use crate::config::Priority;
+
+    pub fn apply(_cmd: &mut Command, _priority: Priority) {}
+
+    pub unsafe fn set(_priority: Priority) {}
+}
@@ -4,6 +4,9 @@
 use build_helper::output;
 use std::process::Command;
 
+/// Tools that are run by a dedicated step above rather than by `RunTool`.
+const DEDICATED: &[&str] = &["src/tools/expand-yaml-anchors", "src/tools/build-manifest"];
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub struct ExpandYamlAnchors;
 
@@ -82,3 +85,37 @@ fn run(self, builder: &Builder<'_>) {
         builder.run(&mut cmd);
     }
 }
+
+/// Runs one of bootstrap's own tools with the arguments passed with `--args`,
+/// building it first if it's out of date.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct RunTool {
+    pub tool: Tool,
+}
+
+impl Step for RunTool {
+    type Output = ();
+    const ONLY_HOSTS: bool = true;
+
+    fn should_run(mut run: ShouldRun<'_>) -> ShouldRun<'_> {
+        for &(_, path) in Tool::ALL {
+            if !DEDICATED.contains(&path) {
+                run = run.path(path);
+            }
+        }
+        run
+    }
+
+    fn make_run(run: RunConfig<'_>) {
+        let tool = Tool::ALL.iter().find(|(_, path)| run.path.ends_with(path));
+        if let Some(&(tool, _)) = tool {
+            run.builder.ensure(RunTool { tool });
+        }
+    }
+
+    fn run(self, builder: &Builder<'_>) {
+        let mut cmd = builder.tool_cmd(self.tool);
+        cmd.args(builder.config.cmd.run_args());
+        builder.run(&mut cmd);
+    }
+}
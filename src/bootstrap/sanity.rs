@@ -3,7 +3,9 @@
 //! This module contains the implementation of ensuring that the build
 //! environment looks reasonable before progressing. This will verify that
 //! various programs like git and python exist, along with ensuring that all C
-//! compilers for cross-compiling are found.
+//! compilers for cross-compiling are found. Optionally it also measures IO
+//! throughput in the build directory to detect interference from antivirus
+//! software.
 //!
 //! In theory if we get past this phase it's a bug if a build fails, but in
 //! practice that's likely not true!
@@ -12,15 +14,47 @@
 use std::env;
 use std::ffi::{OsStr, OsString};
 use std::fs;
-use std::path::PathBuf;
-use std::process::Command;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::time::{Duration, Instant, SystemTime};
 
 use build_helper::{output, t};
 
-use crate::cache::INTERNER;
-use crate::config::Target;
+use crate::change_detection;
+use crate::clean;
+use crate::config::{is_prebuilt_codegen_backend, MtimeCheck, Target};
+use crate::flags::Subcommand;
+use crate::native;
+use crate::util;
+use crate::vcs::Vcs;
 use crate::Build;
 
+/// Number of small files written and read back by the IO diagnostics.
+const IO_DIAGNOSTICS_FILES: u32 = 1000;
+
+/// Files per second below which the IO diagnostics emit a warning. An
+/// unscanned disk typically manages tens of thousands, while real-time
+/// antivirus scanning tends to cut that by an order of magnitude or more.
+const IO_DIAGNOSTICS_MIN_RATE: f64 = 2000.0;
+
+/// Rough sizes, in bytes, of the output of various parts of the build, as
+/// observed for x86_64 Linux hosts. These are used to estimate up front
+/// whether a build is likely to fit on disk for the parts that no earlier
+/// build recorded the size of.
+const LLVM_SIZE: u64 = 2 << 30;
+const LLVM_DEBUGINFO_SIZE: u64 = 8 << 30;
+const COMPILER_STAGE_SIZE: u64 = 3 << 30;
+const DIST_SIZE: u64 = 2 << 30;
+
+/// How far modification times may be off from the clock before they're
+/// considered skewed, which covers filesystems with coarse timestamps.
+const MTIME_TOLERANCE: Duration = Duration::from_secs(2);
+/// The source directories cargo watches for changes. `src/llvm-project` is
+/// skipped, as it's as large as all of these together.
+const MTIME_CHECKED_DIRS: &[&str] = &["compiler", "library", "src"];
+/// How many future-dated files are listed at most.
+const MTIME_MAX_LISTED: usize = 10;
+
 pub struct Finder {
     cache: HashMap<OsString, Option<PathBuf>>,
     path: OsString,
@@ -73,30 +107,40 @@ pub fn check(build: &mut Build) {
     }
 
     let mut cmd_finder = Finder::new();
-    // If we've got a git directory we're gonna need git to update
-    // submodules and learn about various other aspects.
+    // If we've got a git or jj checkout we're gonna need them to learn about
+    // various aspects.
     if build.rust_info.is_git() {
-        cmd_finder.must_have("git");
+        cmd_finder.must_have(if build.vcs == Vcs::Jj { "jj" } else { "git" });
     }
 
     // We need cmake, but only if we're actually building LLVM or sanitizers.
-    let building_llvm = build.config.rust_codegen_backends.contains(&INTERNER.intern_str("llvm"))
-        && build
-            .hosts
-            .iter()
-            .map(|host| {
-                build
+    let building_llvm = build
+        .hosts
+        .iter()
+        .map(|host| {
+            build.config.llvm_enabled(*host)
+                && build
                     .config
                     .target_config
                     .get(host)
                     .map(|config| config.llvm_config.is_none())
                     .unwrap_or(true)
-            })
-            .any(|build_llvm_ourselves| build_llvm_ourselves);
-    if building_llvm || build.config.any_sanitizers_enabled() {
+        })
+        .any(|build_llvm_ourselves| build_llvm_ourselves);
+    // Sanitizer runtimes are only built for targets which have them, so
+    // there's no need for cmake if none of the targets asking for them do.
+    let building_sanitizers = build.targets.iter().any(|target| {
+        build.config.sanitizers_enabled(*target)
+            && !native::supported_sanitizers(&build.out, *target, &build.config.channel).is_empty()
+    });
+    if building_llvm || building_sanitizers {
         cmd_finder.must_have("cmake");
     }
 
+    if build.config.cmd.sign() {
+        cmd_finder.must_have("gpg");
+    }
+
     build.config.python = build
         .config
         .python
@@ -120,6 +164,31 @@ pub fn check(build: &mut Build) {
         .map(|p| cmd_finder.must_have(p))
         .or_else(|| cmd_finder.maybe_have("gdb"));
 
+    // A pinned clippy is only useful if it understands the compiler it's
+    // run on, so insist that it's the version this source tree ships.
+    if let Some(cargo_clippy) = &build.config.initial_cargo_clippy {
+        let driver = cargo_clippy.with_file_name(util::exe("clippy-driver", build.build));
+        for bin in &[cargo_clippy, &driver] {
+            if !bin.is_file() {
+                panic!("\n\nconfigured `build.cargo-clippy` needs {} to exist\n\n", bin.display());
+            }
+        }
+        if !build.config.dry_run {
+            let manifest = t!(fs::read_to_string(build.src.join("src/tools/clippy/Cargo.toml")));
+            let manifest: toml::Value = t!(toml::from_str(&manifest));
+            let expected = manifest["package"]["version"].as_str().unwrap_or("");
+            let version = output(Command::new(cargo_clippy).arg("--version"));
+            if version.split_whitespace().nth(1) != Some(expected) {
+                panic!(
+                    "\n\nconfigured `build.cargo-clippy` reports `{}`, but this source tree \
+                     contains clippy {}\n\n",
+                    version.trim(),
+                    expected
+                );
+            }
+        }
+    }
+
     // We're gonna build some custom C code here and there, host triples
     // also build some C++ shims for LLVM so we need a C++ compiler.
     for target in &build.targets {
@@ -149,7 +218,7 @@ pub fn check(build: &mut Build) {
         }
     }
 
-    if build.config.rust_codegen_backends.contains(&INTERNER.intern_str("llvm")) {
+    if build.config.llvm_enabled(build.build) {
         // Externally configured LLVM requires FileCheck to exist
         let filecheck = build.llvm_filecheck(build.build);
         if !filecheck.starts_with(&build.out) && !filecheck.exists() && build.config.codegen_tests {
@@ -157,6 +226,42 @@ pub fn check(build: &mut Build) {
         }
     }
 
+    // Backends other than LLVM are built from `compiler/rustc_codegen_$name`,
+    // so make sure a backend actually exists before spending an hour building
+    // the compiler it's meant to plug into. In particular the GCC backend is
+    // not part of this source tree. Prebuilt backends must be named like the
+    // ones we build, as that's how rustc finds them in the sysroot.
+    for host in &build.hosts {
+        for backend in build.config.codegen_backends(*host) {
+            if backend == "llvm" {
+                continue;
+            }
+            if is_prebuilt_codegen_backend(backend) {
+                let path = Path::new(&**backend);
+                let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+                if !path.is_file() || !name.contains("rustc_codegen_") {
+                    panic!(
+                        "\n\nprebuilt codegen backend for {} must be an existing \
+                         `rustc_codegen_$name` dylib, but is {}\n\n",
+                        host,
+                        path.display()
+                    );
+                }
+                continue;
+            }
+            let path = build.src.join("compiler").join(format!("rustc_codegen_{}", backend));
+            if !path.join("Cargo.toml").exists() {
+                panic!(
+                    "\n\ncodegen backend `{}` requested for {} is not available \
+                     in this source tree; expected to find it at {}\n\n",
+                    backend,
+                    host,
+                    path.display()
+                );
+            }
+        }
+    }
+
     for target in &build.targets {
         // Can't compile for iOS unless we're on macOS
         if target.contains("apple-ios") && !build.build.contains("apple-darwin") {
@@ -197,6 +302,18 @@ pub fn check(build: &mut Build) {
             }
         }
 
+        // An explicit per-target `sanitizers = true` on a target without any
+        // sanitizer runtimes does nothing, which is probably not what was meant.
+        let target_sanitizers = build.config.target_config.get(target).and_then(|t| t.sanitizers);
+        if target_sanitizers == Some(true)
+            && native::supported_sanitizers(&build.out, *target, &build.config.channel).is_empty()
+        {
+            println!(
+                "warning: sanitizers are enabled for {} but it has no sanitizer runtimes",
+                target
+            );
+        }
+
         if target.contains("msvc") {
             // There are three builds of cmake on windows: MSVC, MinGW, and
             // Cygwin. The Cygwin build does not have generators for Visual
@@ -234,4 +351,253 @@ pub fn check(build: &mut Build) {
             );
         }
     }
+
+    let shares_artifacts =
+        build.config.artifact_cache.is_some() || build.config.llvm_prebuilt_archive.is_some();
+    if shares_artifacts && !cfg!(feature = "artifact-cache") {
+        eprintln!(
+            "error: `build.artifact-cache` and `llvm.prebuilt-archive` need bootstrap to be \
+             built with its `artifact-cache` feature"
+        );
+        eprintln!("help: x.py enables it when config.toml sets either of them");
+        process::exit(1);
+    }
+
+    if build.config.io_diagnostics && !build.config.dry_run {
+        check_io_throughput(build);
+    }
+
+    if !build.config.dry_run {
+        check_disk_space(build);
+    }
+
+    if build.config.mtime_check != MtimeCheck::Off && !build.config.dry_run {
+        check_mtimes(build);
+    }
+}
+
+/// Compares the free space in the build directories against an estimate of
+/// what the requested command will need, so that a build that is bound to run
+/// out of space fails now rather than an hour into linking LLVM. The estimate
+/// uses the sizes recorded by earlier builds where there are any.
+fn check_disk_space(build: &Build) {
+    let stages = match build.config.cmd {
+        Subcommand::Check { .. }
+        | Subcommand::Clippy { .. }
+        | Subcommand::Fix { .. }
+        | Subcommand::Format { .. }
+        | Subcommand::Clean { .. }
+        | Subcommand::Gc { .. }
+        | Subcommand::Setup { .. }
+        | Subcommand::Uninstall
+        | Subcommand::Suggest { .. }
+        | Subcommand::CiSmoke { .. } => return,
+        Subcommand::ExportLlvm { .. } => 0,
+        _ => u64::from(build.config.stage.max(1)),
+    };
+    let hosts = build.hosts.len() as u64;
+    let recorded = clean::recorded_disk_usage(build);
+    let size = |part: String, estimate: u64| recorded.get(&part).copied().unwrap_or(estimate);
+
+    let mut out_size = 0;
+    let mut tmp_size = 0;
+    for host in &build.hosts {
+        let building_llvm = build.is_rust_llvm(*host)
+            && !(build.config.llvm_from_ci && *host == build.config.build);
+        if building_llvm && !build.llvm_out(*host).join("llvm-finished-building").exists() {
+            let estimate =
+                if build.config.llvm_release_debuginfo { LLVM_DEBUGINFO_SIZE } else { LLVM_SIZE };
+            out_size += size(format!("llvm-{}", host), estimate);
+        }
+        for stage in 0..stages {
+            tmp_size += size(format!("stage{}-{}", stage, host), COMPILER_STAGE_SIZE);
+        }
+    }
+    if let Subcommand::Dist { .. } | Subcommand::Install { .. } = build.config.cmd {
+        out_size += size(String::from("dist"), DIST_SIZE * hosts);
+    }
+
+    t!(fs::create_dir_all(&build.out));
+    if build.tmp_out == build.out {
+        check_free_space(build, &build.out, out_size + tmp_size);
+    } else {
+        check_free_space(build, &build.out, out_size);
+        check_free_space(build, &build.tmp_out, tmp_size);
+    }
+}
+
+fn check_free_space(build: &Build, dir: &Path, needed: u64) {
+    let avail = match util::free_space(dir) {
+        Some(avail) => avail,
+        None => return,
+    };
+    build.require_free_space(dir, avail, "refusing to start the build");
+    if avail < needed {
+        println!(
+            "warning: this build may need around {} GiB of disk space in {}, \
+             but only {} GiB are free",
+            needed >> 30,
+            dir.display(),
+            avail >> 30
+        );
+    }
+}
+
+/// Creates, reads back and removes a batch of small files in the build
+/// directory, warning if this is much slower than it should be. Compiling
+/// rustc touches many thousands of small files, and on-access antivirus
+/// scanning of those is a frequent cause of very slow builds on Windows.
+fn check_io_throughput(build: &Build) {
+    let dir = build.out.join("tmp").join("io-diagnostics");
+    let _ = fs::remove_dir_all(&dir);
+    t!(fs::create_dir_all(&dir));
+
+    let contents = [0u8; 512];
+    let start = Instant::now();
+    for i in 0..IO_DIAGNOSTICS_FILES {
+        let path = dir.join(format!("{}.rs", i));
+        t!(fs::write(&path, &contents[..]));
+        t!(fs::read(&path));
+    }
+    t!(fs::remove_dir_all(&dir));
+    let secs = start.elapsed().as_secs_f64().max(f64::EPSILON);
+    let rate = f64::from(IO_DIAGNOSTICS_FILES) / secs;
+    build.verbose(&format!("io diagnostics: {:.0} files/s in {}", rate, build.out.display()));
+
+    if rate >= IO_DIAGNOSTICS_MIN_RATE {
+        return;
+    }
+
+    println!(
+        "warning: small file IO in the build directory is slow ({:.0} files/s, \
+         expected at least {:.0} files/s)",
+        rate, IO_DIAGNOSTICS_MIN_RATE
+    );
+    if cfg!(windows) {
+        println!(
+            "\
+This is usually caused by Windows Defender or another antivirus scanning every
+file the build creates. Consider excluding the build directory, for example by
+running the following in an administrator PowerShell prompt:
+
+    Add-MpPreference -ExclusionPath \"{}\"
+
+or by adding it under Windows Security > Virus & threat protection > Manage
+settings > Exclusions.
+",
+            build.out.display()
+        );
+    } else {
+        println!(
+            "\
+If an on-access antivirus scanner is running, consider excluding {} from it.
+",
+            build.out.display()
+        );
+    }
+    println!("Set `build.io-diagnostics = false` in config.toml to skip this check.");
+}
+
+/// Looks for clock skew between this machine and the filesystem of the build
+/// directory, and for source files with modification times in the future.
+/// Either makes cargo consider the affected crates out of date on every build,
+/// which is common with NFS and containers and otherwise hard to diagnose.
+/// All the sources are only checked with `build.mtime-check = "error"`.
+fn check_mtimes(build: &Build) {
+    let mut problems = Vec::new();
+
+    let probe = build.out.join("tmp").join("mtime-check");
+    t!(fs::create_dir_all(probe.parent().unwrap()));
+    t!(fs::write(&probe, b""));
+    let now = SystemTime::now();
+    let written = t!(t!(fs::metadata(&probe)).modified());
+    let _ = fs::remove_file(&probe);
+    let (skew, direction) = match written.duration_since(now) {
+        Ok(ahead) => (ahead, "ahead of"),
+        Err(behind) => (behind.duration(), "behind"),
+    };
+    if skew > MTIME_TOLERANCE {
+        problems.push(format!(
+            "the clock of the filesystem holding {} is {}s {} the clock of this machine",
+            build.out.display(),
+            skew.as_secs(),
+            direction
+        ));
+    }
+
+    let mut future = Vec::new();
+    let limit = now + MTIME_TOLERANCE;
+    if build.config.mtime_check == MtimeCheck::Error {
+        let skipped = [build.out.clone(), build.src.join("src/llvm-project")];
+        for dir in MTIME_CHECKED_DIRS {
+            find_future_files(&build.src.join(dir), limit, &skipped, &mut future);
+        }
+    } else {
+        // Walking all the sources takes a while on every build, so by default
+        // only the files changed since the merge base are looked at, which
+        // are the ones being edited, or none without version control.
+        let changed = build.vcs.modified_files(&build.src, change_detection::DEFAULT_BASE);
+        for path in changed.unwrap_or_default() {
+            let path = build.src.join(path);
+            if !MTIME_CHECKED_DIRS.iter().any(|dir| path.starts_with(build.src.join(dir))) {
+                continue;
+            }
+            match fs::metadata(&path).and_then(|metadata| metadata.modified()) {
+                Ok(modified) if modified > limit => future.push(path),
+                _ => {}
+            }
+        }
+    }
+    if !future.is_empty() {
+        future.sort();
+        let mut msg =
+            format!("found {} source file(s) with modification times in the future:", future.len());
+        for path in future.iter().take(MTIME_MAX_LISTED) {
+            let path = path.strip_prefix(&build.src).unwrap_or(path);
+            msg.push_str(&format!("\n    {}", path.display()));
+        }
+        if future.len() > MTIME_MAX_LISTED {
+            msg.push_str(&format!("\n    and {} more", future.len() - MTIME_MAX_LISTED));
+        }
+        problems.push(msg);
+    }
+
+    if problems.is_empty() {
+        return;
+    }
+    let msg = format!(
+        "{}\n\
+         This makes cargo rebuild the affected crates every time. Synchronize the clocks, \
+         or `touch` the files once the clocks agree.\n\
+         Set `build.mtime-check = \"off\"` in config.toml to skip this check.",
+        problems.join("\n")
+    );
+    match build.config.mtime_check {
+        MtimeCheck::Error => panic!("\n\n{}\n\n", msg),
+        _ => println!("warning: {}", msg),
+    }
+}
+
+/// Adds the files under `dir` modified after `limit` to `found`, skipping
+/// the directories in `skipped`, version control directories and symlinks.
+fn find_future_files(dir: &Path, limit: SystemTime, skipped: &[PathBuf], found: &mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.filter_map(Result::ok) {
+        let path = entry.path();
+        let ty = match entry.file_type() {
+            Ok(ty) => ty,
+            Err(_) => continue,
+        };
+        if ty.is_dir() && entry.file_name() != ".git" && !skipped.contains(&path) {
+            find_future_files(&path, limit, skipped, found);
+        } else if ty.is_file() {
+            match entry.metadata().and_then(|metadata| metadata.modified()) {
+                Ok(modified) if modified > limit => found.push(path),
+                _ => {}
+            }
+        }
+    }
 }
@@ -1,4 +1,5 @@
-use crate::{t, VERSION};
+use crate::change_tracker::latest_change_id;
+use crate::t;
 use std::fmt::Write as _;
 use std::path::{Path, PathBuf};
 use std::process::Command;
@@ -61,6 +62,76 @@ fn from_str(s: &str) -> Result<Self, Self::Err> {
     }
 }
 
+/// A git hook installed by `x.py setup hooks`.
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub enum GitHook {
+    PrePush,
+    PreCommit,
+}
+
+impl GitHook {
+    fn all() -> impl Iterator<Item = Self> {
+        [GitHook::PrePush, GitHook::PreCommit].iter().copied()
+    }
+
+    /// The script of the hook, which runs the checks of `fmt` and `tidy` that
+    /// CI runs first. `fmt --check` only looks at the files modified on the
+    /// current branch, so it fails fast before tidy runs.
+    fn script(&self) -> String {
+        format!(
+            "#!/usr/bin/env bash
+{marker}
+# Edits to this file are overwritten; delete it to remove the hook.
+
+set -Eeuo pipefail
+
+# https://github.com/rust-lang/rust/issues/77620#issuecomment-705144570
+unset GIT_DIR
+ROOT_DIR=\"$(git rev-parse --show-toplevel)\"
+X_PY=\"$ROOT_DIR/x.py\"
+
+if [[ \"$OSTYPE\" == \"msys\" || \"$OSTYPE\" == \"win32\" ]]; then
+  X_PY=\"python $X_PY\"
+fi
+
+echo \"Running the {hook} hook, which checks formatting and runs tidy\"
+
+cd \"$ROOT_DIR\"
+
+$X_PY fmt --check
+$X_PY test tidy
+",
+            marker = GENERATED_HOOK_MARKER,
+            hook = self,
+        )
+    }
+}
+
+impl FromStr for GitHook {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pre-push" => Ok(GitHook::PrePush),
+            "pre-commit" => Ok(GitHook::PreCommit),
+            _ => Err(format!("unknown git hook: '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for GitHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GitHook::PrePush => write!(f, "pre-push"),
+            GitHook::PreCommit => write!(f, "pre-commit"),
+        }
+    }
+}
+
+/// The line identifying the hooks generated by bootstrap, which are updated
+/// by `update_git_hooks`.
+const GENERATED_HOOK_MARKER: &str = "# Generated by `x.py setup hooks`, which keeps it up to date.";
+
 impl fmt::Display for Profile {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -93,8 +164,9 @@ pub fn setup(src_path: &Path, profile: Profile) {
     let settings = format!(
         "# Includes one of the default files in src/bootstrap/defaults\n\
     profile = \"{}\"\n\
-    changelog-seen = {}\n",
-        profile, VERSION
+    change-id = {}\n",
+        profile,
+        latest_change_id()
     );
     t!(fs::write(path, settings));
 
@@ -125,6 +197,67 @@ pub fn setup(src_path: &Path, profile: Profile) {
     }
 }
 
+/// Implementation of `x.py setup hooks`, which installs `hook` into the git
+/// directory of the source tree.
+pub fn setup_hooks(hook: GitHook) {
+    let dst = git_hooks_dir().join(hook.to_string());
+    if dst.exists() && !is_generated_hook(&dst) {
+        println!(
+            "error: a {} hook not generated by x.py already exists at {}",
+            hook,
+            dst.display()
+        );
+        println!("help: remove it to install the hook of x.py instead");
+        std::process::exit(1);
+    }
+    write_hook(&dst, hook);
+    println!("Installed the {} hook at {}", hook, dst.display());
+    println!("It checks formatting and runs tidy, like CI does first.");
+    println!("x.py keeps it up to date; to remove it, delete {}.", dst.display());
+}
+
+/// Rewrites the hooks installed by `x.py setup hooks` whose script is out of
+/// date, e.g. after the checks they run changed upstream.
+pub fn update_git_hooks(src_path: &Path) {
+    // Only look for a `.git` directory rather than asking git, to not run a
+    // process on each invocation. Hooks in worktrees are updated when
+    // `x.py setup hooks` is run again.
+    let hooks_dir = src_path.join(".git").join("hooks");
+    if !hooks_dir.is_dir() {
+        return;
+    }
+    for hook in GitHook::all() {
+        let path = hooks_dir.join(hook.to_string());
+        if is_generated_hook(&path) && fs::read_to_string(&path).ok() != Some(hook.script()) {
+            write_hook(&path, hook);
+            println!("Updated the {} hook at {}", hook, path.display());
+        }
+    }
+}
+
+fn is_generated_hook(path: &Path) -> bool {
+    fs::read_to_string(path).map_or(false, |script| script.contains(GENERATED_HOOK_MARKER))
+}
+
+fn write_hook(path: &Path, hook: GitHook) {
+    t!(fs::create_dir_all(path.parent().unwrap()));
+    t!(fs::write(path, hook.script()));
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        t!(fs::set_permissions(path, fs::Permissions::from_mode(0o755)));
+    }
+}
+
+fn git_hooks_dir() -> PathBuf {
+    let git =
+        t!(Command::new("git").args(&["rev-parse", "--git-common-dir"]).output().map(|output| {
+            assert!(output.status.success(), "failed to run `git`");
+            PathBuf::from(t!(String::from_utf8(output.stdout)).trim())
+        }));
+    git.join("hooks")
+}
+
 // Used to get the path for `Subcommand::Setup`
 pub fn interactive_path() -> io::Result<Profile> {
     fn abbrev_all() -> impl Iterator<Item = ((String, String), Profile)> {
@@ -172,14 +305,32 @@ fn parse_with_abbrev(input: &str) -> Result<Profile, String> {
     Ok(template)
 }
 
+/// File in the git hooks directory recording that the pre-commit hook was
+/// declined, so that `x.py setup` doesn't offer it again.
+const DECLINED_HOOK_MARKER: &str = "x-py-pre-commit-declined";
+
 // install a git hook to automatically run tidy --bless, if they want
 fn install_git_hook_maybe(src_path: &Path) -> io::Result<()> {
+    let hooks_dir = git_hooks_dir();
+    let declined = hooks_dir.join(DECLINED_HOOK_MARKER);
+    if hooks_dir.join("pre-commit").exists() {
+        return Ok(());
+    }
+    if declined.exists() {
+        println!(
+            "Not offering the tidy git hook, which was declined; delete {} to be asked again.",
+            declined.display()
+        );
+        return Ok(());
+    }
+
     let mut input = String::new();
     println!(
         "Rust's CI will automatically fail if it doesn't pass `tidy`, the internal tool for ensuring code quality.
 If you'd like, x.py can install a git hook for you that will automatically run `tidy --bless` on each commit
 to ensure your code is up to par. If you decide later that this behavior is undesirable,
-simply delete the `pre-commit` file from .git/hooks."
+simply delete the `pre-commit` file from .git/hooks.
+To check formatting as well, run `x.py setup hooks` instead, which installs a `pre-push` hook."
     );
 
     let should_install = loop {
@@ -200,13 +351,7 @@ fn install_git_hook_maybe(src_path: &Path) -> io::Result<()> {
 
     if should_install {
         let src = src_path.join("src").join("etc").join("pre-commit.sh");
-        let git = t!(Command::new("git").args(&["rev-parse", "--git-common-dir"]).output().map(
-            |output| {
-                assert!(output.status.success(), "failed to run `git`");
-                PathBuf::from(t!(String::from_utf8(output.stdout)).trim())
-            }
-        ));
-        let dst = git.join("hooks").join("pre-commit");
+        let dst = hooks_dir.join("pre-commit");
         match fs::hard_link(src, &dst) {
             Err(e) => println!(
                 "error: could not create hook {}: do you already have the git hook installed?\n{}",
@@ -216,7 +361,9 @@ fn install_git_hook_maybe(src_path: &Path) -> io::Result<()> {
             Ok(_) => println!("Linked `src/etc/pre-commit.sh` to `.git/hooks/pre-commit`"),
         };
     } else {
-        println!("Ok, skipping installation!");
+        fs::create_dir_all(&hooks_dir)?;
+        fs::write(&declined, "")?;
+        println!("Ok, skipping installation! x.py won't offer it again.");
     }
     Ok(())
 }
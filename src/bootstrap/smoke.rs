@@ -0,0 +1,182 @@
+//! Implementation of `x.py ci-smoke`.
+//!
+//! Runs a small, fixed selection of builds and tests which catches most
+//! breakage quickly, as a first CI job that doesn't need any configuration.
+//! Each phase is run by a separate invocation of bootstrap, with the flags
+//! ci-smoke was given, which is killed along with everything it started once
+//! the whole run exceeds its time budget.
+//!
+//! On Unix, each phase runs in a process group of its own, which is killed
+//! as a whole. The signals which would have reached it through the process
+//! group of ci-smoke, like that of Ctrl-C, are forwarded to it. On Windows,
+//! killing the phase leaves the processes it started to the job object of
+//! `job.rs`, which ends them once x.py exits.
+
+use std::env;
+use std::process::{self, Command};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use build_helper::t;
+
+use crate::Build;
+
+/// The x.py invocations making up the smoke test, cheapest first.
+const PHASES: &[&[&str]] = &[
+    &["test", "src/tools/tidy"],
+    &["build", "--stage", "1", "library/std"],
+    &["test", "--stage", "1", "src/test/ui/parser"],
+];
+
+/// Runs all `PHASES`, failing if any of them fails or if they don't finish
+/// within `budget` minutes.
+pub fn ci_smoke(build: &Build, budget: u64) {
+    let budget = Duration::from_secs(budget * 60);
+    let start = Instant::now();
+    let flags = global_flags(env::args().skip(1).collect());
+    for phase in PHASES {
+        let args = phase_args(phase, &flags);
+        let invocation = format!("x.py {}", args.join(" "));
+        println!("ci-smoke: running `{}`", invocation);
+        if build.config.dry_run {
+            continue;
+        }
+
+        // Run ourselves again, which inherits the environment x.py set up.
+        let mut cmd = Command::new(t!(env::current_exe()));
+        cmd.args(&args);
+        let mut child = t!(group::spawn(&mut cmd));
+        let status = loop {
+            if let Some(status) = t!(child.try_wait()) {
+                break status;
+            }
+            if let Some(signal) = group::forward_signal(&child) {
+                let _ = child.wait();
+                process::exit(128 + signal);
+            }
+            if start.elapsed() > budget {
+                group::kill(&mut child);
+                eprintln!(
+                    "error: ci-smoke exceeded its budget of {} minutes while running `{}`",
+                    budget.as_secs() / 60,
+                    invocation
+                );
+                process::exit(1);
+            }
+            thread::sleep(Duration::from_millis(500));
+        };
+        if !status.success() {
+            eprintln!("error: ci-smoke failed while running `{}`", invocation);
+            process::exit(1);
+        }
+    }
+
+    let elapsed = start.elapsed().as_secs();
+    println!("ci-smoke passed in {}m{:02}s", elapsed / 60, elapsed % 60);
+}
+
+/// The arguments ci-smoke was given without the subcommand and `--budget`,
+/// which are the global flags, like `-j` or `--stage`, for the phases.
+fn global_flags(args: Vec<String>) -> Vec<String> {
+    let mut flags = Vec::new();
+    let mut args = args.into_iter();
+    let mut subcommand = false;
+    while let Some(arg) = args.next() {
+        if arg == "ci-smoke" && !subcommand {
+            subcommand = true;
+        } else if arg == "--budget" {
+            args.next();
+        } else if !arg.starts_with("--budget=") {
+            flags.push(arg);
+        }
+    }
+    flags
+}
+
+/// The arguments of the invocation of `phase` with `flags`. A `--stage` in
+/// `flags` replaces the one of the phase.
+fn phase_args(phase: &[&str], flags: &[String]) -> Vec<String> {
+    let has_stage = flags.iter().any(|flag| flag == "--stage" || flag.starts_with("--stage="));
+    let mut args = Vec::new();
+    let mut phase = phase.iter();
+    while let Some(arg) = phase.next() {
+        if *arg == "--stage" && has_stage {
+            phase.next();
+            continue;
+        }
+        args.push(arg.to_string());
+    }
+    args.extend(flags.iter().cloned());
+    args
+}
+
+#[cfg(unix)]
+mod group {
+    use std::io;
+    use std::os::unix::process::CommandExt;
+    use std::process::{Child, Command};
+    use std::sync::atomic::{AtomicI32, Ordering};
+
+    /// The last signal ci-smoke received, if any, to be forwarded.
+    static RECEIVED: AtomicI32 = AtomicI32::new(0);
+
+    extern "C" fn record(signal: libc::c_int) {
+        RECEIVED.store(signal, Ordering::SeqCst);
+    }
+
+    /// Spawns `cmd` in a process group of its own, and starts recording the
+    /// signals which would otherwise have ended ci-smoke.
+    pub fn spawn(cmd: &mut Command) -> io::Result<Child> {
+        let handler = record as extern "C" fn(libc::c_int);
+        unsafe {
+            for &signal in &[libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+                libc::signal(signal, handler as libc::sighandler_t);
+            }
+            cmd.pre_exec(|| {
+                if libc::setpgid(0, 0) == 0 {
+                    Ok(())
+                } else {
+                    Err(io::Error::last_os_error())
+                }
+            });
+        }
+        cmd.spawn()
+    }
+
+    /// Forwards the signal ci-smoke received to the process group of
+    /// `child`, and returns it, if there was one.
+    pub fn forward_signal(child: &Child) -> Option<i32> {
+        match RECEIVED.load(Ordering::SeqCst) {
+            0 => None,
+            signal => {
+                unsafe { libc::kill(-(child.id() as libc::pid_t), signal) };
+                Some(signal)
+            }
+        }
+    }
+
+    /// Kills the process group of `child`.
+    pub fn kill(child: &mut Child) {
+        unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) };
+        let _ = child.wait();
+    }
+}
+
+#[cfg(not(unix))]
+mod group {
+    use std::io;
+    use std::process::{Child, Command};
+
+    pub fn spawn(cmd: &mut Command) -> io::Result<Child> {
+        cmd.spawn()
+    }
+
+    pub fn forward_signal(_child: &Child) -> Option<i32> {
+        None
+    }
+
+    pub fn kill(child: &mut Child) {
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+}
@@ -0,0 +1,162 @@
+//! The record of finished steps kept with `build.step-cache`.
+//!
+//! Every x.py invocation walks all the steps it needs and runs cargo for each
+//! of them, even when an earlier invocation just built them, so `x.py test`
+//! after `x.py build` spends a while only to find out nothing changed. With
+//! the step cache enabled, the steps which build std and the compiler record
+//! a fingerprint of their inputs in `build/step-cache.json` after running
+//! cargo: the cargo command, `config.toml`, the compiler used, the stamps of
+//! the steps they depend on, and the modification times of their sources. A
+//! later invocation with the same fingerprint reuses the stamp of the step
+//! without running cargo.
+//!
+//! Fingerprints are made of modification times rather than of the contents
+//! of the sources, so touching a file is enough to run cargo again. Only the
+//! directories of the crates of the workspace are walked for them, and the
+//! submodules, like `src/llvm-project`, are identified by their checked-out
+//! commit and their uncommitted changes instead, as walking them takes longer
+//! than running cargo would. Submodules are only walked when the VCS can't
+//! tell, e.g. with `build.vcs = "none"`.
+//!
+//! Tools and test suites aren't covered: they are mostly built once per
+//! invocation by a single cargo command, which finds out by itself that
+//! nothing changed, while std and the compiler are what later invocations
+//! need before they get to anything else.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+
+use build_helper::t;
+use filetime::FileTime;
+
+use crate::builder::{Builder, Cargo};
+use crate::vcs::Vcs;
+
+/// Returns the fingerprint of a step which runs `cargo` to build from the
+/// paths `inputs` of the source tree, after the steps which wrote `deps`, or
+/// `None` if the step cache is disabled or this is a dry run.
+pub fn fingerprint(
+    builder: &Builder<'_>,
+    cargo: &Cargo,
+    inputs: &[&str],
+    deps: &[PathBuf],
+) -> Option<String> {
+    if !builder.config.step_cache || builder.config.dry_run {
+        return None;
+    }
+    let mut hasher = DefaultHasher::new();
+    format!("{:?}", cargo).hash(&mut hasher);
+    fs::read(&builder.config.config).unwrap_or_default().hash(&mut hasher);
+    // A rebuilt bootstrap may pass different flags to the same command.
+    if let Ok(exe) = env::current_exe() {
+        mtime(&exe).hash(&mut hasher);
+    }
+    for dep in deps {
+        (dep, mtime(dep)).hash(&mut hasher);
+    }
+    for input in inputs {
+        let path = builder.src.join(input);
+        let submodule = is_submodule(&path);
+        if submodule {
+            // Uncommitted changes count too: std includes sources of
+            // `library/stdarch` and `library/backtrace` through `#[path]`, so
+            // cargo can't see that they changed either.
+            let vcs = if builder.vcs == Vcs::None { Vcs::None } else { Vcs::detect(&path) };
+            let commit = vcs.commit_info(&path).map(|info| info.sha);
+            if let (Some(commit), Some(changes)) = (commit, vcs.uncommitted_changes(&path)) {
+                (input, commit, changes).hash(&mut hasher);
+                continue;
+            }
+        }
+        // Of a directory, only the crates in it are sources, except for the
+        // submodules, which are walked as a whole if the VCS can't tell.
+        let mut crates =
+            builder.crates.values().filter(|krate| krate.path.starts_with(&path)).peekable();
+        let dirs = if !submodule && crates.peek().is_some() {
+            crates.map(|krate| krate.path.clone()).collect::<Vec<_>>()
+        } else {
+            vec![path]
+        };
+        let mut newest = (0, 0);
+        let mut files = 0;
+        for dir in &dirs {
+            walk(dir, &mut newest, &mut files);
+        }
+        (input, newest, files).hash(&mut hasher);
+    }
+    Some(format!("{:016x}", hasher.finish()))
+}
+
+/// Returns whether `path` is the checkout of a submodule.
+fn is_submodule(path: &Path) -> bool {
+    path.join(".git").exists()
+}
+
+/// Returns whether the step which wrote `stamp` last recorded `fingerprint`,
+/// so that the stamp is up to date.
+pub fn is_fresh(builder: &Builder<'_>, stamp: &Path, fingerprint: &str) -> bool {
+    stamp.exists()
+        && load(builder).get(&key(stamp)).map_or(false, |recorded| recorded == fingerprint)
+}
+
+/// Records that the step which wrote `stamp` ran with `fingerprint`.
+pub fn record(builder: &Builder<'_>, stamp: &Path, fingerprint: String) {
+    let mut steps = load(builder);
+    steps.insert(key(stamp), fingerprint);
+    t!(fs::create_dir_all(&builder.out));
+    t!(fs::write(path(builder), t!(serde_json::to_string_pretty(&steps))));
+}
+
+fn load(builder: &Builder<'_>) -> HashMap<String, String> {
+    // An unreadable cache is treated as empty; it's rewritten by `record`.
+    fs::read(path(builder))
+        .ok()
+        .and_then(|contents| serde_json::from_slice(&contents).ok())
+        .unwrap_or_default()
+}
+
+fn path(builder: &Builder<'_>) -> PathBuf {
+    builder.out.join("step-cache.json")
+}
+
+/// Steps are identified by their stamp, which is unique to the step, its
+/// compiler and its target.
+fn key(stamp: &Path) -> String {
+    stamp.to_string_lossy().into_owned()
+}
+
+fn mtime(path: &Path) -> Option<(i64, u32)> {
+    let metadata = fs::metadata(path).ok()?;
+    let mtime = FileTime::from_last_modification_time(&metadata);
+    Some((mtime.unix_seconds(), mtime.nanoseconds()))
+}
+
+/// Finds the newest modification time under `path`, and counts the files,
+/// so that removing one changes the fingerprint too.
+fn walk(path: &Path, newest: &mut (i64, u32), files: &mut usize) {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(_) => return,
+    };
+    let mtime = FileTime::from_last_modification_time(&metadata);
+    *newest = (*newest).max((mtime.unix_seconds(), mtime.nanoseconds()));
+    if !metadata.is_dir() {
+        *files += 1;
+        return;
+    }
+    for entry in t!(fs::read_dir(path)) {
+        let entry = t!(entry);
+        if entry.file_name().to_str().map_or(false, |name| name.starts_with('.')) {
+            continue;
+        }
+        // Submodules are identified by their commit, if they are an input.
+        if is_submodule(&entry.path()) {
+            continue;
+        }
+        walk(&entry.path(), newest, files);
+    }
+}
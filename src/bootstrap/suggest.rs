@@ -0,0 +1,50 @@
+//! Implementation of `x.py suggest`.
+//!
+//! Lists the files modified since the merge base with the upstream branch
+//! and works out which `check`, `test` and `doc` steps they affect, using the
+//! same paths that select those steps on the command line.
+
+use std::process::{self, Command};
+
+use crate::builder::{Builder, Kind};
+use crate::change_detection;
+use crate::Build;
+
+/// The subcommands suggestions are made for, in the order they're run.
+const KINDS: &[(Kind, &str)] = &[(Kind::Check, "check"), (Kind::Test, "test"), (Kind::Doc, "doc")];
+
+pub fn suggest(build: &Build, run: bool) {
+    let base = change_detection::DEFAULT_BASE;
+    let changed = build.vcs.modified_files(&build.src, base).unwrap_or_else(|| {
+        eprintln!("error: could not find the files modified since {}", base);
+        process::exit(1);
+    });
+    if changed.is_empty() {
+        println!("No files were modified since {}, so there's nothing to suggest.", base);
+        return;
+    }
+
+    let suggestions = KINDS
+        .iter()
+        .map(|&(kind, subcommand)| (subcommand, Builder::suggested_paths(build, kind, &changed)))
+        .filter(|(_, paths)| !paths.is_empty())
+        .collect::<Vec<_>>();
+    if suggestions.is_empty() {
+        println!("None of the files modified since {} are covered by a step.", base);
+        return;
+    }
+
+    println!("Based on the files modified since {}, try:", base);
+    for (subcommand, paths) in &suggestions {
+        let paths = paths.iter().map(|p| p.display().to_string()).collect::<Vec<_>>();
+        println!("    ./x.py {} {}", subcommand, paths.join(" "));
+    }
+
+    if run {
+        for (subcommand, paths) in &suggestions {
+            let mut cmd = Command::new(build.python());
+            cmd.arg(build.src.join("x.py")).arg(subcommand).args(paths).current_dir(&build.src);
+            build.run(&mut cmd);
+        }
+    }
+}
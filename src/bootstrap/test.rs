@@ -3,6 +3,7 @@
 //! This file implements the various regression test suites that we execute on
 //! our CI.
 
+use std::collections::HashSet;
 use std::env;
 use std::ffi::OsString;
 use std::fmt;
@@ -10,21 +11,24 @@
 use std::iter;
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::time::{Duration, SystemTime};
 
 use build_helper::{self, output, t};
+use serde::Serialize;
 
 use crate::builder::{Builder, Compiler, Kind, RunConfig, ShouldRun, Step};
 use crate::cache::{Interned, INTERNER};
 use crate::compile;
-use crate::config::TargetSelection;
+use crate::config::{TargetSelection, PYTHON_ENV};
 use crate::dist;
 use crate::flags::Subcommand;
 use crate::native;
+use crate::sanity::Finder;
 use crate::tool::{self, SourceType, Tool};
 use crate::toolstate::ToolState;
-use crate::util::{self, add_link_lib_path, dylib_path, dylib_path_var};
+use crate::util::{self, add_link_lib_path, dylib_path, dylib_path_var, glob_matches};
 use crate::Crate as CargoCrate;
-use crate::{envify, DocTests, GitRepo, Mode};
+use crate::{envify, Build, DocTests, GitRepo, Mode};
 
 const ADB_TEST_DIR: &str = "/data/tmp/work";
 
@@ -66,11 +70,86 @@ fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
     }
 }
 
+/// The file in the build directory listing the tests that failed in the last
+/// `x.py test`, as paths to pass to `x.py test`, for `--rerun-failed`.
+pub const FAILED_TESTS: &str = "failed-tests.txt";
+
+/// The file in the build directory listing what failed in the last `x.py test
+/// --no-fail-fast`, as a JSON array of `FailedTest`s.
+pub const FAILURE_SUMMARY: &str = "test-failures.json";
+
+/// A test that failed in a `--no-fail-fast` run, or a whole suite if the
+/// failed tests aren't known, as the command running it only has an exit
+/// status.
+#[derive(Serialize)]
+pub struct FailedTest {
+    pub suite: String,
+    pub test: Option<String>,
+    pub reason: String,
+}
+
+/// Records that `cmd`, which the running step ran, failed. For cargo, which
+/// runs the tests of whole crates, these are recorded as the failed tests.
+fn record_failed_command(builder: &Builder<'_>, cmd: &Command) {
+    builder.delayed_failures.borrow_mut().push(format!("{:?}", cmd));
+    let suite = builder.running_steps.borrow().last().copied().unwrap_or("unknown step");
+    let program = util::program_name(cmd);
+    let packages = if program == "cargo" { cargo_packages(cmd) } else { Vec::new() };
+    builder.failed_tests.borrow_mut().push(FailedTest {
+        suite: suite.to_string(),
+        test: if packages.is_empty() { None } else { Some(packages.join(", ")) },
+        reason: format!("`{}` exited unsuccessfully", program),
+    });
+}
+
+/// The packages a cargo command runs, from its `-p` arguments, or the
+/// directory of its `--manifest-path` if there are none.
+fn cargo_packages(cmd: &Command) -> Vec<String> {
+    // The `Debug` output of commands quotes the program and each argument.
+    let description = format!("{:?}", cmd);
+    let args = description.split('"').skip(1).step_by(2).collect::<Vec<_>>();
+    let mut packages = Vec::new();
+    let mut manifest_dir = None;
+    for pair in args.windows(2) {
+        match pair[0] {
+            // Packages may be given with their version, like `rustdoc:0.0.0`.
+            "-p" | "--package" => packages.push(pair[1].split(':').next().unwrap().to_string()),
+            "--manifest-path" => {
+                let dir = Path::new(pair[1]).parent().and_then(|dir| dir.file_name());
+                manifest_dir = dir.map(|dir| dir.to_string_lossy().into_owned());
+            }
+            _ => {}
+        }
+    }
+    if packages.is_empty() {
+        packages.extend(manifest_dir);
+    }
+    packages
+}
+
+/// Prints the tests that failed over the whole run, after the commands that
+/// failed had scrolled away, and writes them to `FAILURE_SUMMARY`.
+pub fn print_failure_summary(build: &Build) {
+    let failed = build.failed_tests.borrow();
+    if failed.is_empty() {
+        return;
+    }
+    println!("{}\n", build.paint(31, "Summary of failures:"));
+    for failure in failed.iter() {
+        match &failure.test {
+            Some(test) => println!("  {}: {} ({})", failure.suite, test, failure.reason),
+            None => println!("  {}: {}", failure.suite, failure.reason),
+        }
+    }
+    let path = build.out.join(FAILURE_SUMMARY);
+    t!(fs::write(&path, t!(serde_json::to_string_pretty(&*failed))));
+    println!("\nThe failures are also listed in {}", path.display());
+}
+
 fn try_run(builder: &Builder<'_>, cmd: &mut Command) -> bool {
     if !builder.fail_fast {
         if !builder.try_run(cmd) {
-            let mut failures = builder.delayed_failures.borrow_mut();
-            failures.push(format!("{:?}", cmd));
+            record_failed_command(builder, cmd);
             return false;
         }
     } else {
@@ -79,11 +158,84 @@ fn try_run(builder: &Builder<'_>, cmd: &mut Command) -> bool {
     true
 }
 
+/// Runs compiletest like `try_run`, but also records the tests that failed,
+/// which compiletest wrote to `logfile`, in `FAILED_TESTS` before `--fail-fast`
+/// stops the build.
+fn try_run_compiletest(
+    builder: &Builder<'_>,
+    cmd: &mut Command,
+    suite_path: &Path,
+    logfile: &Path,
+) -> bool {
+    let _ = fs::remove_file(logfile);
+    let passed = builder.try_run(cmd);
+    let failed = if builder.config.dry_run {
+        Vec::new()
+    } else {
+        record_failed_tests(builder, suite_path, logfile)
+    };
+    if !passed {
+        if builder.fail_fast {
+            std::process::exit(1);
+        }
+        if failed.is_empty() {
+            // compiletest itself failed, rather than any test.
+            record_failed_command(builder, cmd);
+        } else {
+            builder.delayed_failures.borrow_mut().push(format!("{:?}", cmd));
+            let suite = suite_path.display().to_string();
+            builder.failed_tests.borrow_mut().extend(failed.into_iter().map(|test| FailedTest {
+                suite: suite.clone(),
+                test: Some(test),
+                reason: "failed".to_string(),
+            }));
+        }
+    }
+    passed
+}
+
+/// Adds the failed tests in a compiletest `logfile` to `FAILED_TESTS`, as the
+/// paths of their files, and returns their names as compiletest reports them.
+fn record_failed_tests(builder: &Builder<'_>, suite_path: &Path, logfile: &Path) -> Vec<String> {
+    let log = match fs::read_to_string(logfile) {
+        Ok(log) => log,
+        Err(_) => return Vec::new(),
+    };
+    let record = builder.out.join(FAILED_TESTS);
+    let mut failed = fs::read_to_string(&record)
+        .unwrap_or_default()
+        .lines()
+        .map(String::from)
+        .collect::<Vec<_>>();
+    let mut names = Vec::new();
+    for line in log.lines() {
+        if !line.starts_with("failed") || line.starts_with("failed (allowed)") {
+            continue;
+        }
+        // Test names look like `[ui] ui/foo/bar.rs#revision`, starting with
+        // the name of the suite's directory. Revisions can't be selected on
+        // their own, so the whole file is run again.
+        let name = match line.find("] ") {
+            Some(i) => &line[i + 2..],
+            None => continue,
+        };
+        names.push(name.to_string());
+        let name = name.split('#').next().unwrap();
+        let path = suite_path.parent().unwrap_or(suite_path).join(name);
+        let path = path.to_str().unwrap().to_string();
+        if !failed.contains(&path) {
+            failed.push(path);
+        }
+    }
+    let failed = failed.iter().map(|path| format!("{}\n", path)).collect::<String>();
+    t!(fs::write(&record, failed));
+    names
+}
+
 fn try_run_quiet(builder: &Builder<'_>, cmd: &mut Command) -> bool {
     if !builder.fail_fast {
         if !builder.try_run_quiet(cmd) {
-            let mut failures = builder.delayed_failures.borrow_mut();
-            failures.push(format!("{:?}", cmd));
+            record_failed_command(builder, cmd);
             return false;
         }
     } else {
@@ -128,6 +280,10 @@ fn should_run(run: ShouldRun<'_>) -> ShouldRun<'_> {
     fn make_run(run: RunConfig<'_>) {
         run.builder.ensure(Linkcheck { host: run.target });
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.host)
+    }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
@@ -173,6 +329,14 @@ fn run(self, builder: &Builder<'_>) {
                 .env("RUSTDOC", builder.rustdoc(compiler)),
         );
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.host)
+    }
+
+    fn stage(&self) -> Option<u32> {
+        Some(self.stage)
+    }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
@@ -223,9 +387,18 @@ fn run(self, builder: &Builder<'_>) {
         cargo.env("CARGO_TEST_DISABLE_NIGHTLY", "1");
 
         cargo.env("PATH", &path_for_cargo(builder, compiler));
+        cargo.arg("--").args(builder.config.cmd.test_args());
 
         try_run(builder, &mut cargo.into());
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.host)
+    }
+
+    fn stage(&self) -> Option<u32> {
+        Some(self.stage)
+    }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
@@ -277,6 +450,14 @@ fn run(self, builder: &Builder<'_>) {
             builder.save_toolstate("rls", ToolState::TestPass);
         }
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.host)
+    }
+
+    fn stage(&self) -> Option<u32> {
+        Some(self.stage)
+    }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
@@ -334,135 +515,237 @@ fn run(self, builder: &Builder<'_>) {
             builder.save_toolstate("rustfmt", ToolState::TestPass);
         }
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.host)
+    }
+
+    fn stage(&self) -> Option<u32> {
+        Some(self.stage)
+    }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
-pub struct Miri {
+pub struct RustAnalyzer {
     stage: u32,
     host: TargetSelection,
 }
 
-impl Step for Miri {
+impl Step for RustAnalyzer {
     type Output = ();
     const ONLY_HOSTS: bool = true;
+    const BLESSABLE: bool = true;
 
     fn should_run(run: ShouldRun<'_>) -> ShouldRun<'_> {
-        run.path("src/tools/miri")
+        run.path("src/tools/rust-analyzer")
     }
 
     fn make_run(run: RunConfig<'_>) {
-        run.builder.ensure(Miri { stage: run.builder.top_stage, host: run.target });
+        run.builder.ensure(RustAnalyzer { stage: run.builder.top_stage, host: run.target });
     }
 
-    /// Runs `cargo test` for miri.
+    /// Runs `cargo test` for rust-analyzer.
     fn run(self, builder: &Builder<'_>) {
         let stage = self.stage;
         let host = self.host;
         let compiler = builder.compiler(stage, host);
 
-        let miri =
-            builder.ensure(tool::Miri { compiler, target: self.host, extra_features: Vec::new() });
-        let cargo_miri = builder.ensure(tool::CargoMiri {
+        let build_result = builder.ensure(tool::RustAnalyzer {
             compiler,
             target: self.host,
             extra_features: Vec::new(),
         });
-        if let (Some(miri), Some(_cargo_miri)) = (miri, cargo_miri) {
-            let mut cargo =
-                builder.cargo(compiler, Mode::ToolRustc, SourceType::Submodule, host, "install");
-            cargo.arg("xargo");
-            // Configure `cargo install` path. cargo adds a `bin/`.
-            cargo.env("CARGO_INSTALL_ROOT", &builder.out);
-
-            let mut cargo = Command::from(cargo);
-            if !try_run(builder, &mut cargo) {
-                return;
-            }
+        if build_result.is_none() {
+            eprintln!("failed to test rust-analyzer: could not build");
+            return;
+        }
 
-            // # Run `cargo miri setup`.
-            let mut cargo = tool::prepare_tool_cargo(
-                builder,
-                compiler,
-                Mode::ToolRustc,
-                host,
-                "run",
-                "src/tools/miri/cargo-miri",
-                SourceType::Submodule,
-                &[],
-            );
-            cargo.arg("--").arg("miri").arg("setup");
-
-            // Tell `cargo miri setup` where to find the sources.
-            cargo.env("XARGO_RUST_SRC", builder.src.join("library"));
-            // Tell it where to find Miri.
-            cargo.env("MIRI", &miri);
-            // Debug things.
-            cargo.env("RUST_BACKTRACE", "1");
-            // Let cargo-miri know where xargo ended up.
-            cargo.env("XARGO_CHECK", builder.out.join("bin").join("xargo-check"));
-
-            let mut cargo = Command::from(cargo);
-            if !try_run(builder, &mut cargo) {
-                return;
-            }
+        let mut cargo = tool::prepare_tool_cargo(
+            builder,
+            compiler,
+            Mode::ToolRustc,
+            host,
+            "test",
+            "src/tools/rust-analyzer",
+            SourceType::Submodule,
+            &[],
+        );
 
-            // # Determine where Miri put its sysroot.
-            // To this end, we run `cargo miri setup --print-sysroot` and capture the output.
-            // (We do this separately from the above so that when the setup actually
-            // happens we get some output.)
-            // We re-use the `cargo` from above.
-            cargo.arg("--print-sysroot");
+        // The slow tests check rust-analyzer's own formatting and generated
+        // docs, and write to its source directory when those are out of date,
+        // which isn't something to do from here.
+        cargo.env("SKIP_SLOW_TESTS", "1");
+        // The tests find the standard library's sources through the `rustc`
+        // in PATH, which should be the one under test.
+        cargo.env("PATH", path_for_cargo(builder, compiler));
 
-            // FIXME: Is there a way in which we can re-use the usual `run` helpers?
-            let miri_sysroot = if builder.config.dry_run {
-                String::new()
-            } else {
-                builder.verbose(&format!("running: {:?}", cargo));
-                let out = cargo
-                    .output()
-                    .expect("We already ran `cargo miri setup` before and that worked");
-                assert!(out.status.success(), "`cargo miri setup` returned with non-0 exit code");
-                // Output is "<sysroot>\n".
-                let stdout = String::from_utf8(out.stdout)
-                    .expect("`cargo miri setup` stdout is not valid UTF-8");
-                let sysroot = stdout.trim_end();
-                builder.verbose(&format!("`cargo miri setup --print-sysroot` said: {:?}", sysroot));
-                sysroot.to_owned()
-            };
+        cargo.add_rustc_lib_path(builder, compiler);
+        cargo.arg("--").args(builder.config.cmd.test_args());
 
-            // # Run `cargo test`.
-            let mut cargo = tool::prepare_tool_cargo(
-                builder,
-                compiler,
-                Mode::ToolRustc,
-                host,
-                "test",
-                "src/tools/miri",
-                SourceType::Submodule,
-                &[],
-            );
+        try_run(builder, &mut cargo.into());
+    }
 
-            // miri tests need to know about the stage sysroot
-            cargo.env("MIRI_SYSROOT", miri_sysroot);
-            cargo.env("RUSTC_LIB_PATH", builder.rustc_libdir(compiler));
-            cargo.env("MIRI", miri);
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.host)
+    }
 
-            cargo.arg("--").args(builder.config.cmd.test_args());
+    fn stage(&self) -> Option<u32> {
+        Some(self.stage)
+    }
+}
 
-            cargo.add_rustc_lib_path(builder, compiler);
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Miri {
+    stage: u32,
+    host: TargetSelection,
+}
 
-            if !try_run(builder, &mut cargo.into()) {
-                return;
-            }
+impl Step for Miri {
+    type Output = ();
+    const ONLY_HOSTS: bool = true;
 
-            // # Done!
-            builder.save_toolstate("miri", ToolState::TestPass);
-        } else {
-            eprintln!("failed to test miri: could not build");
+    fn should_run(run: ShouldRun<'_>) -> ShouldRun<'_> {
+        run.path("src/tools/miri")
+    }
+
+    fn make_run(run: RunConfig<'_>) {
+        run.builder.ensure(Miri { stage: run.builder.top_stage, host: run.target });
+    }
+
+    /// Runs `cargo test` for miri.
+    fn run(self, builder: &Builder<'_>) {
+        let stage = self.stage;
+        let host = self.host;
+        let compiler = builder.compiler(stage, host);
+
+        let (miri, _cargo_miri, miri_sysroot) = match setup_miri(builder, compiler, host, host) {
+            Some(setup) => setup,
+            None => return,
+        };
+
+        // # Run `cargo test`.
+        let mut cargo = tool::prepare_tool_cargo(
+            builder,
+            compiler,
+            Mode::ToolRustc,
+            host,
+            "test",
+            "src/tools/miri",
+            SourceType::Submodule,
+            &[],
+        );
+
+        // miri tests need to know about the stage sysroot
+        cargo.env("MIRI_SYSROOT", miri_sysroot);
+        cargo.env("RUSTC_LIB_PATH", builder.rustc_libdir(compiler));
+        cargo.env("MIRI", miri);
+
+        cargo.arg("--").args(builder.config.cmd.test_args());
+
+        cargo.add_rustc_lib_path(builder, compiler);
+
+        if !try_run(builder, &mut cargo.into()) {
+            return;
         }
+
+        // # Done!
+        builder.save_toolstate("miri", ToolState::TestPass);
+    }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.host)
+    }
+
+    fn stage(&self) -> Option<u32> {
+        Some(self.stage)
     }
 }
 
+/// Builds miri and cargo-miri with `compiler` to run on `host` and sets up a
+/// Miri sysroot for `target` from the standard library in `library`,
+/// returning the paths of `miri` and `cargo-miri` and the path of the
+/// sysroot. Returns `None` if any of that failed.
+fn setup_miri(
+    builder: &Builder<'_>,
+    compiler: Compiler,
+    host: TargetSelection,
+    target: TargetSelection,
+) -> Option<(PathBuf, PathBuf, String)> {
+    let miri = builder.ensure(tool::Miri { compiler, target: host, extra_features: Vec::new() });
+    let cargo_miri =
+        builder.ensure(tool::CargoMiri { compiler, target: host, extra_features: Vec::new() });
+    let (miri, cargo_miri) = match (miri, cargo_miri) {
+        (Some(miri), Some(cargo_miri)) => (miri, cargo_miri),
+        _ => {
+            eprintln!("failed to set up miri: could not build");
+            return None;
+        }
+    };
+
+    let mut cargo =
+        builder.cargo(compiler, Mode::ToolRustc, SourceType::Submodule, host, "install");
+    cargo.arg("xargo");
+    // Configure `cargo install` path. cargo adds a `bin/`.
+    cargo.env("CARGO_INSTALL_ROOT", &builder.out);
+
+    let mut cargo = Command::from(cargo);
+    if !try_run(builder, &mut cargo) {
+        return None;
+    }
+
+    // # Run `cargo miri setup`.
+    let mut cargo = tool::prepare_tool_cargo(
+        builder,
+        compiler,
+        Mode::ToolRustc,
+        host,
+        "run",
+        "src/tools/miri/cargo-miri",
+        SourceType::Submodule,
+        &[],
+    );
+    cargo.arg("--").arg("miri").arg("setup");
+    cargo.arg("--target").arg(target.rustc_target_arg());
+
+    // Tell `cargo miri setup` where to find the sources.
+    cargo.env("XARGO_RUST_SRC", builder.src.join("library"));
+    // Tell it where to find Miri.
+    cargo.env("MIRI", &miri);
+    // Debug things.
+    cargo.env("RUST_BACKTRACE", "1");
+    // Let cargo-miri know where xargo ended up.
+    cargo.env("XARGO_CHECK", builder.out.join("bin").join("xargo-check"));
+
+    let mut cargo = Command::from(cargo);
+    if !try_run(builder, &mut cargo) {
+        return None;
+    }
+
+    // # Determine where Miri put its sysroot.
+    // To this end, we run `cargo miri setup --print-sysroot` and capture the output.
+    // (We do this separately from the above so that when the setup actually
+    // happens we get some output.)
+    // We re-use the `cargo` from above.
+    cargo.arg("--print-sysroot");
+
+    // FIXME: Is there a way in which we can re-use the usual `run` helpers?
+    let miri_sysroot = if builder.config.dry_run {
+        String::new()
+    } else {
+        builder.verbose(&format!("running: {:?}", cargo));
+        let out = cargo.output().expect("We already ran `cargo miri setup` before and that worked");
+        assert!(out.status.success(), "`cargo miri setup` returned with non-0 exit code");
+        // Output is "<sysroot>\n".
+        let stdout =
+            String::from_utf8(out.stdout).expect("`cargo miri setup` stdout is not valid UTF-8");
+        let sysroot = stdout.trim_end();
+        builder.verbose(&format!("`cargo miri setup --print-sysroot` said: {:?}", sysroot));
+        sysroot.to_owned()
+    };
+
+    Some((miri, cargo_miri, miri_sysroot))
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub struct CompiletestTest {
     host: TargetSelection,
@@ -500,6 +783,10 @@ fn run(self, builder: &Builder<'_>) {
 
         try_run(builder, &mut cargo.into());
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.host)
+    }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
@@ -561,6 +848,14 @@ fn run(self, builder: &Builder<'_>) {
 
         builder.run(&mut cargo.into());
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.host)
+    }
+
+    fn stage(&self) -> Option<u32> {
+        Some(self.stage)
+    }
 }
 
 fn path_for_cargo(builder: &Builder<'_>, compiler: Compiler) -> OsString {
@@ -611,6 +906,14 @@ fn run(self, builder: &Builder<'_>) {
         }
         try_run(builder, &mut cmd);
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.compiler.host)
+    }
+
+    fn stage(&self) -> Option<u32> {
+        Some(self.compiler.stage)
+    }
 }
 
 #[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
@@ -650,6 +953,10 @@ fn run(self, builder: &Builder<'_>) {
             builder.info("No nodejs found, skipping \"src/test/rustdoc-js-std\" tests");
         }
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
 }
 
 #[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
@@ -686,6 +993,14 @@ fn run(self, builder: &Builder<'_>) {
             builder.info("No nodejs found, skipping \"src/test/rustdoc-js\" tests");
         }
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
+
+    fn stage(&self) -> Option<u32> {
+        Some(self.compiler.stage)
+    }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
@@ -695,6 +1010,7 @@ impl Step for Tidy {
     type Output = ();
     const DEFAULT: bool = true;
     const ONLY_HOSTS: bool = true;
+    const BLESSABLE: bool = true;
 
     /// Runs the `tidy` tool.
     ///
@@ -703,7 +1019,7 @@ impl Step for Tidy {
     /// compiler itself.
     ///
     /// Once tidy passes, this step also runs `fmt --check` if tests are being run
-    /// for the `dev` or `nightly` channels.
+    /// for the `dev` or `nightly` channels, or formats the tree with `--bless`.
     fn run(self, builder: &Builder<'_>) {
         let mut cmd = builder.tool_cmd(Tool::Tidy);
         cmd.arg(&builder.src);
@@ -718,7 +1034,7 @@ fn run(self, builder: &Builder<'_>) {
 
         if builder.config.channel == "dev" || builder.config.channel == "nightly" {
             builder.info("fmt check");
-            crate::format::format(&builder.build, !builder.config.cmd.bless());
+            crate::format::format(&builder.build, !builder.config.cmd.bless(), true);
         }
     }
 
@@ -737,13 +1053,21 @@ fn make_run(run: RunConfig<'_>) {
 impl Step for ExpandYamlAnchors {
     type Output = ();
     const ONLY_HOSTS: bool = true;
+    const BLESSABLE: bool = true;
 
     /// Ensure the `generate-ci-config` tool was run locally.
     ///
     /// The tool in `src/tools` reads the CI definition in `src/ci/builders.yml` and generates the
     /// appropriate configuration for all our CI providers. This step ensures the tool was called
-    /// by the user before committing CI changes.
+    /// by the user before committing CI changes, or calls it with `--bless`.
     fn run(self, builder: &Builder<'_>) {
+        if builder.config.cmd.bless() {
+            builder.info("Expanding YAML anchors in the GitHub Actions configuration");
+            builder.run(
+                &mut builder.tool_cmd(Tool::ExpandYamlAnchors).arg("generate").arg(&builder.src),
+            );
+            return;
+        }
         builder.info("Ensuring the YAML anchors in the GitHub Actions config were expanded");
         try_run(
             builder,
@@ -764,6 +1088,74 @@ fn testdir(builder: &Builder<'_>, host: TargetSelection) -> PathBuf {
     builder.out.join(host.triple).join("test")
 }
 
+/// Removes the output of tests in the suite directory `dir` which haven't been
+/// run for `build.test-output-max-age` days.
+///
+/// Compiletest gives each test (and revision) its own output directory, and
+/// failing tests leave theirs behind, so every directory directly containing
+/// files is treated as the output of one test and removed as a whole once
+/// none of its files have been written to recently.
+fn gc_test_sessions(builder: &Builder<'_>, dir: &Path) {
+    if builder.config.dry_run || !dir.is_dir() {
+        return;
+    }
+    let max_age = Duration::from_secs(builder.config.test_output_max_age * 24 * 60 * 60);
+    let cutoff = match SystemTime::now().checked_sub(max_age) {
+        Some(cutoff) => cutoff,
+        None => return,
+    };
+
+    let mut removed = 0;
+    for entry in builder.read_dir(dir) {
+        if t!(entry.file_type()).is_dir() {
+            removed += gc_test_dir(&entry.path(), cutoff);
+        }
+    }
+    if removed > 0 {
+        builder.info(&format!("Removed stale output of {} tests from {}", removed, dir.display()));
+    }
+}
+
+/// Removes stale test output directories below `dir`, returning how many were
+/// removed.
+fn gc_test_dir(dir: &Path, cutoff: SystemTime) -> usize {
+    let mut subdirs = Vec::new();
+    let mut is_test_output = false;
+    for entry in t!(fs::read_dir(dir)) {
+        let entry = t!(entry);
+        if t!(entry.file_type()).is_dir() {
+            subdirs.push(entry.path());
+        } else {
+            is_test_output = true;
+        }
+    }
+
+    if !is_test_output {
+        return subdirs.iter().map(|subdir| gc_test_dir(subdir, cutoff)).sum();
+    }
+    if newest_mtime(dir) < cutoff {
+        t!(fs::remove_dir_all(dir));
+        1
+    } else {
+        0
+    }
+}
+
+/// Returns the most recent modification time of any file below `dir`.
+fn newest_mtime(dir: &Path) -> SystemTime {
+    let mut newest = SystemTime::UNIX_EPOCH;
+    for entry in t!(fs::read_dir(dir)) {
+        let entry = t!(entry);
+        let mtime = if t!(entry.file_type()).is_dir() {
+            newest_mtime(&entry.path())
+        } else {
+            t!(t!(entry.metadata()).modified())
+        };
+        newest = newest.max(mtime);
+    }
+    newest
+}
+
 macro_rules! default_test {
     ($name:ident { path: $path:expr, mode: $mode:expr, suite: $suite:expr }) => {
         test!($name { path: $path, mode: $mode, suite: $suite, default: true, host: false });
@@ -837,6 +1229,7 @@ impl Step for $name {
             type Output = ();
             const DEFAULT: bool = $default;
             const ONLY_HOSTS: bool = $host;
+            const BLESSABLE: bool = true;
 
             fn should_run(run: ShouldRun<'_>) -> ShouldRun<'_> {
                 run.suite_path($path)
@@ -858,6 +1251,14 @@ fn run(self, builder: &Builder<'_>) {
                     compare_mode: $compare_mode,
                 })
             }
+
+            fn target(&self) -> Option<TargetSelection> {
+                Some(self.target)
+            }
+
+            fn stage(&self) -> Option<u32> {
+                Some(self.compiler.stage)
+            }
         }
     };
 }
@@ -921,6 +1322,52 @@ fn run(self, builder: &Builder<'_>) {
 
 default_test!(Assembly { path: "src/test/assembly", mode: "assembly", suite: "assembly" });
 
+/// Smoke tests of the parallel front-end, which compile code with many threads
+/// to shake out deadlocks and ICEs. Only run by default when the compiler was
+/// built with `rust.parallel-compiler`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ParallelRustc {
+    pub compiler: Compiler,
+    pub target: TargetSelection,
+}
+
+impl Step for ParallelRustc {
+    type Output = ();
+    const DEFAULT: bool = true;
+    const ONLY_HOSTS: bool = true;
+    const BLESSABLE: bool = true;
+
+    fn should_run(run: ShouldRun<'_>) -> ShouldRun<'_> {
+        let builder = run.builder;
+        run.suite_path("src/test/parallel-rustc").default_condition(builder.config.rustc_parallel)
+    }
+
+    fn make_run(run: RunConfig<'_>) {
+        let compiler = run.builder.compiler(run.builder.top_stage, run.build_triple());
+
+        run.builder.ensure(ParallelRustc { compiler, target: run.target });
+    }
+
+    fn run(self, builder: &Builder<'_>) {
+        builder.ensure(Compiletest {
+            compiler: self.compiler,
+            target: self.target,
+            mode: "ui",
+            suite: "parallel-rustc",
+            path: "src/test/parallel-rustc",
+            compare_mode: None,
+        })
+    }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
+
+    fn stage(&self) -> Option<u32> {
+        Some(self.compiler.stage)
+    }
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 struct Compiletest {
     compiler: Compiler,
@@ -967,6 +1414,10 @@ fn run(self, builder: &Builder<'_>) {
             return;
         }
 
+        if builder.config.cmd.gc_sessions() {
+            gc_test_sessions(builder, &testdir(builder, compiler.host).join(suite));
+        }
+
         if suite == "debuginfo" {
             builder
                 .ensure(dist::DebuggerScripts { sysroot: builder.sysroot(compiler), host: target });
@@ -1022,6 +1473,7 @@ fn run(self, builder: &Builder<'_>) {
         cmd.arg("--mode").arg(mode);
         cmd.arg("--target").arg(target.rustc_target_arg());
         cmd.arg("--host").arg(&*compiler.host.triple);
+        cmd.env("RUST_TARGET_PATH", builder.rust_target_path());
         cmd.arg("--llvm-filecheck").arg(builder.llvm_filecheck(builder.config.build));
 
         if builder.config.cmd.bless() {
@@ -1167,7 +1619,7 @@ fn run(self, builder: &Builder<'_>) {
 
         let mut llvm_components_passed = false;
         let mut copts_passed = false;
-        if builder.config.llvm_enabled() {
+        if builder.config.llvm_enabled(builder.config.build) {
             let llvm_config = builder.ensure(native::Llvm { target: builder.config.build });
             if !builder.config.dry_run {
                 let llvm_version = output(Command::new(&llvm_config).arg("--version"));
@@ -1242,7 +1694,9 @@ fn run(self, builder: &Builder<'_>) {
             cmd.arg("--cc").arg("").arg("--cxx").arg("").arg("--cflags").arg("");
         }
 
-        if builder.remote_tested(target) {
+        if let Some(runner) = builder.runner(target) {
+            cmd.arg("--runtool").arg(runner);
+        } else if builder.remote_tested(target) {
             cmd.arg("--remote-test-client").arg(builder.tool_exe(Tool::RemoteTestClient));
         }
 
@@ -1287,16 +1741,23 @@ fn run(self, builder: &Builder<'_>) {
             cmd.arg("--rustfix-coverage");
         }
 
+        if builder.config.cmd.force_rerun() {
+            cmd.arg("--force-rerun");
+        }
+
         cmd.env("BOOTSTRAP_CARGO", &builder.initial_cargo);
 
-        builder.ci_env.force_coloring_in_ci(&mut cmd);
+        builder.forward_color(&mut cmd);
+
+        let logfile = testdir(builder, compiler.host).join(format!("{}.log", suite));
+        cmd.arg("--logfile").arg(&logfile);
 
         builder.info(&format!(
             "Check compiletest suite={} mode={} ({} -> {})",
             suite, mode, &compiler.host, target
         ));
         let _time = util::timeit(&builder);
-        try_run(builder, &mut cmd);
+        try_run_compiletest(builder, &mut cmd, Path::new(suite_path), &logfile);
 
         if let Some(compare_mode) = compare_mode {
             cmd.arg("--compare-mode").arg(compare_mode);
@@ -1305,9 +1766,17 @@ fn run(self, builder: &Builder<'_>) {
                 suite, mode, compare_mode, &compiler.host, target
             ));
             let _time = util::timeit(&builder);
-            try_run(builder, &mut cmd);
+            try_run_compiletest(builder, &mut cmd, Path::new(suite_path), &logfile);
         }
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
+
+    fn stage(&self) -> Option<u32> {
+        Some(self.compiler.stage)
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -1345,6 +1814,14 @@ fn run(self, builder: &Builder<'_>) {
             self.run_local_doc(builder);
         }
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.compiler.host)
+    }
+
+    fn stage(&self) -> Option<u32> {
+        Some(self.compiler.stage)
+    }
 }
 
 impl BookTest {
@@ -1440,6 +1917,14 @@ fn run(self, builder: &Builder<'_>) {
                         is_ext_doc: !$default,
                     });
                 }
+
+                fn target(&self) -> Option<TargetSelection> {
+                    Some(self.compiler.host)
+                }
+
+                fn stage(&self) -> Option<u32> {
+                    Some(self.compiler.stage)
+                }
             }
         )+
     }
@@ -1506,6 +1991,14 @@ fn run(self, builder: &Builder<'_>) {
         builder.ensure(compile::Std { compiler: rustdoc_compiler, target: rustdoc_compiler.host });
         markdown_test(builder, rustdoc_compiler, &output);
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.compiler.host)
+    }
+
+    fn stage(&self) -> Option<u32> {
+        Some(self.compiler.stage)
+    }
 }
 
 fn markdown_test(builder: &Builder<'_>, compiler: Compiler, markdown: &Path) -> bool {
@@ -1572,6 +2065,7 @@ impl Step for CrateLibrustc {
     type Output = ();
     const DEFAULT: bool = true;
     const ONLY_HOSTS: bool = true;
+    const BLESSABLE: bool = true;
 
     fn should_run(run: ShouldRun<'_>) -> ShouldRun<'_> {
         run.krate("rustc-main")
@@ -1604,6 +2098,14 @@ fn run(self, builder: &Builder<'_>) {
             krate: self.krate,
         });
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
+
+    fn stage(&self) -> Option<u32> {
+        Some(self.compiler.stage)
+    }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
@@ -1653,6 +2155,14 @@ fn run(self, builder: &Builder<'_>) {
             krate: INTERNER.intern_str(self.krate),
         });
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
+
+    fn stage(&self) -> Option<u32> {
+        Some(self.compiler.stage)
+    }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -1740,14 +2250,16 @@ fn run(self, builder: &Builder<'_>) {
         if test_kind.subcommand() == "test" && !builder.fail_fast {
             cargo.arg("--no-fail-fast");
         }
+        // `cargo bench` doesn't run doc tests, so `--doc` and `--no-doc` only
+        // apply to `cargo test`.
         match builder.doc_tests {
-            DocTests::Only => {
+            DocTests::Only if test_kind == TestKind::Test => {
                 cargo.arg("--doc");
             }
-            DocTests::No => {
+            DocTests::No if test_kind == TestKind::Test => {
                 cargo.args(&["--lib", "--bins", "--examples", "--tests", "--benches"]);
             }
-            DocTests::Yes => {}
+            _ => {}
         }
 
         cargo.arg("-p").arg(krate);
@@ -1768,7 +2280,9 @@ fn run(self, builder: &Builder<'_>) {
             cargo.arg("--quiet");
         }
 
-        if target.contains("emscripten") {
+        if let Some(runner) = builder.runner(target) {
+            cargo.env(format!("CARGO_TARGET_{}_RUNNER", envify(&target.triple)), runner);
+        } else if target.contains("emscripten") {
             cargo.env(
                 format!("CARGO_TARGET_{}_RUNNER", envify(&target.triple)),
                 builder.config.nodejs.as_ref().expect("nodejs not configured"),
@@ -1792,6 +2306,14 @@ fn run(self, builder: &Builder<'_>) {
         let _time = util::timeit(&builder);
         try_run(builder, &mut cargo.into());
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
+
+    fn stage(&self) -> Option<u32> {
+        Some(self.compiler.stage)
+    }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
@@ -1804,6 +2326,7 @@ impl Step for CrateRustdoc {
     type Output = ();
     const DEFAULT: bool = true;
     const ONLY_HOSTS: bool = true;
+    const BLESSABLE: bool = true;
 
     fn should_run(run: ShouldRun<'_>) -> ShouldRun<'_> {
         run.paths(&["src/librustdoc", "src/tools/rustdoc"])
@@ -1889,8 +2412,162 @@ fn run(self, builder: &Builder<'_>) {
 
         try_run(builder, &mut cargo.into());
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.host)
+    }
+}
+
+/// Runs the tests of a library crate under Miri, for `x.py miri`, to check
+/// the standard library for undefined behavior.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct CrateMiri {
+    compiler: Compiler,
+    target: TargetSelection,
+    krate: Interned<String>,
 }
 
+impl Step for CrateMiri {
+    type Output = ();
+    const DEFAULT: bool = true;
+
+    fn should_run(run: ShouldRun<'_>) -> ShouldRun<'_> {
+        run.krate("test")
+    }
+
+    fn make_run(run: RunConfig<'_>) {
+        let builder = run.builder;
+        let compiler = builder.compiler(builder.top_stage, run.build_triple());
+
+        for krate in builder.in_tree_crates("test", Some(run.target)) {
+            if krate.path.ends_with(&run.path) {
+                builder.ensure(CrateMiri { compiler, target: run.target, krate: krate.name });
+            }
+        }
+    }
+
+    fn run(self, builder: &Builder<'_>) {
+        let compiler = self.compiler;
+        let target = self.target;
+        let krate = self.krate;
+
+        // The Miri sysroot is built by cargo-miri from the sources in
+        // `library`, with the compiler miri is linked to, so changes to the
+        // standard library are picked up without building it for the target.
+        let (miri, cargo_miri, miri_sysroot) =
+            match setup_miri(builder, compiler, compiler.host, target) {
+                Some(setup) => setup,
+                None => {
+                    eprintln!("failed to run the tests of {} under miri", krate);
+                    std::process::exit(1);
+                }
+            };
+
+        let mut cargo = Command::new(cargo_miri);
+        cargo.current_dir(&builder.src);
+        cargo.arg("miri").arg("test");
+        cargo.arg("--target").arg(target.rustc_target_arg());
+        // Miri can't run doc tests.
+        cargo.args(&["--lib", "--tests"]);
+        cargo.arg("-p").arg(krate);
+        cargo.arg("--").args(builder.config.cmd.test_args());
+
+        cargo.env("CARGO", &builder.initial_cargo);
+        cargo.env("RUSTC", builder.rustc(compiler));
+        cargo.env("MIRI", miri);
+        cargo.env("MIRI_SYSROOT", miri_sysroot);
+        cargo.env("CARGO_TARGET_DIR", builder.out.join(&*target.triple).join("miri"));
+        let mut dylib_path = dylib_path();
+        dylib_path.insert(0, PathBuf::from(&*builder.rustc_libdir(compiler)));
+        cargo.env(dylib_path_var(), env::join_paths(&dylib_path).unwrap());
+
+        builder.info(&format!(
+            "Testing {} with miri stage{} ({} -> {})",
+            krate, compiler.stage, &compiler.host, target
+        ));
+        let _time = util::timeit(&builder);
+        try_run(builder, &mut cargo);
+    }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
+
+    fn stage(&self) -> Option<u32> {
+        Some(self.compiler.stage)
+    }
+}
+
+macro_rules! bench_tool {
+    ($($name:ident, $path:expr, $tool_name:expr, $source_type:expr;)+) => {
+        $(
+            #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+            pub struct $name {
+                stage: u32,
+                host: TargetSelection,
+            }
+
+            impl Step for $name {
+                type Output = ();
+                const ONLY_HOSTS: bool = true;
+
+                fn should_run(run: ShouldRun<'_>) -> ShouldRun<'_> {
+                    run.path($path)
+                }
+
+                fn make_run(run: RunConfig<'_>) {
+                    run.builder.ensure($name { stage: run.builder.top_stage, host: run.target });
+                }
+
+                /// Runs `cargo bench` for the tool, against the libraries of
+                /// the compiler of the selected stage.
+                fn run(self, builder: &Builder<'_>) {
+                    let host = self.host;
+                    let compiler = builder.compiler(self.stage, host);
+                    builder.ensure(compile::Rustc { compiler, target: host });
+
+                    let mut cargo = tool::prepare_tool_cargo(
+                        builder,
+                        compiler,
+                        Mode::ToolRustc,
+                        host,
+                        "bench",
+                        $path,
+                        $source_type,
+                        &[],
+                    );
+                    cargo.env("PATH", path_for_cargo(builder, compiler));
+                    cargo.add_rustc_lib_path(builder, compiler);
+                    cargo.arg("--").args(builder.config.cmd.test_args());
+
+                    builder.info(&format!(
+                        "Benchmarking {} stage{} ({})",
+                        $tool_name, compiler.stage, host
+                    ));
+                    let _time = util::timeit(&builder);
+                    try_run(builder, &mut cargo.into());
+                }
+
+                fn target(&self) -> Option<TargetSelection> {
+                    Some(self.host)
+                }
+
+                fn stage(&self) -> Option<u32> {
+                    Some(self.stage)
+                }
+            }
+        )+
+    }
+}
+
+bench_tool!(
+    BenchCargo, "src/tools/cargo", "cargo", SourceType::Submodule;
+    BenchClippy, "src/tools/clippy", "clippy", SourceType::InTree;
+    BenchRustfmt, "src/tools/rustfmt", "rustfmt", SourceType::Submodule;
+    BenchRustAnalyzer, "src/tools/rust-analyzer/crates/rust-analyzer", "rust-analyzer",
+        SourceType::Submodule;
+);
+
 /// Some test suites are run inside emulators or on remote devices, and most
 /// of our test binaries are linked dynamically which means we need to ship
 /// the standard library and such to the emulator ahead of time. This step
@@ -1946,6 +2623,14 @@ fn run(self, builder: &Builder<'_>) {
             }
         }
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
+
+    fn stage(&self) -> Option<u32> {
+        Some(self.compiler.stage)
+    }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
@@ -2015,6 +2700,101 @@ fn run(self, builder: &Builder<'_>) {
     }
 }
 
+/// Example programs from `compiler/rustc_codegen_cranelift/example` that are
+/// built with the Cranelift backend and run, along with the extra flags they
+/// need and the arguments to run them with.
+const CRANELIFT_EXAMPLES: &[(&str, &[&str], &[&str])] = &[
+    ("dst-field-align", &[], &[]),
+    ("std_example", &[], &["arg"]),
+    ("subslice-patterns-const-eval", &["-Cpanic=abort"], &[]),
+    ("track-caller-attribute", &["-Cpanic=abort"], &[]),
+    ("mod_bench", &[], &[]),
+];
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct CodegenCranelift {
+    pub compiler: Compiler,
+    pub target: TargetSelection,
+}
+
+impl Step for CodegenCranelift {
+    type Output = ();
+    const DEFAULT: bool = true;
+    const ONLY_HOSTS: bool = true;
+
+    fn should_run(run: ShouldRun<'_>) -> ShouldRun<'_> {
+        run.paths(&["compiler/rustc_codegen_cranelift", "rustc_codegen_cranelift"])
+    }
+
+    fn make_run(run: RunConfig<'_>) {
+        let builder = run.builder;
+        // Only test the backend if it's actually being built.
+        if !builder
+            .config
+            .codegen_backends(run.build_triple())
+            .contains(&INTERNER.intern_str("cranelift"))
+        {
+            return;
+        }
+        let compiler = builder.compiler(builder.top_stage, run.build_triple());
+        builder.ensure(CodegenCranelift { compiler, target: run.target });
+    }
+
+    /// Builds and runs the Cranelift backend's example programs with a
+    /// compiler that has the backend in its sysroot.
+    fn run(self, builder: &Builder<'_>) {
+        let compiler = self.compiler;
+        let target = self.target;
+
+        builder.ensure(compile::Std { compiler, target });
+
+        let examples = builder.src.join("compiler/rustc_codegen_cranelift/example");
+        let out_dir = testdir(builder, compiler.host).join("cg_clif").join(target.triple);
+        t!(fs::create_dir_all(&out_dir));
+
+        builder.info(&format!(
+            "Testing rustc_codegen_cranelift stage{} ({} -> {})",
+            compiler.stage, &compiler.host, target
+        ));
+        let _time = util::timeit(builder);
+
+        for &(name, flags, args) in CRANELIFT_EXAMPLES {
+            let crate_name = name.replace('-', "_");
+            let mut cmd = Command::new(builder.rustc(compiler));
+            cmd.arg("-Zcodegen-backend=cranelift")
+                .arg("--target")
+                .arg(target.rustc_target_arg())
+                .arg("--crate-type")
+                .arg("bin")
+                .arg("--crate-name")
+                .arg(&crate_name)
+                .arg("--out-dir")
+                .arg(&out_dir)
+                .args(flags)
+                .arg(examples.join(name).with_extension("rs"));
+            util::add_dylib_path(vec![builder.rustc_libdir(compiler)], &mut cmd);
+            if !try_run(builder, &mut cmd) {
+                continue;
+            }
+
+            // We can only run the resulting programs on the host.
+            if target == compiler.host {
+                let mut cmd = Command::new(out_dir.join(util::exe(&crate_name, target)));
+                cmd.args(args);
+                try_run(builder, &mut cmd);
+            }
+        }
+    }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
+
+    fn stage(&self) -> Option<u32> {
+        Some(self.compiler.stage)
+    }
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub struct Bootstrap;
 
@@ -2059,6 +2839,107 @@ fn make_run(run: RunConfig<'_>) {
     }
 }
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct BootstrapPy;
+
+impl Step for BootstrapPy {
+    type Output = ();
+    const ONLY_HOSTS: bool = true;
+
+    /// Runs the Python entry points of the build system, `x.py` and the
+    /// Makefile generated by `./configure`, with each version of Python found
+    /// on the system, checking that bootstrap.py still sets up everything
+    /// rustbuild expects from it. The builds themselves are dry runs.
+    fn run(self, builder: &Builder<'_>) {
+        if builder.config.dry_run {
+            return;
+        }
+
+        let mut finder = Finder::new();
+        let found = ["python3", "python2.7", "python2", "python"]
+            .iter()
+            .filter_map(|python| finder.maybe_have(python))
+            .collect::<Vec<_>>();
+        let make = finder.maybe_have("make");
+        if make.is_none() {
+            builder.info("`make` not found, skipping the Makefile wrapper");
+        }
+
+        // Options making the nested builds use the same build directory and
+        // stage0 compiler as this one.
+        let mut args = vec![String::from("--dry-run"), format!("--build={}", builder.config.build)];
+        if let Some(config) = env::var_os("BOOTSTRAP_CONFIG") {
+            args.push(format!("--config={}", Path::new(&config).display()));
+        }
+        // Without a config the build directory is `build` in the working
+        // directory, which is where x.py was run from.
+        let cwd = builder.out.parent().unwrap();
+
+        let mut versions = HashSet::new();
+        for python in builder.config.python.iter().chain(&found) {
+            let version = Command::new(python)
+                .arg("-c")
+                .arg("import sys; print(sys.version.split()[0])")
+                .output()
+                .ok()
+                .filter(|output| output.status.success())
+                .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string());
+            let version = match version {
+                Some(version) if versions.insert(version.clone()) => version,
+                _ => continue,
+            };
+            builder.info(&format!(
+                "Testing the Python entry points with Python {} ({})",
+                version,
+                python.display()
+            ));
+
+            for subcommand in &[&["build"][..], &["test", "src/tools/tidy"]] {
+                let mut cmd = Command::new(python);
+                cmd.arg(builder.src.join("x.py")).args(*subcommand).args(&args);
+                try_run(builder, check_python_env(&mut cmd).current_dir(cwd));
+            }
+
+            match &make {
+                Some(make) => {
+                    for target in &["check-bootstrap", "tidy"] {
+                        let mut cmd = Command::new(make);
+                        cmd.arg("-f")
+                            .arg(builder.src.join("src/bootstrap/mk/Makefile.in"))
+                            .arg(format!("CFG_SRC_DIR={}/", builder.src.display()))
+                            .arg(format!("CFG_PYTHON={}", python.display()))
+                            .arg(format!("BOOTSTRAP_ARGS={}", args.join(" ")))
+                            .arg(target);
+                        try_run(builder, check_python_env(&mut cmd).current_dir(cwd));
+                    }
+                }
+                None => {
+                    let mut cmd = Command::new(python);
+                    cmd.arg(builder.src.join("src/bootstrap/bootstrap_test.py"));
+                    try_run(builder, &mut cmd);
+                }
+            }
+        }
+    }
+
+    fn should_run(run: ShouldRun<'_>) -> ShouldRun<'_> {
+        run.path("bootstrap-py")
+    }
+
+    fn make_run(run: RunConfig<'_>) {
+        run.builder.ensure(BootstrapPy);
+    }
+}
+
+/// Makes `cmd`, which runs bootstrap.py, fail unless bootstrap.py sets all of
+/// `PYTHON_ENV` itself.
+fn check_python_env(cmd: &mut Command) -> &mut Command {
+    for var in PYTHON_ENV.iter().chain(&["BOOTSTRAP_CONFIG"]) {
+        cmd.env_remove(var);
+    }
+    cmd.env("BOOTSTRAP_CHECK_PYTHON_ENV", "1")
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub struct TierCheck {
     pub compiler: Compiler,
@@ -2101,6 +2982,14 @@ fn run(self, builder: &Builder<'_>) {
         builder.info("platform support check");
         try_run(builder, &mut cargo.into());
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.compiler.host)
+    }
+
+    fn stage(&self) -> Option<u32> {
+        Some(self.compiler.stage)
+    }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
@@ -2134,4 +3023,134 @@ fn run(self, builder: &Builder<'_>) {
             validate: true,
         });
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
+
+    fn stage(&self) -> Option<u32> {
+        Some(self.compiler.stage)
+    }
+}
+
+/// Checks the dynamic symbols exported by the compiler's own dylibs against
+/// the allowlists in `src/etc/dylib-exports`, so that changes to the interface
+/// distributions link against don't go unnoticed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct DylibExports {
+    pub compiler: Compiler,
+}
+
+impl Step for DylibExports {
+    type Output = ();
+    const ONLY_HOSTS: bool = true;
+    const BLESSABLE: bool = true;
+
+    fn should_run(run: ShouldRun<'_>) -> ShouldRun<'_> {
+        run.path("src/etc/dylib-exports")
+    }
+
+    fn make_run(run: RunConfig<'_>) {
+        let compiler = run.builder.compiler(run.builder.top_stage, run.target);
+        run.builder.ensure(DylibExports { compiler });
+    }
+
+    /// Lists the exports of `librustc_driver` and, if LLVM is linked
+    /// dynamically, `libLLVM` with `nm` and fails if any of them isn't matched
+    /// by a pattern of the library's allowlist. With `--bless` they are added
+    /// to the allowlist instead.
+    fn run(self, builder: &Builder<'_>) {
+        let host = self.compiler.host;
+        if !host.contains("linux") {
+            builder.info(&format!("skipping dylib exports check: unsupported host {}", host));
+            return;
+        }
+        builder.info(&format!("dylib exports check stage{} ({})", self.compiler.stage, host));
+        if builder.config.dry_run {
+            return;
+        }
+
+        let libdir = builder.rustc_libdir(self.compiler);
+        for entry in t!(fs::read_dir(&libdir)) {
+            let path = t!(entry).path();
+            let filename = path.file_name().unwrap().to_string_lossy().into_owned();
+            let name = if filename.starts_with("librustc_driver-") {
+                "rustc_driver"
+            } else if filename.starts_with("libLLVM") {
+                "LLVM"
+            } else {
+                continue;
+            };
+            if !filename.ends_with(".so") {
+                continue;
+            }
+
+            let allowlist_path =
+                builder.src.join("src/etc/dylib-exports").join(format!("{}.txt", name));
+            let allowlist = t!(fs::read_to_string(&allowlist_path), &allowlist_path);
+            let patterns = allowlist
+                .lines()
+                .map(|line| line.trim())
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .collect::<Vec<_>>();
+
+            let symbols =
+                output(Command::new("nm").arg("-D").arg("--defined-only").arg("-P").arg(&path));
+            let mut unexpected = symbols
+                .lines()
+                .filter_map(|line| line.split_whitespace().next())
+                .filter(|symbol| !patterns.iter().any(|pattern| glob_matches(pattern, symbol)))
+                .collect::<Vec<_>>();
+            unexpected.sort_unstable();
+            unexpected.dedup();
+            if unexpected.is_empty() {
+                continue;
+            }
+
+            if builder.config.cmd.bless() {
+                let mut allowlist = allowlist;
+                if !allowlist.ends_with('\n') {
+                    allowlist.push('\n');
+                }
+                for symbol in &unexpected {
+                    allowlist.push_str(symbol);
+                    allowlist.push('\n');
+                }
+                t!(fs::write(&allowlist_path, allowlist));
+                builder.info(&format!(
+                    "added {} symbols to {}",
+                    unexpected.len(),
+                    allowlist_path.display()
+                ));
+                continue;
+            }
+
+            eprintln!(
+                "error: {} exports symbols that aren't in {}:",
+                filename,
+                allowlist_path.display()
+            );
+            for symbol in &unexpected {
+                eprintln!("    {}", symbol);
+            }
+            eprintln!("help: if the new exports are intended, run with `--bless` to add them");
+            if builder.fail_fast {
+                std::process::exit(1);
+            }
+            builder.delayed_failures.borrow_mut().push(format!("dylib exports of {}", filename));
+            builder.failed_tests.borrow_mut().push(FailedTest {
+                suite: "dylib exports".to_string(),
+                test: Some(filename.to_string()),
+                reason: format!("{} unexpected exports", unexpected.len()),
+            });
+        }
+    }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.compiler.host)
+    }
+
+    fn stage(&self) -> Option<u32> {
+        Some(self.compiler.stage)
+    }
 }
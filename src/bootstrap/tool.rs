@@ -215,6 +215,14 @@ fn run(self, builder: &Builder<'_>) -> Option<PathBuf> {
             Some(bin)
         }
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
+
+    fn stage(&self) -> Option<u32> {
+        Some(self.compiler.stage)
+    }
 }
 
 pub fn prepare_tool_cargo(
@@ -280,13 +288,18 @@ macro_rules! bootstrap_tool {
         $(,features = $features:expr)*
         ;
     )+) => {
-        #[derive(Copy, PartialEq, Eq, Clone)]
+        #[derive(Debug, Copy, PartialEq, Eq, Clone, Hash)]
         pub enum Tool {
             $(
                 $name,
             )+
         }
 
+        impl Tool {
+            /// Every tool, along with its path in the source tree.
+            pub const ALL: &'static [(Tool, &'static str)] = &[$((Tool::$name, $path),)+];
+        }
+
         impl<'a> Builder<'a> {
             pub fn tool_exe(&self, tool: Tool) -> PathBuf {
                 match tool {
@@ -348,6 +361,14 @@ fn run(self, builder: &Builder<'_>) -> PathBuf {
                     },
                 }).expect("expected to build -- essential tool")
             }
+
+            fn target(&self) -> Option<TargetSelection> {
+                Some(self.target)
+            }
+
+            fn stage(&self) -> Option<u32> {
+                Some(self.compiler.stage)
+            }
         }
         )+
     }
@@ -414,6 +435,14 @@ fn run(self, builder: &Builder<'_>) -> PathBuf {
             })
             .expect("expected to build -- essential tool")
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.compiler.host)
+    }
+
+    fn stage(&self) -> Option<u32> {
+        Some(self.compiler.stage)
+    }
 }
 
 #[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
@@ -450,6 +479,14 @@ fn run(self, builder: &Builder<'_>) -> PathBuf {
             })
             .expect("expected to build -- essential tool")
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
+
+    fn stage(&self) -> Option<u32> {
+        Some(self.compiler.stage)
+    }
 }
 
 #[derive(Debug, Copy, Clone, Hash, PartialEq, Eq, Ord, PartialOrd)]
@@ -537,6 +574,14 @@ fn run(self, builder: &Builder<'_>) -> PathBuf {
             tool_rustdoc
         }
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.compiler.host)
+    }
+
+    fn stage(&self) -> Option<u32> {
+        Some(self.compiler.stage)
+    }
 }
 
 #[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
@@ -609,6 +654,14 @@ fn run(self, builder: &Builder<'_>) -> PathBuf {
         );
         cargo_bin_path
     }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
+
+    fn stage(&self) -> Option<u32> {
+        Some(self.compiler.stage)
+    }
 }
 
 macro_rules! tool_extended {
@@ -677,6 +730,14 @@ fn run(mut $sel, $builder: &Builder<'_>) -> Option<PathBuf> {
                     },
                 })
             }
+
+            fn target(&self) -> Option<TargetSelection> {
+                Some(self.target)
+            }
+
+            fn stage(&self) -> Option<u32> {
+                Some(self.compiler.stage)
+            }
         }
         )+
     }
@@ -0,0 +1,114 @@
+//! The Chrome trace written by `x.py --timings`.
+//!
+//! Each step, each command run through `Build::run` and its variants, each
+//! cargo invocation and each cmake build (which includes running ninja) is
+//! recorded as a complete event of the Trace Event Format. The resulting
+//! `trace.json` can be loaded in chrome://tracing or https://ui.perfetto.dev,
+//! which nest the events by time, so the commands show up under the step
+//! running them.
+//!
+//! Events are appended to the file as they are recorded, in the JSON Array
+//! Format, whose closing bracket is optional, so that a build which fails,
+//! and exits right away, still leaves the trace of what ran until then.
+
+use std::cell::RefCell;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::time::Instant;
+
+use build_helper::t;
+use serde::Serialize;
+
+use crate::util;
+
+/// Events of the Trace Event Format, as described in
+/// https://docs.google.com/document/d/1CvAClvFfyA5R-PhYUmn5OOQtYMH4h6I0nSsKchNAySU
+#[derive(Serialize)]
+struct Event {
+    name: String,
+    cat: &'static str,
+    /// `X` for complete events, which have a duration.
+    ph: &'static str,
+    /// Microseconds since the start of the build
+    ts: u64,
+    dur: u64,
+    pid: u32,
+    tid: u32,
+    args: Args,
+}
+
+#[derive(Serialize)]
+struct Args {
+    description: String,
+}
+
+pub struct Trace {
+    start: Instant,
+    path: PathBuf,
+    /// The trace file, once the first event was written to it.
+    file: RefCell<Option<File>>,
+}
+
+impl Trace {
+    /// Starts a trace which is written to `path`.
+    pub fn new(path: PathBuf) -> Trace {
+        Trace { start: Instant::now(), path, file: RefCell::new(None) }
+    }
+
+    /// Records that a step which started at `start` just finished.
+    pub fn step(&self, name: &str, description: String, start: Instant) {
+        self.record("step", name.to_string(), description, start);
+    }
+
+    /// Records that `cmd`, which was spawned at `start`, just finished.
+    pub fn command(&self, cmd: &Command, start: Instant) {
+        self.record("command", util::program_name(cmd), format!("{:?}", cmd), start);
+    }
+
+    /// Records that the cmake build run by `step`, which started at `start`,
+    /// just finished. The commands it runs, like ninja, aren't seen by
+    /// bootstrap, so they are all covered by this event.
+    pub fn cmake(&self, step: &str, start: Instant) {
+        let description = format!("configure and build with cmake for {}", step);
+        self.record("command", "cmake".to_string(), description, start);
+    }
+
+    fn record(&self, cat: &'static str, name: String, description: String, start: Instant) {
+        let event = Event {
+            name,
+            cat,
+            ph: "X",
+            ts: start.duration_since(self.start).as_micros() as u64,
+            dur: start.elapsed().as_micros() as u64,
+            pid: process::id(),
+            tid: 1,
+            args: Args { description },
+        };
+        let event = t!(serde_json::to_string(&event));
+        let mut file = self.file.borrow_mut();
+        match &mut *file {
+            Some(file) => t!(write!(file, ",\n{}", event)),
+            None => *file = Some(self.create(&event)),
+        }
+    }
+
+    fn create(&self, contents: &str) -> File {
+        t!(fs::create_dir_all(self.path.parent().unwrap()));
+        let mut file = t!(File::create(&self.path));
+        t!(write!(file, "[\n{}", contents));
+        file
+    }
+
+    /// Closes the array of events, once the build succeeded, and returns the
+    /// path of the trace.
+    pub fn finish(&self) -> &Path {
+        let mut file = self.file.borrow_mut();
+        match &mut *file {
+            Some(file) => t!(writeln!(file, "\n]")),
+            None => *file = Some(self.create("]\n")),
+        }
+        &self.path
+    }
+}
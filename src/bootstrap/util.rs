@@ -3,15 +3,19 @@
 //! Simple things like testing the various filesystem operations here and there,
 //! not a lot of interesting happenings here unfortunately.
 
+use std::collections::{BTreeMap, HashMap};
 use std::env;
+use std::ffi::{OsStr, OsString};
 use std::fs;
 use std::io;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::str;
+use std::sync::Mutex;
 use std::time::Instant;
 
 use build_helper::t;
+use lazy_static::lazy_static;
 
 use crate::builder::Builder;
 use crate::config::{Config, TargetSelection};
@@ -111,6 +115,189 @@ pub fn push_exe_path(mut buf: PathBuf, components: &[&str]) -> PathBuf {
     buf
 }
 
+/// Returns the name of the program `cmd` runs, without its directory and
+/// extension, like `cargo`.
+pub fn program_name(cmd: &Command) -> String {
+    // The `Debug` output of commands starts with the quoted program.
+    let description = format!("{:?}", cmd);
+    let program = Path::new(description.split('"').nth(1).unwrap_or(&description));
+    program.file_stem().unwrap_or(program.as_os_str()).to_string_lossy().into_owned()
+}
+
+/// Names the file `record_parent_env` recorded the environment in.
+const PARENT_ENV: &str = "BOOTSTRAP_PARENT_ENV";
+
+/// Records the environment of this process in `path` as the one x.py was run
+/// in, for `reproduction` in the processes it runs. The variables set by
+/// bootstrap.py are left out, as they are part of what x.py adds.
+pub fn record_parent_env(path: &Path) {
+    let mut recorded = String::new();
+    for (key, value) in env::vars_os() {
+        if let (Some(key), Some(value)) = (key.to_str(), value.to_str()) {
+            if !crate::config::PYTHON_ENV.contains(&key) && key != "BOOTSTRAP_CONFIG" {
+                recorded.push_str(&format!("{}={}\0", key, value));
+            }
+        }
+    }
+    t!(fs::create_dir_all(path.parent().unwrap()));
+    t!(fs::write(path, recorded));
+    env::set_var(PARENT_ENV, path);
+}
+
+lazy_static! {
+    /// The variables recorded by `record_command_env`, with the `Debug`
+    /// output of the command they were set on.
+    static ref COMMAND_ENV: Mutex<Vec<(String, Vec<(OsString, Option<OsString>)>)>> =
+        Mutex::new(Vec::new());
+}
+
+/// Records that `env` was set on `cmd` (or removed from it, for `None`), for
+/// `reproduction`, as commands don't tell which variables were set on them.
+/// Nothing is recorded unless `record_parent_env` was called, as nothing is
+/// reproduced then.
+pub fn record_command_env(cmd: &Command, env: Vec<(OsString, Option<OsString>)>) {
+    if env::var_os(PARENT_ENV).is_some() {
+        COMMAND_ENV.lock().unwrap().push((format!("{:?}", cmd), env));
+    }
+}
+
+/// Returns a shell command that runs `cmd` like this process is about to:
+/// from its working directory, with its environment, the variables recorded
+/// for it by `record_command_env` and `overrides`. Only the variables that
+/// differ from the environment recorded by `record_parent_env` are set, so
+/// that the command can be pasted into the shell x.py was run from, e.g. to
+/// run it under a debugger.
+///
+/// Variables set on `cmd` directly, and its working directory if it was
+/// changed, can't be read back from it and are missing.
+pub fn reproduction(cmd: &Command, overrides: &[(&str, &OsStr)]) -> String {
+    let recorded = env::var_os(PARENT_ENV).and_then(|path| fs::read_to_string(path).ok());
+    let recorded = recorded.unwrap_or_default();
+    let parent = recorded
+        .split('\0')
+        .filter_map(|var| var.find('=').map(|i| (&var[..i], &var[i + 1..])))
+        .collect::<HashMap<_, _>>();
+
+    let mut current = env::vars_os()
+        .filter_map(|(key, value)| Some((key.into_string().ok()?, value.into_string().ok()?)))
+        .collect::<BTreeMap<_, _>>();
+    current.remove(PARENT_ENV);
+    // Arguments may have been added to the command since it was recorded.
+    let description = format!("{:?}", cmd);
+    let recorded_env = COMMAND_ENV.lock().unwrap();
+    let recorded_env = recorded_env
+        .iter()
+        .filter(|(recorded, _)| description.starts_with(recorded.as_str()))
+        .max_by_key(|(recorded, _)| recorded.len());
+    for (key, value) in recorded_env.map_or(&[][..], |(_, env)| &env[..]) {
+        let key = key.to_string_lossy().into_owned();
+        match value {
+            Some(value) => current.insert(key, value.to_string_lossy().into_owned()),
+            None => current.remove(&key),
+        };
+    }
+    for (key, value) in overrides {
+        current.insert(key.to_string(), value.to_string_lossy().into_owned());
+    }
+
+    let mut env = Vec::new();
+    let mut removed = parent.keys().filter(|key| !current.contains_key(**key)).collect::<Vec<_>>();
+    removed.sort();
+    for key in removed {
+        env.push(format!("-u {}", key));
+    }
+    for (key, value) in &current {
+        if parent.get(key.as_str()) != Some(&value.as_str()) {
+            env.push(format!("{}={}", key, shell_quote(value)));
+        }
+    }
+
+    let dir = t!(env::current_dir());
+    // The `Debug` output of commands quotes the program and each argument.
+    format!("cd {} && env {} {:?}", shell_quote(&dir.to_string_lossy()), env.join(" "), cmd)
+}
+
+/// Writes the script `<dir>/<name>.sh` running `cmd` again like
+/// `reproduction`, with `comment` saying why, and returns its path.
+pub fn write_reproduction_script(
+    dir: &Path,
+    name: &str,
+    cmd: &Command,
+    overrides: &[(&str, &OsStr)],
+    comment: &str,
+) -> PathBuf {
+    let script = dir.join(format!("{}.sh", name));
+    let contents = format!("#!/bin/sh\n# {}\n{}\n", comment, reproduction(cmd, overrides));
+    t!(fs::create_dir_all(dir));
+    t!(fs::write(&script, contents));
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        t!(fs::set_permissions(&script, fs::Permissions::from_mode(0o755)));
+    }
+    script
+}
+
+/// Quotes `s` for POSIX shells.
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// Whether `pattern` contains any of the wildcards `glob_ends_with` supports.
+pub fn is_glob(pattern: &str) -> bool {
+    pattern.contains(|c| c == '*' || c == '?')
+}
+
+/// Whether the last components of `path` match `pattern`, like
+/// `Path::ends_with` but with wildcards: `*` and `?` match any number of
+/// characters or a single character within a component, and `**` matches any
+/// number of components. For example, `src/tools/*` matches `src/tools/cargo`
+/// and `ui*` matches `src/test/ui-fulldeps`.
+pub fn glob_ends_with(path: &Path, pattern: &str) -> bool {
+    let pattern = pattern.split(|c| c == '/' || c == '\\').filter(|c| !c.is_empty());
+    let pattern = pattern.collect::<Vec<_>>();
+    let path = path.iter().map(|c| c.to_string_lossy()).collect::<Vec<_>>();
+    let path = path.iter().map(|c| &c[..]).collect::<Vec<_>>();
+    return (0..=path.len()).any(|start| components_match(&pattern, &path[start..]));
+
+    fn components_match(pattern: &[&str], path: &[&str]) -> bool {
+        match pattern.split_first() {
+            None => path.is_empty(),
+            Some((&"**", rest)) => (0..=path.len()).any(|i| components_match(rest, &path[i..])),
+            Some((first, rest)) => match path.split_first() {
+                Some((component, path)) => {
+                    glob_matches(first, component) && components_match(rest, path)
+                }
+                None => false,
+            },
+        }
+    }
+}
+
+/// Matches `text` against a `pattern` in which `*` matches any number of
+/// characters and `?` exactly one.
+pub fn glob_matches(pattern: &str, text: &str) -> bool {
+    match pattern.chars().next() {
+        None => text.is_empty(),
+        Some('*') => {
+            let rest = &pattern[1..];
+            text.char_indices()
+                .map(|(i, _)| i)
+                .chain(Some(text.len()))
+                .any(|i| glob_matches(rest, &text[i..]))
+        }
+        Some(c) => {
+            let mut chars = text.chars();
+            match chars.next() {
+                Some(t) if c == '?' || c == t => {
+                    glob_matches(&pattern[c.len_utf8()..], chars.as_str())
+                }
+                _ => false,
+            }
+        }
+    }
+}
+
 pub struct TimeIt(bool, Instant);
 
 /// Returns an RAII structure that prints out how long it took to drop.
@@ -149,7 +336,6 @@ fn symlink_dir_inner(src: &Path, dest: &Path) -> io::Result<()> {
     // http://www.flexhex.com/docs/articles/hard-links.phtml
     #[cfg(windows)]
     fn symlink_dir_inner(target: &Path, junction: &Path) -> io::Result<()> {
-        use std::ffi::OsStr;
         use std::os::windows::ffi::OsStrExt;
         use std::ptr;
 
@@ -260,18 +446,104 @@ pub fn current() -> CiEnv {
             CiEnv::None
         }
     }
+}
+
+/// Whether stdout is a terminal, in which case `--color=auto` colors output.
+pub fn stdout_is_tty() -> bool {
+    return stdout_is_tty_inner();
+
+    #[cfg(unix)]
+    fn stdout_is_tty_inner() -> bool {
+        unsafe { libc::isatty(libc::STDOUT_FILENO) == 1 }
+    }
+
+    // The console may not understand ANSI escapes, so don't guess.
+    #[cfg(not(unix))]
+    fn stdout_is_tty_inner() -> bool {
+        false
+    }
+}
+
+/// Whether stdin is a terminal, so that x.py can ask questions.
+pub fn stdin_is_tty() -> bool {
+    return stdin_is_tty_inner();
+
+    #[cfg(unix)]
+    fn stdin_is_tty_inner() -> bool {
+        unsafe { libc::isatty(libc::STDIN_FILENO) == 1 }
+    }
+
+    #[cfg(not(unix))]
+    fn stdin_is_tty_inner() -> bool {
+        false
+    }
+}
+
+/// Minimum amount of free space, in bytes, required to use `build.tmp-dir`.
+const MIN_TMP_DIR_SPACE: u64 = 10 << 30;
+
+/// Default of `build.min-free-space`, in bytes.
+pub const MIN_FREE_SPACE: u64 = 1 << 30;
+
+/// Returns the number of bytes available to unprivileged users on the
+/// filesystem containing `path`, or `None` if this can't be determined.
+pub fn free_space(path: &Path) -> Option<u64> {
+    return free_space_inner(path);
 
-    /// If in a CI environment, forces the command to run with colors.
-    pub fn force_coloring_in_ci(self, cmd: &mut Command) {
-        if self != CiEnv::None {
-            // Due to use of stamp/docker, the output stream of rustbuild is not
-            // a TTY in CI, so coloring is by-default turned off.
-            // The explicit `TERM=xterm` environment is needed for
-            // `--color always` to actually work. This env var was lost when
-            // compiling through the Makefile. Very strange.
-            cmd.env("TERM", "xterm").args(&["--color", "always"]);
+    // The widths of these fields differ between platforms.
+    #[cfg(unix)]
+    #[allow(clippy::useless_conversion)]
+    fn free_space_inner(path: &Path) -> Option<u64> {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+        unsafe {
+            let mut stat: libc::statvfs = std::mem::zeroed();
+            if libc::statvfs(path.as_ptr(), &mut stat) != 0 {
+                return None;
+            }
+            Some(u64::from(stat.f_bavail) * u64::from(stat.f_frsize))
+        }
+    }
+
+    #[cfg(windows)]
+    fn free_space_inner(path: &Path) -> Option<u64> {
+        use std::os::windows::ffi::OsStrExt;
+        use std::ptr;
+
+        use winapi::um::fileapi::GetDiskFreeSpaceExW;
+        use winapi::um::winnt::ULARGE_INTEGER;
+
+        let path: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+        unsafe {
+            let mut avail: ULARGE_INTEGER = std::mem::zeroed();
+            if GetDiskFreeSpaceExW(path.as_ptr(), &mut avail, ptr::null_mut(), ptr::null_mut())
+                == 0
+            {
+                return None;
+            }
+            Some(*avail.QuadPart())
         }
     }
+
+    #[cfg(not(any(unix, windows)))]
+    fn free_space_inner(_path: &Path) -> Option<u64> {
+        None
+    }
+}
+
+/// Checks whether `dir` is usable as `build.tmp-dir`, creating it if needed.
+pub fn check_tmp_dir(dir: &Path) -> Result<(), String> {
+    fs::create_dir_all(dir).map_err(|e| format!("failed to create it: {}", e))?;
+    match free_space(dir) {
+        Some(avail) if avail < MIN_TMP_DIR_SPACE => Err(format!(
+            "only {} MiB free, but at least {} MiB are required",
+            avail >> 20,
+            MIN_TMP_DIR_SPACE >> 20
+        )),
+        _ => Ok(()),
+    }
 }
 
 pub fn forcing_clang_based_tests() -> bool {
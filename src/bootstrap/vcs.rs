@@ -0,0 +1,224 @@
+//! Queries of the version control system a source tree is checked out with.
+//!
+//! Git checkouts are recognized by their `.git`, which is a file rather than a
+//! directory in linked worktrees and submodules. Jujutsu checkouts have a
+//! `.jj` directory, and are queried with `jj` even when they are colocated
+//! with a git repository, since git's `HEAD` doesn't include the working copy
+//! commit there. Every query returns `None` rather than failing when the VCS
+//! can't answer it, e.g. because history is missing in a shallow clone, so
+//! that builds from tarballs or unusual checkouts still work.
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// The version control system of a source tree.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Vcs {
+    Git,
+    Jj,
+    /// No version control, as for a source tarball, or `build.vcs = "none"`.
+    None,
+}
+
+/// Information about the commit a source tree is checked out at.
+pub struct CommitInfo {
+    pub sha: String,
+    pub short_sha: String,
+    pub commit_date: String,
+}
+
+impl Vcs {
+    /// Parses the value of `build.vcs`.
+    pub fn from_config(name: &str) -> Vcs {
+        match name {
+            "git" => Vcs::Git,
+            "jj" => Vcs::Jj,
+            "none" => Vcs::None,
+            _ => panic!("unknown build.vcs `{}`, expected one of `git`, `jj` or `none`", name),
+        }
+    }
+
+    /// Guesses the version control system of the source tree at `dir`.
+    pub fn detect(dir: &Path) -> Vcs {
+        if dir.join(".jj").is_dir() {
+            Vcs::Jj
+        } else if dir.join(".git").exists() {
+            Vcs::Git
+        } else {
+            Vcs::None
+        }
+    }
+
+    /// Runs the VCS with `args` in `dir` and returns its output, or `None` if
+    /// that failed.
+    fn query(self, dir: &Path, args: &[&str]) -> Option<String> {
+        let program = match self {
+            Vcs::Git => "git",
+            Vcs::Jj => "jj",
+            Vcs::None => return None,
+        };
+        let out = Command::new(program)
+            .args(args)
+            .current_dir(dir)
+            .stderr(Stdio::null())
+            .output()
+            .ok()?;
+        if !out.status.success() {
+            return None;
+        }
+        String::from_utf8(out.stdout).ok()
+    }
+
+    /// Returns information about the commit checked out at `dir`. For jj, this
+    /// is the parent of the working copy commit, as the working copy commit
+    /// itself changes with every edit.
+    pub fn commit_info(self, dir: &Path) -> Option<CommitInfo> {
+        let out = match self {
+            Vcs::Git => self.query(dir, &["log", "-1", "--date=short", "--format=%H%n%cd"])?,
+            Vcs::Jj => self.query(
+                dir,
+                &[
+                    "log",
+                    "--no-graph",
+                    "-r",
+                    "@-",
+                    "-T",
+                    r#"commit_id ++ "\n" ++ committer.timestamp().format("%Y-%m-%d")"#,
+                ],
+            )?,
+            Vcs::None => return None,
+        };
+        let mut lines = out.lines();
+        let sha = lines.next()?.trim().to_string();
+        let commit_date = lines.next()?.trim().to_string();
+        let short_sha = sha.get(..9)?.to_string();
+        Some(CommitInfo { sha, short_sha, commit_date })
+    }
+
+    /// Returns the files changed between the merge base of the checked out
+    /// commit and `base`, and the checked out commit. `base` is a git branch,
+    /// such as `origin/master`.
+    pub fn changed_files(self, dir: &Path, base: &str) -> Option<Vec<PathBuf>> {
+        let files = match self {
+            Vcs::Git => {
+                let merge_base = self.query(dir, &["merge-base", "HEAD", base])?;
+                self.query(dir, &["diff", "--name-only", merge_base.trim(), "HEAD"])?
+            }
+            Vcs::Jj => {
+                let from = format!("fork_point(@ | {})", jj_bookmark(base));
+                self.query(dir, &["diff", "--name-only", "--from", &from, "--to", "@"])?
+            }
+            Vcs::None => return None,
+        };
+        Some(files.lines().map(PathBuf::from).collect())
+    }
+
+    /// Like `changed_files`, but also includes changes to tracked files which
+    /// haven't been committed yet. jj commits the working copy on every
+    /// command, so there the two are the same.
+    pub fn modified_files(self, dir: &Path, base: &str) -> Option<Vec<PathBuf>> {
+        match self {
+            Vcs::Git => {
+                let merge_base = self.query(dir, &["merge-base", "HEAD", base])?;
+                let files = self.query(dir, &["diff", "--name-only", merge_base.trim()])?;
+                Some(files.lines().map(PathBuf::from).collect())
+            }
+            Vcs::Jj => self.changed_files(dir, base),
+            Vcs::None => None,
+        }
+    }
+
+    /// Returns the uncommitted changes to the files in `dir`: the status of
+    /// the changed and untracked files followed by the diff of the tracked
+    /// ones, which is empty if there are none. For jj, this is the diff of the
+    /// working copy commit.
+    pub fn uncommitted_changes(self, dir: &Path) -> Option<String> {
+        match self {
+            Vcs::Git => {
+                let status =
+                    self.query(dir, &["status", "--porcelain", "--untracked-files=normal"])?;
+                let diff = self.query(dir, &["diff", "HEAD"])?;
+                Some(status + &diff)
+            }
+            Vcs::Jj => self.query(dir, &["diff", "--git"]),
+            Vcs::None => None,
+        }
+    }
+
+    /// Returns whether the tracked files at `paths`, relative to `dir`, have
+    /// uncommitted changes. For jj, these are changes in the working copy
+    /// commit.
+    pub fn has_uncommitted_changes(self, dir: &Path, paths: &[&str]) -> Option<bool> {
+        let mut args = match self {
+            Vcs::Git => vec!["status", "--porcelain", "--untracked-files=no", "--"],
+            Vcs::Jj => vec!["diff", "--summary", "--"],
+            Vcs::None => return None,
+        };
+        args.extend(paths);
+        Some(!self.query(dir, &args)?.trim().is_empty())
+    }
+
+    /// Returns an id of the committed content at `path`, relative to `dir`,
+    /// which changes whenever the content does. For git, this is the id of its
+    /// tree or blob. jj can't print those, so there it's the id of the commit,
+    /// as for `commit_info`, which changes more often.
+    pub fn content_id(self, dir: &Path, path: &str) -> Option<String> {
+        match self {
+            Vcs::Git => {
+                let id = self.query(dir, &["rev-parse", &format!("HEAD:{}", path)])?;
+                Some(id.trim().to_string())
+            }
+            Vcs::Jj => Some(self.commit_info(dir)?.sha),
+            Vcs::None => None,
+        }
+    }
+
+    /// Returns the files in `dir` which aren't tracked by the VCS. jj tracks
+    /// new files automatically, so there never are any.
+    pub fn untracked_files(self, dir: &Path) -> Option<Vec<String>> {
+        match self {
+            Vcs::Git => {
+                let status =
+                    self.query(dir, &["status", "--porcelain", "--untracked-files=normal"])?;
+                Some(
+                    status
+                        .lines()
+                        .filter_map(|entry| entry.strip_prefix("?? "))
+                        .map(|path| path.to_string())
+                        .collect(),
+                )
+            }
+            Vcs::Jj => Some(Vec::new()),
+            Vcs::None => None,
+        }
+    }
+
+    /// Returns the number of merge commits since the checked out commit
+    /// branched off `base`, a git branch such as `origin/master`.
+    pub fn merges_since(self, dir: &Path, base: &str) -> Option<u32> {
+        let count = match self {
+            Vcs::Git => {
+                // Note that we use a `..` range, not the `...` symmetric difference.
+                let range = format!("refs/remotes/{}..HEAD", base);
+                self.query(dir, &["rev-list", "--count", "--merges", &range])?
+            }
+            Vcs::Jj => {
+                let revset = format!("merges() & ({}..@-)", jj_bookmark(base));
+                let merges =
+                    self.query(dir, &["log", "--no-graph", "-r", &revset, "-T", r#""x""#])?;
+                merges.trim().len().to_string()
+            }
+            Vcs::None => return None,
+        };
+        count.trim().parse().ok()
+    }
+}
+
+/// Translates a git remote branch such as `origin/master` to the jj revset
+/// naming it, `master@origin`.
+fn jj_bookmark(branch: &str) -> String {
+    match branch.find('/') {
+        Some(i) => format!("{}@{}", &branch[i + 1..], &branch[..i]),
+        None => branch.to_string(),
+    }
+}
@@ -79,4 +79,21 @@ impl DocFS {
         }
         Ok(())
     }
+
+    /// Like `write`, but leaves the file alone if it already has `contents`.
+    ///
+    /// This is used for the static files that every rustdoc documenting into
+    /// the same output directory writes, so that crates documented in
+    /// parallel don't keep rewriting them while holding the shared lock.
+    crate fn write_if_changed<P, C, E>(&self, path: P, contents: C) -> Result<(), E>
+    where
+        P: AsRef<Path>,
+        C: AsRef<[u8]>,
+        E: PathError,
+    {
+        if fs::read(path.as_ref()).map_or(false, |existing| existing == contents.as_ref()) {
+            return Ok(());
+        }
+        self.write(path, contents)
+    }
 }
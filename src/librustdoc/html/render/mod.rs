@@ -703,8 +703,9 @@ fn write_shared(
     let lock_file = cx.dst.join(".lock");
     let _lock = try_err!(flock::Lock::new(&lock_file, true, true, true), &lock_file);
 
-    // Add all the static files. These may already exist, but we just
-    // overwrite them anyway to make sure that they're fresh and up-to-date.
+    // Add all the static files. These may already exist, in which case they
+    // are only overwritten if they differ, to make sure that they're fresh and
+    // up-to-date.
 
     write_minify(
         &cx.shared.fs,
@@ -766,7 +767,7 @@ fn write_shared(
         themes.insert(theme.to_owned());
     }
 
-    let write = |p, c| cx.shared.fs.write(p, c);
+    let write = |p, c| cx.shared.fs.write_if_changed(p, c);
     if (*cx.shared).layout.logo.is_empty() {
         write(cx.path("rust-logo.png"), static_files::RUST_LOGO)?;
     }
@@ -1199,12 +1200,12 @@ fn write_minify(
     if enable_minification {
         if dst.extension() == Some(&OsStr::new("css")) {
             let res = try_none!(minifier::css::minify(contents).ok(), &dst);
-            fs.write(dst, res.as_bytes())
+            fs.write_if_changed(dst, res.as_bytes())
         } else {
-            fs.write(dst, minifier::js::minify(contents).as_bytes())
+            fs.write_if_changed(dst, minifier::js::minify(contents).as_bytes())
         }
     } else {
-        fs.write(dst, contents.as_bytes())
+        fs.write_if_changed(dst, contents.as_bytes())
     }
 }
 
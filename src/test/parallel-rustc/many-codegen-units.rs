@@ -0,0 +1,37 @@
+// Codegens generic code split over many codegen units while the front-end runs
+// with many threads.
+//
+// build-pass
+// compile-flags: -Z threads=16 -C codegen-units=32
+
+pub mod a {
+    pub fn sum<T: Copy + Into<u64>>(xs: &[T]) -> u64 {
+        xs.iter().map(|&x| x.into()).sum()
+    }
+}
+
+pub mod b {
+    pub fn describe<T: std::fmt::Debug>(x: T) -> String {
+        format!("{:?}", x)
+    }
+}
+
+pub mod c {
+    use std::collections::HashMap;
+
+    pub fn count(words: &[&str]) -> HashMap<String, usize> {
+        let mut map = HashMap::new();
+        for word in words {
+            *map.entry(word.to_string()).or_insert(0) += 1;
+        }
+        map
+    }
+}
+
+fn main() {
+    assert_eq!(a::sum(&[1u8, 2, 3]), 6);
+    assert_eq!(a::sum(&[1u16, 2, 3]), 6);
+    assert_eq!(a::sum(&[1u32, 2, 3]), 6);
+    assert_eq!(b::describe(Some((1, "two"))), "Some((1, \"two\"))");
+    assert_eq!(c::count(&["a", "b", "a"])["a"], 2);
+}
@@ -0,0 +1,36 @@
+// Type-checks and codegens many independent items so the parallel front-end
+// has work to spread across threads.
+//
+// build-pass
+// compile-flags: -Z threads=16
+
+macro_rules! items {
+    ($($name:ident)*) => {
+        $(
+            pub struct $name<T>(T);
+
+            impl<T: Clone + std::fmt::Debug> $name<T> {
+                pub fn get(&self) -> T {
+                    self.0.clone()
+                }
+            }
+
+            impl<T: std::fmt::Debug> std::fmt::Display for $name<T> {
+                fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                    write!(f, "{}({:?})", stringify!($name), self.0)
+                }
+            }
+        )*
+
+        fn main() {
+            $(
+                let value = $name(vec![1u8, 2, 3]);
+                assert_eq!(value.get().len(), 3);
+                let _ = value.to_string();
+            )*
+        }
+    };
+}
+
+items!(A0 A1 A2 A3 A4 A5 A6 A7 A8 A9 B0 B1 B2 B3 B4 B5 B6 B7 B8 B9
+       C0 C1 C2 C3 C4 C5 C6 C7 C8 C9 D0 D1 D2 D3 D4 D5 D6 D7 D8 D9);
@@ -0,0 +1,48 @@
+// `-Z randomize-layout` shuffles the fields of Rust structs, but must leave
+// `#[repr(C)]` structs alone, and code which doesn't depend on the order of the
+// fields must keep working.
+
+// run-pass
+// compile-flags: -Z randomize-layout -Z layout-seed=42
+
+#[repr(C)]
+struct C {
+    a: u8,
+    b: u32,
+    c: u16,
+    d: u64,
+}
+
+struct Rust {
+    a: u8,
+    b: u32,
+    c: u16,
+    d: u64,
+}
+
+enum E {
+    A(u8, u32, u16),
+    B { x: u64, y: u8 },
+}
+
+fn offset<T, F>(base: &T, field: &F) -> usize {
+    field as *const F as usize - base as *const T as usize
+}
+
+fn main() {
+    let c = C { a: 1, b: 2, c: 3, d: 4 };
+    assert_eq!(offset(&c, &c.a), 0);
+    assert!(offset(&c, &c.a) < offset(&c, &c.b));
+    assert!(offset(&c, &c.b) < offset(&c, &c.c));
+    assert!(offset(&c, &c.c) < offset(&c, &c.d));
+
+    let r = Rust { a: 1, b: 2, c: 3, d: 4 };
+    assert_eq!((r.a, r.b, r.c, r.d), (1, 2, 3, 4));
+
+    for e in vec![E::A(1, 2, 3), E::B { x: 4, y: 5 }] {
+        match e {
+            E::A(a, b, c) => assert_eq!((a, b, c), (1, 2, 3)),
+            E::B { x, y } => assert_eq!((x, y), (4, 5)),
+        }
+    }
+}
@@ -327,6 +327,9 @@ pub struct Config {
     /// created in `/<build_base>/rustfix_missing_coverage.txt`
     pub rustfix_coverage: bool,
 
+    /// Whether to run tests even if their stamps say they're up to date
+    pub force_rerun: bool,
+
     /// whether to run `tidy` when a rustdoc test fails
     pub has_tidy: bool,
 
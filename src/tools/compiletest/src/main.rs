@@ -138,6 +138,7 @@ pub fn parse_config(args: Vec<String>) -> Config {
             "enable this to generate a Rustfix coverage file, which is saved in \
                 `./<build_base>/rustfix_missing_coverage.txt`",
         )
+        .optflag("", "force-rerun", "rerun tests even if the inputs are unchanged")
         .optflag("h", "help", "show this message");
 
     let (argv0, args_) = args.split_first().unwrap();
@@ -253,6 +254,7 @@ fn make_absolute(path: PathBuf) -> PathBuf {
         remote_test_client: matches.opt_str("remote-test-client").map(PathBuf::from),
         compare_mode: matches.opt_str("compare-mode").map(CompareMode::parse),
         rustfix_coverage: matches.opt_present("rustfix-coverage"),
+        force_rerun: matches.opt_present("force-rerun"),
         has_tidy,
 
         cc: matches.opt_str("cc").unwrap(),
@@ -626,13 +628,14 @@ fn make_test(config: &Config, testpaths: &TestPaths, inputs: &Stamp) -> Vec<test
         .map(|revision| {
             let ignore = early_props.ignore
                 // Ignore tests that already run and are up to date with respect to inputs.
-                || is_up_to_date(
-                    config,
-                    testpaths,
-                    &early_props,
-                    revision.map(|s| s.as_str()),
-                    inputs,
-                );
+                || (!config.force_rerun
+                    && is_up_to_date(
+                        config,
+                        testpaths,
+                        &early_props,
+                        revision.map(|s| s.as_str()),
+                        inputs,
+                    ));
             test::TestDescAndFn {
                 desc: test::TestDesc {
                     name: make_test_name(config, testpaths, revision),